@@ -1 +1,101 @@
 // Benchmark for transfer throughput
+//
+// Runs a `Server` in-process on loopback and drives it with the real client:
+// `Transfer` uploads of files of several sizes, and small requests about
+// stored files (a connection, one exchange on the status stream and the
+// close), which show how quickly the client and server loops react to
+// packets. Certificates, uploads and client sessions live in a temporary
+// directory.
+//
+// Run with: cargo bench --bench transfer_bench
+
+use sftpx::client::{Client, Transfer};
+use sftpx::common::cert_gen::generate_self_signed_cert;
+use sftpx::common::ClientConfig;
+use sftpx::server::{Server, ServerConfig};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FILE_SIZES: [usize; 3] = [1024 * 1024, 16 * 1024 * 1024, 64 * 1024 * 1024];
+const UPLOAD_ROUNDS: usize = 3;
+const REQUEST_ROUNDS: usize = 20;
+
+/// Start a server on a free loopback port, storing uploads below the
+/// current directory
+fn start_server(cert_dir: &Path) -> SocketAddr {
+    let config = ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        cert_path: cert_dir.join("cert.pem").to_string_lossy().into_owned(),
+        key_path: cert_dir.join("key.pem").to_string_lossy().into_owned(),
+        ..ServerConfig::default()
+    };
+
+    let (bound, addr) = mpsc::channel();
+    thread::spawn(move || {
+        let mut server = Server::new(config).expect("server starts");
+        bound.send(server.local_addr().expect("server is bound")).unwrap();
+        if let Err(e) = server.run() {
+            eprintln!("server stopped: {}", e);
+        }
+    });
+    addr.recv().expect("server starts")
+}
+
+fn median(mut samples: Vec<Duration>) -> Duration {
+    samples.sort();
+    samples[samples.len() / 2]
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let work_dir = tempfile::TempDir::new()?;
+    std::env::set_current_dir(work_dir.path())?;
+    let cert_dir = work_dir.path().join("certs");
+    generate_self_signed_cert("127.0.0.1", Some(cert_dir.to_str().unwrap()))?;
+
+    let server_addr = start_server(&cert_dir);
+    let config = ClientConfig::new(server_addr, "localhost".to_string())
+        .disable_cert_verification()
+        .with_session_dir(work_dir.path().join("sessions"));
+
+    println!("In-process server on {} ({} rounds per size)\n", server_addr, UPLOAD_ROUNDS);
+    println!("{:<12} {:>14} {:>14}", "file size", "median time", "MB/s");
+
+    let mut content_seed = 0u8;
+    for size in FILE_SIZES {
+        let mut times = Vec::with_capacity(UPLOAD_ROUNDS);
+        for round in 0..UPLOAD_ROUNDS {
+            // Fresh content and name, so nothing resumes or deduplicates
+            content_seed = content_seed.wrapping_add(1);
+            let path = work_dir.path().join(format!("bench-{}-{}.bin", size, round));
+            let content: Vec<u8> = (0..size).map(|i| (i as u8).wrapping_mul(31) ^ content_seed).collect();
+            std::fs::write(&path, &content)?;
+
+            let mut transfer = Transfer::send_file(config.clone(), path.to_str().unwrap(), "bench")?;
+            let started = Instant::now();
+            transfer.run_send(&path)?;
+            times.push(started.elapsed());
+            std::fs::remove_file(&path)?;
+        }
+
+        let time = median(times);
+        println!("{:<12} {:>11.1} ms {:>14.1}",
+            format!("{} MB", size / (1024 * 1024)),
+            time.as_secs_f64() * 1000.0,
+            size as f64 / time.as_secs_f64() / (1024.0 * 1024.0));
+    }
+
+    let client = Client::new(config);
+    let mut times = Vec::with_capacity(REQUEST_ROUNDS);
+    for _ in 0..REQUEST_ROUNDS {
+        let started = Instant::now();
+        client.list_versions("bench/none.bin")?;
+        times.push(started.elapsed());
+    }
+    println!("\nrequest about a stored file: {:.2} ms median over {} connections",
+        median(times).as_secs_f64() * 1000.0, REQUEST_ROUNDS);
+
+    Ok(())
+}
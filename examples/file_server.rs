@@ -1,7 +1,9 @@
 // Example: Integrated file server that handles both uploads and downloads
 // Run with: cargo run --example file_server
 
+use sftpx::protocol::{ConflictPolicy, MetadataPolicy};
 use sftpx::server::{Server, ServerConfig};
+use sftpx::storage::Retention;
 use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -18,6 +20,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         max_data: 100_000_000,   // 100 MB
         max_stream_data: 10_000_000, // 10 MB per stream
         max_streams: 100,
+        trusted_keys_path: None,
+        metadata_policy: MetadataPolicy::default(),
+        conflict_policy: ConflictPolicy::Overwrite,
+        retention: Retention::default(),
+        max_connections: 256,
     };
     
     // Set up directories
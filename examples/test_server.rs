@@ -1,7 +1,9 @@
 // Example: Test QUIC Server with Migration & Heartbeat support
 // Run with: cargo run --example test_server
 
+use sftpx::protocol::{ConflictPolicy, MetadataPolicy};
 use sftpx::server::{Server, ServerConfig};
+use sftpx::storage::Retention;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("=== QUIC Server Test with Migration & Heartbeat ===\n");
@@ -15,6 +17,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         max_data: 10_000_000,
         max_stream_data: 1_000_000,
         max_streams: 100,
+        trusted_keys_path: None,
+        metadata_policy: MetadataPolicy::default(),
+        conflict_policy: ConflictPolicy::Overwrite,
+        retention: Retention::default(),
+        max_connections: 256,
     };
     
     println!("Server Configuration:");
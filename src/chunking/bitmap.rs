@@ -1,5 +1,6 @@
 // Bitmap for tracking received chunks
 
+use super::bitmap_codec;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
@@ -51,6 +52,9 @@ impl ChunkBitmap {
     }
     
     /// Create a bitmap with exact known size
+    /// 
+    /// An empty file has no EOF chunk to wait for, so its bitmap is complete
+    /// from the start.
     pub fn with_exact_size(total_chunks: u32) -> Self {
         let bitmap_bytes = Self::capacity_to_bytes(total_chunks);
         
@@ -58,7 +62,7 @@ impl ChunkBitmap {
             bitmap: vec![0u8; bitmap_bytes],
             total_chunks: Some(total_chunks),
             received_count: 0,
-            have_eof: false,
+            have_eof: total_chunks == 0,
             capacity: total_chunks,
         }
     }
@@ -287,51 +291,41 @@ impl ChunkBitmap {
     
     /// Save bitmap to disk for resumability
     /// 
-    /// Format: [total_chunks: u32][received_count: u32][have_eof: u8][capacity: u32][bitmap_data: bytes]
+    /// Written in the compact encoding of `to_bytes`
     pub fn save_to_disk<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
         let mut file = File::create(path)?;
-        
-        // Write header
-        file.write_all(&self.total_chunks.unwrap_or(0).to_le_bytes())?;
-        file.write_all(&self.received_count.to_le_bytes())?;
-        file.write_all(&[if self.have_eof { 1 } else { 0 }])?;
-        file.write_all(&self.capacity.to_le_bytes())?;
-        
-        // Write bitmap data
-        file.write_all(&self.bitmap)?;
-        
+        file.write_all(&self.to_bytes())?;
         file.sync_all()?;
         Ok(())
     }
     
     /// Load bitmap from disk for resume
+    /// 
+    /// Also reads the older raw format:
+    /// [total_chunks: u32][received_count: u32][have_eof: u8][capacity: u32][bitmap_data: bytes]
     pub fn load_from_disk<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
-        let mut file = File::open(path)?;
-        
-        // Read header
-        let mut total_chunks_bytes = [0u8; 4];
-        file.read_exact(&mut total_chunks_bytes)?;
-        let total_chunks_val = u32::from_le_bytes(total_chunks_bytes);
-        let total_chunks = if total_chunks_val > 0 { Some(total_chunks_val) } else { None };
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
         
-        let mut received_count_bytes = [0u8; 4];
-        file.read_exact(&mut received_count_bytes)?;
-        let received_count = u32::from_le_bytes(received_count_bytes);
-        
-        let mut have_eof_byte = [0u8; 1];
-        file.read_exact(&mut have_eof_byte)?;
-        let have_eof = have_eof_byte[0] != 0;
+        if bitmap_codec::is_encoded(&data) {
+            return Self::from_bytes(&data);
+        }
         
-        let mut capacity_bytes = [0u8; 4];
-        file.read_exact(&mut capacity_bytes)?;
-        let capacity = u32::from_le_bytes(capacity_bytes);
+        if data.len() < 13 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "bitmap file too short",
+            ));
+        }
         
-        // Read bitmap data
-        let mut bitmap = Vec::new();
-        file.read_to_end(&mut bitmap)?;
+        let total_chunks_val = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let total_chunks = if total_chunks_val > 0 { Some(total_chunks_val) } else { None };
+        let received_count = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        let have_eof = data[8] != 0;
+        let capacity = u32::from_le_bytes(data[9..13].try_into().unwrap());
         
         Ok(Self {
-            bitmap,
+            bitmap: data[13..].to_vec(),
             total_chunks,
             received_count,
             have_eof,
@@ -339,20 +333,44 @@ impl ChunkBitmap {
         })
     }
     
-    /// Get raw bitmap bytes for transmission in ResumeRequest
+    /// Encode the bitmap for transmission in ResumeRequest/ResumeResponse
+    /// 
+    /// Uses the compact format from `flatbuffers/bitmap.fbs`: contiguous
+    /// ranges are run-length encoded, scattered chunks fall back to raw bits.
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.bitmap.clone()
+        bitmap_codec::encode(
+            self.total_chunks.unwrap_or(0),
+            self.capacity,
+            self.have_eof,
+            &self.bitmap,
+        )
+    }
+    
+    /// Decode a bitmap produced by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> std::io::Result<Self> {
+        let decoded = bitmap_codec::decode(data)?;
+        let received_count = decoded.bitmap.iter().map(|byte| byte.count_ones()).sum();
+        
+        // No chunks and EOF seen is an empty file; no EOF, a total not yet known
+        let known = decoded.total_chunks > 0 || decoded.have_eof;
+        Ok(Self {
+            bitmap: decoded.bitmap,
+            total_chunks: known.then_some(decoded.total_chunks),
+            received_count,
+            have_eof: decoded.have_eof,
+            capacity: decoded.capacity,
+        })
     }
     
     /// Get list of received chunk indices for ResumeRequest
     pub fn get_received_chunks(&self) -> Vec<u64> {
         let mut received = Vec::new();
         
-        if let Some(total) = self.total_chunks {
-            for chunk_num in 0..total {
-                if self.is_received(chunk_num) {
-                    received.push(chunk_num as u64);
-                }
+        // Until the EOF chunk arrives, any chunk the bitmap has room for
+        let total = self.total_chunks.unwrap_or(self.capacity);
+        for chunk_num in 0..total {
+            if self.is_received(chunk_num) {
+                received.push(chunk_num as u64);
             }
         }
         
@@ -434,6 +452,19 @@ mod tests {
         assert_eq!(bitmap.progress(), 100.0);
     }
 
+    #[test]
+    fn test_empty_file_is_complete() {
+        let bitmap = ChunkBitmap::with_exact_size(0);
+        assert!(bitmap.is_complete());
+        assert!(bitmap.find_missing().is_empty());
+
+        // Still an empty file, not an unknown length, after a round trip
+        let decoded = ChunkBitmap::from_bytes(&bitmap.to_bytes()).unwrap();
+        assert_eq!(decoded.total_chunks(), Some(0));
+        assert!(decoded.is_complete());
+        assert_eq!(ChunkBitmap::from_bytes(&ChunkBitmap::new(0).to_bytes()).unwrap().total_chunks(), None);
+    }
+
     #[test]
     fn test_find_missing() {
         let mut bitmap = ChunkBitmap::new(10);
@@ -534,6 +565,45 @@ mod tests {
         std::fs::remove_file(path).ok();
     }
     
+    #[test]
+    fn test_load_legacy_format() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("legacy.bitmap");
+        
+        // total=5, received=2, eof, capacity=8, chunks 1 and 4
+        let mut data = Vec::new();
+        data.extend_from_slice(&5u32.to_le_bytes());
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.push(1);
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.push(0b0001_0010);
+        std::fs::write(&path, &data).unwrap();
+        
+        let loaded = ChunkBitmap::load_from_disk(&path).unwrap();
+        assert_eq!(loaded.total_chunks(), Some(5));
+        assert_eq!(loaded.get_received_chunks(), vec![1, 4]);
+        assert!(loaded.has_eof());
+    }
+    
+    #[test]
+    fn test_bytes_roundtrip_is_compact() {
+        let total = 2_000_000;
+        let mut bitmap = ChunkBitmap::with_exact_size(total);
+        for chunk in 0..total - 1000 {
+            bitmap.mark_received(chunk, false);
+        }
+        
+        // ~250 KB of raw bits collapses to a single run
+        let bytes = bitmap.to_bytes();
+        assert!(bytes.len() < 64, "encoded {} bytes", bytes.len());
+        
+        let decoded = ChunkBitmap::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.total_chunks(), Some(total));
+        assert_eq!(decoded.received_count(), total - 1000);
+        assert_eq!(decoded.find_first_missing(1), vec![total - 1000]);
+        assert!(ChunkBitmap::from_bytes(&bitmap.bitmap).is_err());
+    }
+    
     #[test]
     fn test_get_received_chunks() {
         let mut bitmap = ChunkBitmap::new(10);
@@ -543,5 +613,11 @@ mod tests {
         
         let received = bitmap.get_received_chunks();
         assert_eq!(received, vec![0, 2, 4]);
+        
+        // Before the EOF chunk the total is unknown
+        let mut bitmap = ChunkBitmap::new(0);
+        bitmap.mark_received(1, false);
+        bitmap.mark_received(1500, false);
+        assert_eq!(bitmap.get_received_chunks(), vec![1, 1500]);
     }
 }
@@ -0,0 +1,264 @@
+// Compact chunk bitmap encoding (flatbuffers/bitmap.fbs)
+//
+// Used on the wire for resume requests/responses and on disk for .bitmap
+// files. Written against the flatbuffers runtime directly because flatc's
+// generated code does not match the runtime version (see proto/mod.rs).
+
+use flatbuffers::{
+    FlatBufferBuilder, Follow, ForwardsUOffset, InvalidFlatbuffer, Table, VOffsetT, Vector,
+    Verifiable, Verifier,
+};
+use std::io;
+
+/// File identifier from bitmap.fbs
+pub(crate) const FILE_IDENTIFIER: &str = "SXBM";
+
+const VT_TOTAL_CHUNKS: VOffsetT = 4;
+const VT_CAPACITY: VOffsetT = 6;
+const VT_HAVE_EOF: VOffsetT = 8;
+const VT_RUNS: VOffsetT = 10;
+const VT_BITS: VOffsetT = 12;
+
+/// Bitmap fields recovered from an encoded buffer
+pub(crate) struct DecodedBitmap {
+    pub total_chunks: u32,
+    pub capacity: u32,
+    pub have_eof: bool,
+    /// Raw bitmap, `ceil(capacity / 8)` bytes, LSB first
+    pub bitmap: Vec<u8>,
+}
+
+/// Encode a raw bitmap, picking run-length or raw bits, whichever is smaller
+pub(crate) fn encode(total_chunks: u32, capacity: u32, have_eof: bool, bitmap: &[u8]) -> Vec<u8> {
+    let runs = to_runs(bitmap, capacity);
+    let use_runs = runs.len() * 4 < bitmap.len();
+
+    let mut builder = FlatBufferBuilder::with_capacity(64 + bitmap.len().min(runs.len() * 4));
+    let runs = if use_runs { Some(builder.create_vector(&runs)) } else { None };
+    let bits = if use_runs { None } else { Some(builder.create_vector(bitmap)) };
+
+    let start = builder.start_table();
+    builder.push_slot::<u32>(VT_TOTAL_CHUNKS, total_chunks, 0);
+    builder.push_slot::<u32>(VT_CAPACITY, capacity, 0);
+    builder.push_slot::<bool>(VT_HAVE_EOF, have_eof, false);
+    if let Some(runs) = runs {
+        builder.push_slot_always(VT_RUNS, runs);
+    }
+    if let Some(bits) = bits {
+        builder.push_slot_always(VT_BITS, bits);
+    }
+    let root = builder.end_table(start);
+    builder.finish(root, Some(FILE_IDENTIFIER));
+
+    builder.finished_data().to_vec()
+}
+
+/// Whether a buffer carries the bitmap file identifier
+pub(crate) fn is_encoded(data: &[u8]) -> bool {
+    data.len() >= 8 && flatbuffers::buffer_has_identifier(data, FILE_IDENTIFIER, false)
+}
+
+/// Decode and validate an encoded bitmap
+pub(crate) fn decode(data: &[u8]) -> io::Result<DecodedBitmap> {
+    if !is_encoded(data) {
+        return Err(invalid("missing chunk bitmap identifier".to_string()));
+    }
+
+    let table = flatbuffers::root::<BitmapTable>(data)
+        .map_err(|e| invalid(format!("malformed chunk bitmap: {}", e)))?;
+
+    let total_chunks = table.total_chunks();
+    let capacity = table.capacity();
+    if total_chunks > capacity {
+        return Err(invalid(format!(
+            "chunk bitmap total {} exceeds capacity {}",
+            total_chunks, capacity
+        )));
+    }
+
+    let mut bitmap = vec![0u8; capacity.div_ceil(8) as usize];
+    if let Some(runs) = table.runs() {
+        from_runs(runs, capacity, &mut bitmap)?;
+    } else if let Some(bits) = table.bits() {
+        if bits.len() > bitmap.len() {
+            return Err(invalid(format!(
+                "chunk bitmap has {} bytes for {} chunks",
+                bits.len(),
+                capacity
+            )));
+        }
+        bitmap[..bits.len()].copy_from_slice(bits.bytes());
+
+        // Ignore stray bits past the capacity
+        if capacity & 7 != 0 {
+            if let Some(last) = bitmap.last_mut() {
+                *last &= (1u8 << (capacity & 7)) - 1;
+            }
+        }
+    }
+
+    Ok(DecodedBitmap {
+        total_chunks,
+        capacity,
+        have_eof: table.have_eof(),
+        bitmap,
+    })
+}
+
+/// Alternating run lengths starting with missing chunks; a trailing run of
+/// missing chunks is implied and not written
+fn to_runs(bitmap: &[u8], capacity: u32) -> Vec<u32> {
+    let mut runs = Vec::new();
+    let mut received = false;
+    let mut length = 0u32;
+
+    for chunk in 0..capacity {
+        let bit = bitmap[(chunk >> 3) as usize] & (1 << (chunk & 7)) != 0;
+        if bit != received {
+            runs.push(length);
+            received = bit;
+            length = 0;
+        }
+        length += 1;
+    }
+
+    if received {
+        runs.push(length);
+    }
+    runs
+}
+
+fn from_runs(runs: Vector<'_, u32>, capacity: u32, bitmap: &mut [u8]) -> io::Result<()> {
+    let mut position = 0u64;
+
+    for (idx, length) in runs.iter().enumerate() {
+        let end = position + length as u64;
+        if end > capacity as u64 {
+            return Err(invalid(format!("chunk bitmap runs exceed capacity {}", capacity)));
+        }
+        if idx & 1 == 1 {
+            for chunk in position..end {
+                bitmap[(chunk >> 3) as usize] |= 1 << (chunk & 7);
+            }
+        }
+        position = end;
+    }
+
+    Ok(())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read view of the `ChunkBitmap` table
+struct BitmapTable<'a> {
+    table: Table<'a>,
+}
+
+impl<'a> Follow<'a> for BitmapTable<'a> {
+    type Inner = BitmapTable<'a>;
+
+    unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        BitmapTable { table: Table::new(buf, loc) }
+    }
+}
+
+impl Verifiable for BitmapTable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<u32>("total_chunks", VT_TOTAL_CHUNKS, false)?
+            .visit_field::<u32>("capacity", VT_CAPACITY, false)?
+            .visit_field::<bool>("have_eof", VT_HAVE_EOF, false)?
+            .visit_field::<ForwardsUOffset<Vector<'_, u32>>>("runs", VT_RUNS, false)?
+            .visit_field::<ForwardsUOffset<Vector<'_, u8>>>("bits", VT_BITS, false)?
+            .finish();
+        Ok(())
+    }
+}
+
+// Safety: tables are only obtained through `flatbuffers::root`, which has
+// verified every field type below
+impl<'a> BitmapTable<'a> {
+    fn total_chunks(&self) -> u32 {
+        unsafe { self.table.get::<u32>(VT_TOTAL_CHUNKS, Some(0)).unwrap() }
+    }
+
+    fn capacity(&self) -> u32 {
+        unsafe { self.table.get::<u32>(VT_CAPACITY, Some(0)).unwrap() }
+    }
+
+    fn have_eof(&self) -> bool {
+        unsafe { self.table.get::<bool>(VT_HAVE_EOF, Some(false)).unwrap() }
+    }
+
+    fn runs(&self) -> Option<Vector<'a, u32>> {
+        unsafe { self.table.get::<ForwardsUOffset<Vector<'a, u32>>>(VT_RUNS, None) }
+    }
+
+    fn bits(&self) -> Option<Vector<'a, u8>> {
+        unsafe { self.table.get::<ForwardsUOffset<Vector<'a, u8>>>(VT_BITS, None) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitmap_of(capacity: u32, received: impl Iterator<Item = u32>) -> Vec<u8> {
+        let mut bitmap = vec![0u8; capacity.div_ceil(8) as usize];
+        for chunk in received {
+            bitmap[(chunk >> 3) as usize] |= 1 << (chunk & 7);
+        }
+        bitmap
+    }
+
+    #[test]
+    fn test_contiguous_bitmap_uses_runs() {
+        let capacity = 1_000_000;
+        let bitmap = bitmap_of(capacity, (0..400_000).chain(400_100..capacity));
+
+        let encoded = encode(capacity, capacity, true, &bitmap);
+        assert!(encoded.len() < 100, "encoded {} bytes", encoded.len());
+
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(decoded.total_chunks, capacity);
+        assert_eq!(decoded.capacity, capacity);
+        assert!(decoded.have_eof);
+        assert_eq!(decoded.bitmap, bitmap);
+    }
+
+    #[test]
+    fn test_scattered_bitmap_uses_bits() {
+        let capacity = 10_000;
+        let bitmap = bitmap_of(capacity, (0..capacity).filter(|c| c % 3 == 0));
+
+        let encoded = encode(capacity, capacity, false, &bitmap);
+        assert!(encoded.len() < bitmap.len() + 64);
+
+        let decoded = decode(&encoded).unwrap();
+        assert!(!decoded.have_eof);
+        assert_eq!(decoded.bitmap, bitmap);
+    }
+
+    #[test]
+    fn test_empty_and_unknown_total() {
+        let bitmap = vec![0u8; 128];
+        let decoded = decode(&encode(0, 1024, false, &bitmap)).unwrap();
+        assert_eq!(decoded.total_chunks, 0);
+        assert_eq!(decoded.bitmap, bitmap);
+    }
+
+    #[test]
+    fn test_rejects_invalid_input() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(b"not a bitmap at all").is_err());
+
+        // Total larger than capacity
+        assert!(decode(&encode(20, 10, false, &[0u8; 2])).is_err());
+
+        // Truncated buffer
+        let encoded = encode(16, 16, true, &[0xff, 0x00]);
+        assert!(decode(&encoded[..encoded.len() - 4]).is_err());
+    }
+}
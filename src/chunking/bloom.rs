@@ -0,0 +1,146 @@
+// Bloom filter over chunk hashes
+//
+// Sent by the server so a client can tell which chunks are certainly new
+// without asking. `contains` never returns false for an inserted hash;
+// false positives only cost a regular hash-check round trip.
+
+use crate::common::error::{Error, Result};
+
+/// Header: [num_hashes: u32 BE][num_bits: u64 BE]
+const HEADER_LEN: usize = 12;
+
+/// Upper bound on hash functions accepted from the wire
+const MAX_HASHES: u32 = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Size a filter for `items` entries at the given false positive rate
+    pub fn with_capacity(items: usize, false_positive_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        let rate = false_positive_rate.clamp(1e-6, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+
+        let num_bits = ((-items * rate.ln()) / (ln2 * ln2)).ceil().max(8.0) as u64;
+        let num_hashes = ((num_bits as f64 / items) * ln2).round().clamp(1.0, MAX_HASHES as f64) as u32;
+
+        Self {
+            bits: vec![0u8; num_bits.div_ceil(8) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    /// Add a chunk hash
+    pub fn insert(&mut self, item: &[u8]) {
+        for bit in self.bit_positions(item) {
+            self.bits[(bit >> 3) as usize] |= 1 << (bit & 7);
+        }
+    }
+
+    /// `false` means the hash was definitely never inserted
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.bit_positions(item)
+            .all(|bit| self.bits[(bit >> 3) as usize] & (1 << (bit & 7)) != 0)
+    }
+
+    /// Encoded size in bytes
+    pub fn size_bytes(&self) -> usize {
+        HEADER_LEN + self.bits.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size_bytes());
+        bytes.extend_from_slice(&self.num_hashes.to_be_bytes());
+        bytes.extend_from_slice(&self.num_bits.to_be_bytes());
+        bytes.extend_from_slice(&self.bits);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::Protocol("Bloom filter too short".to_string()));
+        }
+
+        let num_hashes = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+        let num_bits = u64::from_be_bytes(bytes[4..12].try_into().unwrap());
+        let bits = bytes[HEADER_LEN..].to_vec();
+
+        if num_hashes == 0 || num_hashes > MAX_HASHES || num_bits == 0 {
+            return Err(Error::Protocol(format!(
+                "Invalid Bloom filter parameters: {} hashes, {} bits",
+                num_hashes, num_bits
+            )));
+        }
+        if bits.len() as u64 != num_bits.div_ceil(8) {
+            return Err(Error::Protocol(format!(
+                "Bloom filter has {} bytes for {} bits",
+                bits.len(),
+                num_bits
+            )));
+        }
+
+        Ok(Self { bits, num_bits, num_hashes })
+    }
+
+    /// Double hashing (Kirsch-Mitzenmacher) over a BLAKE3 digest of the item
+    fn bit_positions(&self, item: &[u8]) -> impl Iterator<Item = u64> {
+        let digest = blake3::hash(item);
+        let bytes = digest.as_bytes();
+        let h1 = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(bytes[8..16].try_into().unwrap()) | 1;
+        let num_bits = self.num_bits;
+
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(i: u64) -> Vec<u8> {
+        blake3::hash(&i.to_le_bytes()).as_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_no_false_negatives() {
+        let mut filter = BloomFilter::with_capacity(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(&hash(i));
+        }
+        assert!((0..1000).all(|i| filter.contains(&hash(i))));
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let mut filter = BloomFilter::with_capacity(5000, 0.01);
+        for i in 0..5000 {
+            filter.insert(&hash(i));
+        }
+
+        let false_positives = (5000..25000).filter(|&i| filter.contains(&hash(i))).count();
+        assert!(false_positives < 400, "{} false positives in 20000", false_positives);
+    }
+
+    #[test]
+    fn test_bytes_roundtrip() {
+        let mut filter = BloomFilter::with_capacity(100, 0.05);
+        filter.insert(&hash(7));
+
+        let bytes = filter.to_bytes();
+        assert_eq!(bytes.len(), filter.size_bytes());
+
+        let decoded = BloomFilter::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, filter);
+        assert!(decoded.contains(&hash(7)));
+
+        assert!(BloomFilter::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(BloomFilter::from_bytes(&[0u8; 4]).is_err());
+    }
+}
@@ -6,6 +6,8 @@ use crate::common::error::{Error, Result};
 use crate::common::types::DEFAULT_CHUNK_SIZE;
 use crate::protocol::chunk::ChunkPacketBuilder;
 use crate::chunking::compress::CompressionType;
+use crate::chunking::sparse;
+use crate::protocol::messages::ChunkRange;
 
 /// File chunker that splits files into fixed-size chunks with metadata
 pub struct FileChunker {
@@ -15,6 +17,8 @@ pub struct FileChunker {
     current_chunk: u64,
     bytes_read: u64,
     builder: ChunkPacketBuilder,
+    /// Chunks in holes of a sparse file, which are skipped
+    holes: Vec<ChunkRange>,
 }
 
 impl FileChunker {
@@ -52,9 +56,16 @@ impl FileChunker {
             current_chunk: 0,
             bytes_read: 0,
             builder: ChunkPacketBuilder::with_compression(compression),
+            holes: Vec::new(),
         })
     }
 
+    /// Skip the chunks a manifest lists as holes; they are never read
+    pub fn with_holes(mut self, holes: Vec<ChunkRange>) -> Self {
+        self.holes = holes;
+        self
+    }
+
     /// Get the total number of chunks for this file
     pub fn total_chunks(&self) -> u64 {
         (self.file_size + self.chunk_size as u64 - 1) / self.chunk_size as u64
@@ -86,6 +97,18 @@ impl FileChunker {
     /// Read and create the next chunk packet
     /// Returns None when all chunks have been read
     pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if sparse::is_hole(&self.holes, self.current_chunk) {
+            let next_data = self.holes.iter()
+                .find(|range| range.contains(self.current_chunk))
+                .map_or(self.current_chunk, ChunkRange::end_chunk);
+            if next_data >= self.total_chunks() {
+                self.current_chunk = self.total_chunks();
+                self.bytes_read = self.file_size;
+                return Ok(None);
+            }
+            self.seek_to_chunk(next_data)?;
+        }
+
         if self.bytes_read >= self.file_size {
             return Ok(None);
         }
@@ -216,6 +239,25 @@ mod tests {
         assert_eq!(count, 4);
     }
 
+    #[test]
+    fn test_chunker_skips_holes() {
+        use crate::protocol::chunk::ChunkPacketParser;
+
+        let test_file = create_test_file(1050);
+        let holes = vec![
+            ChunkRange { first_chunk: 1, chunk_count: 2 },
+            ChunkRange { first_chunk: 7, chunk_count: 4 },
+        ];
+        let mut chunker = FileChunker::new(test_file.path(), Some(100)).unwrap().with_holes(holes);
+
+        let mut ids = Vec::new();
+        while let Some(packet) = chunker.next_chunk().unwrap() {
+            ids.push(ChunkPacketParser::parse(&packet).unwrap().chunk_id);
+        }
+        assert_eq!(ids, vec![0, 3, 4, 5, 6]);
+        assert_eq!(chunker.progress(), 1.0);
+    }
+
     #[test]
     fn test_chunker_progress() {
         let test_file = create_test_file(1000);
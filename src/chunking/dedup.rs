@@ -1,9 +1,10 @@
 // Chunk deduplication based on content hashing
+use super::bloom::BloomFilter;
 use crate::common::error::{Error, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::fs;
-use std::io::{BufReader, BufRead, Write};
+use std::io::{BufReader, BufRead, Read, Seek, SeekFrom, Write};
 
 /// Maps chunk hashes (BLAKE3) to file locations
 /// Format: hash -> (file_path, byte_offset, chunk_size)
@@ -63,14 +64,43 @@ impl ChunkHashIndex {
             .collect()
     }
     
+    /// Read a chunk's data from any indexed location that still holds it
+    /// 
+    /// Locations are re-hashed before use, so files that changed since they
+    /// were indexed are skipped.
+    pub fn read_chunk(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        self.index.get(hash)?.iter().find_map(|location| {
+            let mut file = fs::File::open(&location.file_path).ok()?;
+            file.seek(SeekFrom::Start(location.byte_offset)).ok()?;
+            
+            let mut data = vec![0u8; location.chunk_size as usize];
+            file.read_exact(&mut data).ok()?;
+            
+            (blake3::hash(&data).as_bytes() == hash).then_some(data)
+        })
+    }
+    
+    /// Build a Bloom filter of every indexed hash for clients to pre-check against
+    pub fn bloom_filter(&self, false_positive_rate: f64) -> BloomFilter {
+        let mut filter = BloomFilter::with_capacity(self.index.len(), false_positive_rate);
+        for hash in self.index.keys() {
+            filter.insert(hash);
+        }
+        filter
+    }
+    
     /// Get total number of unique chunks
     pub fn total_chunks(&self) -> usize {
         self.index.len()
     }
     
     /// Save index to disk
+    /// 
+    /// The index is written to a temporary file that then replaces the old
+    /// one, so a reader never sees it half written.
     pub fn save(&self) -> Result<()> {
-        let mut file = fs::File::create(&self.index_file)?;
+        let temp_file = self.index_file.with_extension("db.tmp");
+        let mut file = std::io::BufWriter::new(fs::File::create(&temp_file)?);
         
         for (hash, locations) in &self.index {
             for location in locations {
@@ -86,6 +116,8 @@ impl ChunkHashIndex {
             }
         }
         
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_file, &self.index_file)?;
         Ok(())
     }
     
@@ -138,6 +170,17 @@ impl ChunkHashIndex {
             !locations.is_empty()
         });
     }
+    
+    /// Remove entries for several files at once
+    pub fn remove_files(&mut self, file_paths: &HashSet<PathBuf>) {
+        if file_paths.is_empty() {
+            return;
+        }
+        self.index.retain(|_, locations| {
+            locations.retain(|loc| !file_paths.contains(&loc.file_path));
+            !locations.is_empty()
+        });
+    }
 }
 
 /// Deduplication statistics
@@ -215,6 +258,32 @@ mod tests {
         assert!(!existing.contains(&hash2));
     }
     
+    #[test]
+    fn test_read_chunk_and_bloom_filter() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut index = ChunkHashIndex::new(temp_dir.path()).unwrap();
+        
+        let file_path = temp_dir.path().join("stored.bin");
+        fs::write(&file_path, b"aaaabbbbcccc").unwrap();
+        
+        let hash = blake3::hash(b"bbbb").as_bytes().to_vec();
+        index.add_chunk(hash.clone(), ChunkLocation {
+            file_path: file_path.clone(),
+            byte_offset: 4,
+            chunk_size: 4,
+        });
+        
+        assert_eq!(index.read_chunk(&hash).unwrap(), b"bbbb");
+        
+        let filter = index.bloom_filter(0.01);
+        assert!(filter.contains(&hash));
+        
+        // Stored file changed since it was indexed
+        fs::write(&file_path, b"aaaaXXXXcccc").unwrap();
+        assert!(index.read_chunk(&hash).is_none());
+        assert!(index.read_chunk(&[0u8; 32]).is_none());
+    }
+    
     #[test]
     fn test_save_and_load() {
         let temp_dir = TempDir::new().unwrap();
@@ -231,6 +300,8 @@ mod tests {
             let mut index = ChunkHashIndex::new(temp_dir.path()).unwrap();
             index.add_chunk(hash.clone(), location.clone());
             index.save().unwrap();
+            index.save().unwrap();
+            assert!(!temp_dir.path().join("chunk_index.db.tmp").exists());
         }
         
         // Load in new instance
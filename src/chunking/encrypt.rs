@@ -0,0 +1,534 @@
+// Client-side chunk encryption (AEAD via ring)
+//
+// Chunks are sealed before they leave the client so the server only ever
+// stores ciphertext. Sealing is deterministic per (key, chunk_id, plaintext):
+// the nonce is a keyed BLAKE3 hash of the chunk, which keeps resume and dedup
+// working while never reusing a nonce for two different plaintexts.
+//
+// Sealed chunk layout: nonce (12 bytes) || ciphertext || tag (16 bytes)
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::pbkdf2;
+use crate::common::error::{Error, Result};
+use crate::protocol::messages::EncryptionInfo;
+
+/// AEAD key length in bytes
+pub const KEY_LEN: usize = 32;
+
+/// AEAD nonce length in bytes
+pub const NONCE_LEN: usize = 12;
+
+/// AEAD tag length in bytes
+pub const TAG_LEN: usize = 16;
+
+/// Bytes added to every chunk by sealing
+pub const SEALED_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// Default PBKDF2 iteration count for passphrase-derived keys
+pub const DEFAULT_KDF_ITERATIONS: u32 = 600_000;
+
+/// Salt length for passphrase-derived keys
+const SALT_LEN: usize = 16;
+
+/// Length of the key check value stored in the manifest
+const KEY_CHECK_LEN: usize = 16;
+
+const KDF_PBKDF2: &str = "pbkdf2-sha256";
+const KDF_KEY_FILE: &str = "key-file";
+
+// BLAKE3 derive_key contexts (must never change once data is stored)
+const CONTEXT_ENCRYPTION: &str = "sftpx 2024 chunk encryption key";
+const CONTEXT_NONCE: &str = "sftpx 2024 chunk nonce key";
+const CONTEXT_KEY_CHECK: &str = "sftpx 2024 key check";
+const CONTEXT_SALT: &str = "sftpx 2024 passphrase salt";
+
+/// Supported AEAD algorithms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionAlgorithm {
+    #[default]
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl EncryptionAlgorithm {
+    /// Name recorded in the manifest
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EncryptionAlgorithm::ChaCha20Poly1305 => "chacha20-poly1305",
+            EncryptionAlgorithm::Aes256Gcm => "aes-256-gcm",
+        }
+    }
+
+    /// Parse an algorithm name from the manifest
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chacha20-poly1305" => Some(EncryptionAlgorithm::ChaCha20Poly1305),
+            "aes-256-gcm" => Some(EncryptionAlgorithm::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    fn aead(&self) -> &'static aead::Algorithm {
+        match self {
+            EncryptionAlgorithm::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+            EncryptionAlgorithm::Aes256Gcm => &aead::AES_256_GCM,
+        }
+    }
+}
+
+/// Where the encryption key comes from
+#[derive(Clone)]
+pub enum KeySource {
+    /// Key derived from a passphrase with PBKDF2-HMAC-SHA256
+    Passphrase(String),
+    /// 32-byte key read from a file (raw or hex-encoded)
+    KeyFile(PathBuf),
+}
+
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::Passphrase(_) => write!(f, "Passphrase(<redacted>)"),
+            KeySource::KeyFile(path) => write!(f, "KeyFile({:?})", path),
+        }
+    }
+}
+
+/// Client-side encryption settings
+#[derive(Debug, Clone)]
+pub struct EncryptionConfig {
+    pub algorithm: EncryptionAlgorithm,
+    pub key_source: KeySource,
+    pub kdf_iterations: u32,
+}
+
+impl EncryptionConfig {
+    /// Encrypt with a key derived from a passphrase
+    ///
+    /// Each file gets its own salt, and so its own key: identical content
+    /// uploaded under two paths seals differently and is never deduplicated
+    /// across files. Use a key file to deduplicate between files.
+    pub fn passphrase(passphrase: impl Into<String>) -> Self {
+        Self {
+            algorithm: EncryptionAlgorithm::default(),
+            key_source: KeySource::Passphrase(passphrase.into()),
+            kdf_iterations: DEFAULT_KDF_ITERATIONS,
+        }
+    }
+
+    /// Encrypt with a key loaded from a file
+    pub fn key_file(path: impl Into<PathBuf>) -> Self {
+        Self {
+            algorithm: EncryptionAlgorithm::default(),
+            key_source: KeySource::KeyFile(path.into()),
+            kdf_iterations: 0,
+        }
+    }
+
+    pub fn with_algorithm(mut self, algorithm: EncryptionAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn with_kdf_iterations(mut self, iterations: u32) -> Self {
+        self.kdf_iterations = iterations;
+        self
+    }
+}
+
+/// Seals and opens chunks with keys derived from an `EncryptionConfig`
+#[derive(Clone)]
+pub struct ChunkCipher {
+    algorithm: EncryptionAlgorithm,
+    encryption_key: [u8; KEY_LEN],
+    nonce_key: [u8; KEY_LEN],
+    info: EncryptionInfo,
+}
+
+impl fmt::Debug for ChunkCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkCipher")
+            .field("algorithm", &self.algorithm)
+            .field("kdf", &self.info.kdf)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChunkCipher {
+    /// Create a cipher for uploading a file
+    ///
+    /// Passphrase salts are derived from the session ID so that a resumed
+    /// upload seals every chunk exactly as the interrupted one did. A salt
+    /// shared between files would have to be fixed or derived from the
+    /// passphrase, and stored in the clear it would let the passphrase be
+    /// guessed without running PBKDF2, so files sealed under a passphrase
+    /// do not deduplicate against each other.
+    ///
+    /// # Arguments
+    /// * `config` - Encryption settings
+    /// * `session_id` - Session the file is uploaded under
+    /// * `plain_chunk_size` - Plaintext chunk size used for sealing
+    pub fn for_session(config: &EncryptionConfig, session_id: &str, plain_chunk_size: u32) -> Result<Self> {
+        let salt = match config.key_source {
+            KeySource::Passphrase(_) => {
+                blake3::derive_key(CONTEXT_SALT, session_id.as_bytes())[..SALT_LEN].to_vec()
+            }
+            KeySource::KeyFile(_) => Vec::new(),
+        };
+
+        let master = Self::master_key(config, &salt, config.kdf_iterations)?;
+        let key_check = Self::key_check(&master);
+
+        let info = EncryptionInfo {
+            algorithm: config.algorithm.as_str().to_string(),
+            kdf: match config.key_source {
+                KeySource::Passphrase(_) => KDF_PBKDF2.to_string(),
+                KeySource::KeyFile(_) => KDF_KEY_FILE.to_string(),
+            },
+            salt,
+            kdf_iterations: match config.key_source {
+                KeySource::Passphrase(_) => config.kdf_iterations,
+                KeySource::KeyFile(_) => 0,
+            },
+            plain_chunk_size,
+            key_check,
+        };
+
+        Ok(Self::from_master(config.algorithm, &master, info))
+    }
+
+    /// Recreate the cipher described by a manifest
+    ///
+    /// Fails with `Error::ConfigError` if the configured key does not match
+    /// the key the file was encrypted with.
+    pub fn from_info(config: &EncryptionConfig, info: &EncryptionInfo) -> Result<Self> {
+        let algorithm = EncryptionAlgorithm::from_name(&info.algorithm).ok_or_else(|| {
+            Error::Protocol(format!("Unknown encryption algorithm: {}", info.algorithm))
+        })?;
+
+        let expected_kdf = match config.key_source {
+            KeySource::Passphrase(_) => KDF_PBKDF2,
+            KeySource::KeyFile(_) => KDF_KEY_FILE,
+        };
+        if info.kdf != expected_kdf {
+            return Err(Error::ConfigError(format!(
+                "File was encrypted with {} but a {} key was supplied",
+                info.kdf, expected_kdf
+            )));
+        }
+
+        let master = Self::master_key(config, &info.salt, info.kdf_iterations)?;
+        if Self::key_check(&master) != info.key_check {
+            return Err(Error::ConfigError("Wrong encryption key".to_string()));
+        }
+
+        Ok(Self::from_master(algorithm, &master, info.clone()))
+    }
+
+    /// Encryption parameters to record in the manifest
+    pub fn info(&self) -> &EncryptionInfo {
+        &self.info
+    }
+
+    /// Plaintext chunk size this cipher seals
+    pub fn plain_chunk_size(&self) -> u32 {
+        self.info.plain_chunk_size
+    }
+
+    /// Size of a sealed chunk for a full plaintext chunk
+    pub fn sealed_chunk_size(&self) -> u32 {
+        self.info.plain_chunk_size + SEALED_OVERHEAD as u32
+    }
+
+    /// Seal a plaintext chunk
+    ///
+    /// The chunk ID is bound as associated data so sealed chunks cannot be
+    /// reordered without detection.
+    pub fn seal(&self, chunk_id: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut hasher = blake3::Hasher::new_keyed(&self.nonce_key);
+        hasher.update(&chunk_id.to_le_bytes());
+        hasher.update(plaintext);
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        nonce_bytes.copy_from_slice(&hasher.finalize().as_bytes()[..NONCE_LEN]);
+
+        let mut sealed = Vec::with_capacity(plaintext.len() + SEALED_OVERHEAD);
+        sealed.extend_from_slice(&nonce_bytes);
+        sealed.extend_from_slice(plaintext);
+
+        let tag = self.key()?
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce_bytes),
+                Aad::from(chunk_id.to_le_bytes()),
+                &mut sealed[NONCE_LEN..],
+            )
+            .map_err(|_| Error::Protocol(format!("Failed to encrypt chunk {}", chunk_id)))?;
+        sealed.extend_from_slice(tag.as_ref());
+
+        Ok(sealed)
+    }
+
+    /// Open a sealed chunk, verifying its authenticity
+    pub fn open(&self, chunk_id: u64, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < SEALED_OVERHEAD {
+            return Err(Error::Protocol(format!(
+                "Sealed chunk {} too short: {} bytes",
+                chunk_id,
+                sealed.len()
+            )));
+        }
+
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        nonce_bytes.copy_from_slice(&sealed[..NONCE_LEN]);
+        let mut in_out = sealed[NONCE_LEN..].to_vec();
+
+        let plaintext_len = self.key()?
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce_bytes),
+                Aad::from(chunk_id.to_le_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| Error::Protocol(format!("Failed to decrypt chunk {}: authentication failed", chunk_id)))?
+            .len();
+        in_out.truncate(plaintext_len);
+
+        Ok(in_out)
+    }
+
+    /// Decrypt a sealed file into `output_path`
+    ///
+    /// # Returns
+    /// Number of plaintext bytes written
+    pub fn decrypt_file(&self, sealed_path: &Path, output_path: &Path) -> Result<u64> {
+        let mut input = File::open(sealed_path)?;
+        let mut output = File::create(output_path)?;
+        let mut buffer = vec![0u8; self.sealed_chunk_size() as usize];
+        let mut chunk_id = 0u64;
+        let mut written = 0u64;
+
+        loop {
+            let read = read_full(&mut input, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+
+            let plaintext = self.open(chunk_id, &buffer[..read])?;
+            output.write_all(&plaintext)?;
+            written += plaintext.len() as u64;
+            chunk_id += 1;
+        }
+
+        output.flush()?;
+        Ok(written)
+    }
+
+    fn key(&self) -> Result<LessSafeKey> {
+        let unbound = UnboundKey::new(self.algorithm.aead(), &self.encryption_key)
+            .map_err(|_| Error::ConfigError("Invalid encryption key".to_string()))?;
+        Ok(LessSafeKey::new(unbound))
+    }
+
+    fn from_master(algorithm: EncryptionAlgorithm, master: &[u8; KEY_LEN], info: EncryptionInfo) -> Self {
+        Self {
+            algorithm,
+            encryption_key: blake3::derive_key(CONTEXT_ENCRYPTION, master),
+            nonce_key: blake3::derive_key(CONTEXT_NONCE, master),
+            info,
+        }
+    }
+
+    fn master_key(config: &EncryptionConfig, salt: &[u8], iterations: u32) -> Result<[u8; KEY_LEN]> {
+        let mut master = [0u8; KEY_LEN];
+
+        match &config.key_source {
+            KeySource::Passphrase(passphrase) => {
+                if passphrase.is_empty() {
+                    return Err(Error::ConfigError("Passphrase cannot be empty".to_string()));
+                }
+                let iterations = NonZeroU32::new(iterations).ok_or_else(|| {
+                    Error::ConfigError("KDF iterations must be non-zero".to_string())
+                })?;
+                pbkdf2::derive(
+                    pbkdf2::PBKDF2_HMAC_SHA256,
+                    iterations,
+                    salt,
+                    passphrase.as_bytes(),
+                    &mut master,
+                );
+            }
+            KeySource::KeyFile(path) => {
+                master = load_key_file(path)?;
+            }
+        }
+
+        Ok(master)
+    }
+
+    fn key_check(master: &[u8; KEY_LEN]) -> Vec<u8> {
+        blake3::derive_key(CONTEXT_KEY_CHECK, master)[..KEY_CHECK_LEN].to_vec()
+    }
+}
+
+/// Plaintext size of a sealed file with the given sealed chunk size
+pub fn plaintext_size(sealed_size: u64, sealed_chunk_size: u64) -> u64 {
+    let chunks = sealed_size.div_ceil(sealed_chunk_size);
+    sealed_size.saturating_sub(chunks * SEALED_OVERHEAD as u64)
+}
+
+/// Load a 32-byte key from a file containing raw bytes or hex
+fn load_key_file(path: &Path) -> Result<[u8; KEY_LEN]> {
+    let contents = std::fs::read(path)
+        .map_err(|e| Error::ConfigError(format!("Failed to read key file {:?}: {}", path, e)))?;
+
+    let bytes = if contents.len() == KEY_LEN {
+        contents
+    } else {
+        let text = String::from_utf8_lossy(&contents);
+        hex::decode(text.trim()).map_err(|_| {
+            Error::ConfigError(format!(
+                "Key file {:?} must contain {} raw bytes or {} hex characters",
+                path,
+                KEY_LEN,
+                KEY_LEN * 2
+            ))
+        })?
+    };
+
+    bytes.try_into().map_err(|_| {
+        Error::ConfigError(format!("Key file {:?} must contain a {}-byte key", path, KEY_LEN))
+    })
+}
+
+/// Read until the buffer is full or EOF is reached
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = reader.read(&mut buffer[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn test_config() -> EncryptionConfig {
+        EncryptionConfig::passphrase("correct horse battery staple").with_kdf_iterations(1000)
+    }
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let cipher = ChunkCipher::for_session(&test_config(), "session-1", 4096).unwrap();
+        let data = b"hello encrypted world".to_vec();
+
+        let sealed = cipher.seal(3, &data).unwrap();
+        assert_eq!(sealed.len(), data.len() + SEALED_OVERHEAD);
+        assert_ne!(&sealed[NONCE_LEN..NONCE_LEN + data.len()], data.as_slice());
+
+        assert_eq!(cipher.open(3, &sealed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_seal_is_deterministic() {
+        let cipher = ChunkCipher::for_session(&test_config(), "session-1", 4096).unwrap();
+        let again = ChunkCipher::for_session(&test_config(), "session-1", 4096).unwrap();
+
+        assert_eq!(cipher.seal(0, b"chunk").unwrap(), again.seal(0, b"chunk").unwrap());
+        assert_ne!(cipher.seal(0, b"chunk").unwrap(), cipher.seal(1, b"chunk").unwrap());
+    }
+
+    #[test]
+    fn test_open_rejects_wrong_chunk_id() {
+        let cipher = ChunkCipher::for_session(&test_config(), "session-1", 4096).unwrap();
+        let sealed = cipher.seal(1, b"chunk").unwrap();
+        assert!(cipher.open(2, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_open_rejects_tampering() {
+        let cipher = ChunkCipher::for_session(&test_config(), "session-1", 4096).unwrap();
+        let mut sealed = cipher.seal(0, b"chunk").unwrap();
+        sealed[NONCE_LEN] ^= 0xFF;
+        assert!(cipher.open(0, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_from_info_checks_key() {
+        let cipher = ChunkCipher::for_session(&test_config(), "session-1", 4096).unwrap();
+        let info = cipher.info().clone();
+
+        let reopened = ChunkCipher::from_info(&test_config(), &info);
+        assert!(reopened.is_ok());
+
+        let wrong = EncryptionConfig::passphrase("wrong").with_kdf_iterations(1000);
+        assert!(ChunkCipher::from_info(&wrong, &info).is_err());
+    }
+
+    #[test]
+    fn test_dedup_across_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let key_path = temp_dir.path().join("shared.key");
+        std::fs::write(&key_path, [7u8; KEY_LEN]).unwrap();
+
+        // A key file seals the same chunk alike in every file
+        let key_file = EncryptionConfig::key_file(&key_path);
+        let first = ChunkCipher::for_session(&key_file, "upload_a.bin_01", 1024).unwrap();
+        let second = ChunkCipher::for_session(&key_file, "upload_b.bin_02", 1024).unwrap();
+        assert_eq!(first.seal(0, b"same chunk").unwrap(), second.seal(0, b"same chunk").unwrap());
+
+        // A passphrase salts each file on its own, so the same chunk differs
+        let first = ChunkCipher::for_session(&test_config(), "upload_a.bin_01", 1024).unwrap();
+        let second = ChunkCipher::for_session(&test_config(), "upload_b.bin_02", 1024).unwrap();
+        assert_ne!(first.info().salt, second.info().salt);
+        assert_ne!(first.seal(0, b"same chunk").unwrap(), second.seal(0, b"same chunk").unwrap());
+
+        // Each file's manifest still opens with the passphrase alone
+        let reopened = ChunkCipher::from_info(&test_config(), second.info()).unwrap();
+        assert_eq!(reopened.open(0, &second.seal(0, b"same chunk").unwrap()).unwrap(), b"same chunk");
+    }
+
+    #[test]
+    fn test_key_file_hex_and_raw() {
+        let temp_dir = TempDir::new().unwrap();
+        let raw_path = temp_dir.path().join("raw.key");
+        let hex_path = temp_dir.path().join("hex.key");
+        std::fs::write(&raw_path, [7u8; KEY_LEN]).unwrap();
+        std::fs::write(&hex_path, format!("{}\n", hex::encode([7u8; KEY_LEN]))).unwrap();
+
+        let raw = ChunkCipher::for_session(&EncryptionConfig::key_file(&raw_path), "s", 1024).unwrap();
+        let hex = ChunkCipher::for_session(&EncryptionConfig::key_file(&hex_path), "s", 1024).unwrap();
+        assert_eq!(raw.seal(0, b"data").unwrap(), hex.seal(0, b"data").unwrap());
+
+        let short_path = temp_dir.path().join("short.key");
+        std::fs::write(&short_path, b"too short").unwrap();
+        assert!(ChunkCipher::for_session(&EncryptionConfig::key_file(&short_path), "s", 1024).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let cipher = ChunkCipher::for_session(&test_config(), "session-1", 1024).unwrap();
+        let data: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+
+        let sealed_path = temp_dir.path().join("file.sealed");
+        let mut sealed = Vec::new();
+        for (chunk_id, chunk) in data.chunks(1024).enumerate() {
+            sealed.extend(cipher.seal(chunk_id as u64, chunk).unwrap());
+        }
+        std::fs::write(&sealed_path, &sealed).unwrap();
+        assert_eq!(plaintext_size(sealed.len() as u64, cipher.sealed_chunk_size() as u64), 2500);
+
+        let output_path = temp_dir.path().join("file.plain");
+        assert_eq!(cipher.decrypt_file(&sealed_path, &output_path).unwrap(), 2500);
+        assert_eq!(std::fs::read(&output_path).unwrap(), data);
+    }
+}
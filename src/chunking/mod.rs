@@ -1,10 +1,16 @@
 pub mod chunker;
 pub mod hasher;
 pub mod bitmap;
+mod bitmap_codec;
 pub mod table;
 pub mod compress;
 pub mod dedup;
 pub mod parallel;
+pub mod encrypt;
+pub mod tree;
+pub mod bloom;
+pub mod stream;
+pub mod sparse;
 
 pub use chunker::{FileChunker, ChunkIterator};
 pub use hasher::ChunkHasher;
@@ -15,7 +21,11 @@ pub use compress::{
     create_compressor, compress_chunk, decompress_chunk
 };
 pub use parallel::{
-    ParallelChunker, ProcessedChunk, RawChunk,
+    ParallelChunker, ParallelChunkIterator, ProcessedChunk, RawChunk,
     compute_chunk_hashes_parallel
 };
-pub use dedup::{ChunkHashIndex, ChunkLocation, DedupStats};
\ No newline at end of file
+pub use dedup::{ChunkHashIndex, ChunkLocation, DedupStats};
+pub use bloom::BloomFilter;
+pub use stream::{StreamChunker, StreamItem};
+pub use encrypt::{ChunkCipher, EncryptionAlgorithm, EncryptionConfig, KeySource};
+pub use tree::{HashTree, TreeVerifier};
@@ -2,12 +2,18 @@
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use crossbeam_channel::{bounded, Receiver};
 use rayon::prelude::*;
 use crate::common::error::{Error, Result};
 use crate::common::types::DEFAULT_CHUNK_SIZE;
 use crate::protocol::chunk::ChunkPacketBuilder;
 use crate::chunking::compress::CompressionType;
+use crate::chunking::encrypt::{ChunkCipher, SEALED_OVERHEAD};
+use crate::chunking::sparse;
+use crate::chunking::tree::HashTree;
+use crate::protocol::messages::ChunkRange;
 
 /// Represents a raw chunk read from disk before compression
 #[derive(Debug, Clone)]
@@ -28,15 +34,23 @@ pub struct ProcessedChunk {
 }
 
 /// Parallel file chunker that pre-reads and processes chunks in parallel
+#[derive(Clone)]
 pub struct ParallelChunker {
     file_path: std::path::PathBuf,
     file_size: u64,
     chunk_size: usize,
     compression: CompressionType,
     total_chunks: u64,
+    /// Chunks produced by `process_chunks`, `first_chunk..end_chunk`
+    first_chunk: u64,
+    end_chunk: u64,
     #[allow(dead_code)]
     worker_threads: usize,
     pipeline_depth: usize,
+    cipher: Option<ChunkCipher>,
+    hash_tree: Option<Arc<HashTree>>,
+    /// Chunks in holes of a sparse file, which are not produced
+    holes: Arc<Vec<ChunkRange>>,
 }
 
 impl ParallelChunker {
@@ -66,11 +80,47 @@ impl ParallelChunker {
             chunk_size,
             compression,
             total_chunks,
+            first_chunk: 0,
+            end_chunk: total_chunks,
             worker_threads,
             pipeline_depth,
+            cipher: None,
+            hash_tree: None,
+            holes: Arc::new(Vec::new()),
         })
     }
     
+    /// Seal every chunk with `cipher` before it is packetized
+    /// 
+    /// Packets then carry ciphertext at sealed offsets and compression is
+    /// skipped, since encrypted data does not compress.
+    pub fn with_cipher(mut self, cipher: ChunkCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+    
+    /// Attach each chunk's proof from `tree` to its packet
+    /// 
+    /// Used with tree-hash manifests, where the receiver only knows the root.
+    pub fn with_hash_tree(mut self, tree: Arc<HashTree>) -> Self {
+        self.hash_tree = Some(tree);
+        self
+    }
+    
+    /// Skip the chunks a manifest lists as holes; they are never read
+    pub fn with_holes(mut self, holes: &[ChunkRange]) -> Self {
+        self.holes = Arc::new(holes.to_vec());
+        self
+    }
+    
+    /// Only produce the chunks in `range` (e.g. one stripe of a file sent
+    /// over several connections)
+    pub fn with_chunk_range(mut self, range: std::ops::Range<u64>) -> Self {
+        self.end_chunk = range.end.min(self.total_chunks);
+        self.first_chunk = range.start.min(self.end_chunk);
+        self
+    }
+    
     /// Get total number of chunks
     pub fn total_chunks(&self) -> u64 {
         self.total_chunks
@@ -83,20 +133,15 @@ impl ParallelChunker {
     
     /// Process all chunks in parallel and return an iterator
     pub fn process_chunks(&self) -> Result<ParallelChunkIterator> {
-        ParallelChunkIterator::new(
-            &self.file_path,
-            self.file_size,
-            self.chunk_size,
-            self.compression,
-            self.total_chunks,
-            self.pipeline_depth,
-        )
+        ParallelChunkIterator::new(self.clone())
     }
     
     /// Process chunks in batches for better cache locality
     pub fn process_batch(&self, start_chunk: u64, batch_size: usize) -> Result<Vec<ProcessedChunk>> {
-        let end_chunk = (start_chunk + batch_size as u64).min(self.total_chunks);
-        let chunks_to_read: Vec<u64> = (start_chunk..end_chunk).collect();
+        let end_chunk = (start_chunk + batch_size as u64).min(self.end_chunk);
+        let chunks_to_read: Vec<u64> = (start_chunk..end_chunk)
+            .filter(|&chunk_id| !sparse::is_hole(&self.holes, chunk_id))
+            .collect();
         
         // Read all chunks in the batch
         let raw_chunks: Vec<RawChunk> = chunks_to_read
@@ -146,12 +191,17 @@ impl ParallelChunker {
     
     /// Process a raw chunk (compute hash, compress, build packet)
     fn process_raw_chunk(&self, raw: RawChunk) -> Result<ProcessedChunk> {
+        if let Some(cipher) = &self.cipher {
+            return self.process_sealed_chunk(raw, cipher);
+        }
+        
         // Compute hash
         let checksum = blake3::hash(&raw.data);
         let hash = checksum.as_bytes().to_vec();
         
         // Build packet with compression
-        let mut builder = ChunkPacketBuilder::with_compression(self.compression);
+        let mut builder = ChunkPacketBuilder::with_compression(self.compression)
+            .with_proof(self.proof(raw.chunk_id)?);
         let packet = builder.build(
             raw.chunk_id,
             raw.offset,
@@ -168,6 +218,39 @@ impl ParallelChunker {
             end_of_file: raw.end_of_file,
         })
     }
+    
+    /// Seal a raw chunk and build its packet at the sealed file offset
+    fn process_sealed_chunk(&self, raw: RawChunk, cipher: &ChunkCipher) -> Result<ProcessedChunk> {
+        let sealed = cipher.seal(raw.chunk_id, &raw.data)?;
+        let hash = blake3::hash(&sealed).as_bytes().to_vec();
+        let offset = raw.chunk_id * cipher.sealed_chunk_size() as u64;
+        debug_assert_eq!(sealed.len(), raw.data.len() + SEALED_OVERHEAD);
+        
+        let mut builder = ChunkPacketBuilder::new().with_proof(self.proof(raw.chunk_id)?);
+        let packet = builder.build(
+            raw.chunk_id,
+            offset,
+            sealed.len() as u32,
+            &hash,
+            raw.end_of_file,
+            &sealed,
+        )?;
+        
+        Ok(ProcessedChunk {
+            chunk_id: raw.chunk_id,
+            packet,
+            hash,
+            end_of_file: raw.end_of_file,
+        })
+    }
+    
+    /// Hash tree proof for a chunk (empty without a tree)
+    fn proof(&self, chunk_id: u64) -> Result<Vec<Vec<u8>>> {
+        match &self.hash_tree {
+            Some(tree) => tree.proof(chunk_id),
+            None => Ok(Vec::new()),
+        }
+    }
 }
 
 /// Iterator that produces processed chunks with parallel pipeline
@@ -177,35 +260,18 @@ pub struct ParallelChunkIterator {
 }
 
 impl ParallelChunkIterator {
-    fn new(
-        file_path: &Path,
-        file_size: u64,
-        chunk_size: usize,
-        compression: CompressionType,
-        total_chunks: u64,
-        pipeline_depth: usize,
-    ) -> Result<Self> {
-        let (tx, rx) = bounded(pipeline_depth);
-        let file_path = file_path.to_path_buf();
+    fn new(chunker: ParallelChunker) -> Result<Self> {
+        let (tx, rx) = bounded(chunker.pipeline_depth);
+        let end_chunk = chunker.end_chunk;
         
         // Spawn worker thread that orchestrates the pipeline
         let worker_handle = std::thread::spawn(move || {
-            let chunker = ParallelChunker {
-                file_path: file_path.clone(),
-                file_size,
-                chunk_size,
-                compression,
-                total_chunks,
-                worker_threads: num_cpus::get(),
-                pipeline_depth,
-            };
-            
             // Process chunks in batches for better performance
             let batch_size = 8; // Process 8 chunks at a time
-            let mut current_chunk = 0;
+            let mut current_chunk = chunker.first_chunk;
             
-            while current_chunk < total_chunks {
-                let batch_end = (current_chunk + batch_size as u64).min(total_chunks);
+            while current_chunk < end_chunk {
+                let batch_end = (current_chunk + batch_size as u64).min(end_chunk);
                 
                 // Process batch in parallel
                 match chunker.process_batch(current_chunk, batch_size) {
@@ -249,24 +315,54 @@ impl Iterator for ParallelChunkIterator {
 pub fn compute_chunk_hashes_parallel(
     file_path: &Path,
     chunk_size: usize,
+) -> Result<Vec<Vec<u8>>> {
+    compute_sparse_chunk_hashes(file_path, chunk_size, &[], None)
+}
+
+/// Fail with `Error::Cancelled` once `cancelled` is set
+pub(crate) fn check_cancelled(cancelled: Option<&AtomicBool>) -> Result<()> {
+    match cancelled {
+        Some(flag) if flag.load(Ordering::Acquire) => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// Pre-compute all chunk hashes in parallel, hashing the chunks in `holes`
+/// as zeros without reading them
+/// 
+/// Stops with `Error::Cancelled` once `cancelled` is set.
+pub fn compute_sparse_chunk_hashes(
+    file_path: &Path,
+    chunk_size: usize,
+    holes: &[ChunkRange],
+    cancelled: Option<&AtomicBool>,
 ) -> Result<Vec<Vec<u8>>> {
     let file = File::open(file_path)?;
     let file_size = file.metadata()?.len();
     let total_chunks = (file_size + chunk_size as u64 - 1) / chunk_size as u64;
     
     let chunk_ids: Vec<u64> = (0..total_chunks).collect();
+    let zero_hash = (!holes.is_empty()).then(|| sparse::zero_chunk_hash(chunk_size));
     
     // Read and hash all chunks in parallel
     let hashes: Vec<Vec<u8>> = chunk_ids
         .par_iter()
         .filter_map(|&chunk_id| {
-            let mut file = File::open(file_path).ok()?;
+            check_cancelled(cancelled).ok()?;
             let offset = chunk_id * chunk_size as u64;
-            file.seek(SeekFrom::Start(offset)).ok()?;
-            
             let remaining = file_size - offset;
             let to_read = std::cmp::min(remaining, chunk_size as u64) as usize;
             
+            if sparse::is_hole(holes, chunk_id) {
+                return Some(match &zero_hash {
+                    Some(hash) if to_read == chunk_size => hash.clone(),
+                    _ => sparse::zero_chunk_hash(to_read),
+                });
+            }
+            
+            let mut file = File::open(file_path).ok()?;
+            file.seek(SeekFrom::Start(offset)).ok()?;
+            
             let mut buffer = vec![0u8; to_read];
             file.read_exact(&mut buffer).ok()?;
             
@@ -275,6 +371,7 @@ pub fn compute_chunk_hashes_parallel(
         })
         .collect();
     
+    check_cancelled(cancelled)?;
     if hashes.len() != total_chunks as usize {
         return Err(Error::Protocol("Failed to hash all chunks".into()));
     }
@@ -314,6 +411,91 @@ mod tests {
         assert_eq!(count, 5);
     }
     
+    #[test]
+    fn test_parallel_chunker_with_chunk_range() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(&vec![7u8; 10 * 1024 + 100]).unwrap();
+        temp_file.flush().unwrap();
+        
+        let chunker = ParallelChunker::new(
+            temp_file.path(),
+            Some(1024),
+            CompressionType::None,
+            Some(2),
+        ).unwrap();
+        
+        let ids = |range| chunker.clone().with_chunk_range(range).process_chunks().unwrap()
+            .map(|result| result.unwrap().chunk_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids(3..6), vec![3, 4, 5]);
+        assert_eq!(ids(9..20), vec![9, 10]);
+        assert!(ids(11..20).is_empty());
+        
+        let last = chunker.clone().with_chunk_range(10..11).process_chunks().unwrap().next().unwrap().unwrap();
+        assert!(last.end_of_file);
+    }
+    
+    #[test]
+    fn test_parallel_chunker_with_cipher() {
+        use crate::chunking::encrypt::EncryptionConfig;
+        use crate::protocol::chunk::ChunkPacketParser;
+        
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        temp_file.write_all(&data).unwrap();
+        temp_file.flush().unwrap();
+        
+        let config = EncryptionConfig::passphrase("secret").with_kdf_iterations(1000);
+        let cipher = ChunkCipher::for_session(&config, "session", 1024).unwrap();
+        let chunker = ParallelChunker::new(
+            temp_file.path(),
+            Some(1024),
+            CompressionType::Zstd,
+            Some(2),
+        ).unwrap().with_cipher(cipher.clone());
+        
+        for result in chunker.process_chunks().unwrap() {
+            let chunk = result.unwrap();
+            let view = ChunkPacketParser::parse(&chunk.packet).unwrap();
+            view.verify_checksum().unwrap();
+            assert_eq!(view.byte_offset, chunk.chunk_id * cipher.sealed_chunk_size() as u64);
+            
+            let plain = cipher.open(chunk.chunk_id, &view.data).unwrap();
+            let start = chunk.chunk_id as usize * 1024;
+            assert_eq!(plain, &data[start..start + plain.len()]);
+        }
+    }
+    
+    #[test]
+    fn test_parallel_chunker_with_hash_tree() {
+        use crate::chunking::tree::TreeVerifier;
+        use crate::protocol::chunk::ChunkPacketParser;
+        
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        temp_file.write_all(&data).unwrap();
+        temp_file.flush().unwrap();
+        
+        let hashes = compute_chunk_hashes_parallel(temp_file.path(), 1024).unwrap();
+        let tree = Arc::new(HashTree::from_chunk_hashes(&hashes).unwrap());
+        let verifier = TreeVerifier::new(tree.root(), tree.total_chunks()).unwrap();
+        let chunker = ParallelChunker::new(
+            temp_file.path(),
+            Some(1024),
+            CompressionType::None,
+            Some(2),
+        ).unwrap().with_hash_tree(tree);
+        
+        let mut count = 0;
+        for result in chunker.process_chunks().unwrap() {
+            let chunk = result.unwrap();
+            let view = ChunkPacketParser::parse(&chunk.packet).unwrap();
+            verifier.verify(view.chunk_id, &view.checksum, &view.proof).unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 5);
+    }
+    
     #[test]
     fn test_parallel_hash_computation() {
         let mut temp_file = NamedTempFile::new().unwrap();
@@ -0,0 +1,192 @@
+// Sparse file support
+//
+// Holes in a sparse file read back as zeros without taking up disk space.
+// The sender finds them with SEEK_DATA/SEEK_HOLE and lists the chunks lying
+// wholly inside one in the manifest. Those chunks are never read or sent:
+// their hashes are those of zeros, and the receiver punches the same chunks
+// out of the file it assembles, so it ends up with the same length and hash
+// and as sparse as the original. Where holes cannot be found, files are
+// treated as fully allocated.
+
+use crate::common::error::Result;
+use crate::protocol::messages::ChunkRange;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// Chunks of the file at `path` that lie wholly inside holes
+pub fn detect_holes(path: &Path, chunk_size: u32) -> Result<Vec<ChunkRange>> {
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    Ok(hole_chunks(&find_holes(&file, file_size)?, chunk_size as u64, file_size))
+}
+
+/// Byte ranges of the holes in the first `file_size` bytes of `file`
+#[cfg(target_os = "linux")]
+pub fn find_holes(file: &File, file_size: u64) -> Result<Vec<Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut holes = Vec::new();
+    let mut offset = 0u64;
+
+    while offset < file_size {
+        // SAFETY: lseek only reads the descriptor, which `file` keeps open
+        let hole = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let hole = hole as u64;
+        if hole >= file_size {
+            break;
+        }
+
+        // No data after the hole (ENXIO) means it runs to the end
+        // SAFETY: as above
+        let data = unsafe { libc::lseek(fd, hole as libc::off_t, libc::SEEK_DATA) };
+        let end = if data >= 0 {
+            (data as u64).min(file_size)
+        } else {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::ENXIO) {
+                return Err(error.into());
+            }
+            file_size
+        };
+
+        holes.push(hole..end);
+        offset = end;
+    }
+
+    Ok(holes)
+}
+
+/// Byte ranges of the holes in the first `file_size` bytes of `file`
+#[cfg(not(target_os = "linux"))]
+pub fn find_holes(file: &File, file_size: u64) -> Result<Vec<Range<u64>>> {
+    let _ = (file, file_size);
+    Ok(Vec::new())
+}
+
+/// Chunks wholly inside the byte ranges `holes` of a `file_size`-byte file
+///
+/// A hole reaching the end of the file covers a shorter final chunk too.
+pub fn hole_chunks(holes: &[Range<u64>], chunk_size: u64, file_size: u64) -> Vec<ChunkRange> {
+    let total_chunks = file_size.div_ceil(chunk_size);
+    holes.iter()
+        .filter_map(|hole| {
+            let first_chunk = hole.start.div_ceil(chunk_size);
+            let end_chunk = if hole.end >= file_size { total_chunks } else { hole.end / chunk_size };
+            (end_chunk > first_chunk).then(|| ChunkRange { first_chunk, chunk_count: end_chunk - first_chunk })
+        })
+        .collect()
+}
+
+/// Whether `chunk_id` lies in one of the ordered ranges `holes`
+pub fn is_hole(holes: &[ChunkRange], chunk_id: u64) -> bool {
+    let index = holes.partition_point(|range| range.end_chunk() <= chunk_id);
+    holes.get(index).is_some_and(|range| range.contains(chunk_id))
+}
+
+/// Hash of a chunk of `len` zeros, as a hole chunk has
+pub fn zero_chunk_hash(len: usize) -> Vec<u8> {
+    blake3::hash(&vec![0u8; len]).as_bytes().to_vec()
+}
+
+/// Number of chunks in `holes` that fall within `chunks`
+pub fn hole_count(holes: &[ChunkRange], chunks: Range<u64>) -> u64 {
+    holes.iter()
+        .map(|range| range.end_chunk().min(chunks.end).saturating_sub(range.first_chunk.max(chunks.start)))
+        .sum()
+}
+
+/// Turn `len` bytes of `file` at `offset` into a hole
+///
+/// Filesystems that cannot deallocate get zeros written instead, so the
+/// range reads back the same either way.
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // SAFETY: fallocate only changes the file behind the open descriptor
+        let result = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if result == 0 {
+            return Ok(());
+        }
+    }
+
+    write_zeros(file, offset, len)
+}
+
+fn write_zeros(mut file: &File, offset: u64, len: u64) -> Result<()> {
+    let zeros = vec![0u8; len.min(64 * 1024) as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..count])?;
+        remaining -= count as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn range(first_chunk: u64, chunk_count: u64) -> ChunkRange {
+        ChunkRange { first_chunk, chunk_count }
+    }
+
+    #[test]
+    fn test_hole_chunks_cover_whole_chunks_only() {
+        // Chunks of 100 bytes in a 1050-byte file: 11 chunks, the last of 50
+        let holes = [50..320, 400..500, 700..1050];
+        assert_eq!(hole_chunks(&holes, 100, 1050), vec![range(1, 2), range(4, 1), range(7, 4)]);
+        assert!(hole_chunks(&[10..90, 950..990], 100, 1050).is_empty());
+
+        let chunks = hole_chunks(&holes, 100, 1050);
+        let ids: Vec<u64> = (0..11).filter(|&id| is_hole(&chunks, id)).collect();
+        assert_eq!(ids, vec![1, 2, 4, 7, 8, 9, 10]);
+        assert_eq!(hole_count(&chunks, 0..11), 7);
+        assert_eq!(hole_count(&chunks, 2..8), 3);
+    }
+
+    #[test]
+    fn test_find_and_punch_holes() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let file = temp_file.as_file();
+        let chunk = 64 * 1024u64;
+        file.set_len(8 * chunk).unwrap();
+        write_all_at(file, 3 * chunk, &[7u8; 100]);
+
+        // Filesystems without hole support report none, which is still correct
+        let holes = find_holes(file, 8 * chunk).unwrap();
+        let chunks = hole_chunks(&holes, chunk, 8 * chunk);
+        assert!(!is_hole(&chunks, 3));
+        if !holes.is_empty() {
+            assert!(is_hole(&chunks, 0) && is_hole(&chunks, 7));
+        }
+
+        punch_hole(file, 3 * chunk, chunk).unwrap();
+        assert_eq!(std::fs::read(temp_file.path()).unwrap(), vec![0u8; 8 * chunk as usize]);
+    }
+
+    fn write_all_at(mut file: &File, offset: u64, data: &[u8]) {
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(data).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+// Chunking of input of unknown length
+//
+// Pipes and stdin can be neither measured nor re-read, so their chunks are
+// cut as the input arrives. A reader thread fills each chunk, reads one
+// chunk ahead to know which is last and hashes the whole input as it goes;
+// the totals come out after the last chunk, or alone for empty input.
+// Senders take chunks without waiting, so a slow producer never stalls the
+// connection.
+
+use std::io::{ErrorKind, Read};
+use crossbeam_channel::{bounded, Receiver, TryRecvError};
+use crate::common::error::{Error, Result};
+use crate::protocol::chunk::ChunkPacketBuilder;
+use crate::protocol::messages::ManifestTrailer;
+use crate::chunking::compress::CompressionType;
+use crate::chunking::parallel::ProcessedChunk;
+
+/// Chunks read ahead of the sender
+const STREAM_PIPELINE_DEPTH: usize = 8;
+
+/// Something read from the input
+#[derive(Debug)]
+pub enum StreamItem {
+    /// Next chunk, in order
+    Chunk(ProcessedChunk),
+    /// The input has ended; totals for the manifest trailer
+    End(ManifestTrailer),
+}
+
+/// Cuts a stream into chunk packets on a reader thread
+pub struct StreamChunker {
+    receiver: Receiver<Result<StreamItem>>,
+    _worker_handle: std::thread::JoinHandle<()>,
+}
+
+impl StreamChunker {
+    /// Start reading `reader` in chunks of `chunk_size` bytes
+    pub fn new<R: Read + Send + 'static>(reader: R, chunk_size: usize, compression: CompressionType) -> Self {
+        let (tx, rx) = bounded(STREAM_PIPELINE_DEPTH);
+
+        let worker_handle = std::thread::spawn(move || {
+            let mut send = |item| tx.send(item).is_ok();
+            if let Err(e) = read_stream(reader, chunk_size, compression, &mut send) {
+                send(Err(e));
+            }
+        });
+
+        Self {
+            receiver: rx,
+            _worker_handle: worker_handle,
+        }
+    }
+
+    /// Next chunk, or the end of the input, if it has been read yet
+    pub fn try_next(&mut self) -> Result<Option<StreamItem>> {
+        match self.receiver.try_recv() {
+            Ok(item) => item.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(Error::Protocol(
+                "Input stream reader stopped".to_string()
+            )),
+        }
+    }
+}
+
+/// Waits for the next chunk; ends after the totals
+impl Iterator for StreamChunker {
+    type Item = Result<StreamItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Read, hash and packetize the whole input, handing each item to `send`
+/// until it reports the receiver gone
+fn read_stream<R: Read>(
+    mut reader: R,
+    chunk_size: usize,
+    compression: CompressionType,
+    send: &mut impl FnMut(Result<StreamItem>) -> bool,
+) -> Result<()> {
+    let mut builder = ChunkPacketBuilder::with_compression(compression);
+    let mut file_hasher = blake3::Hasher::new();
+    let mut chunk_id = 0u64;
+    let mut offset = 0u64;
+
+    let mut current = read_full(&mut reader, chunk_size)?;
+    if current.is_empty() {
+        send(Ok(StreamItem::End(ManifestTrailer {
+            file_size: 0,
+            total_chunks: 0,
+            file_hash: file_hasher.finalize().as_bytes().to_vec(),
+        })));
+        return Ok(());
+    }
+
+    loop {
+        let next = read_full(&mut reader, chunk_size)?;
+        let end_of_file = next.is_empty();
+
+        file_hasher.update(&current);
+        let hash = blake3::hash(&current).as_bytes().to_vec();
+        let packet = builder.build(chunk_id, offset, current.len() as u32, &hash, end_of_file, &current)?;
+
+        let chunk = ProcessedChunk { chunk_id, packet, hash, end_of_file };
+        if !send(Ok(StreamItem::Chunk(chunk))) {
+            return Ok(());
+        }
+
+        chunk_id += 1;
+        offset += current.len() as u64;
+
+        if end_of_file {
+            send(Ok(StreamItem::End(ManifestTrailer {
+                file_size: offset,
+                total_chunks: chunk_id,
+                file_hash: file_hasher.finalize().as_bytes().to_vec(),
+            })));
+            return Ok(());
+        }
+        current = next;
+    }
+}
+
+/// Read up to `len` bytes, short only at the end of the input
+fn read_full<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    let mut filled = 0;
+
+    while filled < len {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    buffer.truncate(filled);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_stream_chunks_and_totals() {
+        let data: Vec<u8> = (0..10u8).collect();
+        let items: Vec<StreamItem> = StreamChunker::new(Cursor::new(data.clone()), 4, CompressionType::None)
+            .map(|item| item.unwrap())
+            .collect();
+
+        assert_eq!(items.len(), 4);
+        for (index, item) in items[..3].iter().enumerate() {
+            let StreamItem::Chunk(chunk) = item else {
+                panic!("expected chunk, got {:?}", item);
+            };
+            assert_eq!(chunk.chunk_id, index as u64);
+            assert_eq!(chunk.end_of_file, index == 2);
+            let start = index * 4;
+            let end = (start + 4).min(data.len());
+            assert_eq!(chunk.hash, blake3::hash(&data[start..end]).as_bytes().to_vec());
+        }
+
+        let StreamItem::End(trailer) = &items[3] else {
+            panic!("expected totals, got {:?}", items[3]);
+        };
+        assert_eq!(trailer.file_size, 10);
+        assert_eq!(trailer.total_chunks, 3);
+        assert_eq!(trailer.file_hash, blake3::hash(&data).as_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_empty_stream_has_only_totals() {
+        let mut chunker = StreamChunker::new(Cursor::new(Vec::new()), 4, CompressionType::None);
+        let Some(Ok(StreamItem::End(trailer))) = chunker.next() else {
+            panic!("expected totals");
+        };
+        assert_eq!(trailer.file_size, 0);
+        assert_eq!(trailer.total_chunks, 0);
+        assert_eq!(trailer.file_hash, blake3::hash(b"").as_bytes().to_vec());
+        assert!(chunker.next().is_none());
+    }
+}
@@ -0,0 +1,254 @@
+// BLAKE3 hash tree over chunk hashes (verified streaming)
+//
+// Instead of listing every chunk hash in the manifest, the sender can publish
+// only the root of a binary Merkle tree whose leaves are the chunk hashes.
+// Each chunk packet then carries the sibling hashes on its path to the root,
+// so the receiver verifies every chunk on arrival while holding nothing but
+// the root and the chunk count.
+//
+// Levels are built by hashing adjacent pairs; an odd node at the end of a
+// level is promoted unchanged. Leaf and parent hashes are domain separated
+// so a parent can never be passed off as a chunk.
+
+use crate::common::error::{Error, Result};
+
+/// Hash length in bytes (BLAKE3)
+pub const HASH_LEN: usize = 32;
+
+/// Domain separation prefix for leaf nodes
+const LEAF_PREFIX: u8 = 0x00;
+
+/// Domain separation prefix for parent nodes
+const PARENT_PREFIX: u8 = 0x01;
+
+type Node = [u8; HASH_LEN];
+
+/// Complete hash tree kept by the sender to produce per-chunk proofs
+#[derive(Debug, Clone)]
+pub struct HashTree {
+    /// `levels[0]` holds the leaves, the last level holds the root
+    levels: Vec<Vec<Node>>,
+}
+
+impl HashTree {
+    /// Build a tree from per-chunk BLAKE3 hashes in chunk order
+    pub fn from_chunk_hashes(chunk_hashes: &[Vec<u8>]) -> Result<Self> {
+        if chunk_hashes.is_empty() {
+            return Err(Error::Protocol("Hash tree needs at least one chunk".to_string()));
+        }
+
+        let mut leaves = Vec::with_capacity(chunk_hashes.len());
+        for (idx, hash) in chunk_hashes.iter().enumerate() {
+            leaves.push(leaf_node(&to_node(hash).map_err(|_| {
+                Error::Protocol(format!(
+                    "Chunk hash {} has invalid size: {} bytes (expected {})",
+                    idx,
+                    hash.len(),
+                    HASH_LEN
+                ))
+            })?));
+        }
+
+        let mut levels = vec![leaves];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => parent_node(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        Ok(Self { levels })
+    }
+
+    /// Root hash to publish in the manifest
+    pub fn root(&self) -> &[u8] {
+        &self.levels[self.levels.len() - 1][0]
+    }
+
+    /// Number of chunks (leaves) covered by the tree
+    pub fn total_chunks(&self) -> u64 {
+        self.levels[0].len() as u64
+    }
+
+    /// Sibling hashes from a chunk's leaf up to the root
+    ///
+    /// Levels where the node is promoted without a sibling contribute nothing,
+    /// so proofs are at most `ceil(log2(total_chunks))` hashes long.
+    pub fn proof(&self, chunk_id: u64) -> Result<Vec<Vec<u8>>> {
+        if chunk_id >= self.total_chunks() {
+            return Err(Error::Protocol(format!(
+                "Chunk {} outside hash tree of {} chunks",
+                chunk_id,
+                self.total_chunks()
+            )));
+        }
+
+        let mut proof = Vec::with_capacity(self.levels.len() - 1);
+        let mut index = chunk_id as usize;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(sibling.to_vec());
+            }
+            index /= 2;
+        }
+
+        Ok(proof)
+    }
+}
+
+/// Verifies chunks against a published tree root as they arrive
+#[derive(Debug, Clone)]
+pub struct TreeVerifier {
+    root: Node,
+    total_chunks: u64,
+}
+
+impl TreeVerifier {
+    /// Create a verifier for a manifest's tree root and chunk count
+    pub fn new(root: &[u8], total_chunks: u64) -> Result<Self> {
+        let root = to_node(root)?;
+        if total_chunks == 0 {
+            return Err(Error::Protocol("Hash tree needs at least one chunk".to_string()));
+        }
+        Ok(Self { root, total_chunks })
+    }
+
+    /// Check a chunk's hash and proof against the root
+    pub fn verify(&self, chunk_id: u64, chunk_hash: &[u8], proof: &[Vec<u8>]) -> Result<()> {
+        if chunk_id >= self.total_chunks {
+            return Err(Error::Protocol(format!(
+                "Chunk {} outside hash tree of {} chunks",
+                chunk_id, self.total_chunks
+            )));
+        }
+
+        let mut node = leaf_node(&to_node(chunk_hash)?);
+        let mut siblings = proof.iter();
+        let mut index = chunk_id;
+        let mut width = self.total_chunks;
+
+        while width > 1 {
+            if index ^ 1 < width {
+                let sibling = to_node(siblings.next().ok_or_else(|| {
+                    Error::Protocol(format!("Hash tree proof too short for chunk {}", chunk_id))
+                })?)?;
+                node = if index & 1 == 0 {
+                    parent_node(&node, &sibling)
+                } else {
+                    parent_node(&sibling, &node)
+                };
+            }
+            index /= 2;
+            width = width.div_ceil(2);
+        }
+
+        if siblings.next().is_some() {
+            return Err(Error::Protocol(format!("Hash tree proof too long for chunk {}", chunk_id)));
+        }
+
+        if node != self.root {
+            return Err(Error::Protocol(format!("Chunk {} does not match hash tree root", chunk_id)));
+        }
+
+        Ok(())
+    }
+}
+
+fn to_node(hash: &[u8]) -> Result<Node> {
+    hash.try_into().map_err(|_| {
+        Error::Protocol(format!(
+            "Invalid hash size: {} (expected {} bytes for BLAKE3)",
+            hash.len(),
+            HASH_LEN
+        ))
+    })
+}
+
+fn leaf_node(chunk_hash: &Node) -> Node {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(chunk_hash);
+    *hasher.finalize().as_bytes()
+}
+
+fn parent_node(left: &Node, right: &Node) -> Node {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[PARENT_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_hashes(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| blake3::hash(&(i as u64).to_le_bytes()).as_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_every_chunk_verifies() {
+        for count in [1, 2, 3, 5, 8, 13] {
+            let hashes = chunk_hashes(count);
+            let tree = HashTree::from_chunk_hashes(&hashes).unwrap();
+            let verifier = TreeVerifier::new(tree.root(), count as u64).unwrap();
+
+            for (id, hash) in hashes.iter().enumerate() {
+                let proof = tree.proof(id as u64).unwrap();
+                assert!(proof.len() <= (count as f64).log2().ceil() as usize);
+                verifier.verify(id as u64, hash, &proof).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_wrong_chunk_rejected() {
+        let hashes = chunk_hashes(5);
+        let tree = HashTree::from_chunk_hashes(&hashes).unwrap();
+        let verifier = TreeVerifier::new(tree.root(), 5).unwrap();
+
+        // Right proof, wrong data
+        assert!(verifier.verify(2, &hashes[3], &tree.proof(2).unwrap()).is_err());
+        // Right data, wrong position
+        assert!(verifier.verify(3, &hashes[2], &tree.proof(2).unwrap()).is_err());
+        // Truncated and padded proofs
+        let mut proof = tree.proof(2).unwrap();
+        proof.pop();
+        assert!(verifier.verify(2, &hashes[2], &proof).is_err());
+        let mut proof = tree.proof(2).unwrap();
+        proof.push(vec![0u8; HASH_LEN]);
+        assert!(verifier.verify(2, &hashes[2], &proof).is_err());
+    }
+
+    #[test]
+    fn test_root_depends_on_chunk_count() {
+        let hashes = chunk_hashes(4);
+        let tree = HashTree::from_chunk_hashes(&hashes).unwrap();
+        let shorter = HashTree::from_chunk_hashes(&hashes[..3]).unwrap();
+        assert_ne!(tree.root(), shorter.root());
+
+        // A single-chunk tree is not just the chunk hash
+        let single = HashTree::from_chunk_hashes(&hashes[..1]).unwrap();
+        assert_ne!(single.root(), hashes[0].as_slice());
+        assert!(single.proof(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert!(HashTree::from_chunk_hashes(&[]).is_err());
+        assert!(HashTree::from_chunk_hashes(&[vec![0u8; 16]]).is_err());
+        assert!(TreeVerifier::new(&[0u8; 16], 1).is_err());
+        assert!(TreeVerifier::new(&[0u8; 32], 0).is_err());
+
+        let tree = HashTree::from_chunk_hashes(&chunk_hashes(2)).unwrap();
+        assert!(tree.proof(2).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+// Async client API for embedding transfers in a tokio application
+//
+// Uploads run natively on the runtime: the QUIC connection is driven over a
+// tokio socket, and the upload's protocol is the same sans-IO state machine
+// the blocking client drives (see `engine`). Building the manifest hashes the
+// whole file, so it runs on the blocking pool; chunks are read, hashed and
+// packed on the chunker's worker threads as before. Downloads are not
+// native yet: they run the blocking receive path on the blocking pool.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use log::{debug, info};
+use tokio::net::UdpSocket;
+use crate::common::error::{Error, Result};
+use crate::common::types::MAX_DATAGRAM_SIZE;
+use crate::transport::event_socket::wait_time;
+use crate::transport::StreamAllocator;
+use super::connection::ClientConnection;
+use super::transfer::{OutgoingUpload, Placement, Transfer, FLUSH_QUIET_TIME, NETWORK_WAIT};
+use super::Client;
+
+/// Longest the handshake may take
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl Client {
+    /// Upload a file to the server on the current tokio runtime
+    ///
+    /// The file goes over one connection on its own transfer streams, with
+    /// resume, dedup, encryption and signing as configured. Striping and
+    /// small-file packing are only done by the blocking `Transfer` API.
+    ///
+    /// # Returns
+    /// Manifest and chunk bytes sent
+    pub async fn upload(&self, file_path: impl AsRef<Path>, destination: &str) -> Result<u64> {
+        let result = self.upload_file(file_path.as_ref().to_path_buf(), destination).await;
+        if let Err(e) = &result {
+            self.observer.on_error(None, e);
+        }
+        result
+    }
+
+    async fn upload_file(&self, file_path: PathBuf, destination: &str) -> Result<u64> {
+        let path_str = file_path.to_str()
+            .ok_or_else(|| Error::Protocol(format!("Invalid file name: {:?}", file_path)))?;
+        let transfer = self.send_file(path_str, destination)?;
+
+        // Build the manifest off the runtime's worker threads
+        let (mut transfer, prepared) = spawn_blocking(move || {
+            let prepared = transfer.prepare_upload(&file_path, Placement::Root);
+            (transfer, prepared)
+        }).await?;
+        let prepared = prepared?;
+
+        let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+        socket.connect(self.config.server_addr).await?;
+        let local_addr = socket.local_addr()?;
+
+        info!("Client: connecting to {}", self.config.server_addr);
+        let mut connection = ClientConnection::new(&self.config, local_addr)?;
+        let mut buf = vec![0u8; 65535];
+        let mut out = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        handshake(&socket, &mut connection, &mut buf, &mut out, local_addr).await?;
+
+        // No streams are open yet, so this is the server's full stream limit
+        let stream_limit = connection.peer_streams_left_bidi();
+        transfer.open_streams(&mut connection)?;
+
+        let mut allocator = StreamAllocator::new();
+        let streams = allocator.allocate(stream_limit)?;
+        let upload = transfer.open_upload(&mut connection, &mut allocator, stream_limit, streams, prepared)?;
+
+        let bytes_sent = send_upload(&mut transfer, &socket, &mut connection, &mut buf, &mut out, local_addr, upload).await?;
+
+        // Clean close
+        let _ = connection.close(true, 0x00, b"done");
+        flush_packets(&socket, &mut connection, &mut out).await?;
+
+        Ok(bytes_sent)
+    }
+
+    /// Download a file from the server on the current tokio runtime
+    ///
+    /// Unlike `upload`, this is not native async: the blocking receive path
+    /// (`Transfer::run_receive`) runs on the runtime's blocking pool and
+    /// holds one of its threads until the download ends. Dropping the
+    /// future does not stop it; cancel through `Transfer::cancel_handle`
+    /// on a `Transfer` run directly instead.
+    ///
+    /// # Returns
+    /// Path of the received file
+    pub async fn download(&self, session_id: &str) -> Result<PathBuf> {
+        let mut transfer = self.receive_file(session_id)?;
+        spawn_blocking(move || transfer.run_receive()).await?
+    }
+}
+
+/// Run `f` on the blocking pool
+async fn spawn_blocking<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await
+        .map_err(|e| Error::Protocol(format!("Transfer task failed: {}", e)))
+}
+
+/// Complete the QUIC handshake
+async fn handshake(
+    socket: &UdpSocket,
+    connection: &mut ClientConnection,
+    buf: &mut [u8],
+    out: &mut [u8],
+    local_addr: SocketAddr,
+) -> Result<()> {
+    info!("Client: waiting for handshake to complete...");
+    let start_time = Instant::now();
+
+    while !(connection.is_established() && connection.peer_streams_left_bidi() > 0) {
+        if start_time.elapsed() > HANDSHAKE_TIMEOUT {
+            return Err(Error::Protocol(format!(
+                "Handshake timeout after {} seconds", HANDSHAKE_TIMEOUT.as_secs()
+            )));
+        }
+        if connection.is_closed() {
+            return Err(Error::ConnectionClosed);
+        }
+        pump_network(socket, connection, buf, out, local_addr, NETWORK_WAIT).await?;
+    }
+
+    info!("Client: handshake complete! (took {:.2}s)", start_time.elapsed().as_secs_f64());
+    Ok(())
+}
+
+/// Drive an upload's state machine until it is sent, then flush
+///
+/// The same loop as the blocking client's upload phase, for one upload:
+/// it only waits on the network when the upload cannot move.
+async fn send_upload(
+    transfer: &mut Transfer,
+    socket: &UdpSocket,
+    connection: &mut ClientConnection,
+    buf: &mut [u8],
+    out: &mut [u8],
+    local_addr: SocketAddr,
+    mut upload: OutgoingUpload,
+) -> Result<u64> {
+    loop {
+        let now = Instant::now();
+        let progressed = transfer.step_upload(&mut upload, connection, buf, now)?;
+        if upload.is_sent() {
+            break;
+        }
+
+        if connection.is_closed() {
+            return Err(Error::ConnectionClosed);
+        }
+
+        if progressed {
+            flush_packets(socket, connection, out).await?;
+            recv_packets(socket, connection, buf, local_addr)?;
+        } else {
+            let limit = upload.deadline()
+                .map_or(NETWORK_WAIT, |deadline| deadline.saturating_duration_since(now).min(NETWORK_WAIT));
+            pump_network(socket, connection, buf, out, local_addr, limit).await?;
+        }
+    }
+    let bytes_sent = transfer.finish_upload(upload);
+
+    // Final flush - keep exchanging packets until nothing has been left to
+    // send for a while
+    let mut quiet_since = Instant::now();
+    loop {
+        if flush_packets(socket, connection, out).await.unwrap_or(0) > 0 {
+            quiet_since = Instant::now();
+        } else if quiet_since.elapsed() > FLUSH_QUIET_TIME {
+            break;
+        }
+
+        let limit = FLUSH_QUIET_TIME.saturating_sub(quiet_since.elapsed());
+        if pump_network(socket, connection, buf, out, local_addr, limit).await.is_err() {
+            break;
+        }
+    }
+
+    info!("Client: file upload complete ({} bytes sent)", bytes_sent);
+    Ok(bytes_sent)
+}
+
+/// Send every packet the connection has ready
+///
+/// # Returns
+/// Number of datagrams sent
+async fn flush_packets(socket: &UdpSocket, connection: &mut ClientConnection, out: &mut [u8]) -> Result<usize> {
+    let mut sent = 0;
+    while let Ok((len, send_info)) = connection.send(out) {
+        socket.send_to(&out[..len], send_info.to).await?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// Feed the connection every datagram waiting on the socket, without waiting
+///
+/// # Returns
+/// Whether any datagram was received
+fn recv_packets(
+    socket: &UdpSocket,
+    connection: &mut ClientConnection,
+    buf: &mut [u8],
+    local_addr: SocketAddr,
+) -> Result<bool> {
+    let mut received = false;
+    loop {
+        match socket.try_recv_from(buf) {
+            Ok((len, from)) => {
+                let recv_info = quiche::RecvInfo { from, to: local_addr };
+                if let Err(e) = connection.recv(&mut buf[..len], recv_info) {
+                    debug!("Client: conn.recv error: {:?}", e);
+                }
+                received = true;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(received),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::from(e)),
+        }
+    }
+}
+
+/// Flush outgoing packets, then take in incoming ones
+///
+/// If nothing has arrived, waits until a datagram does, the connection's
+/// next timer fires or `limit` passes; expired timers are handled.
+///
+/// # Returns
+/// Whether any datagram was received
+async fn pump_network(
+    socket: &UdpSocket,
+    connection: &mut ClientConnection,
+    buf: &mut [u8],
+    out: &mut [u8],
+    local_addr: SocketAddr,
+    limit: Duration,
+) -> Result<bool> {
+    flush_packets(socket, connection, out).await?;
+    if recv_packets(socket, connection, buf, local_addr)? {
+        return Ok(true);
+    }
+
+    if let Ok(ready) = tokio::time::timeout(wait_time(connection.timeout(), limit), socket.readable()).await {
+        ready?;
+    }
+    if connection.timeout().is_some_and(|timer| timer.is_zero()) {
+        connection.on_timeout();
+    }
+    recv_packets(socket, connection, buf, local_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::config::ClientConfig;
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn test_futures_are_send() {
+        // Checked at compile time; the futures are never polled
+        let client = Client::new(ClientConfig::default());
+        assert_send(&client.upload("missing.bin", "server"));
+        assert_send(&client.download("missing"));
+    }
+}
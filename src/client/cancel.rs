@@ -0,0 +1,54 @@
+// Cancellation of in-flight transfers
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Application error code a cancelled transfer closes its connection with
+pub const CANCELLED_ERROR_CODE: u64 = 0x12;
+
+/// Stops a running transfer from another thread
+///
+/// Clones share one flag, so a handle taken before the transfer starts can
+/// be moved elsewhere and triggered while it runs. The transfer notices at
+/// its next step: it saves its resume state, closes the connection with
+/// `CANCELLED_ERROR_CODE` and fails with `Error::Cancelled`, ending in
+/// `TransferState::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the transfer to stop
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// The flag work outside the transfer loop, such as hashing, checks
+    pub(crate) fn flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_cancellation() {
+        let handle = CancelHandle::new();
+        let other = handle.clone();
+        assert!(!handle.is_cancelled());
+
+        std::thread::spawn(move || other.cancel()).join().unwrap();
+        assert!(handle.is_cancelled());
+    }
+}
@@ -8,6 +8,7 @@ use ring::rand::SecureRandom;
 use crate::common::error::{Error, Result};
 use crate::common::config::ClientConfig;
 use crate::common::types::*;
+use crate::engine::Outbox;
 
 pub struct ClientConnection {
     conn: quiche::Connection,
@@ -147,6 +148,20 @@ impl ClientConnection {
             })
     }
     
+    /// Write a state machine's queued stream writes, as far as the streams take them
+    pub fn flush_outbox(&mut self, outbox: &mut Outbox) -> Result<usize> {
+        outbox
+            .flush(|stream_id, data, fin| self.conn.stream_send(stream_id, data, fin))
+            .map_err(|e| Error::Quic(format!("Stream send error: {:?}", e)))
+    }
+    
+    /// Bytes the stream can take right now without blocking
+    pub fn stream_capacity(&self, stream_id: u64) -> Result<usize> {
+        self.conn
+            .stream_capacity(stream_id)
+            .map_err(|e| Error::Quic(format!("Stream capacity error on {}: {:?}", stream_id, e)))
+    }
+    
     pub fn stream_priority(&mut self, stream_id: u64, urgency: u8, incremental: bool) -> Result<()> {
         self.conn
             .stream_priority(stream_id, urgency, incremental)
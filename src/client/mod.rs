@@ -1,31 +1,45 @@
 // Client module - QUIC client implementation
 
+#[cfg(feature = "async")]
+mod async_transfer;
+mod cancel;
 mod connection;
 mod streams;
 mod session;
+mod ordered;
 pub mod receiver;
 mod sender;
+mod sync;
 pub mod transfer;
 
+pub use cancel::{CancelHandle, CANCELLED_ERROR_CODE};
 pub use connection::ClientConnection;
 pub use streams::{StreamManager, StreamType};
 pub use session::ClientSession;
 pub use receiver::FileReceiver;
+pub use ordered::{OrderedChunkWriter, DEFAULT_REORDER_BUFFER};
 pub use sender::DataSender;
+pub use sync::SyncPlan;
 pub use transfer::Transfer;
 
-use crate::common::error::Result;
+use crate::common::error::{Error, Result};
 use crate::common::config::ClientConfig;
+use crate::protocol::directory::DirectoryBuilder;
+use crate::protocol::messages::{FileVersion, StorageAction, StorageRequest};
+use crate::observer::{self, SharedObserver, TransferObserver};
+use std::path::Path;
+use std::sync::Arc;
 
 /// Main client interface
 pub struct Client {
     config: ClientConfig,
+    observer: SharedObserver,
 }
 
 impl Client {
     /// Create a new client with the given configuration
     pub fn new(config: ClientConfig) -> Self {
-        Self { config }
+        Self { config, observer: observer::noop() }
     }
     
     /// Create a client with default configuration
@@ -33,6 +47,7 @@ impl Client {
     pub fn default() -> Self {
         Self {
             config: ClientConfig::default(),
+            observer: observer::noop(),
         }
     }
     
@@ -40,27 +55,99 @@ impl Client {
     /// Uses certs/cert.pem for TLS verification
     pub fn with_defaults(server_addr: &str) -> Result<Self> {
         let addr = server_addr.parse()
-            .map_err(|e: std::net::AddrParseError| crate::common::error::Error::ConfigError(format!("Invalid address: {}", e)))?;
+            .map_err(|e: std::net::AddrParseError| Error::ConfigError(format!("Invalid address: {}", e)))?;
         
         let config = ClientConfig::new(addr, "localhost".to_string())
             .with_ca_cert(std::path::PathBuf::from("certs/cert.pem"));
         
-        Ok(Self { config })
+        Ok(Self { config, observer: observer::noop() })
+    }
+    
+    /// Report the events of every transfer this client starts to `observer`
+    pub fn with_observer(mut self, observer: Arc<dyn TransferObserver>) -> Self {
+        self.observer = observer;
+        self
     }
     
     /// Send a file to the server
     pub fn send_file(&self, file_path: &str, destination: &str) -> Result<Transfer> {
-        Transfer::send_file(self.config.clone(), file_path, destination)
+        Ok(Transfer::send_file(self.config.clone(), file_path, destination)?.with_observer(self.observer.clone()))
+    }
+    
+    /// Send input of unknown length (stdin, a pipe) to `remote_path` on the server
+    pub fn send_stream(&self, remote_path: &str) -> Result<Transfer> {
+        Ok(Transfer::send_stream(self.config.clone(), remote_path)?.with_observer(self.observer.clone()))
+    }
+    
+    /// Download the file at `remote_path` from the server
+    pub fn download_file(&self, remote_path: &str) -> Result<Transfer> {
+        Ok(Transfer::download(self.config.clone(), remote_path)?.with_observer(self.observer.clone()))
     }
     
     /// Receive a file from the server
     pub fn receive_file(&self, session_id: &str) -> Result<Transfer> {
-        Transfer::receive_file(self.config.clone(), session_id)
+        Ok(Transfer::receive_file(self.config.clone(), session_id)?.with_observer(self.observer.clone()))
     }
     
     /// Resume a previous transfer
     pub fn resume_transfer(&self, session_id: &str) -> Result<Transfer> {
-        Transfer::resume(self.config.clone(), session_id)
+        Ok(Transfer::resume(self.config.clone(), session_id)?.with_observer(self.observer.clone()))
+    }
+    
+    /// Versions the server keeps of the file at `remote_path`, oldest first
+    pub fn list_versions(&self, remote_path: &str) -> Result<Vec<FileVersion>> {
+        let request = StorageRequest {
+            path: remote_path.to_string(),
+            action: StorageAction::ListVersions as i32,
+            version: 0,
+            entries: Vec::new(),
+            signature: None,
+        };
+        let mut transfer = Transfer::storage_request(self.config.clone())?.with_observer(self.observer.clone());
+        Ok(transfer.run_storage_request(&request)?.versions)
+    }
+    
+    /// Put back version `version` of the file at `remote_path` on the server
+    /// 
+    /// The file it replaces is kept as a version in turn.
+    pub fn restore_version(&self, remote_path: &str, version: u64) -> Result<FileVersion> {
+        let request = StorageRequest {
+            path: remote_path.to_string(),
+            action: StorageAction::RestoreVersion as i32,
+            version,
+            entries: Vec::new(),
+            signature: None,
+        };
+        let mut transfer = Transfer::storage_request(self.config.clone())?.with_observer(self.observer.clone());
+        transfer.run_storage_request(&request)?.restored
+            .ok_or_else(|| Error::Protocol("Server restored no version".to_string()))
+    }
+    
+    /// Compare `local_dir` with `remote_dir` on the server, to mirror one to
+    /// the other
+    /// 
+    /// With `delete`, entries only the server has are to be removed.
+    pub fn plan_sync(&self, local_dir: &Path, remote_dir: &str, delete: bool) -> Result<SyncPlan> {
+        let local = DirectoryBuilder::new(local_dir).walk()?;
+        let request = StorageRequest {
+            path: remote_dir.to_string(),
+            action: StorageAction::ListTree as i32,
+            version: 0,
+            entries: Vec::new(),
+            signature: None,
+        };
+        let mut transfer = Transfer::storage_request(self.config.clone())?.with_observer(self.observer.clone());
+        let remote = transfer.run_storage_request(&request)?.entries;
+        SyncPlan::new(remote_dir, local, &remote, delete)
+    }
+    
+    /// Carry out `plan`, making its remote directory match the local one
+    /// 
+    /// # Returns
+    /// Bytes sent
+    pub fn sync(&self, plan: &SyncPlan) -> Result<u64> {
+        let mut transfer = Transfer::storage_request(self.config.clone())?.with_observer(self.observer.clone());
+        transfer.run_sync(plan)
     }
     
     /// Get the current client configuration
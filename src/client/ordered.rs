@@ -0,0 +1,303 @@
+// In-order output of downloaded chunks
+//
+// Chunks spread over several data streams, or retransmitted, can arrive out
+// of order. For output that cannot seek (stdout, a pipe) each chunk is
+// verified against the manifest as it arrives, written at once if it is the
+// next one due, and otherwise held until the chunks before it are in. Once
+// the chunks held reach a cap in bytes the receiver stops reading the stream
+// (see `has_room`), so QUIC flow control holds the sender back instead of a
+// missing chunk using up memory.
+// Chunks the manifest lists as holes are never sent and are written as zeros
+// when their turn comes.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use crate::chunking::{sparse, TreeVerifier};
+use crate::common::error::{Error, Result};
+use crate::protocol::chunk::ChunkPacketView;
+use crate::protocol::messages::{ChunkRange, Manifest};
+
+/// Default cap on chunk data held back for ordering (64 MB)
+pub const DEFAULT_REORDER_BUFFER: usize = 64 * 1024 * 1024;
+
+/// What a chunk is verified against
+enum ChunkCheck {
+    /// The manifest's hash of each chunk
+    Hashes(Vec<Vec<u8>>),
+    /// A tree-hash manifest's root, with the proof each chunk carries
+    Tree(TreeVerifier),
+}
+
+/// Writes verified chunks of one file to `W` in chunk order
+pub struct OrderedChunkWriter<W: Write> {
+    writer: W,
+    check: ChunkCheck,
+    total_chunks: u64,
+    file_hash: Vec<u8>,
+    file_size: u64,
+    chunk_size: u64,
+    /// Chunks in holes of a sparse file, written as zeros
+    holes: Vec<ChunkRange>,
+    /// Next chunk to write
+    next_chunk: u64,
+    /// Verified chunks waiting for the ones before them
+    pending: BTreeMap<u64, Vec<u8>>,
+    pending_bytes: usize,
+    max_pending_bytes: usize,
+    file_hasher: blake3::Hasher,
+    bytes_written: u64,
+}
+
+impl<W: Write> OrderedChunkWriter<W> {
+    /// Write the file `manifest` describes to `writer`, with room to hold
+    /// back `max_pending_bytes` of chunk data
+    ///
+    /// The manifest must carry its chunk hashes or a tree root; encrypted
+    /// files are decrypted from a complete file and cannot be streamed.
+    pub fn new(writer: W, manifest: &Manifest, max_pending_bytes: usize) -> Result<Self> {
+        if manifest.encryption.is_some() {
+            return Err(Error::ConfigError(format!(
+                "{} is encrypted and cannot be streamed", manifest.file_name
+            )));
+        }
+
+        let check = match &manifest.tree_root {
+            Some(root) => ChunkCheck::Tree(TreeVerifier::new(root, manifest.total_chunks)?),
+            None if manifest.chunk_hashes.len() as u64 == manifest.total_chunks => {
+                ChunkCheck::Hashes(manifest.chunk_hashes.clone())
+            }
+            None => return Err(Error::Protocol(format!(
+                "Manifest lists {} chunk hashes for {} chunks",
+                manifest.chunk_hashes.len(), manifest.total_chunks
+            ))),
+        };
+
+        let mut ordered = Self {
+            writer,
+            check,
+            total_chunks: manifest.total_chunks,
+            file_hash: manifest.file_hash.clone(),
+            file_size: manifest.file_size,
+            chunk_size: manifest.chunk_size as u64,
+            holes: manifest.holes.clone(),
+            next_chunk: 0,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            max_pending_bytes,
+            file_hasher: blake3::Hasher::new(),
+            bytes_written: 0,
+        };
+
+        // A hole at the start is due straight away
+        ordered.write_holes()?;
+        Ok(ordered)
+    }
+
+    /// Verify a received chunk and write it, with any held chunks it frees
+    ///
+    /// Chunks already written or held are ignored. A chunk that fails
+    /// verification is an error. A chunk pushed without room is still held;
+    /// the reader checks `has_room` before reading more.
+    pub fn push(&mut self, chunk: ChunkPacketView) -> Result<()> {
+        let chunk_id = chunk.chunk_id;
+        if chunk_id >= self.total_chunks {
+            return Err(Error::Protocol(format!(
+                "Chunk {} is past the end of a {}-chunk file", chunk_id, self.total_chunks
+            )));
+        }
+        if chunk_id < self.next_chunk || self.pending.contains_key(&chunk_id) {
+            log::debug!("Duplicate chunk {} received, ignoring", chunk_id);
+            return Ok(());
+        }
+        if sparse::is_hole(&self.holes, chunk_id) {
+            log::debug!("Chunk {} lies in a hole, ignoring", chunk_id);
+            return Ok(());
+        }
+
+        self.verify(&chunk)?;
+
+        if chunk_id != self.next_chunk {
+            self.pending_bytes += chunk.data.len();
+            self.pending.insert(chunk_id, chunk.data);
+            return Ok(());
+        }
+
+        self.write_chunk(&chunk.data)?;
+        self.write_holes()?;
+        while let Some(data) = self.pending.remove(&self.next_chunk) {
+            self.pending_bytes -= data.len();
+            self.write_chunk(&data)?;
+            self.write_holes()?;
+        }
+        Ok(())
+    }
+
+    /// Whether every chunk has been written
+    pub fn is_complete(&self) -> bool {
+        self.next_chunk == self.total_chunks
+    }
+
+    /// Chunks written so far
+    pub fn chunks_written(&self) -> u64 {
+        self.next_chunk
+    }
+
+    /// Chunk data held back for ordering
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes
+    }
+
+    /// Whether more chunks may be read before the ones held are written
+    pub fn has_room(&self) -> bool {
+        self.pending_bytes < self.max_pending_bytes
+    }
+
+    /// Check the file hash over everything written and flush the output
+    ///
+    /// # Returns
+    /// Bytes written
+    pub fn finish(&mut self) -> Result<u64> {
+        if !self.is_complete() {
+            return Err(Error::Protocol(format!(
+                "Output ended at chunk {} of {}", self.next_chunk, self.total_chunks
+            )));
+        }
+
+        let computed = self.file_hasher.finalize();
+        if computed.as_bytes() != self.file_hash.as_slice() {
+            return Err(Error::HashMismatch {
+                expected: self.file_hash.clone(),
+                actual: computed.as_bytes().to_vec(),
+            });
+        }
+
+        self.writer.flush()?;
+        Ok(self.bytes_written)
+    }
+
+    fn verify(&self, chunk: &ChunkPacketView) -> Result<()> {
+        let computed = blake3::hash(&chunk.data);
+        let expected = match &self.check {
+            ChunkCheck::Hashes(hashes) => &hashes[chunk.chunk_id as usize],
+            ChunkCheck::Tree(verifier) => {
+                verifier.verify(chunk.chunk_id, &chunk.checksum, &chunk.proof)?;
+                &chunk.checksum
+            }
+        };
+
+        if computed.as_bytes() != expected.as_slice() {
+            return Err(Error::HashMismatch {
+                expected: expected.clone(),
+                actual: computed.as_bytes().to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Write zeros for the hole chunks due next, if any
+    fn write_holes(&mut self) -> Result<()> {
+        let mut zeros = Vec::new();
+        while self.next_chunk < self.total_chunks && sparse::is_hole(&self.holes, self.next_chunk) {
+            let offset = self.next_chunk * self.chunk_size;
+            let len = self.chunk_size.min(self.file_size.saturating_sub(offset)) as usize;
+            zeros.resize(len, 0);
+            self.write_chunk(&zeros)?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.write_all(data)?;
+        self.file_hasher.update(data);
+        self.bytes_written += data.len() as u64;
+        self.next_chunk += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::chunk::{ChunkPacketBuilder, ChunkPacketParser};
+
+    fn chunk(data: &[u8], chunk_id: u64, end_of_file: bool) -> ChunkPacketView {
+        let hash = blake3::hash(data);
+        let packet = ChunkPacketBuilder::new()
+            .build(chunk_id, chunk_id * 4, data.len() as u32, hash.as_bytes(), end_of_file, data)
+            .unwrap();
+        ChunkPacketParser::parse(&packet).unwrap()
+    }
+
+    fn manifest(content: &[u8]) -> Manifest {
+        Manifest {
+            file_name: "dump.tar".to_string(),
+            file_size: content.len() as u64,
+            chunk_size: 4,
+            total_chunks: content.chunks(4).count() as u64,
+            file_hash: blake3::hash(content).as_bytes().to_vec(),
+            chunk_hashes: content.chunks(4).map(|c| blake3::hash(c).as_bytes().to_vec()).collect(),
+            ..Manifest::default()
+        }
+    }
+
+    #[test]
+    fn test_out_of_order_chunks_written_in_order() {
+        let content: Vec<u8> = (0..14u8).collect();
+        let pieces: Vec<&[u8]> = content.chunks(4).collect();
+        let mut output = Vec::new();
+        let mut writer = OrderedChunkWriter::new(&mut output, &manifest(&content), 8).unwrap();
+
+        writer.push(chunk(pieces[2], 2, false)).unwrap();
+        assert!(writer.has_room());
+        writer.push(chunk(pieces[1], 1, false)).unwrap();
+        assert_eq!(writer.chunks_written(), 0);
+        assert_eq!(writer.pending_bytes(), 8);
+
+        // The buffer is full: the reader stops rather than the transfer
+        assert!(!writer.has_room());
+
+        writer.push(chunk(pieces[0], 0, false)).unwrap();
+        writer.push(chunk(pieces[0], 0, false)).unwrap();
+        assert_eq!(writer.chunks_written(), 3);
+        assert_eq!(writer.pending_bytes(), 0);
+        assert!(writer.has_room());
+
+        writer.push(chunk(pieces[3], 3, true)).unwrap();
+        assert!(writer.is_complete());
+        assert_eq!(writer.finish().unwrap(), 14);
+        assert_eq!(output, content);
+    }
+
+    #[test]
+    fn test_holes_written_as_zeros() {
+        let mut content = vec![0u8; 18];
+        content[8..12].copy_from_slice(&[1, 2, 3, 4]);
+        let pieces: Vec<&[u8]> = content.chunks(4).collect();
+        let mut sparse_manifest = manifest(&content);
+        sparse_manifest.holes = vec![
+            ChunkRange { first_chunk: 0, chunk_count: 2 },
+            ChunkRange { first_chunk: 3, chunk_count: 2 },
+        ];
+
+        let mut output = Vec::new();
+        let mut writer = OrderedChunkWriter::new(&mut output, &sparse_manifest, 8).unwrap();
+        assert_eq!(writer.chunks_written(), 2);
+
+        writer.push(chunk(pieces[2], 2, false)).unwrap();
+        assert!(writer.is_complete());
+        assert_eq!(writer.finish().unwrap(), 18);
+        assert_eq!(output, content);
+    }
+
+    #[test]
+    fn test_chunk_not_in_manifest_rejected() {
+        let content: Vec<u8> = (0..8u8).collect();
+        let mut output = Vec::new();
+        let mut writer = OrderedChunkWriter::new(&mut output, &manifest(&content), 8).unwrap();
+
+        // Consistent with its own checksum, but not the manifest's chunk
+        assert!(writer.push(chunk(&[9, 9, 9, 9], 0, false)).is_err());
+        assert!(writer.finish().is_err());
+        assert!(output.is_empty());
+    }
+}
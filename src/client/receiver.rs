@@ -9,6 +9,9 @@ use crate::common::types::ChunkId;
 use crate::protocol::chunk::{ChunkPacketParser, ChunkPacketView};
 use crate::retransmission::missing::MissingChunkTracker;
 use crate::protocol::control::ControlMessage;
+use crate::chunking::tree::TreeVerifier;
+use crate::chunking::sparse;
+use crate::protocol::messages::ChunkRange;
 
 /// Synchronization mode for chunk writes
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -55,6 +58,10 @@ pub struct FileReceiver {
     auto_retransmit: bool,
     /// In-memory buffer for BufferedInMemory mode
     memory_buffer: Option<Vec<u8>>,
+    /// Verifies chunk proofs for tree-hash manifests
+    tree_verifier: Option<TreeVerifier>,
+    /// Leave the .part file in place on drop so a later transfer can resume
+    keep_partial: bool,
 }
 
 impl FileReceiver {
@@ -82,7 +89,7 @@ impl FileReceiver {
         sync_mode: SyncMode,
     ) -> Result<Self> {
         let final_file_path = output_dir.join(filename);
-        let part_file_path = output_dir.join(format!("{}.part", filename));
+        let part_file_path = crate::storage::partial::part_file_path(output_dir, filename);
         
         // Create or open .part file
         let part_file = OpenOptions::new()
@@ -124,6 +131,8 @@ impl FileReceiver {
             control_sender: None,
             auto_retransmit: false,
             memory_buffer,
+            tree_verifier: None,
+            keep_partial: false,
         })
     }
     
@@ -174,8 +183,12 @@ impl FileReceiver {
             )));
         }
         
-        // Verify checksum
-        if let Err(e) = chunk.verify_checksum() {
+        // Verify checksum, and the chunk's place in the hash tree if there is one
+        let verified = chunk.verify_checksum().and_then(|_| match &self.tree_verifier {
+            Some(verifier) => verifier.verify(chunk.chunk_id, &chunk.checksum, &chunk.proof),
+            None => Ok(()),
+        });
+        if let Err(e) = verified {
             log::error!("Chunk {} failed checksum verification: {:?}", chunk.chunk_id, e);
             
             // If auto-retransmit is enabled, send NACK and request retransmission
@@ -209,46 +222,7 @@ impl FileReceiver {
         }
         
         // Write chunk - either to memory buffer or disk
-        match self.sync_mode {
-            SyncMode::BufferedInMemory => {
-                // Write to in-memory buffer (super fast!)
-                if let Some(buffer) = &mut self.memory_buffer {
-                    let start = chunk.byte_offset as usize;
-                    let end = start + chunk.data.len();
-                    
-                    // Grow buffer if needed (for dynamic file sizes)
-                    if end > buffer.len() {
-                        buffer.resize(end, 0);
-                    }
-                    
-                    buffer[start..end].copy_from_slice(&chunk.data);
-                }
-                // No disk I/O at all!
-            }
-            _ => {
-                // Write to disk for other modes
-                self.part_file.seek(SeekFrom::Start(chunk.byte_offset))?;
-                self.part_file.write_all(&chunk.data)?;
-                
-                // Sync based on configured mode
-                match self.sync_mode {
-                    SyncMode::FlushOnly => {
-                        self.part_file.flush()?;
-                    }
-                    SyncMode::SyncAll => {
-                        self.part_file.flush()?;
-                        self.part_file.sync_all()?;
-                    }
-                    SyncMode::SyncEvery(n) => {
-                        self.part_file.flush()?;
-                        if (chunk.chunk_id + 1) % n == 0 {
-                            self.part_file.sync_all()?;
-                        }
-                    }
-                    SyncMode::BufferedInMemory => unreachable!(),
-                }
-            }
-        }
+        self.write_at(chunk.chunk_id, chunk.byte_offset, &chunk.data)?;
         
         // Update tracking
         self.received_chunks.insert(chunk.chunk_id);
@@ -399,6 +373,203 @@ impl FileReceiver {
         Ok(())
     }
     
+    /// Set the size of a file whose length was unknown when receiving began
+    ///
+    /// A stream's totals arrive after its chunks. Chunks past the end, left
+    /// in the .part file by an earlier and longer attempt, are cut off. An
+    /// empty file has no final chunk, so its totals are set before receiving.
+    pub fn set_totals(&mut self, file_size: u64, total_chunks: u64) -> Result<()> {
+        if self.end_of_file_received && self.total_chunks != total_chunks {
+            return Err(Error::Protocol(format!(
+                "Final chunk makes {} chunks, totals say {}",
+                self.total_chunks, total_chunks
+            )));
+        }
+
+        self.received_chunks.retain(|&chunk_id| chunk_id < total_chunks);
+        self.file_size = file_size;
+        self.total_chunks = total_chunks;
+        self.end_of_file_received = true;
+
+        match &mut self.memory_buffer {
+            Some(buffer) => buffer.resize(file_size as usize, 0),
+            None => self.part_file.set_len(file_size)?,
+        }
+        Ok(())
+    }
+
+    /// Make the chunks written so far durable
+    ///
+    /// Does nothing for an in-memory receive buffer, which only reaches the
+    /// disk on finalization.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.memory_buffer.is_none() {
+            self.part_file.flush()?;
+            self.part_file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Verify every chunk against a tree-hash manifest's root
+    /// 
+    /// Chunks without a valid proof are rejected like checksum failures.
+    pub fn set_tree_root(&mut self, root: &[u8], total_chunks: u64) -> Result<()> {
+        self.tree_verifier = Some(TreeVerifier::new(root, total_chunks)?);
+        Ok(())
+    }
+    
+    /// Keep the .part file if the receiver is dropped before finalization
+    /// 
+    /// The partial file can then be scanned to resume the transfer later.
+    /// `abort()` still removes it.
+    pub fn keep_partial_on_drop(&mut self) {
+        self.keep_partial = true;
+    }
+    
+    /// Path the file is stored at once finalized
+    pub fn final_path(&self) -> &Path {
+        &self.final_file_path
+    }
+    
+    /// Store the finalized file at `path` instead of beside its .part file
+    pub fn set_final_path(&mut self, path: PathBuf) {
+        self.final_file_path = path;
+    }
+    
+    /// Write chunk data at its offset according to the sync mode
+    fn write_at(&mut self, chunk_id: ChunkId, byte_offset: u64, data: &[u8]) -> Result<()> {
+        match self.sync_mode {
+            SyncMode::BufferedInMemory => {
+                // Write to in-memory buffer (super fast!)
+                if let Some(buffer) = &mut self.memory_buffer {
+                    let start = byte_offset as usize;
+                    let end = start + data.len();
+                    
+                    // Grow buffer if needed (for dynamic file sizes)
+                    if end > buffer.len() {
+                        buffer.resize(end, 0);
+                    }
+                    
+                    buffer[start..end].copy_from_slice(data);
+                }
+                // No disk I/O at all!
+            }
+            _ => {
+                // Write to disk for other modes
+                self.part_file.seek(SeekFrom::Start(byte_offset))?;
+                self.part_file.write_all(data)?;
+                
+                // Sync based on configured mode
+                match self.sync_mode {
+                    SyncMode::FlushOnly => {
+                        self.part_file.flush()?;
+                    }
+                    SyncMode::SyncAll => {
+                        self.part_file.flush()?;
+                        self.part_file.sync_all()?;
+                    }
+                    SyncMode::SyncEvery(n) => {
+                        self.part_file.flush()?;
+                        if (chunk_id + 1) % n == 0 {
+                            self.part_file.sync_all()?;
+                        }
+                    }
+                    SyncMode::BufferedInMemory => unreachable!(),
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Store a chunk the receiver already had locally (e.g. from the
+    /// dedup index) as if it had arrived from the sender
+    /// 
+    /// The caller is responsible for having verified `data` against the
+    /// manifest's chunk hash.
+    pub fn write_local_chunk(&mut self, chunk_id: ChunkId, data: &[u8], chunk_size: u64, total_chunks: u64) -> Result<()> {
+        if chunk_id >= total_chunks || self.received_chunks.contains(&chunk_id) {
+            return Ok(());
+        }
+        
+        self.write_at(chunk_id, chunk_id * chunk_size, data)?;
+        self.track_existing_chunk(chunk_id, chunk_size, total_chunks);
+        Ok(())
+    }
+    
+    /// Count chunks already verified in the .part file as received
+    /// 
+    /// Used when resuming: these chunks are not sent again, so the receiver
+    /// must know about them to recognize completion.
+    /// 
+    /// # Arguments
+    /// * `chunk_ids` - Chunks whose data on disk is known to be correct
+    /// * `chunk_size` - Chunk size from the manifest
+    /// * `total_chunks` - Total chunks from the manifest
+    pub fn mark_existing_chunks(&mut self, chunk_ids: &[ChunkId], chunk_size: u64, total_chunks: u64) -> Result<()> {
+        if matches!(self.sync_mode, SyncMode::BufferedInMemory) {
+            return Err(Error::Protocol(
+                "Cannot resume into an in-memory receive buffer".to_string()
+            ));
+        }
+        
+        for &chunk_id in chunk_ids {
+            self.track_existing_chunk(chunk_id, chunk_size, total_chunks);
+        }
+        
+        Ok(())
+    }
+    
+    /// Leave the chunks a manifest lists as holes unwritten, counting them
+    /// as received
+    /// 
+    /// Anything an earlier attempt left in those ranges of the .part file is
+    /// punched out, so the finished file is sparse where the original was.
+    /// 
+    /// # Arguments
+    /// * `holes` - Hole chunk ranges from the manifest
+    /// * `chunk_size` - Chunk size from the manifest
+    /// * `total_chunks` - Total chunks from the manifest
+    pub fn mark_holes(&mut self, holes: &[ChunkRange], chunk_size: u64, total_chunks: u64) -> Result<()> {
+        for range in holes {
+            let end_chunk = range.end_chunk().min(total_chunks);
+            if range.first_chunk >= end_chunk {
+                continue;
+            }
+            
+            let offset = range.first_chunk * chunk_size;
+            let end = (end_chunk * chunk_size).min(self.file_size);
+            match &mut self.memory_buffer {
+                Some(buffer) => buffer[offset as usize..end as usize].fill(0),
+                None => sparse::punch_hole(&self.part_file, offset, end - offset)?,
+            }
+            
+            for chunk_id in range.first_chunk..end_chunk {
+                self.track_existing_chunk(chunk_id, chunk_size, total_chunks);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn track_existing_chunk(&mut self, chunk_id: ChunkId, chunk_size: u64, total_chunks: u64) {
+        if chunk_id >= total_chunks || !self.received_chunks.insert(chunk_id) {
+            return;
+        }
+        
+        let offset = chunk_id * chunk_size;
+        self.bytes_received += chunk_size.min(self.file_size.saturating_sub(offset));
+        
+        if let Some(tracker) = &mut self.missing_tracker {
+            tracker.mark_received(chunk_id);
+        }
+        
+        if chunk_id == total_chunks - 1 {
+            self.end_of_file_received = true;
+            self.total_chunks = total_chunks;
+        }
+    }
+    
     /// Verify the complete file hash matches the expected hash
     /// This performs end-to-end integrity verification
     pub fn verify_file_hash(&mut self) -> Result<()> {
@@ -509,6 +680,7 @@ impl FileReceiver {
     /// Abort the transfer and clean up the partial file
     /// This is useful when explicitly canceling a transfer
     pub fn abort(mut self) -> Result<()> {
+        self.keep_partial = false;
         self.cleanup()
     }
     
@@ -548,7 +720,12 @@ impl FileReceiver {
 /// Automatically clean up partial file on drop if not finalized
 impl Drop for FileReceiver {
     fn drop(&mut self) {
-        if !self.finalized {
+        if !self.finalized && self.keep_partial {
+            log::info!(
+                "FileReceiver dropped without finalization, keeping partial file for resume: {}",
+                self.part_file_path.display()
+            );
+        } else if !self.finalized {
             log::warn!(
                 "FileReceiver dropped without finalization, cleaning up partial file: {}",
                 self.part_file_path.display()
@@ -669,6 +846,24 @@ mod tests {
         assert!(!part_file_path.exists());
     }
     
+    #[test]
+    fn test_keep_partial_on_drop() {
+        let temp_dir = TempDir::new().unwrap();
+        let part_file_path = temp_dir.path().join("test.dat.part");
+        
+        {
+            let mut receiver = FileReceiver::new(temp_dir.path(), "test.dat", 100).unwrap();
+            receiver.keep_partial_on_drop();
+        }
+        assert!(part_file_path.exists());
+        
+        // Aborting still removes it
+        let mut receiver = FileReceiver::new(temp_dir.path(), "test.dat", 100).unwrap();
+        receiver.keep_partial_on_drop();
+        receiver.abort().unwrap();
+        assert!(!part_file_path.exists());
+    }
+    
     #[test]
     fn test_explicit_abort() {
         let temp_dir = TempDir::new().unwrap();
@@ -977,6 +1172,121 @@ mod tests {
         assert_eq!(messages[0].chunk_ids, vec![1]);
     }
     
+    #[test]
+    fn test_receive_chunks_with_tree_proofs() {
+        use crate::chunking::tree::HashTree;
+        
+        let temp_dir = TempDir::new().unwrap();
+        let chunks = [vec![1u8; 50], vec![2u8; 50], vec![3u8; 20]];
+        let hashes: Vec<Vec<u8>> = chunks.iter()
+            .map(|c| blake3::hash(c).as_bytes().to_vec())
+            .collect();
+        let tree = HashTree::from_chunk_hashes(&hashes).unwrap();
+        
+        let mut receiver = FileReceiver::new(temp_dir.path(), "tree.dat", 120).unwrap();
+        receiver.set_tree_root(tree.root(), 3).unwrap();
+        
+        // A chunk with an intact checksum but no proof is rejected
+        let packet = ChunkPacketBuilder::new()
+            .build(0, 0, 50, &hashes[0], false, &chunks[0])
+            .unwrap();
+        assert!(receiver.receive_chunk(&packet).is_err());
+        
+        // As is a chunk carrying another chunk's proof
+        let packet = ChunkPacketBuilder::new()
+            .with_proof(tree.proof(1).unwrap())
+            .build(0, 0, 50, &hashes[0], false, &chunks[0])
+            .unwrap();
+        assert!(receiver.receive_chunk(&packet).is_err());
+        
+        for (id, data) in chunks.iter().enumerate() {
+            let packet = ChunkPacketBuilder::new()
+                .with_proof(tree.proof(id as u64).unwrap())
+                .build(id as u64, id as u64 * 50, data.len() as u32, &hashes[id], id == 2, data)
+                .unwrap();
+            receiver.receive_chunk(&packet).unwrap();
+        }
+        assert!(receiver.is_complete());
+    }
+    
+    #[test]
+    fn test_resume_with_existing_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let data: Vec<u8> = (0..120u8).collect();
+        
+        // Chunks 0 and 2 are already on disk from an earlier attempt
+        let mut part = vec![0u8; 120];
+        part[..50].copy_from_slice(&data[..50]);
+        part[100..].copy_from_slice(&data[100..]);
+        std::fs::write(temp_dir.path().join("resume.dat.part"), &part).unwrap();
+        
+        let mut receiver = FileReceiver::new(temp_dir.path(), "resume.dat", 120).unwrap();
+        receiver.set_expected_hash(blake3::hash(&data).as_bytes().to_vec()).unwrap();
+        receiver.mark_existing_chunks(&[0, 2], 50, 3).unwrap();
+        assert!(!receiver.is_complete());
+        assert_eq!(receiver.missing_chunks(), vec![1]);
+        assert_eq!(receiver.bytes_received, 70);
+        
+        let packet = ChunkPacketBuilder::new()
+            .build(1, 50, 50, blake3::hash(&data[50..100]).as_bytes(), false, &data[50..100])
+            .unwrap();
+        receiver.receive_chunk(&packet).unwrap();
+        assert!(receiver.is_complete());
+        
+        let final_path = receiver.finalize().unwrap();
+        assert_eq!(std::fs::read(final_path).unwrap(), data);
+    }
+    
+    #[test]
+    fn test_stream_totals_after_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let data: Vec<u8> = (0..80u8).collect();
+
+        // An earlier, longer attempt left three chunks; only the first matches
+        let mut part = vec![0xffu8; 150];
+        part[..50].copy_from_slice(&data[..50]);
+        std::fs::write(temp_dir.path().join("stream.dat.part"), &part).unwrap();
+
+        let mut receiver = FileReceiver::new(temp_dir.path(), "stream.dat", 0).unwrap();
+        receiver.mark_existing_chunks(&[0, 2], 50, u64::MAX).unwrap();
+
+        let packet = ChunkPacketBuilder::new()
+            .build(1, 50, 30, blake3::hash(&data[50..]).as_bytes(), true, &data[50..])
+            .unwrap();
+        receiver.receive_chunk(&packet).unwrap();
+        assert!(!receiver.is_complete());
+
+        assert!(receiver.set_totals(80, 3).is_err());
+        receiver.set_totals(80, 2).unwrap();
+        receiver.set_expected_hash(blake3::hash(&data).as_bytes().to_vec()).unwrap();
+        assert!(receiver.is_complete());
+
+        let final_path = receiver.finalize().unwrap();
+        assert_eq!(std::fs::read(final_path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_write_local_chunk() {
+        let temp_dir = TempDir::new().unwrap();
+        let data: Vec<u8> = (0..100u8).collect();
+        
+        let mut receiver = FileReceiver::new(temp_dir.path(), "dedup.dat", 100).unwrap();
+        receiver.set_expected_hash(blake3::hash(&data).as_bytes().to_vec()).unwrap();
+        
+        // Last chunk comes from local storage, first from the sender
+        receiver.write_local_chunk(1, &data[60..], 60, 2).unwrap();
+        assert!(!receiver.is_complete());
+        
+        let packet = ChunkPacketBuilder::new()
+            .build(0, 0, 60, blake3::hash(&data[..60]).as_bytes(), false, &data[..60])
+            .unwrap();
+        receiver.receive_chunk(&packet).unwrap();
+        assert!(receiver.is_complete());
+        
+        let final_path = receiver.finalize().unwrap();
+        assert_eq!(std::fs::read(final_path).unwrap(), data);
+    }
+    
     #[test]
     fn test_disable_auto_retransmit() {
         let temp_dir = tempfile::tempdir().unwrap();
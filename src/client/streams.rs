@@ -3,6 +3,8 @@
 use std::collections::HashMap;
 use crate::common::error::Result;
 use super::connection::ClientConnection;
+use crate::transport::priority::{data_stream_priority, stream_priority};
+use crate::transport::TransferStreams;
 
 pub use crate::common::types::StreamType;
 
@@ -13,7 +15,9 @@ pub const STREAM_CONTROL: u64 = 0;     // Client-initiated bidirectional - Contr
 pub const STREAM_MANIFEST: u64 = 4;    // Client-initiated bidirectional - File manifest/metadata
 pub const STREAM_DATA: u64 = 8;        // Client-initiated bidirectional - File data chunks
 pub const STREAM_STATUS: u64 = 12;     // Client-initiated bidirectional - Transfer status updates
+#[allow(dead_code)]
 pub const STREAM_HASH_CHECK: u64 = 16; // Client-initiated bidirectional - Hash check requests/responses (changed from 1)
+#[allow(dead_code)]
 pub const STREAM_RESUME: u64 = 20;    // Client-initiated bidirectional - Resume requests
 #[allow(dead_code)]
 pub const STREAM_DELTA: u64 = 5;       // Server-initiated bidirectional - Delta sync requests/patches
@@ -83,15 +87,11 @@ impl StreamManager {
     }
     
     /// Set stream priority based on stream type
+    /// 
+    /// Control, status, resume and hash check messages go ahead of chunk
+    /// data (see `transport::priority`).
     pub fn set_stream_priority(&self, conn: &mut ClientConnection, stream_id: u64) -> Result<()> {
-        let (urgency, incremental) = match stream_id {
-            STREAM_CONTROL => (0, false),  // Highest priority, non-incremental
-            STREAM_MANIFEST => (2, false), // High priority, non-incremental (metadata)
-            STREAM_DATA => (4, true),      // Medium priority, incremental (bulk data)
-            STREAM_STATUS => (3, false),   // Medium-high priority, non-incremental (status updates)
-            _ => (7, true),                // Lowest priority for unknown streams
-        };
-        
+        let (urgency, incremental) = stream_priority(stream_id);
         conn.stream_priority(stream_id, urgency, incremental)?;
         Ok(())
     }
@@ -163,6 +163,23 @@ impl StreamManager {
         Ok(())
     }
     
+    /// Set priorities for the streams of one transfer
+    pub fn initialize_transfer_streams(&self, conn: &mut ClientConnection, streams: &TransferStreams) -> Result<()> {
+        for stream_id in streams.all() {
+            self.set_stream_priority(conn, stream_id)?;
+        }
+        Ok(())
+    }
+    
+    /// Set priorities for a transfer's extra data streams
+    pub fn initialize_data_streams(&self, conn: &mut ClientConnection, data_streams: &[u64]) -> Result<()> {
+        let (urgency, incremental) = data_stream_priority();
+        for &stream_id in data_streams {
+            conn.stream_priority(stream_id, urgency, incremental)?;
+        }
+        Ok(())
+    }
+    
     /// Get all stream IDs
     pub fn get_all_stream_ids(&self) -> Vec<u64> {
         vec![STREAM_CONTROL, STREAM_MANIFEST, STREAM_DATA, STREAM_STATUS]
@@ -0,0 +1,254 @@
+// One-way mirroring of a local directory to one on the server
+//
+// The server lists the directory it stores: every entry, with each file's
+// size and hash. Local files of another size are changed; those of the same
+// size are hashed and compared. Only new and changed files are uploaded, as
+// one recursive upload whose directory manifest lists just them, so resume
+// and dedup work as for any upload. When deleting, a remote entry of the
+// other kind than the local one (a file where a directory is) is removed
+// before anything is sent, to make way for it; entries only the server has
+// are removed once every upload is stored, so a failed upload leaves the
+// server with all it had.
+
+use crate::common::error::{Error, Result};
+use crate::protocol::directory::TreeNode;
+use crate::protocol::messages::{ConflictPolicy, DirectoryEntry, StorageAction, StorageRequest};
+use crate::storage::compute_file_hash;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+
+/// What mirroring a local directory changes on the server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// Directory on the server, below its upload directory
+    pub remote_dir: String,
+    /// Local entries the server lacks or stores differently, parents first
+    pub changed: Vec<TreeNode>,
+    /// Remote entries of the other kind than the local ones, '/'-separated
+    /// below `remote_dir`; removed before anything is sent
+    pub replace: Vec<String>,
+    /// Entries only the server has, '/'-separated below `remote_dir`;
+    /// removed once the uploads are stored. A directory's contents are
+    /// removed with it and not listed, here or in `replace`.
+    pub remove: Vec<String>,
+    /// Number of files the server already stores as they are locally
+    pub unchanged: usize,
+}
+
+impl SyncPlan {
+    /// Compare the `local` entries of a directory with the `remote` ones
+    /// stored at `remote_dir`
+    ///
+    /// Remote extras are only removed when `delete` is set; an entry that is
+    /// a file on one side and a directory on the other cannot be replaced
+    /// without it.
+    pub fn new(remote_dir: &str, local: Vec<TreeNode>, remote: &[DirectoryEntry], delete: bool) -> Result<Self> {
+        let remote_entries: HashMap<&str, &DirectoryEntry> =
+            remote.iter().map(|entry| (entry.relative_path.as_str(), entry)).collect();
+        let local_paths: HashSet<&str> = local.iter().map(|node| node.relative_path.as_str()).collect();
+        let mut replace: Vec<String> = Vec::new();
+        let mut remove: Vec<String> = Vec::new();
+
+        // A remote entry of the other kind goes before the local one is sent
+        for node in &local {
+            if let Some(entry) = remote_entries.get(node.relative_path.as_str()) {
+                if entry.is_dir != node.is_dir {
+                    if !delete {
+                        return Err(Error::FileExists(format!(
+                            "{}/{} is a {} on the server (sync with deletion to replace it)",
+                            remote_dir,
+                            entry.relative_path,
+                            if entry.is_dir { "directory" } else { "file" }
+                        )));
+                    }
+                    replace.push(entry.relative_path.clone());
+                }
+            }
+        }
+        if delete {
+            for entry in remote.iter().filter(|entry| !local_paths.contains(entry.relative_path.as_str())) {
+                remove.push(entry.relative_path.clone());
+            }
+        }
+
+        // Only the topmost of the entries removed need asking for
+        let replace = topmost(replace, &[]);
+        let remove = topmost(remove, &replace);
+        let replaced: HashSet<&str> = replace.iter().map(String::as_str).collect();
+
+        // Files of the same size on both sides are told apart by hash
+        let unchanged: Vec<bool> = local
+            .par_iter()
+            .map(|node| match remote_entries.get(node.relative_path.as_str()) {
+                Some(_) if replaced.contains(node.relative_path.as_str()) => Ok(false),
+                Some(entry) if node.is_dir => Ok(entry.is_dir),
+                Some(entry) if entry.file_size != node.size => Ok(false),
+                Some(_) if node.size == 0 => Ok(true),
+                Some(entry) => {
+                    let hash = compute_file_hash(&mut File::open(&node.path)?)?;
+                    Ok(hash.as_bytes().as_slice() == entry.file_hash.as_slice())
+                }
+                None => Ok(false),
+            })
+            .collect::<Result<_>>()?;
+
+        let mut plan = SyncPlan {
+            remote_dir: remote_dir.to_string(),
+            ..SyncPlan::default()
+        };
+        for (node, unchanged) in local.into_iter().zip(unchanged) {
+            if !unchanged {
+                plan.changed.push(node);
+            } else if !node.is_dir {
+                plan.unchanged += 1;
+            }
+        }
+        plan.replace = replace;
+        plan.remove = remove;
+        Ok(plan)
+    }
+
+    /// Whether the server already mirrors the local directory
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.replace.is_empty() && self.remove.is_empty()
+    }
+
+    /// Number of bytes of the files to upload
+    pub fn upload_size(&self) -> u64 {
+        self.changed.iter().map(|node| node.size).sum()
+    }
+
+    /// What the uploads of a sync ask the server to do with the files they
+    /// change, given the `configured` policy
+    ///
+    /// A changed file has to replace the stored one, whatever the server
+    /// does with taken names by default, or the next sync finds it changed
+    /// again; keeping the stored one as a version replaces it too.
+    pub fn conflict_policy(configured: ConflictPolicy) -> ConflictPolicy {
+        match configured {
+            ConflictPolicy::Version => ConflictPolicy::Version,
+            _ => ConflictPolicy::Overwrite,
+        }
+    }
+
+    /// Request removing the entries of the other kind than the local ones
+    pub(crate) fn replacement_request(&self) -> StorageRequest {
+        self.request_removing(&self.replace)
+    }
+
+    /// Request removing the entries only the server has
+    pub(crate) fn removal_request(&self) -> StorageRequest {
+        self.request_removing(&self.remove)
+    }
+
+    fn request_removing(&self, entries: &[String]) -> StorageRequest {
+        StorageRequest {
+            path: self.remote_dir.clone(),
+            action: StorageAction::RemoveEntries as i32,
+            version: 0,
+            entries: entries.to_vec(),
+            signature: None,
+        }
+    }
+}
+
+/// The `paths` not below another of them or one of `removed_first`, sorted
+fn topmost(mut paths: Vec<String>, removed_first: &[String]) -> Vec<String> {
+    paths.sort();
+    let removed: HashSet<&str> = paths.iter().chain(removed_first).map(String::as_str).collect();
+    paths
+        .iter()
+        .filter(|path| !path.match_indices('/').any(|(end, _)| removed.contains(&path[..end])))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::directory::DirectoryBuilder;
+    use tempfile::TempDir;
+
+    fn remote_file(relative_path: &str, content: &[u8]) -> DirectoryEntry {
+        DirectoryEntry {
+            relative_path: relative_path.to_string(),
+            file_size: content.len() as u64,
+            file_hash: blake3::hash(content).as_bytes().to_vec(),
+            ..DirectoryEntry::default()
+        }
+    }
+
+    fn remote_dir(relative_path: &str) -> DirectoryEntry {
+        DirectoryEntry {
+            relative_path: relative_path.to_string(),
+            is_dir: true,
+            ..DirectoryEntry::default()
+        }
+    }
+
+    #[test]
+    fn test_sync_plan() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("app.js"), b"same").unwrap();
+        std::fs::write(root.join("index.html"), b"new!").unwrap();
+        std::fs::write(root.join("assets/logo.svg"), b"<svg>").unwrap();
+        std::fs::write(root.join("empty"), b"").unwrap();
+        let local = || DirectoryBuilder::new(root).walk().unwrap();
+
+        let remote = vec![
+            remote_file("app.js", b"same"),
+            remote_dir("assets"),
+            remote_file("empty", b""),
+            remote_file("index.html", b"old!"),
+            remote_dir("old"),
+            remote_file("old/bundle.js", b"stale"),
+            remote_file("stale.css", b"body"),
+        ];
+
+        let plan = SyncPlan::new("site", local(), &remote, false).unwrap();
+        let changed: Vec<&str> = plan.changed.iter().map(|node| node.relative_path.as_str()).collect();
+        assert_eq!(changed, vec!["assets/logo.svg", "index.html"]);
+        assert_eq!(plan.unchanged, 2);
+        assert_eq!(plan.upload_size(), 9);
+        assert!(plan.replace.is_empty() && plan.remove.is_empty());
+
+        // Deleting removes only the topmost extras, after the uploads
+        let plan = SyncPlan::new("site", local(), &remote, true).unwrap();
+        assert!(plan.replace.is_empty());
+        assert_eq!(plan.remove, vec!["old", "stale.css"]);
+        assert_eq!(plan.removal_request().entries, plan.remove);
+
+        // A directory where a file was is only replaced when deleting,
+        // before the uploads
+        let clash = vec![remote_file("assets", b"file")];
+        assert!(matches!(SyncPlan::new("site", local(), &clash, false), Err(Error::FileExists(_))));
+        let plan = SyncPlan::new("site", local(), &clash, true).unwrap();
+        assert_eq!(plan.replace, vec!["assets"]);
+        assert_eq!(plan.replacement_request().entries, plan.replace);
+        assert!(plan.remove.is_empty());
+        assert_eq!(plan.changed.len(), 5);
+
+        // Extras inside a replaced directory go with it
+        std::fs::write(root.join("old"), b"now a file").unwrap();
+        let plan = SyncPlan::new("site", local(), &remote, true).unwrap();
+        assert_eq!(plan.replace, vec!["old"]);
+        assert_eq!(plan.remove, vec!["stale.css"]);
+
+        let all = local().into_iter().map(|node| match node.is_dir {
+            true => remote_dir(&node.relative_path),
+            false => remote_file(&node.relative_path, &std::fs::read(&node.path).unwrap()),
+        });
+        assert!(SyncPlan::new("site", local(), &all.collect::<Vec<_>>(), true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sync_conflict_policy() {
+        assert_eq!(SyncPlan::conflict_policy(ConflictPolicy::Default), ConflictPolicy::Overwrite);
+        assert_eq!(SyncPlan::conflict_policy(ConflictPolicy::Rename), ConflictPolicy::Overwrite);
+        assert_eq!(SyncPlan::conflict_policy(ConflictPolicy::SkipIdentical), ConflictPolicy::Overwrite);
+        assert_eq!(SyncPlan::conflict_policy(ConflictPolicy::Version), ConflictPolicy::Version);
+    }
+}
@@ -1,6 +1,7 @@
 // Client-side transfer logic
 
-use std::net::UdpSocket;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::time::Duration;
 use std::path::{Path, PathBuf};
 use log::{info, debug, error, warn};
@@ -8,16 +9,109 @@ use crate::common::error::{Error, Result};
 use crate::common::config::ClientConfig;
 use crate::common::types::*;
 use crate::protocol::manifest::ManifestBuilder;
-use crate::transport::manifest_stream::ManifestReceiver;
+use crate::transport::manifest_pages::{ManifestEvent, PagedManifestReceiver};
 use crate::protocol::control::ControlMessage;
+use crate::protocol::chunk::{ChunkFrameReader, ChunkPacketParser};
 use crate::client::receiver::FileReceiver;
+use super::ordered::{OrderedChunkWriter, DEFAULT_REORDER_BUFFER};
+use super::cancel::{CancelHandle, CANCELLED_ERROR_CODE};
 use super::connection::ClientConnection;
-use super::streams::{StreamManager, STREAM_CONTROL, STREAM_HASH_CHECK, STREAM_RESUME, STREAM_MANIFEST, STREAM_DATA, STREAM_STATUS};
-use crate::protocol::hash_check::{HashCheckRequestSender, HashCheckResponseReceiver};
-use crate::protocol::resume::{ResumeRequestSender, ResumeResponseReceiver};
-use crate::chunking::ChunkBitmap;
+use super::streams::{StreamManager, STREAM_CONTROL, STREAM_MANIFEST, STREAM_DATA, STREAM_STATUS};
+use crate::protocol::bundle::{BundleBuilder, PackMember};
+use crate::protocol::directory::{self, DirectoryBuilder, TreeNode};
+use crate::protocol::messages::{
+    BundleIndex, DirectoryManifest, FileMetadata, Manifest, StorageAction, StorageRequest, StorageResponse, StripeInfo,
+};
+use crate::protocol::metadata;
+use crate::protocol::signing::ManifestSigner;
+use crate::chunking::{sparse, ChunkBitmap, ChunkCipher, HashTree, ParallelChunkIterator, StreamChunker, StreamItem};
+use crate::transport::{StreamAllocator, TransferStreams};
+use crate::engine::{ChunkOutcome, UploadMachine, UploadOptions};
+use crate::observer::{self, ResumeDecision, SharedObserver, TransferObserver, TransferSummary};
+use crate::transport::event_socket::{wait_time, EventSocket};
 use super::session::ClientSession;
-use std::collections::HashMap;
+use super::sync::SyncPlan;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Longest a phase waits on the network before re-checking its deadlines
+pub(super) const NETWORK_WAIT: Duration = Duration::from_millis(100);
+
+/// How long a stream write may make no progress before giving up
+const SEND_STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Quiet period that ends the final flush after the last chunk
+pub(super) const FLUSH_QUIET_TIME: Duration = Duration::from_millis(500);
+
+/// Directory small files are packed into before upload
+const BUNDLE_DIR: &str = "sftpx_bundles";
+
+/// Where an uploaded file ends up on the server
+pub(super) enum Placement {
+    /// Directly in the upload directory, under its own name
+    Root,
+    /// At a path below the upload directory (recursive uploads)
+    Path { relative_path: String, mode: u32 },
+    /// Unpacked from a bundle of small files
+    Bundle(BundleIndex),
+}
+
+/// A file ready to upload, with its manifest already built
+pub(super) struct PreparedUpload {
+    file_path: PathBuf,
+    manifest: Manifest,
+    cipher: Option<ChunkCipher>,  // Set when encryption is configured
+    hash_tree: Option<Arc<HashTree>>,  // Set for tree-hash manifests
+    stream: Option<StreamChunker>,  // Set for input of unknown length
+}
+
+impl PreparedUpload {
+    /// The same file, sending only one stripe of its chunks
+    fn stripe(&self, index: u32, count: u32) -> Self {
+        Self {
+            file_path: self.file_path.clone(),
+            manifest: Manifest {
+                stripe: Some(StripeInfo { index, count }),
+                ..self.manifest.clone()
+            },
+            cipher: self.cipher.clone(),
+            hash_tree: self.hash_tree.clone(),
+            stream: None,
+        }
+    }
+}
+
+/// One file being uploaded on its own transfer streams
+pub(super) struct OutgoingUpload {
+    /// Protocol state of the upload
+    machine: UploadMachine,
+    file_path: PathBuf,
+    cipher: Option<ChunkCipher>,
+    hash_tree: Option<Arc<HashTree>>,
+    /// Chunks read and packed from the file, once the upload is sending
+    chunk_iter: Option<ParallelChunkIterator>,
+    /// Chunks of a streaming upload, until its input ends
+    stream: Option<StreamChunker>,
+    /// Chunks this upload sends (a stripe's share of the file); unknown
+    /// (zero) for a stream until its input ends
+    total_chunks: u64,
+    start_time: Instant,
+    /// Local bundle file, deleted once it has been sent
+    bundle_path: Option<PathBuf>,
+}
+
+impl OutgoingUpload {
+    /// Whether every stream is finished and everything queued has gone to QUIC
+    pub(super) fn is_sent(&mut self) -> bool {
+        self.machine.is_finished() && self.machine.outbox().is_empty()
+    }
+    
+    /// When the upload next needs `step_upload` even if nothing arrives
+    pub(super) fn deadline(&self) -> Option<Instant> {
+        self.machine.timeout()
+    }
+}
 
 pub struct Transfer {
     config: ClientConfig,
@@ -26,9 +120,11 @@ pub struct Transfer {
     stream_manager: StreamManager,
     session: Option<ClientSession>,
     #[allow(dead_code)]
-    socket: Option<UdpSocket>,
+    socket: Option<EventSocket>,
     state: TransferState,
     resume_bitmaps: HashMap<String, ChunkBitmap>,  // In-memory bitmap storage by session_id
+    observer: SharedObserver,
+    cancel: CancelHandle,
 }
 
 impl Transfer {
@@ -54,6 +150,60 @@ impl Transfer {
             socket: None,
             state: TransferState::Initializing,
             resume_bitmaps: HashMap::new(),
+            observer: observer::noop(),
+            cancel: CancelHandle::new(),
+        })
+    }
+    
+    /// Create a new transfer for sending input of unknown length
+    /// 
+    /// The server stores it at `remote_path`; the input itself is handed to
+    /// `run_send_stream`.
+    pub fn send_stream(config: ClientConfig, remote_path: &str) -> Result<Self> {
+        let session = ClientSession::new(
+            PathBuf::from("-"),
+            0,
+            config.chunk_size,
+            remote_path.to_string(),
+            TransferDirection::Send,
+        );
+        
+        Ok(Self {
+            config,
+            connection: None,
+            stream_manager: StreamManager::new(),
+            session: Some(session),
+            socket: None,
+            state: TransferState::Initializing,
+            resume_bitmaps: HashMap::new(),
+            observer: observer::noop(),
+            cancel: CancelHandle::new(),
+        })
+    }
+    
+    /// Create a new transfer for downloading the file at `remote_path` on
+    /// the server
+    pub fn download(config: ClientConfig, remote_path: &str) -> Result<Self> {
+        let file_name = Path::new(remote_path).file_name()
+            .ok_or_else(|| Error::ConfigError(format!("No file to download in {}", remote_path)))?;
+        let session = ClientSession::new(
+            PathBuf::from(file_name),
+            0,
+            config.chunk_size,
+            remote_path.to_string(),
+            TransferDirection::Receive,
+        );
+        
+        Ok(Self {
+            config,
+            connection: None,
+            stream_manager: StreamManager::new(),
+            session: Some(session),
+            socket: None,
+            state: TransferState::Initializing,
+            resume_bitmaps: HashMap::new(),
+            observer: observer::noop(),
+            cancel: CancelHandle::new(),
         })
     }
     
@@ -68,6 +218,8 @@ impl Transfer {
             socket: None,
             state: TransferState::Initializing,
             resume_bitmaps: HashMap::new(),
+            observer: observer::noop(),
+            cancel: CancelHandle::new(),
         })
     }
     
@@ -83,15 +235,43 @@ impl Transfer {
             socket: None,
             state: TransferState::Resuming,
             resume_bitmaps: HashMap::new(),
+            observer: observer::noop(),
+            cancel: CancelHandle::new(),
+        })
+    }
+    
+    /// Create a transfer that works on the server's stored files rather than
+    /// a single file (see `run_storage_request` and `run_sync`)
+    pub fn storage_request(config: ClientConfig) -> Result<Self> {
+        Ok(Self {
+            config,
+            connection: None,
+            stream_manager: StreamManager::new(),
+            session: None,
+            socket: None,
+            state: TransferState::Initializing,
+            resume_bitmaps: HashMap::new(),
+            observer: observer::noop(),
+            cancel: CancelHandle::new(),
         })
     }
     
+    /// Report this transfer's events to `observer`
+    pub fn with_observer(mut self, observer: Arc<dyn TransferObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+    
+    /// Handle that cancels this transfer from another thread
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+    
     /// Main transfer event loop with proper handshake
     pub fn run(&mut self) -> Result<()> {
         // Bind UDP socket
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        let socket = EventSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
         socket.connect(self.config.server_addr)?;
-        socket.set_nonblocking(false)?;  // Use blocking mode initially
         
         let local_addr = socket.local_addr()?;
         let peer_addr = self.config.server_addr;
@@ -114,33 +294,14 @@ impl Transfer {
         let mut handshake_iter = 0;
         
         loop {
-            // Receive packets
-            socket.set_read_timeout(Some(Duration::from_millis(100)))?;
-            match socket.recv_from(&mut buf) {
-                Ok((len, from)) => {
-                    debug!("Client: recv {} bytes from {}", len, from);
-                    let recv_info = quiche::RecvInfo { from, to: local_addr };
-                    match connection.recv(&mut buf[..len], recv_info) {
-                        Ok(_) => {},
-                        Err(e) => debug!("Client: conn.recv error: {:?}", e),
-                    }
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || 
-                          e.kind() == std::io::ErrorKind::TimedOut => {
-                    // Timeout is normal during handshake
-                }
-                Err(e) => return Err(Error::from(e)),
-            }
-            
-            // Send handshake packets
-            while let Ok((len, send_info)) = connection.send(&mut out) {
-                socket.send_to(&out[..len], send_info.to)?;
-            }
+            // Exchange handshake packets, waiting for the server's reply
+            pump_network(&socket, &mut connection, &mut buf, &mut out, local_addr, NETWORK_WAIT)?;
+            flush_packets(&socket, &mut connection, &mut out)?;
             
             // Check if handshake complete
             if connection.is_established() && connection.peer_streams_left_bidi() > 0 {
                 info!("Client: handshake complete!");
-                self.state = TransferState::Handshaking;
+                self.set_state(TransferState::Handshaking);
                 break;
             }
             
@@ -149,8 +310,6 @@ impl Transfer {
                 debug!("Client: handshake iter={} is_established={} peer_streams_left_bidi={}",
                     handshake_iter, connection.is_established(), connection.peer_streams_left_bidi());
             }
-            
-            std::thread::sleep(Duration::from_millis(10));
         }
         
         // Initialize stream priorities
@@ -159,7 +318,7 @@ impl Transfer {
         
         // --- APPLICATION DATA PHASE ---
         info!("Client: sending application messages on 4 streams...");
-        self.state = TransferState::Transferring;
+        self.set_state(TransferState::Transferring);
         
         // Send test messages on each stream
         let messages: Vec<(u64, &[u8])> = vec![
@@ -187,20 +346,13 @@ impl Transfer {
         info!("Client: waiting for server responses...");
         
         // Wait for responses from all 4 streams
-        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
         let mut done = false;
         let mut received_streams = std::collections::HashSet::new();
         
         loop {
-            match socket.recv_from(&mut buf) {
-                Ok((len, from)) => {
-                    debug!("Client: recv {} bytes from {}", len, from);
-                    let recv_info = quiche::RecvInfo { from, to: local_addr };
-                    match connection.recv(&mut buf[..len], recv_info) {
-                        Ok(_) => {},
-                        Err(e) => debug!("Client: conn.recv error: {:?}", e),
-                    }
-                    
+            match pump_network(&socket, &mut connection, &mut buf, &mut out, local_addr, NETWORK_WAIT) {
+                Ok(false) => {}
+                Ok(true) => {
                     let readable: Vec<u64> = connection.readable().collect();
                     if !readable.is_empty() {
                         debug!("Client: readable streams: {:?}", readable);
@@ -236,26 +388,18 @@ impl Transfer {
                         done = true;
                     }
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || 
-                          e.kind() == std::io::ErrorKind::TimedOut => {
-                    // Normal timeout
-                }
                 Err(e) => {
-                    error!("Client: recv_from error: {:?}", e);
+                    error!("Client: network error: {:?}", e);
                     break;
                 }
             }
             
             // Send any pending packets
-            while let Ok((len, send_info)) = connection.send(&mut out) {
-                socket.send_to(&out[..len], send_info.to)?;
-            }
+            flush_packets(&socket, &mut connection, &mut out)?;
             
             if done || connection.is_closed() {
                 break;
             }
-            
-            std::thread::sleep(Duration::from_millis(10));
         }
         
         // Clean close
@@ -266,7 +410,7 @@ impl Transfer {
             socket.send_to(&out[..len], send_info.to)?;
         }
         
-        self.state = TransferState::Completed;
+        self.set_state(TransferState::Completed);
         info!("Client: transfer complete!");
         
         // Print statistics
@@ -280,12 +424,16 @@ impl Transfer {
     /// Run an integrated file receive transfer with all components
     /// This orchestrates: QUIC handshake -> Manifest receive -> Chunk receive -> Verification
     pub fn run_receive(&mut self) -> Result<PathBuf> {
+        let result = self.receive();
+        self.report(result)
+    }
+    
+    fn receive(&mut self) -> Result<PathBuf> {
         info!("Starting integrated file receive transfer");
         
         // Bind UDP socket
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        let socket = EventSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
         socket.connect(self.config.server_addr)?;
-        socket.set_nonblocking(false)?;
         
         let local_addr = socket.local_addr()?;
         let peer_addr = self.config.server_addr;
@@ -303,60 +451,62 @@ impl Transfer {
         socket.send_to(&out[..len], send_info.to)?;
         
         // --- HANDSHAKE PHASE ---
-        self.handshake_phase(&socket, &mut connection, &mut buf, &mut out, local_addr)?;
+        // Cancelling here closes the connection the same way as later on
+        let result = self.handshake_phase(&socket, &mut connection, &mut buf, &mut out, local_addr)
+            .and_then(|()| self.receive_on_connection(&socket, &mut connection, &mut buf, &mut out, local_addr));
+        close_connection(&socket, &mut connection, &mut out, &result)?;
         
-        // Initialize streams
-        self.stream_manager.initialize_streams(&mut connection)?;
-        info!("Client: initialized streams");
+        let output_path = result?;
+        self.set_state(TransferState::Completed);
+        info!("Client: transfer complete! File saved to: {:?}", output_path);
+        
+        Ok(output_path)
+    }
+    
+    /// Receive the file over an established connection
+    fn receive_on_connection(
+        &mut self,
+        socket: &EventSocket,
+        connection: &mut ClientConnection,
+        buf: &mut [u8],
+        out: &mut [u8],
+        local_addr: SocketAddr,
+    ) -> Result<PathBuf> {
+        let streams = self.request_download(socket, connection, buf, out, local_addr)?;
         
         // --- MANIFEST RECEIVE PHASE ---
-        let manifest = self.receive_manifest_phase(&socket, &mut connection, &mut buf, &mut out, local_addr)?;
+        self.set_state(TransferState::ReceivingManifest);
+        let manifest = self.receive_manifest_phase(socket, connection, buf, out, local_addr, &streams, false)?;
         info!("Client: received manifest for file: {}", manifest.file_name);
         
         // --- FILE RECEIVE PHASE ---
-        let output_path = self.receive_file_phase(
-            &socket,
-            &mut connection,
-            &mut buf,
-            &mut out,
-            local_addr,
-            &manifest,
-        )?;
-        
-        // Clean close
-        let _ = connection.close(true, 0x00, b"done");
-        while let Ok((len, send_info)) = connection.send(&mut out) {
-            socket.send_to(&out[..len], send_info.to)?;
-        }
-        
-        self.state = TransferState::Completed;
-        info!("Client: transfer complete! File saved to: {:?}", output_path);
-        
-        Ok(output_path)
+        self.set_state(TransferState::Transferring);
+        self.receive_file_phase(socket, connection, buf, out, local_addr, &streams, &manifest)
     }
     
-    /// Run an integrated file send transfer (upload to server)
-    /// This orchestrates: QUIC handshake -> Build manifest -> Send manifest -> Send chunks
-    pub fn run_send(&mut self, file_path: &Path) -> Result<u64> {
-        info!("Starting integrated file send transfer");
-        
-        // Check if we have resume bitmaps (indicates this might be a resume)
-        // Give server a moment to cleanup any old connection
-        let resume_dir = std::path::PathBuf::from("sftpx_resume");
-        if resume_dir.exists() && resume_dir.read_dir().ok().map(|mut d| d.next().is_some()).unwrap_or(false) {
-            info!("Client: detected potential resume, waiting 200ms for server cleanup...");
-            std::thread::sleep(Duration::from_millis(200));
-        }
+    /// Receive a file and write it to `writer` in chunk order as it arrives
+    /// 
+    /// For output that cannot seek, such as stdout or a pipe. Each chunk is
+    /// verified against the manifest before it is written; chunks arriving
+    /// early are held back, and once `DEFAULT_REORDER_BUFFER` bytes are held
+    /// the data stream is not read until the output catches up.
+    /// 
+    /// # Returns
+    /// Bytes written
+    pub fn run_receive_to<W: Write>(&mut self, writer: W) -> Result<u64> {
+        let result = self.receive_to(writer);
+        self.report(result)
+    }
+    
+    fn receive_to<W: Write>(&mut self, writer: W) -> Result<u64> {
+        info!("Starting streaming file receive transfer");
         
         // Bind UDP socket
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        let socket = EventSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
         socket.connect(self.config.server_addr)?;
-        socket.set_nonblocking(false)?;
         
         let local_addr = socket.local_addr()?;
-        let peer_addr = self.config.server_addr;
-        
-        info!("Client: connecting to {}", peer_addr);
+        info!("Client: connecting to {}", self.config.server_addr);
         
         // Create QUIC connection
         let mut connection = ClientConnection::new(&self.config, local_addr)?;
@@ -369,197 +519,717 @@ impl Transfer {
         socket.send_to(&out[..len], send_info.to)?;
         
         // --- HANDSHAKE PHASE ---
-        self.handshake_phase(&socket, &mut connection, &mut buf, &mut out, local_addr)?;
-        
-        // Initialize streams
-        self.stream_manager.initialize_streams(&mut connection)?;
-        info!("Client: initialized streams");
-        
-        // --- MANIFEST BUILD AND SEND PHASE ---
-        let (manifest_bytes, manifest, existing_hashes) = self.send_manifest_phase(
-            &socket,
-            &mut connection,
-            &mut buf,
-            &mut out,
-            local_addr,
-            file_path,
-        )?;
-        
-        // --- RESUME PROTOCOL PHASE (check if server has partial file) ---
-        let skip_chunks = self.check_resume_phase(
-            &socket,
-            &mut connection,
-            &mut buf,
-            &mut out,
-            local_addr,
-            &manifest,
-        )?;
-        
-        // --- FILE SEND PHASE ---
-        let chunks_bytes = self.send_file_phase(
-            &socket,
-            &mut connection,
-            &mut buf,
-            &mut out,
-            local_addr,
-            file_path,
-            &manifest,
-            &existing_hashes,
-            &skip_chunks,
-        )?;
-        
-        // Clean close
-        let _ = connection.close(true, 0x00, b"done");
-        while let Ok((len, send_info)) = connection.send(&mut out) {
-            socket.send_to(&out[..len], send_info.to)?;
-        }
+        // Cancelling here closes the connection the same way as later on
+        let result = self.handshake_phase(&socket, &mut connection, &mut buf, &mut out, local_addr)
+            .and_then(|()| self.receive_to_on_connection(&socket, &mut connection, &mut buf, &mut out, local_addr, writer));
+        close_connection(&socket, &mut connection, &mut out, &result)?;
         
-        self.state = TransferState::Completed;
-        let total = manifest_bytes + chunks_bytes;
-        info!("Client: upload complete! Sent {} bytes total", total);
+        let bytes_written = result?;
+        self.set_state(TransferState::Completed);
+        info!("Client: transfer complete! {} bytes written in order", bytes_written);
         
-        Ok(total)
+        Ok(bytes_written)
     }
     
-    /// Handshake phase - establish QUIC connection
-    fn handshake_phase(
+    /// Receive the file over an established connection into `writer`
+    fn receive_to_on_connection<W: Write>(
         &mut self,
-        socket: &UdpSocket,
+        socket: &EventSocket,
         connection: &mut ClientConnection,
         buf: &mut [u8],
         out: &mut [u8],
-        local_addr: std::net::SocketAddr,
-    ) -> Result<()> {
-        info!("Client: waiting for handshake to complete...");
+        local_addr: SocketAddr,
+        writer: W,
+    ) -> Result<u64> {
+        let streams = self.request_download(socket, connection, buf, out, local_addr)?;
         
-        let start_time = std::time::Instant::now();
-        let handshake_timeout = Duration::from_secs(30); // 30 second timeout (increased for resume scenarios)
-        let mut iterations = 0;
-        let mut last_log_time = start_time;
+        // --- MANIFEST RECEIVE PHASE ---
+        // Chunk hashes are kept to verify each chunk before it is written
+        self.set_state(TransferState::ReceivingManifest);
+        let manifest = self.receive_manifest_phase(socket, connection, buf, out, local_addr, &streams, true)?;
+        info!("Client: received manifest for file: {}", manifest.file_name);
+        
+        // --- ORDERED RECEIVE PHASE ---
+        self.set_state(TransferState::Transferring);
+        let mut writer = OrderedChunkWriter::new(writer, &manifest, DEFAULT_REORDER_BUFFER)?;
+        let mut frames = ChunkFrameReader::new();
         
         loop {
-            iterations += 1;
+            self.check_cancelled()?;
             
-            // Check timeout
-            if start_time.elapsed() > handshake_timeout {
-                return Err(Error::Protocol(format!(
-                    "Handshake timeout after {} seconds (iterations: {})",
-                    handshake_timeout.as_secs(),
-                    iterations
-                )));
-            }
+            // Exchange packets, waiting until the server sends more
+            pump_network(socket, connection, buf, out, local_addr, NETWORK_WAIT)?;
             
-            socket.set_read_timeout(Some(Duration::from_millis(100)))?;
-            match socket.recv_from(buf) {
-                Ok((len, from)) => {
-                    let recv_info = quiche::RecvInfo { from, to: local_addr };
-                    let _ = connection.recv(&mut buf[..len], recv_info);
-                    last_log_time = std::time::Instant::now();
+            // A full reorder buffer leaves the rest in QUIC's flow control
+            // window, which holds the server back
+            let mut stream_ended = false;
+            while writer.has_room() {
+                let Ok((read, fin)) = connection.stream_recv(streams.data, buf) else {
+                    break;
+                };
+                frames.push(&buf[..read]);
+                while let Some(packet) = frames.next_packet()? {
+                    let chunk = ChunkPacketParser::parse(&packet)?;
+                    self.observer.on_chunk_acked(&manifest.session_id, chunk.chunk_id);
+                    writer.push(chunk)?;
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock ||
-                          e.kind() == std::io::ErrorKind::TimedOut => {
-                    // Log progress every 2 seconds
-                    if last_log_time.elapsed() >= Duration::from_secs(2) {
-                        info!("Client: waiting for handshake... ({:.1}s elapsed)", start_time.elapsed().as_secs_f64());
-                        last_log_time = std::time::Instant::now();
-                    }
+                if fin {
+                    stream_ended = true;
+                    break;
                 }
-                Err(e) => return Err(Error::from(e)),
             }
             
-            while let Ok((len, send_info)) = connection.send(out) {
-                socket.send_to(&out[..len], send_info.to)?;
-            }
+            flush_packets(socket, connection, out)?;
             
-            if connection.is_established() && connection.peer_streams_left_bidi() > 0 {
-                info!("Client: handshake complete! (took {:.2}s, {} iterations)", 
-                    start_time.elapsed().as_secs_f64(), iterations);
-                self.state = TransferState::Handshaking;
-                break;
+            if writer.is_complete() {
+                info!("All {} chunks written in order", manifest.total_chunks);
+                return writer.finish();
             }
             
-            std::thread::sleep(Duration::from_millis(10));
+            if stream_ended {
+                return Err(Error::Protocol(format!(
+                    "Data stream ended after {} of {} chunks",
+                    writer.chunks_written(), manifest.total_chunks
+                )));
+            }
+        }
+    }
+    
+    /// Ask the server for the session's file and open the streams it is
+    /// sent on
+    /// 
+    /// The server answers the request on the status stream, refusing a file
+    /// it does not have, and sends the file once the client has finished its
+    /// side of the transfer's manifest and data streams.
+    fn request_download(
+        &mut self,
+        socket: &EventSocket,
+        connection: &mut ClientConnection,
+        buf: &mut [u8],
+        out: &mut [u8],
+        local_addr: SocketAddr,
+    ) -> Result<TransferStreams> {
+        let remote_path = self.session.as_ref()
+            .map(|session| session.destination.clone())
+            .ok_or_else(|| Error::ConfigError("No remote file to download".to_string()))?;
+        let request = StorageRequest {
+            path: remote_path,
+            action: StorageAction::Download as i32,
+            ..StorageRequest::default()
+        };
+        let response = self.storage_exchange_on_connection(socket, connection, buf, out, local_addr, &request)?;
+        if let Some(error) = response.error {
+            return Err(Error::Protocol(error));
         }
         
-        Ok(())
+        let streams = TransferStreams::for_transfer(0);
+        self.stream_manager.initialize_transfer_streams(connection, &streams)?;
+        connection.stream_send(streams.manifest, &[], true)?;
+        connection.stream_send(streams.data, &[], true)?;
+        flush_packets(socket, connection, out)?;
+        Ok(streams)
     }
     
-    /// Manifest receive phase - receive and parse manifest
-    fn receive_manifest_phase(
+    /// Send a request about stored files, such as listing or restoring the
+    /// versions of one, and wait for the server's answer
+    /// 
+    /// A request the server could not carry out fails with its reason.
+    pub fn run_storage_request(&mut self, request: &StorageRequest) -> Result<StorageResponse> {
+        let result = self.storage_exchange(request);
+        self.report(result)
+    }
+    
+    fn storage_exchange(&mut self, request: &StorageRequest) -> Result<StorageResponse> {
+        // Bind UDP socket
+        let socket = EventSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+        socket.connect(self.config.server_addr)?;
+        
+        let local_addr = socket.local_addr()?;
+        info!("Client: connecting to {}", self.config.server_addr);
+        
+        // Create QUIC connection
+        let mut connection = ClientConnection::new(&self.config, local_addr)?;
+        
+        let mut buf = vec![0u8; 65535];
+        let mut out = vec![0u8; MAX_DATAGRAM_SIZE];
+        
+        // Send initial packet
+        let (len, send_info) = connection.send(&mut out)?;
+        socket.send_to(&out[..len], send_info.to)?;
+        
+        // --- HANDSHAKE PHASE ---
+        // Cancelling here closes the connection the same way as later on
+        let result = self.handshake_phase(&socket, &mut connection, &mut buf, &mut out, local_addr)
+            .and_then(|()| self.storage_exchange_on_connection(&socket, &mut connection, &mut buf, &mut out, local_addr, request));
+        close_connection(&socket, &mut connection, &mut out, &result)?;
+        
+        let response = result?;
+        self.set_state(TransferState::Completed);
+        match response.error {
+            Some(error) => Err(Error::Protocol(error)),
+            None => Ok(response),
+        }
+    }
+    
+    /// Send the request on the status stream and read the answer from it
+    fn storage_exchange_on_connection(
         &mut self,
-        socket: &UdpSocket,
+        socket: &EventSocket,
         connection: &mut ClientConnection,
         buf: &mut [u8],
         out: &mut [u8],
-        local_addr: std::net::SocketAddr,
-    ) -> Result<crate::protocol::messages::Manifest> {
-        info!("Client: receiving manifest on stream {}...", STREAM_MANIFEST);
+        local_addr: SocketAddr,
+        request: &StorageRequest,
+    ) -> Result<StorageResponse> {
+        // Sign the request so a server with trusted keys acts on it
+        let mut request = request.clone();
+        if let Some(key_path) = &self.config.signing_key {
+            ManifestSigner::from_key_file(key_path)?.sign_request(&mut request);
+        }
         
-        let mut manifest_receiver = ManifestReceiver::new();
+        self.open_streams(connection)?;
+        self.send_stream_data(socket, connection, buf, out, local_addr, STREAM_STATUS, &request.encode_to_vec())?;
+        info!("Client: sent {:?} for {}", request.action(), request.path);
         
+        let mut answer = Vec::new();
         loop {
-            // Receive packets
-            socket.set_read_timeout(Some(Duration::from_millis(100)))?;
-            match socket.recv_from(buf) {
-                Ok((len, from)) => {
-                    let recv_info = quiche::RecvInfo { from, to: local_addr };
-                    let _ = connection.recv(&mut buf[..len], recv_info);
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock ||
-                          e.kind() == std::io::ErrorKind::TimedOut => {}
-                Err(e) => return Err(Error::from(e)),
+            self.check_cancelled()?;
+            if connection.is_closed() {
+                return Err(Error::ConnectionClosed);
             }
             
-            // Check for readable streams
-            let readable: Vec<u64> = connection.readable().collect();
-            for stream_id in readable {
-                if stream_id == STREAM_MANIFEST {
-                    loop {
-                        match connection.stream_recv(stream_id, buf) {
-                            Ok((read, fin)) => {
-                                if read == 0 {
-                                    break;
-                                }
-                                
-                                debug!("Client: received {} bytes on manifest stream", read);
-                                
-                                if let Some(manifest) = manifest_receiver.receive_chunk(&buf[..read], fin)? {
-                                    return Ok(manifest);
-                                }
-                                
-                                if fin {
-                                    break;
-                                }
-                            }
-                            Err(_) => break,
-                        }
-                    }
-                }
-            }
+            // Exchange packets, waiting until the server answers
+            pump_network(socket, connection, buf, out, local_addr, NETWORK_WAIT)?;
             
-            // Send any pending packets
-            while let Ok((len, send_info)) = connection.send(out) {
-                socket.send_to(&out[..len], send_info.to)?;
+            while let Ok((read, fin)) = connection.stream_recv(STREAM_STATUS, buf) {
+                answer.extend_from_slice(&buf[..read]);
+                if fin {
+                    return StorageResponse::decode_from_bytes(&answer)
+                        .map_err(|e| Error::DeserializationError(e.to_string()));
+                }
             }
-            
-            std::thread::sleep(Duration::from_millis(10));
         }
     }
     
-    /// File receive phase - receive chunks and assemble file
-    fn receive_file_phase(
-        &mut self,
-        socket: &UdpSocket,
-        connection: &mut ClientConnection,
-        buf: &mut [u8],
-        out: &mut [u8],
-        local_addr: std::net::SocketAddr,
-        manifest: &crate::protocol::messages::Manifest,
-    ) -> Result<PathBuf> {
-        info!("Client: receiving file data on stream {}...", STREAM_DATA);
+    /// Run an integrated file send transfer (upload to server)
+    /// This orchestrates: QUIC handshake -> Build manifest -> Send manifest -> Send chunks
+    pub fn run_send(&mut self, file_path: &Path) -> Result<u64> {
+        self.run_send_many(&[file_path.to_path_buf()])
+    }
+    
+    /// Upload input of unknown length, such as stdin or a pipe
+    /// 
+    /// Chunks are sent as `reader` produces them and the manifest's totals
+    /// follow its end. The session's destination is the path the server
+    /// stores the upload at, below its upload directory.
+    pub fn run_send_stream<R: Read + Send + 'static>(&mut self, reader: R) -> Result<u64> {
+        let result = self.send_stream_input(reader);
+        self.report(result)
+    }
+    
+    fn send_stream_input<R: Read + Send + 'static>(&mut self, reader: R) -> Result<u64> {
+        let remote_path = self.session.as_ref()
+            .map(|session| session.destination.clone())
+            .ok_or_else(|| Error::ConfigError("No destination for the stream".to_string()))?;
+        info!("Starting streaming upload to {}", remote_path);
+        
+        let upload = self.prepare_stream(reader, &remote_path)?;
+        self.send_uploads(vec![upload], None)
+    }
+    
+    /// Upload several files over one connection
+    /// 
+    /// Each file gets its own transfer streams; their chunks are interleaved
+    /// so the files progress concurrently.
+    pub fn run_send_many(&mut self, files: &[PathBuf]) -> Result<u64> {
+        let result = self.send_many(files);
+        self.report(result)
+    }
+    
+    fn send_many(&mut self, files: &[PathBuf]) -> Result<u64> {
+        info!("Starting integrated file send transfer ({} files)", files.len());
         
-        // Create file receiver with output directory from session or current directory
-        let output_dir = self.config.session_dir.parent()
+        if files.is_empty() {
+            return Err(Error::ConfigError("No files to send".to_string()));
+        }
+        
+        let mut uploads = Vec::new();
+        let mut small_files = Vec::new();
+        
+        for file_path in files {
+            let size = std::fs::metadata(file_path)?.len();
+            match self.pack_threshold() {
+                Some(threshold) if size <= threshold => {
+                    let file_name = file_path.file_name()
+                        .and_then(|n| n.to_str())
+                        .ok_or_else(|| Error::Protocol(format!("Invalid file name: {:?}", file_path)))?;
+                    small_files.push(PackMember {
+                        path: file_path.clone(),
+                        relative_path: file_name.to_string(),
+                        size,
+                        mode: 0,
+                    });
+                }
+                _ => uploads.push(self.prepare_upload(file_path, Placement::Root)?),
+            }
+        }
+        
+        uploads.extend(self.prepare_bundles(small_files)?);
+        
+        self.send_uploads(uploads, None)
+    }
+    
+    /// Upload a directory recursively
+    /// 
+    /// The server recreates the directory under its upload root from a
+    /// directory manifest; every non-empty file is then uploaded as its own
+    /// transfer, with resume and dedup working per file as usual.
+    pub fn run_send_dir(&mut self, root: &Path) -> Result<u64> {
+        let result = self.send_dir(root);
+        self.report(result)
+    }
+    
+    fn send_dir(&mut self, root: &Path) -> Result<u64> {
+        let builder = DirectoryBuilder::new(root);
+        let tree = DirectoryManifest::new(builder.root_name()?);
+        self.send_tree(tree, builder.walk()?)
+    }
+    
+    /// Make a directory on the server match a local one (see `SyncPlan`)
+    /// 
+    /// Remote entries of the other kind than the local ones are removed
+    /// first; the changed entries are then sent as a recursive upload to the
+    /// plan's remote directory, replacing the files stored there whatever
+    /// the server's conflict policy (see `SyncPlan::conflict_policy`).
+    /// Entries only the server has are removed once that has succeeded.
+    pub fn run_sync(&mut self, plan: &SyncPlan) -> Result<u64> {
+        let result = self.sync(plan);
+        self.report(result)
+    }
+    
+    fn sync(&mut self, plan: &SyncPlan) -> Result<u64> {
+        if self.config.encryption.is_some() {
+            return Err(Error::ConfigError("Sync compares plain files and cannot encrypt them".to_string()));
+        }
+        if plan.is_empty() {
+            self.set_state(TransferState::Completed);
+            return Ok(0);
+        }
+        self.config.conflict_policy = SyncPlan::conflict_policy(self.config.conflict_policy);
+        
+        if !plan.replace.is_empty() {
+            let replaced = self.storage_exchange(&plan.replacement_request())?.removed;
+            info!("Client: removed {} entries to replace below {}", replaced.len(), plan.remote_dir);
+        }
+        
+        let mut bytes_sent = 0;
+        if !plan.changed.is_empty() {
+            info!("Client: syncing {} changed entries to {} ({} unchanged files)",
+                plan.changed.len(), plan.remote_dir, plan.unchanged);
+            bytes_sent = self.send_tree(DirectoryManifest::new(plan.remote_dir.clone()), plan.changed.clone())?;
+        }
+        
+        // Extras go only once everything is stored
+        if !plan.remove.is_empty() {
+            let removed = self.storage_exchange(&plan.removal_request())?.removed;
+            info!("Client: removed {} entries below {}", removed.len(), plan.remote_dir);
+        }
+        Ok(bytes_sent)
+    }
+    
+    /// Upload `nodes` as the entries of `tree`
+    fn send_tree(&mut self, mut tree: DirectoryManifest, nodes: Vec<TreeNode>) -> Result<u64> {
+        // Small files travel in bundles; note which bundle holds each
+        let threshold = self.pack_threshold();
+        let small_files: Vec<PackMember> = nodes.iter()
+            .filter(|node| !node.is_dir && node.size > 0 && threshold.is_some_and(|t| node.size <= t))
+            .map(|node| PackMember {
+                path: node.path.clone(),
+                relative_path: tree.upload_path(&node.relative_path),
+                size: node.size,
+                mode: self.kept_mode(node.mode),
+            })
+            .collect();
+        
+        let mut uploads = self.prepare_bundles(small_files)?;
+        let mut packed = HashMap::new();
+        for bundle in &uploads {
+            for member in bundle.manifest.bundle.iter().flat_map(|index| &index.members) {
+                packed.insert(member.relative_path.clone(), (member.clone(), bundle.manifest.session_id.clone()));
+            }
+        }
+        
+        for node in nodes {
+            let upload_path = tree.upload_path(&node.relative_path);
+            let mode = self.kept_mode(node.mode);
+            if node.is_dir {
+                tree.add_directory(&node.relative_path, mode, self.capture_metadata(&node.path)?);
+            } else if node.size == 0 {
+                tree.add_empty_file(&node.relative_path, mode, self.capture_metadata(&node.path)?);
+            } else if let Some((member, session_id)) = packed.get(&upload_path) {
+                tree.add_packed_file(&node.relative_path, member, session_id);
+            } else {
+                let placement = Placement::Path { relative_path: upload_path, mode };
+                let prepared = self.prepare_upload(&node.path, placement)?;
+                tree.add_file(&node.relative_path, mode, &prepared.manifest);
+                uploads.push(prepared);
+            }
+        }
+        
+        info!("Starting recursive upload of {} ({} entries, {} files to transfer, {} bytes)",
+            tree.root_name, tree.entries.len(), uploads.len(), tree.total_size);
+        
+        self.send_uploads(uploads, Some(&tree))
+    }
+    
+    /// Send prepared uploads, over as many connections as the server's stream
+    /// limit requires
+    fn send_uploads(&mut self, uploads: Vec<PreparedUpload>, tree: Option<&DirectoryManifest>) -> Result<u64> {
+        let mut queue: VecDeque<PreparedUpload> = uploads.into();
+        let mut total = 0u64;
+        
+        // A lone file can be striped across several connections
+        let stripable = tree.is_none()
+            && queue.len() == 1
+            && queue.front().is_some_and(|upload| upload.manifest.bundle.is_none() && upload.stream.is_none());
+        if self.config.stripes > 1 && stripable {
+            if let Some(upload) = queue.pop_front() {
+                total = self.send_striped(upload)?;
+            }
+        }
+        
+        // A tree of directories and empty files alone still has to be sent
+        let mut tree_pending = tree.is_some();
+        while !queue.is_empty() || tree_pending {
+            tree_pending = false;
+            total += self.send_batch(&mut queue, tree)?;
+            if !queue.is_empty() {
+                info!("Client: {} files left, reconnecting for the next batch", queue.len());
+            }
+        }
+        
+        self.set_state(TransferState::Completed);
+        info!("Client: upload complete! Sent {} bytes total", total);
+        
+        Ok(total)
+    }
+    
+    /// Upload one file over several connections at once
+    /// 
+    /// Each connection runs on its own thread with its own socket and sends
+    /// a contiguous range of the file's chunks; the server assembles them
+    /// into one file.
+    fn send_striped(&mut self, upload: PreparedUpload) -> Result<u64> {
+        let count = self.config.stripes as u32;
+        info!("Client: striping {} ({} chunks) across {} connections",
+            upload.manifest.file_name, upload.manifest.total_chunks, count);
+        
+        let results: Vec<Result<u64>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..count)
+                .map(|index| {
+                    let mut worker = Transfer::stripe_worker(self.config.clone(), self.observer.clone(), self.cancel.clone());
+                    let stripe = upload.stripe(index, count);
+                    scope.spawn(move || worker.send_batch(&mut VecDeque::from([stripe]), None))
+                })
+                .collect();
+            
+            workers.into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|_| {
+                    Err(Error::Protocol("Stripe thread panicked".to_string()))
+                }))
+                .collect()
+        });
+        
+        let mut total = 0u64;
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(sent) => total += sent,
+                Err(e) => {
+                    error!("Client: stripe {} of {} failed: {}", index + 1, count, e);
+                    return Err(e);
+                }
+            }
+        }
+        
+        if self.resume_bitmaps.remove(&upload.manifest.session_id).is_some() {
+            debug!("Client: removed resume bitmap from memory after striped transfer");
+        }
+        
+        Ok(total)
+    }
+    
+    /// Transfer state for one connection of a striped upload
+    fn stripe_worker(config: ClientConfig, observer: SharedObserver, cancel: CancelHandle) -> Self {
+        Self {
+            config,
+            connection: None,
+            stream_manager: StreamManager::new(),
+            session: None,
+            socket: None,
+            state: TransferState::Transferring,
+            resume_bitmaps: HashMap::new(),
+            observer,
+            cancel,
+        }
+    }
+    
+    /// Open a connection and upload as many queued files as its streams allow
+    fn send_batch(&mut self, queue: &mut VecDeque<PreparedUpload>, tree: Option<&DirectoryManifest>) -> Result<u64> {
+        // Bind UDP socket
+        let socket = EventSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+        socket.connect(self.config.server_addr)?;
+        
+        let local_addr = socket.local_addr()?;
+        let peer_addr = self.config.server_addr;
+        
+        info!("Client: connecting to {}", peer_addr);
+        
+        // Create QUIC connection
+        let mut connection = ClientConnection::new(&self.config, local_addr)?;
+        
+        let mut buf = vec![0u8; 65535];
+        let mut out = vec![0u8; MAX_DATAGRAM_SIZE];
+        
+        // Send initial packet
+        let (len, send_info) = connection.send(&mut out)?;
+        socket.send_to(&out[..len], send_info.to)?;
+        
+        // --- HANDSHAKE PHASE ---
+        // Cancelling here closes the connection the same way as later on
+        let result = self.handshake_phase(&socket, &mut connection, &mut buf, &mut out, local_addr)
+            .and_then(|()| self.send_on_connection(&socket, &mut connection, &mut buf, &mut out, local_addr, queue, tree));
+        close_connection(&socket, &mut connection, &mut out, &result)?;
+        result
+    }
+    
+    /// Upload the directory manifest, if any, and queued files over an
+    /// established connection
+    #[allow(clippy::too_many_arguments)]
+    fn send_on_connection(
+        &mut self,
+        socket: &EventSocket,
+        connection: &mut ClientConnection,
+        buf: &mut [u8],
+        out: &mut [u8],
+        local_addr: SocketAddr,
+        queue: &mut VecDeque<PreparedUpload>,
+        tree: Option<&DirectoryManifest>,
+    ) -> Result<u64> {
+        // No streams are open yet, so this is the server's full stream limit
+        let stream_limit = connection.peer_streams_left_bidi();
+        
+        // Initialize streams
+        self.open_streams(connection)?;
+        
+        let mut sent_bytes = 0u64;
+        
+        // --- DIRECTORY MANIFEST (recursive uploads) ---
+        if let Some(tree) = tree {
+            let encoded = tree.encode_to_vec();
+            sent_bytes += self.send_stream_data(
+                socket, connection, buf, out, local_addr, STREAM_CONTROL, &encoded,
+            )? as u64;
+            info!("Client: directory manifest sent ({} entries, {} bytes)", tree.entries.len(), encoded.len());
+        }
+        
+        let mut allocator = StreamAllocator::new();
+        let mut uploads = Vec::new();
+        
+        while !queue.is_empty() {
+            let streams = match allocator.allocate(stream_limit) {
+                Ok(streams) => streams,
+                Err(e) if uploads.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            let Some(prepared) = queue.pop_front() else {
+                break;
+            };
+            uploads.push(self.open_upload(connection, &mut allocator, stream_limit, streams, prepared)?);
+        }
+        
+        // --- MANIFEST, RESUME AND FILE SEND PHASES ---
+        if !uploads.is_empty() {
+            sent_bytes += self.send_file_phase(socket, connection, buf, out, local_addr, uploads)?;
+        }
+        
+        Ok(sent_bytes)
+    }
+    
+    /// Open the shared control and status streams on a new connection
+    pub(super) fn open_streams(&mut self, connection: &mut ClientConnection) -> Result<()> {
+        self.stream_manager.initialize_streams(connection)?;
+        info!("Client: initialized streams");
+        Ok(())
+    }
+    
+    /// Open an upload's transfer streams and start its protocol on them
+    /// 
+    /// Extra data streams are taken from `allocator` when configured and
+    /// announced in the manifest; without room for them the upload uses one.
+    pub(super) fn open_upload(
+        &mut self,
+        connection: &mut ClientConnection,
+        allocator: &mut StreamAllocator,
+        stream_limit: u64,
+        streams: TransferStreams,
+        mut prepared: PreparedUpload,
+    ) -> Result<OutgoingUpload> {
+        self.stream_manager.initialize_transfer_streams(connection, &streams)?;
+        
+        // Extra data streams, announced in the manifest
+        if self.config.data_streams > 1 {
+            match allocator.allocate_data_streams(self.config.data_streams - 1, stream_limit) {
+                Ok(data_streams) => {
+                    self.stream_manager.initialize_data_streams(connection, &data_streams)?;
+                    prepared.manifest.data_streams = data_streams;
+                }
+                Err(e) => warn!("Client: transfer {} uses one data stream: {}", streams.transfer_id, e),
+            }
+        }
+        
+        self.start_upload(prepared, streams)
+    }
+    
+    /// Handshake phase - establish QUIC connection
+    fn handshake_phase(
+        &mut self,
+        socket: &EventSocket,
+        connection: &mut ClientConnection,
+        buf: &mut [u8],
+        out: &mut [u8],
+        local_addr: std::net::SocketAddr,
+    ) -> Result<()> {
+        info!("Client: waiting for handshake to complete...");
+        
+        let start_time = std::time::Instant::now();
+        let handshake_timeout = Duration::from_secs(30); // 30 second timeout (increased for resume scenarios)
+        let mut iterations = 0;
+        let mut last_log_time = start_time;
+        
+        loop {
+            iterations += 1;
+            self.check_cancelled()?;
+            
+            // Check timeout
+            if start_time.elapsed() > handshake_timeout {
+                return Err(Error::Protocol(format!(
+                    "Handshake timeout after {} seconds (iterations: {})",
+                    handshake_timeout.as_secs(),
+                    iterations
+                )));
+            }
+            
+            if pump_network(socket, connection, buf, out, local_addr, NETWORK_WAIT)? {
+                last_log_time = std::time::Instant::now();
+            } else if last_log_time.elapsed() >= Duration::from_secs(2) {
+                // Log progress every 2 seconds
+                info!("Client: waiting for handshake... ({:.1}s elapsed)", start_time.elapsed().as_secs_f64());
+                last_log_time = std::time::Instant::now();
+            }
+            flush_packets(socket, connection, out)?;
+            
+            if connection.is_established() && connection.peer_streams_left_bidi() > 0 {
+                info!("Client: handshake complete! (took {:.2}s, {} iterations)", 
+                    start_time.elapsed().as_secs_f64(), iterations);
+                self.set_state(TransferState::Handshaking);
+                break;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Manifest receive phase - receive and parse manifest
+    /// 
+    /// Unless `keep_hashes` is set, chunk hash pages are dropped as they
+    /// arrive so memory stays bounded; downloaded chunks are verified by their
+    /// packet checksums and the file hash instead.
+    #[allow(clippy::too_many_arguments)]
+    fn receive_manifest_phase(
+        &mut self,
+        socket: &EventSocket,
+        connection: &mut ClientConnection,
+        buf: &mut [u8],
+        out: &mut [u8],
+        local_addr: std::net::SocketAddr,
+        streams: &TransferStreams,
+        keep_hashes: bool,
+    ) -> Result<Manifest> {
+        info!("Client: receiving manifest on stream {}...", streams.manifest);
+        
+        let mut manifest_receiver = PagedManifestReceiver::new();
+        let mut header = None;
+        
+        loop {
+            self.check_cancelled()?;
+            
+            // Exchange packets, waiting until the server sends more
+            pump_network(socket, connection, buf, out, local_addr, NETWORK_WAIT)?;
+            
+            // Check for readable streams
+            let readable: Vec<u64> = connection.readable().collect();
+            for stream_id in readable {
+                if stream_id == streams.manifest {
+                    loop {
+                        match connection.stream_recv(stream_id, buf) {
+                            Ok((read, fin)) => {
+                                if read == 0 {
+                                    break;
+                                }
+                                
+                                debug!("Client: received {} bytes on manifest stream", read);
+                                
+                                for event in manifest_receiver.receive_chunk(&buf[..read], fin)? {
+                                    match event {
+                                        ManifestEvent::Header(manifest) => {
+                                            streams.check_transfer_id(manifest.transfer_id)?;
+                                            header = Some(*manifest);
+                                        }
+                                        ManifestEvent::Page(page) => {
+                                            debug!("Client: manifest page at chunk {} ({} hashes)",
+                                                page.first_chunk, page.chunk_hashes.len());
+                                            if let Some(header) = header.as_mut().filter(|_| keep_hashes) {
+                                                header.chunk_hashes.extend(page.chunk_hashes);
+                                            }
+                                        }
+                                        ManifestEvent::Complete => {
+                                            return header.take().ok_or_else(|| {
+                                                Error::Protocol("Manifest complete without a header".to_string())
+                                            });
+                                        }
+                                    }
+                                }
+                                
+                                if fin {
+                                    break;
+                                }
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                }
+            }
+            
+            // Send any pending packets
+            flush_packets(socket, connection, out)?;
+        }
+    }
+    
+    /// File receive phase - receive chunks and assemble file
+    fn receive_file_phase(
+        &mut self,
+        socket: &EventSocket,
+        connection: &mut ClientConnection,
+        buf: &mut [u8],
+        out: &mut [u8],
+        local_addr: std::net::SocketAddr,
+        streams: &TransferStreams,
+        manifest: &crate::protocol::messages::Manifest,
+    ) -> Result<PathBuf> {
+        info!("Client: receiving file data on stream {}...", streams.data);
+        
+        // Create file receiver with output directory from session or current directory
+        let output_dir = self.config.session_dir.parent()
             .unwrap_or_else(|| std::path::Path::new("."));
         std::fs::create_dir_all(output_dir)?;
         
@@ -572,6 +1242,18 @@ impl Transfer {
         // Set expected hash from manifest
         receiver.set_expected_hash(manifest.file_hash.clone())?;
         
+        if let Some(root) = &manifest.tree_root {
+            receiver.set_tree_root(root, manifest.total_chunks)?;
+        }
+        
+        // An empty file has no final chunk to wait for
+        if manifest.total_chunks == 0 {
+            receiver.set_totals(0, 0)?;
+        }
+        
+        // Chunks in holes are never sent; they stay holes here
+        receiver.mark_holes(&manifest.holes, manifest.chunk_size as u64, manifest.total_chunks)?;
+        
         // Setup control message sender for auto-retransmit
         let session_id = manifest.session_id.clone();
         let control_sender = Box::new(move |msg: ControlMessage| {
@@ -582,49 +1264,46 @@ impl Transfer {
         
         receiver.enable_auto_retransmit(session_id, control_sender);
         
+        let mut frames = ChunkFrameReader::new();
         let mut last_progress = 0.0;
         
         loop {
-            // Receive packets
-            socket.set_read_timeout(Some(Duration::from_millis(100)))?;
-            match socket.recv_from(buf) {
-                Ok((len, from)) => {
-                    let recv_info = quiche::RecvInfo { from, to: local_addr };
-                    let _ = connection.recv(&mut buf[..len], recv_info);
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock ||
-                          e.kind() == std::io::ErrorKind::TimedOut => {}
-                Err(e) => return Err(Error::from(e)),
-            }
+            self.check_cancelled()?;
+            
+            // Exchange packets, waiting until the server sends more
+            pump_network(socket, connection, buf, out, local_addr, NETWORK_WAIT)?;
             
             // Check for readable streams
             let readable: Vec<u64> = connection.readable().collect();
             for stream_id in readable {
-                if stream_id == STREAM_DATA {
+                if stream_id == streams.data {
                     loop {
                         match connection.stream_recv(stream_id, buf) {
                             Ok((read, fin)) => {
-                                if read == 0 {
+                                if read == 0 && !fin {
                                     break;
                                 }
                                 
-                                // Receive chunk
-                                match receiver.receive_chunk(&buf[..read]) {
-                                    Ok(chunk) => {
-                                        let progress = receiver.progress();
-                                        if progress - last_progress > 0.1 {
-                                            info!("Progress: {:.1}%", progress * 100.0);
-                                            last_progress = progress;
+                                // Receive each chunk complete in the stream so far
+                                frames.push(&buf[..read]);
+                                while let Some(packet) = frames.next_packet()? {
+                                    match receiver.receive_chunk(&packet) {
+                                        Ok(chunk) => {
+                                            let progress = receiver.progress();
+                                            if progress - last_progress > 0.1 {
+                                                info!("Progress: {:.1}%", progress * 100.0);
+                                                last_progress = progress;
+                                            }
+                                            
+                                            if chunk.end_of_file {
+                                                info!("Received final chunk");
+                                            }
                                         }
-                                        
-                                        if chunk.end_of_file {
-                                            info!("Received final chunk");
+                                        Err(e) => {
+                                            error!("Chunk receive error: {:?}", e);
+                                            // Auto-retransmit will handle this
                                         }
                                     }
-                                    Err(e) => {
-                                        error!("Chunk receive error: {:?}", e);
-                                        // Auto-retransmit will handle this
-                                    }
                                 }
                                 
                                 if fin {
@@ -646,6 +1325,8 @@ impl Transfer {
             if receiver.is_complete() {
                 info!("All chunks received! Finalizing file...");
                 let final_path = receiver.finalize()?;
+                let final_path = self.decrypt_download(manifest, final_path)?;
+                metadata::apply(&final_path, manifest.mode, manifest.metadata.as_ref(), &self.config.metadata)?;
                 return Ok(final_path);
             }
             
@@ -657,23 +1338,49 @@ impl Transfer {
                     failed.len()
                 )));
             }
-            
-            std::thread::sleep(Duration::from_millis(10));
         }
     }
     
-    /// Send manifest phase - build and send manifest to server
-    /// Returns (bytes_sent, manifest, existing_hashes)
-    fn send_manifest_phase(
-        &mut self,
-        socket: &UdpSocket,
-        connection: &mut ClientConnection,
-        buf: &mut [u8],
-        out: &mut [u8],
-        local_addr: std::net::SocketAddr,
-        file_path: &Path,
-    ) -> Result<(u64, crate::protocol::messages::Manifest, Vec<Vec<u8>>)> {
-        info!("Client: building manifest for upload...");
+    /// Decrypt a downloaded file in place if its manifest marks it as sealed
+    fn decrypt_download(
+        &self,
+        manifest: &crate::protocol::messages::Manifest,
+        sealed_path: PathBuf,
+    ) -> Result<PathBuf> {
+        let info = match &manifest.encryption {
+            Some(info) => info,
+            None => return Ok(sealed_path),
+        };
+        
+        let encryption = self.config.encryption.as_ref().ok_or_else(|| {
+            Error::ConfigError(format!(
+                "{} is encrypted; a passphrase or key file is required",
+                manifest.file_name
+            ))
+        })?;
+        let cipher = ChunkCipher::from_info(encryption, info)?;
+        
+        info!("Client: decrypting {} ({})", manifest.file_name, info.algorithm);
+        let mut temp_name = sealed_path.as_os_str().to_owned();
+        temp_name.push(".decrypting");
+        let temp_path = PathBuf::from(temp_name);
+        
+        match cipher.decrypt_file(&sealed_path, &temp_path) {
+            Ok(bytes) => {
+                std::fs::rename(&temp_path, &sealed_path)?;
+                info!("Client: decrypted {} bytes", bytes);
+                Ok(sealed_path)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_path);
+                Err(e)
+            }
+        }
+    }
+    
+    /// Build (and sign) the manifest for a file before any connection is made
+    pub(super) fn prepare_upload(&self, file_path: &Path, placement: Placement) -> Result<PreparedUpload> {
+        info!("Client: building manifest for {:?}...", file_path);
         
         // Generate deterministic session ID based on file path (for resume capability)
         let file_name = file_path.file_name()
@@ -687,943 +1394,628 @@ impl Transfer {
         
         let session_id = format!("upload_{}_{}", file_name, hex::encode(&hash.as_bytes()[..8]));
         
-        // Build manifest using parallel hash computation for better performance
-        let manifest = ManifestBuilder::new(session_id.clone())
+        // Captured before hashing, which may move the access time; a bundle's
+        // members carry their own
+        let file_metadata = match placement {
+            Placement::Bundle(_) => None,
+            _ => self.capture_metadata(file_path)?,
+        };
+        
+        // Build manifest using parallel hash computation for better performance;
+        // chunks in holes of a sparse file are listed rather than read
+        let mut builder = ManifestBuilder::new(session_id.clone())
             .file_path(file_path)
             .chunk_size(self.config.chunk_size as u32)
-            .build_parallel()?;
-        
-        info!("Client: sending manifest ({} chunks, {} bytes total)", 
-            manifest.total_chunks, manifest.file_size);
-        
-        // Send manifest on STREAM_MANIFEST
-        // Encode manifest first
-        let encoded = manifest.encode_to_vec();
-        info!("Client: manifest encoded ({} bytes)", encoded.len());
-        
-        // Send with retry on partial writes
-        let mut total_sent = 0usize;
-        let mut offset = 0usize;
-        let max_retries = 100;
-        let mut retries = 0;
-        
-        while offset < encoded.len() {
-            let remaining = &encoded[offset..];
-            let is_last = offset + remaining.len() == encoded.len();
-            
-            match connection.stream_send(STREAM_MANIFEST, remaining, is_last) {
-                Ok(written) => {
-                    if written > 0 {
-                        offset += written;
-                        total_sent += written;
-                        retries = 0; // Reset retry counter on progress
-                        
-                        // Flush packets
-                        while let Ok((len, send_info)) = connection.send(out) {
-                            socket.send_to(&out[..len], send_info.to)?;
-                        }
-                    } else {
-                        // No bytes written, wait and retry
-                        retries += 1;
-                        if retries > max_retries {
-                            return Err(Error::Protocol(format!(
-                                "Manifest send stalled: {}/{} bytes sent",
-                                total_sent, encoded.len()
-                            )));
-                        }
-                        std::thread::sleep(Duration::from_millis(10));
-                    }
-                }
-                Err(_e) => {
-                    // Stream not writable, flush and retry
-                    retries += 1;
-                    if retries > max_retries {
-                        return Err(Error::Protocol(format!(
-                            "Manifest send timeout: {}/{} bytes sent",
-                            total_sent, encoded.len()
-                        )));
-                    }
-                    
-                    while let Ok((len, send_info)) = connection.send(out) {
-                        socket.send_to(&out[..len], send_info.to)?;
-                    }
-                    
-                    // Receive ACKs
-                    socket.set_read_timeout(Some(Duration::from_millis(10)))?;
-                    if let Ok((len, from)) = socket.recv_from(buf) {
-                        let recv_info = quiche::RecvInfo { from, to: local_addr };
-                        let _ = connection.recv(&mut buf[..len], recv_info);
-                    }
-                    
-                    std::thread::sleep(Duration::from_millis(10));
-                }
-            }
+            .detect_holes()
+            .cancel_flag(self.cancel.flag());
+        
+        // Seal chunks client-side if encryption is configured
+        let mut cipher = None;
+        if let Some(encryption) = &self.config.encryption {
+            let session_cipher = ChunkCipher::for_session(encryption, &session_id, self.config.chunk_size as u32)?;
+            info!("Client: encrypting chunks with {}", session_cipher.info().algorithm);
+            builder = builder.encryption(session_cipher.clone());
+            cipher = Some(session_cipher);
         }
         
-        info!("Client: manifest sent ({} bytes)", total_sent);
-        
-        // Phase 3: Hash check on dedicated server-initiated stream (STREAM_HASH_CHECK)
-        // SKIP hash check for maximum performance - proceed directly to upload
-        info!("Client: skipping hash check for maximum performance");
-        let existing_hashes = vec![];
-        
-        /*
-        // Original hash check code - disabled for performance
-        info!("Client: performing hash check for deduplication");
-        
-        let chunk_hashes: Vec<Vec<u8>> = manifest.chunk_hashes.clone();
-        let existing_hashes = self.hash_check_phase(
-            socket,
-            connection,
-            buf,
-            out,
-            local_addr,
-            &session_id,
-            chunk_hashes,
-        )?;
-        */
-        
-        info!("Client: hash check complete, {} chunks already exist", existing_hashes.len());
-        
-        Ok((total_sent as u64, manifest, existing_hashes))
-    }
-    
-    /// Resume protocol phase - check if server has partial file
-    fn check_resume_phase(
-        &mut self,
-        socket: &UdpSocket,
-        connection: &mut ClientConnection,
-        buf: &mut [u8],
-        out: &mut [u8],
-        local_addr: std::net::SocketAddr,
-        manifest: &crate::protocol::messages::Manifest,
-    ) -> Result<std::collections::HashSet<u64>> {
-        use std::collections::HashSet;
-        
-        // Check for existing resume bitmap (load from disk first, then memory)
-        let bitmap = if let Ok(disk_bitmap) = self.load_resume_bitmap(&manifest.session_id) {
-            info!("Client: found resume bitmap on disk with {} chunks received", disk_bitmap.received_count());
-            disk_bitmap
-        } else if let Some(saved_bitmap) = self.resume_bitmaps.get(&manifest.session_id) {
-            info!("Client: found saved bitmap in memory, attempting resume...");
-            info!("Client: loaded bitmap with {} chunks received", saved_bitmap.received_count());
-            
-            // Create a copy for use
-            let total_chunks = saved_bitmap.total_chunks().unwrap_or(0);
-            let mut bitmap_copy = ChunkBitmap::with_exact_size(total_chunks);
-            for i in 0..total_chunks {
-                if saved_bitmap.is_received(i) {
-                    bitmap_copy.mark_received(i, saved_bitmap.is_complete() && i == total_chunks - 1);
-                }
-            }
-            bitmap_copy
+        // An empty file has no chunk hashes to build a tree from
+        let is_empty = std::fs::metadata(file_path)?.len() == 0;
+        let mut hash_tree = None;
+        let mut manifest = if self.config.tree_manifest && !is_empty {
+            let (manifest, tree) = builder.build_tree()?;
+            info!("Client: tree-hash manifest, root {}", hex::encode(tree.root()));
+            hash_tree = Some(Arc::new(tree));
+            manifest
         } else {
-            info!("Client: no saved bitmap found, starting fresh transfer");
-            return Ok(HashSet::new());
+            builder.build_parallel()?
         };
         
-        // Don't resume if no progress was made
-        if bitmap.received_count() == 0 {
-            info!("Client: bitmap shows no progress, starting fresh");
-            return Ok(HashSet::new());
-        }
-        
-        // Send resume request to server
-        let resume_request_sender = ResumeRequestSender::new();
-        let received_chunks = bitmap.get_received_chunks();
-        let last_chunk = received_chunks.last().copied();
-        
-        info!("Client: sending resume request for {} received chunks", received_chunks.len());
-        
-        let send_result = resume_request_sender.send_request(
-            manifest.session_id.clone(),
-            received_chunks.clone(),
-            Some(bitmap.to_bytes()),
-            last_chunk,
-            |data: &[u8], fin: bool| -> std::result::Result<usize, quiche::Error> {
-                match connection.stream_send(STREAM_RESUME, data, fin) {
-                    Ok(n) => Ok(n),
-                    Err(_) => Err(quiche::Error::Done), // Map to quiche error
-                }
-            }
-        );
-        
-        if let Err(e) = send_result {
-            warn!("Client: failed to send resume request: {}, starting fresh", e);
-            return Ok(HashSet::new());
-        }
-        
-        // Flush the request
-        socket.set_read_timeout(Some(Duration::from_millis(10)))?;
-        for _ in 0..20 {
-            loop {
-                match connection.send(out) {
-                    Ok((write, send_info)) => {
-                        if write > 0 {
-                            socket.send_to(&out[..write], send_info.to)?;
-                        }
-                    }
-                    Err(_) => break,
-                }
+        manifest.metadata = file_metadata;
+        match placement {
+            Placement::Root => {
+                manifest.mode = self.kept_mode(directory::file_mode(&std::fs::metadata(file_path)?));
             }
-            
-            // Receive ACKs
-            if let Ok((len, from)) = socket.recv_from(buf) {
-                let recv_info = quiche::RecvInfo { from, to: local_addr };
-                let _ = connection.recv(&mut buf[..len], recv_info);
+            Placement::Path { relative_path, mode } => {
+                manifest.relative_path = relative_path;
+                manifest.mode = mode;
             }
-            
-            std::thread::sleep(Duration::from_millis(5));
+            Placement::Bundle(index) => manifest.bundle = Some(index),
         }
         
-        info!("Client: resume request sent, waiting for response...");
-        
-        // Receive resume response
-        let mut response_receiver = ResumeResponseReceiver::new();
-        let mut received_response = false;
-        let mut skip_chunks = HashSet::new();
-        let mut idle_iterations = 0;
-        const MAX_IDLE: usize = 50; // 50 * 10ms = 500ms timeout (reduced from 2s)
-        
-        while !received_response && idle_iterations < MAX_IDLE {
-            // Flush outgoing packets
-            while let Ok((write, send_info)) = connection.send(out) {
-                socket.send_to(&out[..write], send_info.to)?;
-                idle_iterations = 0;
-            }
-            
-            // Receive data
-            match socket.recv_from(buf) {
-                Ok((len, from)) => {
-                    let recv_info = quiche::RecvInfo { from, to: local_addr };
-                    let _ = connection.recv(&mut buf[..len], recv_info);
-                    idle_iterations = 0;
-                    
-                    info!("Client: received packet during resume wait (iteration {})", idle_iterations);
-                    
-                    // Check for resume response on STREAM_RESUME
-                    while let Ok((read, fin)) = connection.stream_recv(STREAM_RESUME, buf) {
-                        if read > 0 {
-                            if let Some(response) = response_receiver.receive_chunk(&buf[..read], fin)? {
-                                if response.accepted {
-                                    info!("Client: resume accepted! Server needs {} chunks", response.chunks_remaining);
-                                    
-                                    // Build skip set - chunks NOT in missing list
-                                    let missing_set: HashSet<u64> = response.missing_chunks.iter().copied().collect();
-                                    for chunk_idx in 0..manifest.total_chunks {
-                                        if !missing_set.contains(&chunk_idx) {
-                                            skip_chunks.insert(chunk_idx);
-                                        }
-                                    }
-                                    
-                                    info!("Client: will skip {} chunks, send {} chunks", 
-                                        skip_chunks.len(), response.missing_chunks.len());
-                                } else {
-                                    warn!("Client: resume rejected by server: {:?}", response.error);
-                                }
-                                received_response = true;
-                                break;
-                            }
-                        }
-                        if fin {
-                            break;
-                        }
-                    }
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock ||
-                              e.kind() == std::io::ErrorKind::TimedOut => {
-                    idle_iterations += 1;
-                    if idle_iterations % 10 == 0 {
-                        info!("Client: waiting for resume response... ({}/{})", idle_iterations, MAX_IDLE);
-                    }
-                    std::thread::sleep(Duration::from_millis(10));
-                }
-                Err(e) => return Err(Error::from(e)),
-            }
-            
-            if connection.is_closed() {
-                warn!("Client: connection closed during resume");
-                return Err(Error::ConnectionClosed);
-            }
-        }
-        
-        if !received_response {
-            warn!("Client: no resume response received after 500ms, starting fresh transfer");
-            warn!("Client: (server may not support resume or is not responding)");
-            return Ok(HashSet::new());
+        // Sign the manifest so the server can authenticate the sender
+        if let Some(key_path) = &self.config.signing_key {
+            let signer = ManifestSigner::from_key_file(key_path)?;
+            signer.sign(&mut manifest);
+            info!("Client: manifest signed with key {}", hex::encode(signer.public_key()));
         }
         
-        Ok(skip_chunks)
+        Ok(PreparedUpload {
+            file_path: file_path.to_path_buf(),
+            manifest,
+            cipher,
+            hash_tree,
+            stream: None,
+        })
     }
     
-    /// Save bitmap for resume capability (to disk AND memory)
-    fn save_resume_bitmap(&mut self, session_id: &str, bitmap: &ChunkBitmap) -> Result<()> {
-        let total_chunks = bitmap.total_chunks().unwrap_or(0);
-        
-        // Get or create the bitmap in the HashMap
-        let stored_bitmap = self.resume_bitmaps
-            .entry(session_id.to_string())
-            .or_insert_with(|| ChunkBitmap::with_exact_size(total_chunks));
-        
-        // Update in-memory bitmap
-        let received_count = bitmap.received_count();
-        for i in (received_count.saturating_sub(100))..total_chunks {
-            if bitmap.is_received(i) && !stored_bitmap.is_received(i) {
-                stored_bitmap.mark_received(i, bitmap.is_complete() && i == total_chunks - 1);
-            }
+    /// Start reading input of unknown length and build its manifest header
+    /// 
+    /// The session ID comes from the remote path, so a rerun with the same
+    /// input resumes from the chunks the server has stored. Encryption,
+    /// signing and tree manifests all need the whole file up front.
+    fn prepare_stream<R: Read + Send + 'static>(&self, reader: R, remote_path: &str) -> Result<PreparedUpload> {
+        if self.config.encryption.is_some() || self.config.signing_key.is_some() || self.config.tree_manifest {
+            return Err(Error::ConfigError(
+                "Streaming uploads cannot be encrypted, signed or use tree manifests".to_string()
+            ));
         }
         
-        // Save to disk in sftpx_resume directory (cross-platform)
-        let resume_dir = std::path::PathBuf::from("sftpx_resume");
-        if !resume_dir.exists() {
-            std::fs::create_dir_all(&resume_dir)?;
-        }
+        let file_name = Path::new(remote_path).file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| Error::ConfigError(format!("Invalid remote path: {:?}", remote_path)))?;
         
-        let bitmap_path = resume_dir.join(format!("{}.bitmap", session_id));
-        bitmap.save_to_disk(&bitmap_path)?;
+        let hash = blake3::hash(format!("stream:{}", remote_path).as_bytes());
+        let session_id = format!("upload_{}_{}", file_name, hex::encode(&hash.as_bytes()[..8]));
         
-        debug!("Client: saved resume bitmap to disk and memory for session {} ({}/{} chunks)", 
-            session_id, bitmap.received_count(), total_chunks);
-        Ok(())
+        let manifest = Manifest {
+            session_id,
+            file_name: file_name.to_string(),
+            chunk_size: self.config.chunk_size as u32,
+            compression: "none".to_string(),
+            relative_path: remote_path.to_string(),
+            streaming: true,
+            ..Manifest::default()
+        };
+        
+        Ok(PreparedUpload {
+            file_path: PathBuf::from("-"),
+            manifest,
+            cipher: None,
+            hash_tree: None,
+            stream: Some(StreamChunker::new(reader, self.config.chunk_size, self.config.compression)),
+        })
     }
     
-    /// Load bitmap from disk for resume capability
-    fn load_resume_bitmap(&self, session_id: &str) -> Result<ChunkBitmap> {
-        let resume_dir = std::path::PathBuf::from("sftpx_resume");
-        let bitmap_path = resume_dir.join(format!("{}.bitmap", session_id));
+    /// Metadata of a local file to send along, if the policy keeps any
+    fn capture_metadata(&self, path: &Path) -> Result<Option<FileMetadata>> {
+        let captured = metadata::capture(path, &self.config.metadata)?;
+        Ok((captured != FileMetadata::default()).then_some(captured))
+    }
+    
+    /// Permission bits to send, or 0 (left to the receiver) if not kept
+    fn kept_mode(&self, mode: u32) -> u32 {
+        if self.config.metadata.mode { mode } else { 0 }
+    }
+    
+    /// Packing threshold, unless packing is off or cannot be used
+    fn pack_threshold(&self) -> Option<u64> {
+        let threshold = self.config.pack_threshold?;
+        if self.config.encryption.is_some() {
+            warn!("Client: small-file packing is not available with encryption, sending files individually");
+            return None;
+        }
+        Some(threshold)
+    }
+    
+    /// Pack small files into bundles and build a manifest for each bundle
+    fn prepare_bundles(&self, small_files: Vec<PackMember>) -> Result<Vec<PreparedUpload>> {
+        if small_files.is_empty() {
+            return Ok(Vec::new());
+        }
         
-        if bitmap_path.exists() {
-            info!("Client: loading resume bitmap from disk: {:?}", bitmap_path);
-            ChunkBitmap::load_from_disk(&bitmap_path)
-                .map_err(|e| Error::Io(e))
-        } else {
-            Err(Error::Protocol(format!("No resume bitmap found for session {}", session_id)))
+        let builder = BundleBuilder::new(BUNDLE_DIR).metadata(self.config.metadata);
+        let file_count = small_files.len();
+        let mut bundles = Vec::new();
+        
+        for group in builder.group(small_files) {
+            let (bundle_path, index) = builder.write(&group)?;
+            bundles.push(self.prepare_upload(&bundle_path, Placement::Bundle(index))?);
         }
+        
+        info!("Client: packed {} small files into {} bundles", file_count, bundles.len());
+        Ok(bundles)
     }
     
-    /// Hash check phase - check which chunks already exist on server
-    #[allow(dead_code)]
-    fn hash_check_phase(
+    /// Helper: Send a whole message on a stream and finish it
+    /// 
+    /// Partial writes are retried as ACKs and flow control credit arrive.
+    #[allow(clippy::too_many_arguments)]
+    fn send_stream_data(
         &mut self,
-        socket: &UdpSocket,
+        socket: &EventSocket,
         connection: &mut ClientConnection,
         buf: &mut [u8],
         out: &mut [u8],
         local_addr: std::net::SocketAddr,
-        session_id: &str,
-        chunk_hashes: Vec<Vec<u8>>,
-    ) -> Result<Vec<Vec<u8>>> {
-        info!("Client: starting hash check phase for {} chunk hashes", chunk_hashes.len());
-        
-        // Send hash check request on client-initiated stream (STREAM_HASH_CHECK = 16)
-        // Client opens the stream and both parties can read/write
-        let hash_sender = HashCheckRequestSender::new();
-        
-        // Retry sending if stream isn't ready
-        let mut send_attempts = 0;
-        let send_result = loop {
-            match hash_sender.send_request(
-                session_id.to_string(),
-                chunk_hashes.clone(),
-                |data, fin| {
-                    debug!("Client: attempt {} - writing {} bytes to hash check stream (fin={})", send_attempts + 1, data.len(), fin);
-                    connection.stream_send(STREAM_HASH_CHECK, data, fin)
-                },
-            ) {
-                Ok(result) => break result,
-                Err(e) => {
-                    send_attempts += 1;
-                    if send_attempts >= 3 {
-                        warn!("Client: failed to send hash check request after {} attempts: {}", send_attempts, e);
-                        return Ok(vec![]); // Continue without dedup
-                    }
-                    warn!("Client: hash check send attempt {} failed: {}, retrying...", send_attempts, e);
-                    std::thread::sleep(Duration::from_millis(100));
-                }
-            }
-        };
+        stream_id: u64,
+        encoded: &[u8],
+    ) -> Result<usize> {
+        // Send with retry on partial writes
+        let mut total_sent = 0usize;
+        let mut offset = 0usize;
+        let mut last_progress = Instant::now();
         
-        info!("Client: hash check request prepared: {} bytes", send_result);
-        
-        // Set short read timeout for non-blocking receive during flush
-        socket.set_read_timeout(Some(Duration::from_millis(10)))?;
-        
-        // Actively flush and receive to ensure ALL data is sent
-        // The stream_send call buffers data, we need to send packets until buffer is empty
-        let mut consecutive_no_send = 0;
-        const MAX_NO_SEND: usize = 100;  // More iterations to ensure large messages are sent
-        
-        while consecutive_no_send < MAX_NO_SEND {
-            // Send outgoing packets - keep sending until no more packets
-            let mut sent_any = false;
-            loop {
-                match connection.send(out) {
-                    Ok((write, send_info)) => {
-                        if write > 0 {
-                            socket.send_to(&out[..write], send_info.to)?;
-                            sent_any = true;
-                            consecutive_no_send = 0;
-                            debug!("Client: sent {} bytes during hash check flush", write);
-                        }
-                    }
-                    Err(_) => break,
-                }
-            }
+        while offset < encoded.len() {
+            self.check_cancelled()?;
+            let remaining = &encoded[offset..];
+            let is_last = offset + remaining.len() == encoded.len();
             
-            // Receive to process ACKs and window updates
-            match socket.recv_from(buf) {
-                Ok((len, from)) => {
-                    let recv_info = quiche::RecvInfo {
-                        to: local_addr,
-                        from,
-                    };
-                    if let Ok(read) = connection.recv(&mut buf[..len], recv_info) {
-                        if read > 0 {
-                            debug!("Client: received {} bytes during hash check flush", len);
-                            consecutive_no_send = 0;  // Reset on receive too
-                        }
-                    }
+            match connection.stream_send(stream_id, remaining, is_last) {
+                Ok(written) if written > 0 => {
+                    offset += written;
+                    total_sent += written;
+                    last_progress = Instant::now();
+                    flush_packets(socket, connection, out)?;
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {}  // Expected with short timeout
-                Err(e) => {
-                    warn!("Client: unexpected recv error during hash check flush: {}", e);
+                _ => {
+                    // Stream not writable until the server acknowledges data
+                    // or grants more credit
+                    if last_progress.elapsed() > SEND_STALL_TIMEOUT {
+                        return Err(Error::Protocol(format!(
+                            "Send on stream {} stalled: {}/{} bytes sent",
+                            stream_id, total_sent, encoded.len()
+                        )));
+                    }
+                    pump_network(socket, connection, buf, out, local_addr, NETWORK_WAIT)?;
                 }
             }
-            
-            if !sent_any {
-                consecutive_no_send += 1;
-            }
-            
-            std::thread::sleep(Duration::from_millis(10));
         }
         
-        info!("Client: hash check request sent, waiting for response...");
-        
-        // Receive hash check response on same stream
-        let mut response_receiver = HashCheckResponseReceiver::new();
-        let mut received_response = false;
-        let mut existing_hashes = vec![];
-        let mut idle_iterations = 0;
-        const MAX_IDLE: usize = 500;  // 500 * 10ms = 5 seconds
-        
-        debug!("Client: waiting for hash check response on stream {}...", STREAM_HASH_CHECK);
-        
-        let start_time = std::time::Instant::now();
-        
-        while !received_response && idle_iterations < MAX_IDLE {
-            // Flush outgoing packets
-            let mut sent_packets = 0;
-            while let Ok((write, send_info)) = connection.send(out) {
-                socket.send_to(&out[..write], send_info.to)?;
-                sent_packets += 1;
-                idle_iterations = 0;
-            }
+        Ok(total_sent)
+    }
+    
+    /// Chunks an earlier session sent for `session_id`, from disk or memory
+    fn resume_bitmap(&self, session_id: &str) -> Option<ChunkBitmap> {
+        if let Ok(disk_bitmap) = self.load_resume_bitmap(session_id) {
+            info!("Client: found resume bitmap on disk with {} chunks received", disk_bitmap.received_count());
+            Some(disk_bitmap)
+        } else if let Some(saved_bitmap) = self.resume_bitmaps.get(session_id) {
+            info!("Client: found saved bitmap in memory, attempting resume...");
+            info!("Client: loaded bitmap with {} chunks received", saved_bitmap.received_count());
             
-            // Receive data
-            match socket.recv_from(buf) {
-                Ok((len, from)) => {
-                    let recv_info = quiche::RecvInfo {
-                        to: local_addr,
-                        from,
-                    };
-                    
-                    let _ = connection.recv(&mut buf[..len], recv_info);
-                    idle_iterations = 0;
-                    
-                    // Check for hash check response on STREAM_HASH_CHECK
-                    while let Ok((read, fin)) = connection.stream_recv(STREAM_HASH_CHECK, &mut buf[..]) {
-                        if read > 0 {
-                            debug!("Client: received {} bytes on hash check stream", read);
-                            if let Some(response) = response_receiver.receive_chunk(&buf[..read], fin)? {
-                                existing_hashes = response.existing_hashes;
-                                received_response = true;
-                                info!("Client: hash check response received with {} existing chunks", existing_hashes.len());
-                                break;
-                            }
-                        }
-                        if fin {
-                            break;
-                        }
-                    }
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    idle_iterations += 1;
-                    std::thread::sleep(Duration::from_millis(10));
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
-                    // Treat timeout like WouldBlock during hash check wait
-                    idle_iterations += 1;
-                    std::thread::sleep(Duration::from_millis(10));
-                }
-                Err(e) => {
-                    warn!("Client: unexpected error during hash check response wait: {}", e);
-                    return Err(Error::from(e));
+            // Create a copy for use
+            let total_chunks = saved_bitmap.total_chunks().unwrap_or(0);
+            let mut bitmap_copy = ChunkBitmap::with_exact_size(total_chunks);
+            for i in 0..total_chunks {
+                if saved_bitmap.is_received(i) {
+                    bitmap_copy.mark_received(i, saved_bitmap.is_complete() && i == total_chunks - 1);
                 }
             }
-            
-            // Log progress every second
-            if idle_iterations % 100 == 0 && idle_iterations > 0 {
-                debug!("Client: hash check waiting... {:.1}s elapsed, {} packets sent recently", 
-                    start_time.elapsed().as_secs_f64(), sent_packets);
-            }
-            
-            if connection.is_closed() {
-                warn!("Client: connection closed during hash check");
-                return Err(Error::ConnectionClosed);
+            Some(bitmap_copy)
+        } else {
+            // Ask anyway: the server verifies its .part file itself, so a
+            // lost or empty bitmap on this side still resumes whatever made
+            // it to disk
+            info!("Client: no saved bitmap found, asking server to check for a partial file");
+            None
+        }
+    }
+    
+    /// Save bitmap for resume capability (to disk AND memory)
+    fn save_resume_bitmap(&mut self, session_id: &str, bitmap: &ChunkBitmap) -> Result<()> {
+        let total_chunks = bitmap.total_chunks().unwrap_or(0);
+        
+        // Get or create the bitmap in the HashMap
+        let stored_bitmap = self.resume_bitmaps
+            .entry(session_id.to_string())
+            .or_insert_with(|| ChunkBitmap::with_exact_size(total_chunks));
+        
+        // Update in-memory bitmap
+        let received_count = bitmap.received_count();
+        for i in (received_count.saturating_sub(100))..total_chunks {
+            if bitmap.is_received(i) && !stored_bitmap.is_received(i) {
+                stored_bitmap.mark_received(i, bitmap.is_complete() && i == total_chunks - 1);
             }
         }
         
-        if !received_response {
-            warn!("Client: hash check response not received after {} iterations, proceeding without dedup", idle_iterations);
-            // Continue without deduplication rather than failing
-            return Ok(vec![]);
+        // Save to disk in sftpx_resume directory (cross-platform)
+        let resume_dir = std::path::PathBuf::from("sftpx_resume");
+        if !resume_dir.exists() {
+            std::fs::create_dir_all(&resume_dir)?;
         }
         
-        Ok(existing_hashes)
+        let bitmap_path = resume_dir.join(format!("{}.bitmap", session_id));
+        bitmap.save_to_disk(&bitmap_path)?;
+        
+        debug!("Client: saved resume bitmap to disk and memory for session {} ({}/{} chunks)", 
+            session_id, bitmap.received_count(), total_chunks);
+        Ok(())
     }
     
-    /// Send file phase - send file chunks to server
-    fn send_file_phase(
-        &mut self,
-        socket: &UdpSocket,
-        connection: &mut ClientConnection,
-        buf: &mut [u8],
-        out: &mut [u8],
-        local_addr: std::net::SocketAddr,
-        file_path: &Path,
-        manifest: &crate::protocol::messages::Manifest,
-        existing_hashes: &[Vec<u8>],
-        skip_chunks: &std::collections::HashSet<u64>,
-    ) -> Result<u64> {
-        use crate::chunking::ParallelChunker;
-        use std::collections::HashSet;
+    /// Load bitmap from disk for resume capability
+    fn load_resume_bitmap(&self, session_id: &str) -> Result<ChunkBitmap> {
+        let resume_dir = std::path::PathBuf::from("sftpx_resume");
+        let bitmap_path = resume_dir.join(format!("{}.bitmap", session_id));
         
-        info!("Client: starting file chunk upload...");
+        if bitmap_path.exists() {
+            info!("Client: loading resume bitmap from disk: {:?}", bitmap_path);
+            ChunkBitmap::load_from_disk(&bitmap_path)
+                .map_err(|e| Error::Io(e))
+        } else {
+            Err(Error::Protocol(format!("No resume bitmap found for session {}", session_id)))
+        }
+    }
+    
+    /// Start the protocol for one file on its transfer streams
+    /// 
+    /// The manifest and resume request are queued right away; chunks are
+    /// read once the server has answered.
+    fn start_upload(&mut self, prepared: PreparedUpload, streams: TransferStreams) -> Result<OutgoingUpload> {
+        let bundle_path = prepared.manifest.bundle.is_some().then(|| prepared.file_path.clone());
+        // A stream resumes from what the server has stored, not what was sent
+        let resume_bitmap = match prepared.stream {
+            Some(_) => None,
+            None => self.resume_bitmap(&prepared.manifest.session_id),
+        };
+        let streaming = prepared.stream.is_some();
+        let options = UploadOptions {
+            dedup: self.config.dedup,
+            dedup_filter: self.config.dedup_filter,
+        };
         
-        // Set socket to non-blocking mode for maximum performance
-        socket.set_nonblocking(true)?;
+        // What the server does if the name is taken; not covered by the signature
+        let mut manifest = prepared.manifest;
+        manifest.conflict_policy = self.config.conflict_policy as i32;
+        
+        let machine = UploadMachine::new(streams, manifest, resume_bitmap.as_ref(), options, Instant::now())?;
+        let chunk_range = machine.chunk_range();
+        // Chunks in holes are never sent, so they do not count
+        let hole_chunks = sparse::hole_count(&machine.manifest().holes, chunk_range.clone());
+        self.observer.on_state(Some(&machine.manifest().session_id), TransferState::SendingManifest);
+        
+        Ok(OutgoingUpload {
+            machine,
+            file_path: prepared.file_path,
+            cipher: prepared.cipher,
+            hash_tree: prepared.hash_tree,
+            chunk_iter: None,
+            stream: prepared.stream,
+            total_chunks: if streaming { 0 } else { chunk_range.end - chunk_range.start - hole_chunks },
+            start_time: Instant::now(),
+            bundle_path,
+        })
+    }
+    
+    /// Start reading and packing an upload's chunks
+    fn start_chunks(&self, upload: &OutgoingUpload) -> Result<ParallelChunkIterator> {
+        use crate::chunking::ParallelChunker;
         
-        // Convert existing hashes to HashSet for efficient lookup
-        let existing_set: HashSet<&[u8]> = existing_hashes.iter()
-            .map(|v| v.as_slice())
-            .collect();
+        let streams = upload.machine.streams();
+        let manifest = upload.machine.manifest();
+        let chunk_range = upload.machine.chunk_range();
         
         // Create parallel chunker for high-performance processing
-        let chunker = ParallelChunker::new(
-            file_path,
+        let mut chunker = ParallelChunker::new(
+            &upload.file_path,
             Some(self.config.chunk_size),
             self.config.compression,
             None, // Auto-detect CPU count
-        )?;
-        
-        let total_chunks = chunker.total_chunks();
-        let mut bytes_sent = 0u64;
-        let mut chunk_count = 0u64;
-        let mut chunks_skipped = 0u64;
-        
-        info!("Client: uploading {} chunks ({} bytes) with compression: {:?}", 
-            total_chunks, chunker.file_size(), self.config.compression);
+)?.with_chunk_range(chunk_range.clone())
+            .with_holes(&manifest.holes);
         
-        if !existing_set.is_empty() {
-            info!("Client: {} chunks already exist on server (will skip via dedup)", existing_set.len());
+        if let Some(cipher) = &upload.cipher {
+            chunker = chunker.with_cipher(cipher.clone());
         }
         
-        if !skip_chunks.is_empty() {
-            info!("Client: {} chunks already received by server (will skip via resume)", skip_chunks.len());
+        if let Some(tree) = &upload.hash_tree {
+            chunker = chunker.with_hash_tree(tree.clone());
         }
         
-        // Create bitmap for tracking sent chunks (for resume capability)
-        let mut sent_bitmap = ChunkBitmap::with_exact_size(total_chunks as u32);
-        
-        // Track performance
-        let start_time = std::time::Instant::now();
-        
-        // Mark skipped chunks as already sent
-        for &chunk_idx in skip_chunks {
-            if chunk_idx < total_chunks {
-                sent_bitmap.mark_received(chunk_idx as u32, chunk_idx == total_chunks - 1);
-            }
+        info!("Client: transfer {}: uploading {} chunks ({} bytes) with compression: {:?}", 
+            streams.transfer_id, upload.total_chunks, chunker.file_size(), self.config.compression);
+        if let Some(stripe) = &manifest.stripe {
+            info!("Client: stripe {} of {}: chunks {}..{}", stripe.index + 1, stripe.count, chunk_range.start, chunk_range.end);
         }
         
         // Process chunks in parallel pipeline
-        let mut chunk_iter = chunker.process_chunks()?;
+        chunker.process_chunks()
+    }
+    
+    /// Upload phase - drive every upload's state machine until all are sent
+    /// 
+    /// Uploads sharing the connection exchange their manifests and resume
+    /// requests together, then take turns, one chunk each, so they progress
+    /// together on their own data streams. The loop only waits on the
+    /// network when no upload can move.
+    fn send_file_phase(
+        &mut self,
+        socket: &EventSocket,
+        connection: &mut ClientConnection,
+        buf: &mut [u8],
+        out: &mut [u8],
+        local_addr: std::net::SocketAddr,
+        mut uploads: Vec<OutgoingUpload>,
+    ) -> Result<u64> {
+        info!("Client: starting file upload ({} transfers)...", uploads.len());
         
-        // Send chunks immediately without pipelining - simpler and more reliable
-        while let Some(chunk_result) = chunk_iter.next() {
-            let processed_chunk = chunk_result?;
-            let chunk_id = processed_chunk.chunk_id;
-            let is_last = processed_chunk.end_of_file;
-            
-            // Check if this chunk should be skipped (resume mode)
-            if skip_chunks.contains(&chunk_id) {
-                chunks_skipped += 1;
-                chunk_count += 1;
-                
-                if chunk_count % 10 == 0 {
-                    info!("Client: skipped chunk {}/{} (resume)", chunk_count, total_chunks);
+        let mut bytes_sent = 0u64;
+        
+        while !uploads.is_empty() {
+            if self.cancel.is_cancelled() {
+                // Keep what has been sent so a later run resumes from it
+                for upload in &uploads {
+                    self.save_upload_bitmap(upload);
                 }
-                continue;
+                return Err(Error::Cancelled);
             }
             
-            // Check if this chunk's hash exists on server (dedup)
-            let should_skip = existing_set.contains(processed_chunk.hash.as_slice());
+            let now = Instant::now();
+            let mut progressed = false;
+            let mut idx = 0;
             
-            if should_skip {
-                chunks_skipped += 1;
-                chunk_count += 1;
+            while idx < uploads.len() {
+                progressed |= self.step_upload(&mut uploads[idx], connection, buf, now)?;
                 
-                if chunk_count % 10 == 0 {
-                    info!("Client: skipped chunk {}/{} (dedup)", chunk_count, total_chunks);
+                if uploads[idx].is_sent() {
+                    let upload = uploads.remove(idx);
+                    bytes_sent += self.finish_upload(upload);
+                } else {
+                    idx += 1;
                 }
-                continue;
             }
             
-            // Combine length prefix and chunk data
-            let len_bytes = (processed_chunk.packet.len() as u32).to_be_bytes();
-            let mut combined_data = Vec::with_capacity(4 + processed_chunk.packet.len());
-            combined_data.extend_from_slice(&len_bytes);
-            combined_data.extend_from_slice(&processed_chunk.packet);
-            
-            // Send chunk - optimized single call
-            self.send_chunk_fast(
-                connection, socket, buf, out, local_addr,
-                STREAM_DATA, &combined_data, is_last
-            )?;
-            
-            bytes_sent += processed_chunk.packet.len() as u64;
-            chunk_count += 1;
-            
-            // Mark chunk as sent in bitmap for resume capability
-            let is_eof_chunk = chunk_count == total_chunks;
-            sent_bitmap.mark_received((chunk_count - 1) as u32, is_eof_chunk);
-            
-            // Periodically save bitmap for resume (every 100 chunks)
-            if chunk_count % 100 == 0 || is_eof_chunk {
-                if let Err(e) = self.save_resume_bitmap(&manifest.session_id, &sent_bitmap) {
-                    warn!("Client: failed to save resume bitmap: {}", e);
-                }
+            if connection.is_closed() {
+                return Err(Error::ConnectionClosed);
             }
             
-            if chunk_count % 50 == 0 || is_last {
-                let elapsed = start_time.elapsed().as_secs_f64();
-                let speed_mbps = if elapsed > 0.0 {
-                    (bytes_sent as f64 / elapsed) / (1024.0 * 1024.0)
-                } else {
-                    0.0
-                };
-                info!("Client: sent chunk {}/{} ({:.1}%) - {:.2} MB/s", 
-                    chunk_count, total_chunks, 
-                    (chunk_count as f64 / total_chunks as f64) * 100.0,
-                    speed_mbps);
+            if progressed {
+                // Flush packets and take in any ACKs already queued
+                flush_packets(socket, connection, out)?;
+                recv_packets(socket, connection, buf, local_addr)?;
+            } else {
+                // Nothing moves until the server answers, a stream has room
+                // or a deadline passes
+                let limit = uploads.iter()
+                    .filter_map(OutgoingUpload::deadline)
+                    .min()
+                    .map_or(NETWORK_WAIT, |deadline| deadline.saturating_duration_since(now).min(NETWORK_WAIT));
+                pump_network(socket, connection, buf, out, local_addr, limit)?;
             }
         }
         
-        let total_elapsed = start_time.elapsed().as_secs_f64();
-        let avg_speed_mbps = if total_elapsed > 0.0 {
-            (bytes_sent as f64 / total_elapsed) / (1024.0 * 1024.0)
-        } else {
-            0.0
-        };
-        
-        info!("Client: average upload speed: {:.2} MB/s", avg_speed_mbps);
-        
-        if chunks_skipped > 0 {
-            info!("Client: deduplication saved {} chunks ({:.1}%)", 
-                chunks_skipped, 
-                (chunks_skipped as f64 / total_chunks as f64) * 100.0);
-        }
-        
-        // Final flush - keep sending until connection is drained or nothing left to send
+        // Final flush - keep exchanging packets until nothing has been left
+        // to send for a while
         info!("Client: flushing final packets...");
-        let mut idle_iterations = 0;
-        let max_idle_iterations = 100; // ~500ms of idle time
+        let mut quiet_since = Instant::now();
         
         loop {
-            // Send any pending packets
-            let mut sent_any = false;
-            while let Ok((len, send_info)) = connection.send(out) {
-                match socket.send_to(&out[..len], send_info.to) {
-                    Ok(_) => { sent_any = true; },
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                    Err(_) => break,  // Ignore other errors during flush
-                }
-            }
-            
-            // Receive ACKs
-            socket.set_read_timeout(Some(Duration::from_millis(10))).ok();
-            match socket.recv_from(buf) {
-                Ok((len, from)) => {
-                    let recv_info = quiche::RecvInfo { from, to: local_addr };
-                    let _ = connection.recv(&mut buf[..len], recv_info);
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {},
-                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {},
-                Err(_) => {},  // Ignore errors during flush
+            // Errors are ignored here; all data has been handed to QUIC
+            if flush_packets(socket, connection, out).unwrap_or(0) > 0 {
+                quiet_since = Instant::now();
+            } else if quiet_since.elapsed() > FLUSH_QUIET_TIME {
+                info!("Client: flush complete (no more packets to send)");
+                break;
             }
             
-            // If nothing was sent, increment idle counter
-            if !sent_any {
-                idle_iterations += 1;
-                if idle_iterations > max_idle_iterations {
-                    info!("Client: flush complete (no more packets to send)");
-                    break;
-                }
-            } else {
-                idle_iterations = 0; // Reset on activity
+            // Wait for ACKs and the connection's timers
+            let limit = FLUSH_QUIET_TIME.saturating_sub(quiet_since.elapsed());
+            if pump_network(socket, connection, buf, out, local_addr, limit).is_err() {
+                break;
             }
-            
-            std::thread::sleep(Duration::from_millis(5));
         }
         
         info!("Client: file upload complete ({} bytes sent)", bytes_sent);
         
-        // Remove bitmap from memory after successful transfer
-        if self.resume_bitmaps.remove(&manifest.session_id).is_some() {
-            debug!("Client: removed resume bitmap from memory after successful transfer");
-        }
-        
         Ok(bytes_sent)
     }
     
-    /// Helper: Fast chunk send - write as much as possible without blocking
-    fn send_chunk_fast(
-        &self,
+    /// Helper: Move one upload along as far as it can go right now
+    /// 
+    /// Hands the machine the server's replies and the time, then the next
+    /// chunk if it takes one, and writes what it queued to the connection.
+    /// 
+    /// # Returns
+    /// Whether anything was sent or decided
+    pub(super) fn step_upload(
+        &mut self,
+        upload: &mut OutgoingUpload,
         connection: &mut ClientConnection,
-        socket: &UdpSocket,
         buf: &mut [u8],
-        out: &mut [u8],
-        local_addr: std::net::SocketAddr,
-        stream_id: u64,
-        data: &[u8],
-        fin: bool,
-    ) -> Result<()> {
-        let mut written = 0;
-        socket.set_nonblocking(true).ok();
-        
-        let mut stall_iterations = 0;
-        
-        // Write data to stream buffer and ensure it gets sent
-        while written < data.len() {
-            let prev_written = written;
-            
-            // Write to stream
-            match connection.stream_send(stream_id, &data[written..], fin && written >= data.len() - 1) {
-                Ok(w) if w > 0 => {
-                    written += w;
-                    stall_iterations = 0;
+        now: Instant,
+    ) -> Result<bool> {
+        let was_sending = upload.machine.is_sending();
+        for stream_id in upload.machine.reply_streams() {
+            while let Ok((read, fin)) = connection.stream_recv(stream_id, buf) {
+                upload.machine.on_stream_data(stream_id, &buf[..read], fin)?;
+                if fin {
+                    break;
                 }
-                Ok(_) => {}
-                Err(ref e) if format!("{:?}", e).contains("Done") => {}
-                Err(e) => return Err(e),
             }
-            
-            // Flush packets
-            loop {
-                match connection.send(out) {
-                    Ok((len, send_info)) => {
-                        if let Ok(_) = socket.send_to(&out[..len], send_info.to) {
-                            stall_iterations = 0;
-                        }
-                    }
-                    Err(_) => break,
+        }
+        upload.machine.on_timeout(now)?;
+        
+        if !was_sending && upload.machine.is_sending() {
+            let session_id = &upload.machine.manifest().session_id;
+            let decision = ResumeDecision::from_counts(upload.machine.resumed_chunks(), upload.total_chunks);
+            self.observer.on_resume(session_id, decision);
+            self.observer.on_state(Some(session_id), TransferState::Transferring);
+        }
+        
+        let mut progressed = false;
+        
+        if upload.machine.wants_chunk() && upload.stream.is_some() {
+            match upload.stream.as_mut().map(StreamChunker::try_next).transpose()?.flatten() {
+                Some(StreamItem::Chunk(chunk)) => upload.machine.push_chunk(chunk)?,
+                Some(StreamItem::End(trailer)) => {
+                    info!("Client: input ended after {} chunks ({} bytes)", trailer.total_chunks, trailer.file_size);
+                    upload.total_chunks = trailer.total_chunks;
+                    upload.machine.finish_stream(trailer)?;
+                    upload.stream = None;
+                    progressed = true;
                 }
+                None => {}
             }
-            
-            // Receive ACKs
-            for _ in 0..5 {
-                if let Ok((len, from)) = socket.recv_from(buf) {
-                    let recv_info = quiche::RecvInfo { from, to: local_addr };
-                    let _ = connection.recv(&mut buf[..len], recv_info);
-                    stall_iterations = 0;
-                }
+        } else if upload.machine.wants_chunk() {
+            if upload.chunk_iter.is_none() {
+                upload.chunk_iter = Some(self.start_chunks(upload)?);
             }
-            
-            // If stalled, yield briefly
-            if written == prev_written {
-                stall_iterations += 1;
-                if stall_iterations > 100 {
-                    std::thread::sleep(Duration::from_micros(50));
-                    stall_iterations = 0;
-                } else if stall_iterations > 20 {
-                    std::thread::yield_now();
+            match upload.chunk_iter.as_mut().and_then(Iterator::next) {
+                Some(chunk) => upload.machine.push_chunk(chunk?)?,
+                None => {
+                    upload.machine.finish()?;
+                    progressed = true;
                 }
             }
         }
         
-        Ok(())
-    }
-    
-    /// Helper: Send multiple chunks in a pipeline for better throughput
-    #[allow(dead_code)]
-    fn send_pipelined_chunks(
-        &self,
-        connection: &mut ClientConnection,
-        socket: &UdpSocket,
-        buf: &mut [u8],
-        out: &mut [u8],
-        local_addr: std::net::SocketAddr,
-        stream_id: u64,
-        chunks: &mut Vec<Vec<u8>>,
-        fin: bool,
-    ) -> Result<()> {
-        if chunks.is_empty() {
-            return Ok(());
+        // Send on the data stream with room for the chunk
+        let outcome = upload.machine.poll_chunk(now, |stream_id| connection.stream_capacity(stream_id).ok())?;
+        if let Some(outcome) = outcome {
+            self.record_chunk(upload, outcome);
+            progressed = true;
         }
         
-        socket.set_nonblocking(true).ok();
-        
-        let mut chunk_index = 0;
-        let mut chunk_offset = 0;
-        let start_time = std::time::Instant::now();
-        let max_duration = Duration::from_secs(300);
+        if connection.flush_outbox(upload.machine.outbox())? > 0 {
+            progressed = true;
+        }
         
-        let mut no_progress_count = 0;
+        Ok(progressed)
+    }
+    
+    /// Helper: Log a chunk the machine has sent or skipped and save resume state
+    fn record_chunk(&mut self, upload: &OutgoingUpload, outcome: ChunkOutcome) {
+        let stats = upload.machine.stats();
+        let chunk_count = stats.chunks_handled;
+        let total_chunks = upload.total_chunks;
+        let session_id = &upload.machine.manifest().session_id;
+        
+        match outcome {
+            ChunkOutcome::Sent { chunk_id, bytes } => self.observer.on_chunk_sent(session_id, chunk_id, bytes),
+            ChunkOutcome::Deduplicated { chunk_id } => self.observer.on_dedup_hit(session_id, chunk_id),
+            ChunkOutcome::Resumed { .. } => {}
+        }
         
-        while chunk_index < chunks.len() {
-            let prev_chunk_index = chunk_index;
-            let prev_offset = chunk_offset;
-            
-            // Try to write current chunk
-            let current_chunk = &chunks[chunk_index];
-            let is_final = fin && chunk_index == chunks.len() - 1 && chunk_offset + 1 >= current_chunk.len();
-            
-            match connection.stream_send(stream_id, &current_chunk[chunk_offset..], is_final) {
-                Ok(w) if w > 0 => {
-                    chunk_offset += w;
-                    no_progress_count = 0;
-                    
-                    // Move to next chunk if current is complete
-                    if chunk_offset >= current_chunk.len() {
-                        chunk_index += 1;
-                        chunk_offset = 0;
-                    }
-                }
-                Ok(_) => {}
-                Err(ref e) if format!("{:?}", e).contains("Done") => {}
-                Err(e) => return Err(e),
+        match outcome {
+            ChunkOutcome::Resumed { .. } if chunk_count.is_multiple_of(10) => {
+                info!("Client: skipped chunk {}/{} (resume)", chunk_count, total_chunks);
             }
-            
-            // Flush packets
-            while let Ok((len, send_info)) = connection.send(out) {
-                match socket.send_to(&out[..len], send_info.to) {
-                    Ok(_) => { no_progress_count = 0; },
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                    Err(e) => return Err(Error::from(e)),
-                }
+            ChunkOutcome::Deduplicated { .. } if chunk_count.is_multiple_of(10) => {
+                info!("Client: skipped chunk {}/{} (dedup)", chunk_count, total_chunks);
             }
-            
-            // Receive ACKs
-            for _ in 0..5 {
-                match socket.recv_from(buf) {
-                    Ok((len, from)) => {
-                        let recv_info = quiche::RecvInfo { from, to: local_addr };
-                        let _ = connection.recv(&mut buf[..len], recv_info);
-                        no_progress_count = 0;
-                    }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                    Err(_) => break,
-                }
+            ChunkOutcome::Sent { .. } if upload.stream.is_some() && chunk_count.is_multiple_of(50) => {
+                info!("Client: sent chunk {} of stream ({} bytes)", chunk_count, stats.bytes_sent);
             }
-            
-            // Backoff if stalled
-            if chunk_index == prev_chunk_index && chunk_offset == prev_offset {
-                no_progress_count += 1;
-                if no_progress_count > 50 {
-                    std::thread::sleep(Duration::from_micros(100));
-                    no_progress_count = 0;
-                } else if no_progress_count > 10 {
-                    std::thread::yield_now();
+            ChunkOutcome::Sent { .. } if upload.stream.is_some() => {}
+            ChunkOutcome::Sent { .. } => {
+                let is_eof_chunk = chunk_count == total_chunks;
+                
+                // Periodically save bitmap for resume (every 100 chunks)
+                if chunk_count.is_multiple_of(100) || is_eof_chunk {
+                    self.save_upload_bitmap(upload);
+                }
+                
+                if chunk_count.is_multiple_of(50) || is_eof_chunk {
+                    let elapsed = upload.start_time.elapsed().as_secs_f64();
+                    let speed_mbps = if elapsed > 0.0 {
+                        (stats.bytes_sent as f64 / elapsed) / (1024.0 * 1024.0)
+                    } else {
+                        0.0
+                    };
+                    info!("Client: sent chunk {}/{} ({:.1}%) - {:.2} MB/s", 
+                        chunk_count, total_chunks, 
+                        (chunk_count as f64 / total_chunks as f64) * 100.0,
+                        speed_mbps);
                 }
             }
-            
-            if start_time.elapsed() > max_duration {
-                return Err(Error::Protocol("Pipelined send timeout".to_string()));
-            }
+            _ => {}
         }
-        
-        chunks.clear();
-        Ok(())
     }
     
-    /// Helper: Send data with flow control handling
-    #[allow(dead_code)]
-    fn send_data_with_flow_control(
-        &self,
-        connection: &mut ClientConnection,
-        socket: &UdpSocket,
-        buf: &mut [u8],
-        out: &mut [u8],
-        local_addr: std::net::SocketAddr,
-        stream_id: u64,
-        data: &[u8],
-        fin: bool,
-    ) -> Result<()> {
-        let mut written = 0;
-        let start_time = std::time::Instant::now();
-        let max_duration = Duration::from_secs(300);
+    /// Helper: Save the chunks an upload has sent so far for resume
+    /// 
+    /// Stripes and streams rely on the server's partial file alone.
+    fn save_upload_bitmap(&mut self, upload: &OutgoingUpload) {
+        let manifest = upload.machine.manifest();
+        if manifest.stripe.is_some() || manifest.streaming {
+            return;
+        }
+        let session_id = manifest.session_id.clone();
+        if let Err(e) = self.save_resume_bitmap(&session_id, upload.machine.sent_bitmap()) {
+            warn!("Client: failed to save resume bitmap: {}", e);
+        }
+    }
+    
+    /// Helper: Close out an upload whose streams have all been finished
+    /// 
+    /// # Returns
+    /// Manifest and chunk bytes sent for the upload
+    pub(super) fn finish_upload(&mut self, upload: OutgoingUpload) -> u64 {
+        let stats = upload.machine.stats();
+        let manifest = upload.machine.manifest();
+        
+        if let Some((outcome, stored_name)) = upload.machine.conflict() {
+            info!("Client: {} already existed on the server ({:?}), stored as {}",
+                manifest.file_name, outcome, stored_name);
+            self.observer.on_conflict(&manifest.session_id, outcome, stored_name);
+        }
+        
+        if let Some(filtered) = upload.machine.filtered_chunks() {
+            info!("Client: {} chunks skipped via dedup ({} ruled out locally by the server's filter)",
+                stats.chunks_deduped, filtered);
+        }
         
-        socket.set_nonblocking(true).ok();
+        let elapsed = upload.start_time.elapsed();
+        let total_elapsed = elapsed.as_secs_f64();
+        let avg_speed_mbps = if total_elapsed > 0.0 {
+            (stats.bytes_sent as f64 / total_elapsed) / (1024.0 * 1024.0)
+        } else {
+            0.0
+        };
         
-        let mut no_progress_count = 0;
+        info!("Client: transfer {} ({}) sent, average upload speed: {:.2} MB/s",
+            upload.machine.streams().transfer_id, manifest.file_name, avg_speed_mbps);
         
-        while written < data.len() {
-            let prev_written = written;
-            
-            // Try to write to stream
-            match connection.stream_send(stream_id, &data[written..], fin && written >= data.len() - 1) {
-                Ok(w) if w > 0 => {
-                    written += w;
-                    no_progress_count = 0;
-                }
-                Ok(_) => {}
-                Err(ref e) if format!("{:?}", e).contains("Done") => {}
-                Err(e) => return Err(e),
-            }
-            
-            // Flush all packets
-            while let Ok((len, send_info)) = connection.send(out) {
-                match socket.send_to(&out[..len], send_info.to) {
-                    Ok(_) => { no_progress_count = 0; },
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                    Err(e) => return Err(Error::from(e)),
-                }
-            }
-            
-            // Receive ACKs
-            for _ in 0..5 {
-                match socket.recv_from(buf) {
-                    Ok((len, from)) => {
-                        let recv_info = quiche::RecvInfo { from, to: local_addr };
-                        let _ = connection.recv(&mut buf[..len], recv_info);
-                        no_progress_count = 0;
-                    }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                    Err(_) => break,
-                }
-            }
-            
-            // If no progress, yield briefly
-            if written == prev_written {
-                no_progress_count += 1;
-                if no_progress_count > 50 {
-                    std::thread::sleep(Duration::from_micros(100));
-                    no_progress_count = 0;
-                } else if no_progress_count > 10 {
-                    std::thread::yield_now();
-                }
-            }
-            
-            if start_time.elapsed() > max_duration {
-                return Err(Error::Protocol(format!(
-                    "Flow control timeout: sent {}/{} bytes ({:.1}%) after {:.1}s",
-                    written, data.len(), (written as f64 / data.len() as f64) * 100.0,
-                    start_time.elapsed().as_secs_f64()
-                )));
+        if stats.chunks_skipped > 0 {
+            info!("Client: deduplication saved {} chunks ({:.1}%)", 
+                stats.chunks_skipped, 
+                (stats.chunks_skipped as f64 / upload.total_chunks as f64) * 100.0);
+        }
+        
+        // Remove bitmap from memory after successful transfer
+        if self.resume_bitmaps.remove(&manifest.session_id).is_some() {
+            debug!("Client: removed resume bitmap from memory after successful transfer");
+        }
+        
+        if let Some(bundle_path) = &upload.bundle_path {
+            if let Err(e) = std::fs::remove_file(bundle_path) {
+                warn!("Client: failed to remove bundle {:?}: {}", bundle_path, e);
             }
         }
         
-        Ok(())
+        self.observer.on_state(Some(&manifest.session_id), TransferState::Completed);
+        self.observer.on_complete(&TransferSummary {
+            session_id: manifest.session_id.clone(),
+            file_name: manifest.file_name.clone(),
+            path: upload.file_path.clone(),
+            file_size: manifest.file_size,
+            bytes: stats.bytes_sent,
+            chunks_transferred: stats.chunks_handled - stats.chunks_skipped,
+            chunks_skipped: stats.chunks_skipped,
+            chunks_deduped: stats.chunks_deduped,
+            elapsed,
+        });
+        
+        stats.manifest_bytes + stats.bytes_sent
     }
     
     pub fn session(&self) -> Option<&ClientSession> {
@@ -1637,4 +2029,123 @@ impl Transfer {
     pub fn state(&self) -> TransferState {
         self.state
     }
-}
\ No newline at end of file
+    
+    /// Move the connection to `state` and tell the observer
+    fn set_state(&mut self, state: TransferState) {
+        self.state = state;
+        self.observer.on_state(None, state);
+    }
+    
+    /// Tell the observer if a run failed, then hand back its result
+    fn report<T>(&mut self, result: Result<T>) -> Result<T> {
+        match &result {
+            Ok(_) => {}
+            Err(Error::Cancelled) => {
+                info!("Client: transfer cancelled");
+                self.set_state(TransferState::Cancelled);
+            }
+            Err(e) => {
+                self.observer.on_error(None, e);
+                self.set_state(TransferState::Failed);
+            }
+        }
+        result
+    }
+    
+    /// Fail with `Error::Cancelled` once the cancel handle has been triggered
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+/// Close a connection once its transfer has ended
+/// 
+/// A finished transfer closes with "done" and a cancelled one with
+/// `CANCELLED_ERROR_CODE`; after other failures the connection is left as
+/// it is.
+fn close_connection<T>(
+    socket: &EventSocket,
+    connection: &mut ClientConnection,
+    out: &mut [u8],
+    result: &Result<T>,
+) -> Result<()> {
+    let closed = match result {
+        Ok(_) => connection.close(true, 0x00, b"done"),
+        Err(Error::Cancelled) => connection.close(true, CANCELLED_ERROR_CODE, b"cancelled"),
+        Err(_) => return Ok(()),
+    };
+    if closed.is_ok() {
+        flush_packets(socket, connection, out)?;
+    }
+    Ok(())
+}
+
+/// Send every packet the connection has ready
+/// 
+/// # Returns
+/// Number of datagrams sent
+fn flush_packets(socket: &EventSocket, connection: &mut ClientConnection, out: &mut [u8]) -> Result<usize> {
+    let mut sent = 0;
+    while let Ok((len, send_info)) = connection.send(out) {
+        socket.send_to(&out[..len], send_info.to)?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// Feed the connection every datagram waiting on the socket, without blocking
+/// 
+/// # Returns
+/// Whether any datagram was received
+fn recv_packets(
+    socket: &EventSocket,
+    connection: &mut ClientConnection,
+    buf: &mut [u8],
+    local_addr: SocketAddr,
+) -> Result<bool> {
+    let mut received = false;
+    loop {
+        match socket.recv_from(buf) {
+            Ok((len, from)) => {
+                let recv_info = quiche::RecvInfo { from, to: local_addr };
+                if let Err(e) = connection.recv(&mut buf[..len], recv_info) {
+                    debug!("Client: conn.recv error: {:?}", e);
+                }
+                received = true;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(received),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::from(e)),
+        }
+    }
+}
+
+/// Flush outgoing packets, then take in incoming ones
+/// 
+/// If nothing has arrived, blocks until a datagram does, the connection's
+/// next timer fires or `limit` passes; expired timers are handled.
+/// 
+/// # Returns
+/// Whether any datagram was received
+fn pump_network(
+    socket: &EventSocket,
+    connection: &mut ClientConnection,
+    buf: &mut [u8],
+    out: &mut [u8],
+    local_addr: SocketAddr,
+    limit: Duration,
+) -> Result<bool> {
+    flush_packets(socket, connection, out)?;
+    if recv_packets(socket, connection, buf, local_addr)? {
+        return Ok(true);
+    }
+    
+    socket.wait(Some(wait_time(connection.timeout(), limit)))?;
+    if connection.timeout().is_some_and(|timer| timer.is_zero()) {
+        connection.on_timeout();
+    }
+    recv_packets(socket, connection, buf, local_addr)
+}
@@ -106,16 +106,8 @@ pub fn generate_self_signed_cert(server_ip: &str, output_dir: Option<&str>) -> R
         ))
     })?;
 
-    println!("\n✅ Certificates generated successfully:");
-    println!("   {:?} - Certificate (includes localhost + {})", cert_path, server_ip);
-    println!("   {:?} - Private key", key_path);
-    println!("\nCertificate SANs:");
-    println!("   - DNS: localhost");
-    println!("   - DNS: *.local");
-    println!("   - IP: 127.0.0.1");
-    if server_ip != "127.0.0.1" && server_ip != "localhost" {
-        println!("   - IP/DNS: {}", server_ip);
-    }
+    log::info!("Certificates generated: {:?}, {:?} (SANs: localhost, *.local, 127.0.0.1, {})",
+        cert_path, key_path, server_ip);
 
     Ok(())
 }
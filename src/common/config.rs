@@ -5,6 +5,9 @@ use std::path::PathBuf;
 use std::time::Duration;
 use crate::common::error::{Error, Result};
 use crate::chunking::compress::CompressionType;
+use crate::chunking::encrypt::EncryptionConfig;
+use crate::protocol::messages::ConflictPolicy;
+use crate::protocol::metadata::MetadataPolicy;
 
 #[derive(Debug, Clone)]
 pub struct ClientConfig {
@@ -17,6 +20,16 @@ pub struct ClientConfig {
     pub verify_cert: bool,
     pub ca_cert_path: Option<PathBuf>,
     pub compression: CompressionType,
+    pub encryption: Option<EncryptionConfig>,
+    pub signing_key: Option<PathBuf>,
+    pub tree_manifest: bool,
+    pub dedup: bool,
+    pub dedup_filter: bool,
+    pub pack_threshold: Option<u64>,
+    pub stripes: usize,
+    pub data_streams: usize,
+    pub metadata: MetadataPolicy,
+    pub conflict_policy: ConflictPolicy,
 }
 
 impl Default for ClientConfig {
@@ -31,6 +44,16 @@ impl Default for ClientConfig {
             verify_cert: false, // Default to no verification for easier testing
             ca_cert_path: Some(PathBuf::from("certs/cert.pem")), // Default cert path
             compression: CompressionType::None,  // Default: no compression
+            encryption: None,  // Default: server stores plaintext
+            signing_key: None,  // Default: unsigned manifests
+            tree_manifest: false,  // Default: flat list of chunk hashes
+            dedup: false,  // Default: send every chunk
+            dedup_filter: false,  // Default: ask about every chunk when deduplicating
+            pack_threshold: None,  // Default: every file is its own transfer
+            stripes: 1,  // Default: one connection per upload
+            data_streams: 1,  // Default: chunks share the transfer's data stream
+            metadata: MetadataPolicy::default(),  // Default: keep modes and times
+            conflict_policy: ConflictPolicy::Default,  // Default: the server decides about taken names
         }
     }
 }
@@ -91,6 +114,82 @@ impl ClientConfig {
         self.compression = compression;
         self
     }
+    
+    /// Encrypt chunks client-side before upload and decrypt on download
+    ///
+    /// With a passphrase every file is sealed under its own key, so dedup
+    /// only finds chunks of the same file; with a key file it works across
+    /// files as for plaintext.
+    pub fn with_encryption(mut self, encryption: EncryptionConfig) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+    
+    /// Sign manifests with an Ed25519 PKCS#8 key file
+    pub fn with_signing_key(mut self, key_path: PathBuf) -> Self {
+        self.signing_key = Some(key_path);
+        self
+    }
+    
+    /// Send only the root of the chunk hash tree in the manifest, with each
+    /// chunk carrying its own proof (for very large files)
+    pub fn with_tree_manifest(mut self) -> Self {
+        self.tree_manifest = true;
+        self
+    }
+    
+    /// Skip chunks the server already stores, checked in pipelined batches
+    pub fn with_dedup(mut self) -> Self {
+        self.dedup = true;
+        self
+    }
+    
+    /// Deduplicate, first fetching the server's Bloom filter so chunks it
+    /// certainly lacks are never asked about
+    pub fn with_dedup_filter(mut self) -> Self {
+        self.dedup = true;
+        self.dedup_filter = true;
+        self
+    }
+    
+    /// Pack files of at most `threshold` bytes into shared bundle transfers
+    /// that the server unpacks (not combined with encryption)
+    pub fn with_packing(mut self, threshold: u64) -> Self {
+        self.pack_threshold = Some(threshold);
+        self
+    }
+    
+    /// Stripe a single-file upload across `connections` QUIC connections,
+    /// each with its own socket and thread
+    pub fn with_stripes(mut self, connections: usize) -> Self {
+        self.stripes = connections.max(1);
+        self
+    }
+    
+    /// Spread each transfer's chunks over `streams` data streams, so a lost
+    /// packet only delays the chunks on one of them
+    pub fn with_data_streams(mut self, streams: usize) -> Self {
+        self.data_streams = streams.max(1);
+        self
+    }
+    
+    /// Keep file owners as well, applied where the receiver is privileged
+    pub fn with_ownership(mut self) -> Self {
+        self.metadata.ownership = true;
+        self
+    }
+    
+    /// Keep extended attributes as well
+    pub fn with_xattrs(mut self) -> Self {
+        self.metadata.xattrs = true;
+        self
+    }
+    
+    /// Ask the server to handle files already stored under an upload's name by `policy`
+    pub fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
 }
 
 #[derive(Debug, Clone)]
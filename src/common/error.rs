@@ -16,16 +16,19 @@ pub enum Error {
     InvalidOffset,
     TransferTimeout,
     ConnectionClosed,
+    Cancelled,
     StreamError(u64),
     SerializationError(String),
     DeserializationError(String),
     FileNotFound(String),
+    FileExists(String),
     PermissionDenied(String),
     DiskFull,
     ConfigError(String),
     TlsError(String),
     Compression(String),
     Decompression(String),
+    Signature(String),
 }
 
 impl fmt::Display for Error {
@@ -44,16 +47,19 @@ impl fmt::Display for Error {
             Error::InvalidOffset => write!(f, "Invalid file offset"),
             Error::TransferTimeout => write!(f, "Transfer timeout"),
             Error::ConnectionClosed => write!(f, "Connection closed"),
+            Error::Cancelled => write!(f, "Transfer cancelled"),
             Error::StreamError(id) => write!(f, "Stream {} error", id),
             Error::SerializationError(e) => write!(f, "Serialization error: {}", e),
             Error::DeserializationError(e) => write!(f, "Deserialization error: {}", e),
             Error::FileNotFound(path) => write!(f, "File not found: {}", path),
+            Error::FileExists(path) => write!(f, "File already exists: {}", path),
             Error::PermissionDenied(path) => write!(f, "Permission denied: {}", path),
             Error::DiskFull => write!(f, "Disk full"),
             Error::ConfigError(e) => write!(f, "Configuration error: {}", e),
             Error::TlsError(e) => write!(f, "TLS error: {}", e),
             Error::Compression(e) => write!(f, "Compression error: {}", e),
             Error::Decompression(e) => write!(f, "Decompression error: {}", e),
+            Error::Signature(e) => write!(f, "Signature error: {}", e),
         }
     }
 }
@@ -0,0 +1,218 @@
+// Sans-IO transfer engine
+//
+// The upload protocol (manifest, resume, pipelined hash checks, chunk data,
+// completion) runs as state machines that never touch a socket or a QUIC
+// connection. They are handed the bytes read from each stream and queue the
+// bytes they want written in an `Outbox`; a driver moves both between the
+// machines and quiche, and decides when to wait for the network. Time is
+// passed in, so a machine's deadlines fire when its driver says they do.
+//
+// The client side of an upload is `UploadMachine`; the server side is the
+// server's `IncomingUpload`, which buffers what it is handed in an `Inbox`.
+// Tests drive the two against each other in memory.
+
+pub mod upload;
+
+pub use upload::{ChunkOutcome, UploadMachine, UploadOptions, UploadStats};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Bytes a state machine wants written on a stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamWrite {
+    pub stream_id: u64,
+    pub data: Vec<u8>,
+    pub fin: bool,
+}
+
+/// Stream writes waiting to be handed to QUIC, in the order they were made
+#[derive(Debug, Default)]
+pub struct Outbox {
+    writes: VecDeque<StreamWrite>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `data` for `stream_id`, finishing the stream if `fin`
+    ///
+    /// # Returns
+    /// The number of bytes queued, all of them, so this can stand in for a
+    /// stream send in the protocol senders
+    pub fn write(&mut self, stream_id: u64, data: &[u8], fin: bool) -> usize {
+        match self.writes.back_mut() {
+            Some(last) if last.stream_id == stream_id && !last.fin => {
+                last.data.extend_from_slice(data);
+                last.fin = fin;
+            }
+            _ => self.writes.push_back(StreamWrite { stream_id, data: data.to_vec(), fin }),
+        }
+        data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Bytes queued across all streams
+    pub fn queued_bytes(&self) -> usize {
+        self.writes.iter().map(|write| write.data.len()).sum()
+    }
+
+    /// Take every queued write, for drivers that deliver them whole
+    pub fn take(&mut self) -> Vec<StreamWrite> {
+        self.writes.drain(..).collect()
+    }
+
+    /// Hand queued writes to `send`, which returns how many bytes the stream took
+    ///
+    /// A stream that takes only part of a write, or reports `Done`, keeps the
+    /// rest queued and is passed over until the next flush; writes on other
+    /// streams still go out.
+    ///
+    /// # Returns
+    /// The number of bytes sent
+    pub fn flush<F>(&mut self, mut send: F) -> Result<usize, quiche::Error>
+    where
+        F: FnMut(u64, &[u8], bool) -> Result<usize, quiche::Error>,
+    {
+        let mut blocked = HashSet::new();
+        let mut sent = 0;
+        let mut index = 0;
+
+        while index < self.writes.len() {
+            let write = &mut self.writes[index];
+            if blocked.contains(&write.stream_id) {
+                index += 1;
+                continue;
+            }
+
+            match send(write.stream_id, &write.data, write.fin) {
+                Ok(written) if written >= write.data.len() => {
+                    sent += write.data.len();
+                    self.writes.remove(index);
+                }
+                Ok(written) => {
+                    sent += written;
+                    write.data.drain(..written);
+                    blocked.insert(write.stream_id);
+                    index += 1;
+                }
+                Err(quiche::Error::Done) => {
+                    blocked.insert(write.stream_id);
+                    index += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(sent)
+    }
+}
+
+/// Bytes read from a stream that a state machine has not consumed yet
+#[derive(Debug, Default)]
+struct Inbound {
+    data: Vec<u8>,
+    fin: bool,
+}
+
+/// Stream data handed to a state machine, held until it is ready for it
+#[derive(Debug, Default)]
+pub struct Inbox {
+    streams: HashMap<u64, Inbound>,
+}
+
+impl Inbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add bytes read from `stream_id`; `fin` marks the end of the stream
+    pub fn push(&mut self, stream_id: u64, data: &[u8], fin: bool) {
+        let inbound = self.streams.entry(stream_id).or_default();
+        inbound.data.extend_from_slice(data);
+        inbound.fin |= fin;
+    }
+
+    /// Whether anything is waiting on `stream_id`
+    pub fn has_data(&self, stream_id: u64) -> bool {
+        self.streams.contains_key(&stream_id)
+    }
+
+    /// Take what is waiting on `stream_id`, with whether the stream has ended
+    pub fn take(&mut self, stream_id: u64) -> Option<(Vec<u8>, bool)> {
+        self.streams.remove(&stream_id).map(|inbound| (inbound.data, inbound.fin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbox_coalesces_writes() {
+        let mut outbox = Outbox::new();
+        assert_eq!(outbox.write(8, b"abc", false), 3);
+        outbox.write(8, b"def", true);
+        outbox.write(4, b"xy", false);
+        outbox.write(8, b"z", false);
+
+        assert_eq!(outbox.queued_bytes(), 9);
+        assert_eq!(outbox.take(), vec![
+            StreamWrite { stream_id: 8, data: b"abcdef".to_vec(), fin: true },
+            StreamWrite { stream_id: 4, data: b"xy".to_vec(), fin: false },
+            StreamWrite { stream_id: 8, data: b"z".to_vec(), fin: false },
+        ]);
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn test_outbox_flush_passes_over_blocked_streams() {
+        let mut outbox = Outbox::new();
+        outbox.write(8, b"0123456789", false);
+        outbox.write(4, b"manifest", true);
+        outbox.write(8, b"more", true);
+
+        // Stream 8 takes four bytes, then has no room
+        let mut room = 4;
+        let mut delivered = Vec::new();
+        let sent = outbox.flush(|stream_id, data, fin| {
+            let take = if stream_id == 8 { data.len().min(room) } else { data.len() };
+            if take == 0 && !data.is_empty() {
+                return Err(quiche::Error::Done);
+            }
+            if stream_id == 8 {
+                room -= take;
+            }
+            delivered.push((stream_id, data[..take].to_vec(), fin && take == data.len()));
+            Ok(take)
+        }).unwrap();
+
+        assert_eq!(sent, 12);
+        assert_eq!(delivered, vec![(8, b"0123".to_vec(), false), (4, b"manifest".to_vec(), true)]);
+        assert_eq!(outbox.queued_bytes(), 10);
+
+        // With room again the rest goes out in order
+        let mut rest = Vec::new();
+        outbox.flush(|_, data, _| {
+            rest.extend_from_slice(data);
+            Ok(data.len())
+        }).unwrap();
+        assert_eq!(rest, b"456789more");
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn test_inbox_take() {
+        let mut inbox = Inbox::new();
+        assert!(!inbox.has_data(4));
+        inbox.push(4, b"head", false);
+        inbox.push(4, b"er", true);
+        assert!(inbox.has_data(4));
+        assert_eq!(inbox.take(4), Some((b"header".to_vec(), true)));
+        assert_eq!(inbox.take(4), None);
+    }
+}
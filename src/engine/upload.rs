@@ -0,0 +1,704 @@
+// Client side of one upload, as a sans-IO state machine
+//
+// Creating the machine queues the paged manifest and the resume request.
+// The server's resume response (or its deadline passing) starts the data
+// phase, which takes chunks one at a time from the driver: chunks the server
+// already holds are skipped, others wait for the pipelined hash check to
+// rule on them when dedup is on, and the rest are framed onto a data stream.
+// Once the driver runs out of chunks, `finish` ends every data stream.
+// A server that already stores an identical file under the name answers the
+// resume request by skipping the upload, which then finishes without a data
+// phase; one that refuses to replace it fails the upload.
+//
+// A streaming manifest's header goes out alone; the chunk hashes follow in
+// pages as chunks are handed in, and `finish_stream` closes the manifest
+// stream with a page carrying the totals.
+
+use super::Outbox;
+use crate::chunking::{ChunkBitmap, ProcessedChunk};
+use crate::common::error::{Error, Result};
+use crate::protocol::hash_check::{DedupDecision, HashCheckPipeline};
+use crate::protocol::messages::{ConflictOutcome, Manifest, ManifestPage, ManifestTrailer, ResumeResponse};
+use crate::protocol::resume::{ResumeRequestSender, ResumeResponseReceiver};
+use crate::transport::manifest_pages::PagedManifestSender;
+use crate::transport::{DataStreamScheduler, TransferStreams};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the server's resume response
+pub const RESUME_RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to wait on an unanswered hash check before sending without dedup
+pub const DEDUP_ANSWER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Chunk hashes per manifest page of a streaming upload
+const STREAM_PAGE_HASHES: usize = 256;
+
+/// Protocol options of an upload
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadOptions {
+    /// Ask the server which chunks its chunk index already holds
+    pub dedup: bool,
+    /// Fetch a Bloom filter of the chunk index before asking
+    pub dedup_filter: bool,
+}
+
+/// What became of a chunk handed to the machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkOutcome {
+    /// Queued on a data stream, with the size of its packet
+    Sent { chunk_id: u64, bytes: u64 },
+    /// Already held by the server from an earlier session
+    Resumed { chunk_id: u64 },
+    /// Found in the server's chunk index
+    Deduplicated { chunk_id: u64 },
+}
+
+impl ChunkOutcome {
+    pub fn chunk_id(&self) -> u64 {
+        match *self {
+            Self::Sent { chunk_id, .. } | Self::Resumed { chunk_id } | Self::Deduplicated { chunk_id } => chunk_id,
+        }
+    }
+}
+
+/// Counters of an upload's chunks
+#[derive(Debug, Clone, Default)]
+pub struct UploadStats {
+    /// Chunks sent or skipped
+    pub chunks_handled: u64,
+    /// Chunks skipped for either reason
+    pub chunks_skipped: u64,
+    /// Chunks skipped because the server's chunk index held them
+    pub chunks_deduped: u64,
+    /// Chunk packet bytes sent
+    pub bytes_sent: u64,
+    /// Encoded manifest bytes sent
+    pub manifest_bytes: u64,
+}
+
+/// How far the upload has got
+#[derive(Debug)]
+enum Phase {
+    /// Manifest and resume request queued, waiting for the resume response
+    Resume { deadline: Instant },
+    /// Taking chunks
+    Data,
+    /// Every data stream has been finished
+    Finished,
+}
+
+/// Client side of one upload on a transfer's streams
+pub struct UploadMachine {
+    streams: TransferStreams,
+    manifest: Manifest,
+    options: UploadOptions,
+    phase: Phase,
+    outbox: Outbox,
+    resume_receiver: ResumeResponseReceiver,
+    /// Chunks this upload sends; a stripe's share of the file
+    chunk_range: Range<u64>,
+    skip_chunks: HashSet<u64>,
+    dedup: Option<HashCheckPipeline>,
+    /// When the chunk waiting on a hash check answer gives up on dedup
+    dedup_deadline: Option<Instant>,
+    scheduler: DataStreamScheduler,
+    /// Chunk handed in but not yet sent or skipped
+    pending: Option<ProcessedChunk>,
+    sent_bitmap: ChunkBitmap,
+    /// Data streams already finished
+    fin_sent: HashSet<u64>,
+    /// Hashes not yet paged out, for a streaming manifest
+    stream_page: Option<ManifestPage>,
+    /// What the server does about a file already stored under the name,
+    /// and the name the upload is stored under
+    conflict: Option<(ConflictOutcome, String)>,
+    stats: UploadStats,
+}
+
+impl UploadMachine {
+    /// Start an upload of the file `manifest` describes on `streams`
+    ///
+    /// `resume_bitmap` holds the chunks an earlier session sent; the server
+    /// is asked to resume either way, since it checks its partial file itself.
+    pub fn new(
+        streams: TransferStreams,
+        mut manifest: Manifest,
+        resume_bitmap: Option<&ChunkBitmap>,
+        options: UploadOptions,
+        now: Instant,
+    ) -> Result<Self> {
+        manifest.transfer_id = streams.transfer_id;
+        let mut outbox = Outbox::new();
+
+        // Manifest header and chunk hash pages on the manifest stream
+        log::info!("Client: sending manifest ({} chunks, {} bytes total)",
+            manifest.total_chunks, manifest.file_size);
+        let encoded = PagedManifestSender::new().encode(&manifest)?;
+        outbox.write(streams.manifest, &encoded, !manifest.streaming);
+        log::info!("Client: manifest encoded ({} bytes)", encoded.len());
+
+        // The bitmap goes in its compact encoding rather than as a chunk list
+        let received_count = resume_bitmap.map_or(0, |b| b.received_count());
+        let last_chunk = resume_bitmap.and_then(|b| {
+            (0..b.total_chunks().unwrap_or(0)).rev().find(|&i| b.is_received(i)).map(u64::from)
+        });
+        log::info!("Client: sending resume request for {} received chunks", received_count);
+        ResumeRequestSender::for_transfer(streams.transfer_id).send_request(
+            manifest.session_id.clone(),
+            Vec::new(),
+            resume_bitmap.map(|b| b.to_bytes()),
+            last_chunk,
+            |data, fin| Ok(outbox.write(streams.resume, data, fin)),
+        )?;
+
+        // A stripe only sends its share of the chunks, and a stream's length
+        // is not known until it ends
+        let chunk_range = match &manifest.stripe {
+            Some(stripe) => stripe.chunk_range(manifest.total_chunks),
+            None if manifest.streaming => 0..u64::MAX,
+            None => 0..manifest.total_chunks,
+        };
+        let sent_bitmap = if manifest.streaming {
+            ChunkBitmap::new(0)
+        } else {
+            ChunkBitmap::with_exact_size(manifest.total_chunks as u32)
+        };
+        let stream_page = manifest.streaming.then(ManifestPage::default);
+        let data_streams = std::iter::once(streams.data)
+            .chain(manifest.data_streams.iter().copied())
+            .collect();
+
+        Ok(Self {
+            sent_bitmap,
+            scheduler: DataStreamScheduler::new(data_streams),
+            streams,
+            manifest,
+            options,
+            phase: Phase::Resume { deadline: now + RESUME_RESPONSE_TIMEOUT },
+            outbox,
+            resume_receiver: ResumeResponseReceiver::new(),
+            chunk_range,
+            skip_chunks: HashSet::new(),
+            dedup: None,
+            dedup_deadline: None,
+            pending: None,
+            fin_sent: HashSet::new(),
+            stream_page,
+            conflict: None,
+            stats: UploadStats {
+                manifest_bytes: encoded.len() as u64,
+                ..UploadStats::default()
+            },
+        })
+    }
+
+    pub fn streams(&self) -> &TransferStreams {
+        &self.streams
+    }
+
+    /// Manifest being sent, with its transfer ID set
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Chunks this upload sends
+    pub fn chunk_range(&self) -> Range<u64> {
+        self.chunk_range.clone()
+    }
+
+    /// Streams the server answers on, for the driver to read
+    pub fn reply_streams(&self) -> [u64; 2] {
+        [self.streams.resume, self.streams.hash_check]
+    }
+
+    /// Stream writes waiting for the driver
+    pub fn outbox(&mut self) -> &mut Outbox {
+        &mut self.outbox
+    }
+
+    /// What the server said it does about a file already stored under the
+    /// upload's name, and the name the upload is stored under
+    pub fn conflict(&self) -> Option<(ConflictOutcome, &str)> {
+        self.conflict.as_ref().map(|(outcome, name)| (*outcome, name.as_str()))
+    }
+
+    /// Chunks sent or held by the server, for saving resume state
+    pub fn sent_bitmap(&self) -> &ChunkBitmap {
+        &self.sent_bitmap
+    }
+
+    pub fn stats(&self) -> &UploadStats {
+        &self.stats
+    }
+
+    /// Chunks the server's dedup filter ruled out, when dedup is on
+    pub fn filtered_chunks(&self) -> Option<u64> {
+        self.dedup.as_ref().map(HashCheckPipeline::filtered_chunks)
+    }
+
+    /// Chunks of this upload's range the server already held when the data
+    /// phase started
+    pub fn resumed_chunks(&self) -> u64 {
+        self.skip_chunks.iter().filter(|chunk_id| self.chunk_range.contains(chunk_id)).count() as u64
+    }
+
+    /// Whether the resume exchange is over and chunks are being taken
+    pub fn is_sending(&self) -> bool {
+        matches!(self.phase, Phase::Data)
+    }
+
+    /// Whether the machine can take another chunk: it is sending, has no
+    /// chunk waiting, and everything queued so far has been handed to QUIC
+    pub fn wants_chunk(&self) -> bool {
+        self.is_sending() && self.pending.is_none() && self.outbox.is_empty()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.phase, Phase::Finished)
+    }
+
+    /// When `on_timeout` next has something to do
+    pub fn timeout(&self) -> Option<Instant> {
+        match self.phase {
+            Phase::Resume { deadline } => Some(deadline),
+            Phase::Data => self.dedup_deadline,
+            Phase::Finished => None,
+        }
+    }
+
+    /// Take in bytes read from one of the reply streams
+    pub fn on_stream_data(&mut self, stream_id: u64, data: &[u8], fin: bool) -> Result<()> {
+        if stream_id == self.streams.resume {
+            if !matches!(self.phase, Phase::Resume { .. }) {
+                return Ok(());
+            }
+            match self.resume_receiver.receive_chunk(data, fin)? {
+                Some(response) => {
+                    self.streams.check_transfer_id(response.transfer_id)?;
+                    if self.record_conflict(&response)? {
+                        return Ok(());
+                    }
+                    let skip_chunks = self.resume_skip_chunks(response)?;
+                    self.start_data(skip_chunks)?;
+                }
+                None if fin => {
+                    log::warn!("Client: resume stream ended without a response, starting fresh transfer");
+                    self.start_data(HashSet::new())?;
+                }
+                None => {}
+            }
+        } else if stream_id == self.streams.hash_check {
+            if let Some(pipeline) = self.dedup.as_mut() {
+                pipeline.receive(data, fin)?;
+                let (outbox, stream) = (&mut self.outbox, self.streams.hash_check);
+                pipeline.pump(|data, fin| Ok(outbox.write(stream, data, fin)))?;
+            }
+        } else {
+            log::debug!("Client: ignoring {} bytes on stream {}", data.len(), stream_id);
+        }
+        Ok(())
+    }
+
+    /// Act on deadlines that have passed by `now`
+    pub fn on_timeout(&mut self, now: Instant) -> Result<()> {
+        match self.phase {
+            Phase::Resume { deadline } if now >= deadline => {
+                log::warn!("Client: no resume response received after {:?}, starting fresh transfer",
+                    RESUME_RESPONSE_TIMEOUT);
+                log::warn!("Client: (server may not support resume or is not responding)");
+                self.start_data(HashSet::new())?;
+            }
+            Phase::Data if self.dedup_deadline.is_some_and(|deadline| now >= deadline) => {
+                if let (Some(pipeline), Some(chunk)) = (self.dedup.as_mut(), &self.pending) {
+                    log::warn!("Client: no hash check answer for chunk {} after {:?}, sending remaining chunks without dedup",
+                        chunk.chunk_id, DEDUP_ANSWER_TIMEOUT);
+                    pipeline.abandon();
+                }
+                self.dedup_deadline = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Hand in the next chunk of the upload's range
+    pub fn push_chunk(&mut self, chunk: ProcessedChunk) -> Result<()> {
+        if !self.is_sending() || self.pending.is_some() {
+            return Err(Error::Protocol(format!(
+                "Transfer {} cannot take chunk {} now", self.streams.transfer_id, chunk.chunk_id
+            )));
+        }
+        if let Some(page) = self.stream_page.as_mut() {
+            if chunk.chunk_id != page.first_chunk + page.chunk_hashes.len() as u64 {
+                return Err(Error::Protocol(format!(
+                    "Transfer {} streams chunks in order, got chunk {}", self.streams.transfer_id, chunk.chunk_id
+                )));
+            }
+            page.chunk_hashes.push(chunk.hash.clone());
+            if page.chunk_hashes.len() >= STREAM_PAGE_HASHES {
+                self.send_stream_page(None)?;
+            }
+        }
+        self.pending = Some(chunk);
+        Ok(())
+    }
+
+    /// Send or skip the chunk handed in, once it can be decided
+    ///
+    /// `capacity` reports how many bytes a data stream can take right now,
+    /// for picking the stream the chunk goes out on.
+    ///
+    /// # Returns
+    /// What became of the chunk, or `None` while it waits on a hash check
+    /// answer (or no chunk is waiting)
+    pub fn poll_chunk(
+        &mut self,
+        now: Instant,
+        capacity: impl FnMut(u64) -> Option<usize>,
+    ) -> Result<Option<ChunkOutcome>> {
+        let Some(chunk_id) = self.pending.as_ref().map(|chunk| chunk.chunk_id) else {
+            return Ok(None);
+        };
+
+        if self.skip_chunks.contains(&chunk_id) {
+            self.pending = None;
+            self.stats.chunks_handled += 1;
+            self.stats.chunks_skipped += 1;
+            return Ok(Some(ChunkOutcome::Resumed { chunk_id }));
+        }
+
+        if let Some(pipeline) = &self.dedup {
+            match pipeline.decision(chunk_id) {
+                DedupDecision::Pending => {
+                    self.dedup_deadline.get_or_insert(now + DEDUP_ANSWER_TIMEOUT);
+                    return Ok(None);
+                }
+                DedupDecision::Skip => {
+                    self.pending = None;
+                    self.dedup_deadline = None;
+                    self.stats.chunks_handled += 1;
+                    self.stats.chunks_skipped += 1;
+                    self.stats.chunks_deduped += 1;
+                    return Ok(Some(ChunkOutcome::Deduplicated { chunk_id }));
+                }
+                DedupDecision::Send => self.dedup_deadline = None,
+            }
+        }
+
+        let Some(chunk) = self.pending.take() else {
+            return Ok(None);
+        };
+
+        // Length prefix and packet, on the data stream with room for them
+        let mut framed = Vec::with_capacity(4 + chunk.packet.len());
+        framed.extend_from_slice(&(chunk.packet.len() as u32).to_be_bytes());
+        framed.extend_from_slice(&chunk.packet);
+        let stream_id = self.scheduler.next_stream(framed.len(), capacity);
+        self.outbox.write(stream_id, &framed, chunk.end_of_file);
+        if chunk.end_of_file {
+            self.fin_sent.insert(stream_id);
+        }
+
+        let bytes = chunk.packet.len() as u64;
+        self.stats.bytes_sent += bytes;
+        self.stats.chunks_handled += 1;
+        self.sent_bitmap.mark_received(chunk_id as u32, chunk.end_of_file);
+        Ok(Some(ChunkOutcome::Sent { chunk_id, bytes }))
+    }
+
+    /// End the upload once every chunk has been handed in and decided
+    ///
+    /// Data streams that did not carry the last chunk get their FIN now.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.manifest.streaming {
+            return Err(Error::Protocol(format!(
+                "Streaming transfer {} must finish with its totals", self.streams.transfer_id
+            )));
+        }
+        self.end_data_streams()
+    }
+
+    /// End a streaming upload once its input has run out
+    ///
+    /// The remaining chunk hashes and `trailer` close the manifest stream,
+    /// and the manifest kept here takes on the totals.
+    pub fn finish_stream(&mut self, trailer: ManifestTrailer) -> Result<()> {
+        let Some(page) = &self.stream_page else {
+            return Err(Error::Protocol(format!(
+                "Transfer {} is not streaming", self.streams.transfer_id
+            )));
+        };
+        let chunks_seen = page.first_chunk + page.chunk_hashes.len() as u64;
+        if trailer.total_chunks != chunks_seen {
+            return Err(Error::Protocol(format!(
+                "Transfer {} streamed {} chunks, totals say {}", self.streams.transfer_id, chunks_seen, trailer.total_chunks
+            )));
+        }
+
+        self.end_data_streams()?;
+        self.manifest.file_size = trailer.file_size;
+        self.manifest.total_chunks = trailer.total_chunks;
+        self.manifest.file_hash = trailer.file_hash.clone();
+        self.send_stream_page(Some(trailer))
+    }
+
+    /// Queue the chunk hashes collected since the last page, ending the
+    /// manifest stream when the totals go with them
+    fn send_stream_page(&mut self, trailer: Option<ManifestTrailer>) -> Result<()> {
+        let Some(page) = self.stream_page.as_mut() else {
+            return Ok(());
+        };
+        let chunk_hashes = std::mem::take(&mut page.chunk_hashes);
+        let first_chunk = page.first_chunk;
+        page.first_chunk += chunk_hashes.len() as u64;
+
+        let fin = trailer.is_some();
+        let encoded = PagedManifestSender::new().encode_page(&ManifestPage { first_chunk, chunk_hashes, trailer })?;
+        self.stats.manifest_bytes += encoded.len() as u64;
+        self.outbox.write(self.streams.manifest, &encoded, fin);
+        Ok(())
+    }
+
+    /// Finish every data stream that did not carry the last chunk
+    fn end_data_streams(&mut self) -> Result<()> {
+        if !self.is_sending() || self.pending.is_some() {
+            return Err(Error::Protocol(format!(
+                "Transfer {} finished before its chunks were sent", self.streams.transfer_id
+            )));
+        }
+
+        for &stream_id in self.scheduler.streams() {
+            if !self.fin_sent.contains(&stream_id) {
+                self.outbox.write(stream_id, &[], true);
+            }
+        }
+        self.phase = Phase::Finished;
+        Ok(())
+    }
+
+    /// Note what the server does about a file already stored under the name
+    ///
+    /// # Returns
+    /// Whether the upload ends here, the stored file being identical
+    fn record_conflict(&mut self, response: &ResumeResponse) -> Result<bool> {
+        let outcome = response.conflict();
+        if outcome == ConflictOutcome::NoConflict {
+            return Ok(false);
+        }
+        let stored_name = response.stored_name.clone().unwrap_or_else(|| self.manifest.file_name.clone());
+        self.conflict = Some((outcome, stored_name.clone()));
+
+        match outcome {
+            ConflictOutcome::Refused => Err(Error::FileExists(self.manifest.file_name.clone())),
+            ConflictOutcome::Skipped => {
+                log::info!("Client: {} is already stored on the server, nothing to send", self.manifest.file_name);
+                self.phase = Phase::Finished;
+                Ok(true)
+            }
+            _ => {
+                log::info!("Client: {} already exists on the server ({:?}), storing as {}",
+                    self.manifest.file_name, outcome, stored_name);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Chunks to skip, from the server's resume response
+    fn resume_skip_chunks(&self, response: ResumeResponse) -> Result<HashSet<u64>> {
+        let mut skip_chunks = HashSet::new();
+
+        if !response.accepted {
+            log::warn!("Client: resume rejected by server: {:?}", response.error);
+            return Ok(skip_chunks);
+        }
+        log::info!("Client: resume accepted! Server needs {} chunks", response.chunks_remaining);
+
+        // Chunks the server holds, or those NOT in its missing list
+        if let Some(bytes) = &response.received_bitmap {
+            let server_bitmap = ChunkBitmap::from_bytes(bytes)?;
+            skip_chunks.extend(server_bitmap.get_received_chunks()
+                .into_iter()
+                .filter(|&chunk_idx| self.manifest.streaming || chunk_idx < self.manifest.total_chunks));
+        } else {
+            let missing_set: HashSet<u64> = response.missing_chunks.iter().copied().collect();
+            skip_chunks.extend((0..self.manifest.total_chunks).filter(|chunk_idx| !missing_set.contains(chunk_idx)));
+        }
+
+        log::info!("Client: will skip {} chunks, send {} chunks",
+            skip_chunks.len(), self.manifest.total_chunks.saturating_sub(skip_chunks.len() as u64));
+        Ok(skip_chunks)
+    }
+
+    /// Leave the resume exchange and start taking chunks
+    fn start_data(&mut self, skip_chunks: HashSet<u64>) -> Result<()> {
+        let total_chunks = self.manifest.total_chunks;
+        for &chunk_idx in &skip_chunks {
+            self.sent_bitmap.mark_received(chunk_idx as u32, chunk_idx + 1 == total_chunks);
+        }
+
+        // Ask the server which chunks it already has, running ahead of the
+        // chunks being sent. Tree-hash manifests carry no chunk list to ask about.
+        if self.options.dedup && !self.manifest.chunk_hashes.is_empty() {
+            let not_needed: HashSet<u64> = (0..total_chunks)
+                .filter(|chunk_id| skip_chunks.contains(chunk_id) || !self.chunk_range.contains(chunk_id))
+                .collect();
+            let mut pipeline = HashCheckPipeline::new(
+                self.manifest.session_id.clone(),
+                self.manifest.chunk_hashes.clone(),
+                &not_needed,
+            ).for_transfer(self.streams.transfer_id);
+
+            let (outbox, stream) = (&mut self.outbox, self.streams.hash_check);
+            if self.options.dedup_filter {
+                pipeline.request_filter(|data, fin| Ok(outbox.write(stream, data, fin)))?;
+            }
+            pipeline.pump(|data, fin| Ok(outbox.write(stream, data, fin)))?;
+            log::info!("Client: pipelined hash check enabled (filter: {})", self.options.dedup_filter);
+            self.dedup = Some(pipeline);
+        }
+
+        self.skip_chunks = skip_chunks;
+        self.phase = Phase::Data;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::StreamWrite;
+    use crate::protocol::resume::ResumeResponseSender;
+
+    fn manifest(total_chunks: u64) -> Manifest {
+        Manifest {
+            session_id: "session".to_string(),
+            file_name: "file.bin".to_string(),
+            file_size: total_chunks * 3,
+            chunk_size: 3,
+            total_chunks,
+            ..Manifest::default()
+        }
+    }
+
+    fn chunk(chunk_id: u64, end_of_file: bool) -> ProcessedChunk {
+        ProcessedChunk {
+            chunk_id,
+            packet: vec![chunk_id as u8; 3],
+            hash: Vec::new(),
+            end_of_file,
+        }
+    }
+
+    #[test]
+    fn test_resume_timeout_starts_fresh_transfer() {
+        let streams = TransferStreams::for_transfer(1);
+        let now = Instant::now();
+        let mut machine = UploadMachine::new(streams, manifest(1), None, UploadOptions::default(), now).unwrap();
+
+        let writes = machine.outbox().take();
+        let written: Vec<(u64, bool)> = writes.iter().map(|write| (write.stream_id, write.fin)).collect();
+        assert_eq!(written, vec![(streams.manifest, true), (streams.resume, true)]);
+        assert_eq!(machine.stats().manifest_bytes, writes[0].data.len() as u64);
+
+        // No answer: the deadline starts the data phase
+        assert!(!machine.wants_chunk());
+        assert_eq!(machine.timeout(), Some(now + RESUME_RESPONSE_TIMEOUT));
+        machine.on_timeout(now + Duration::from_millis(100)).unwrap();
+        assert!(!machine.is_sending());
+        machine.on_timeout(now + RESUME_RESPONSE_TIMEOUT).unwrap();
+        assert!(machine.wants_chunk());
+
+        machine.push_chunk(chunk(0, true)).unwrap();
+        assert_eq!(machine.poll_chunk(now, |_| None).unwrap(), Some(ChunkOutcome::Sent { chunk_id: 0, bytes: 3 }));
+        assert_eq!(machine.resumed_chunks(), 0);
+        machine.finish().unwrap();
+        assert!(machine.is_finished());
+
+        // The last chunk finished the only data stream
+        assert_eq!(machine.outbox().take(), vec![StreamWrite {
+            stream_id: streams.data,
+            data: vec![0, 0, 0, 3, 0, 0, 0],
+            fin: true,
+        }]);
+        assert!(machine.sent_bitmap().is_received(0));
+    }
+
+    #[test]
+    fn test_resume_response_skips_held_chunks() {
+        let streams = TransferStreams::for_transfer(0);
+        let now = Instant::now();
+        let mut machine = UploadMachine::new(streams, manifest(3), None, UploadOptions::default(), now).unwrap();
+        machine.outbox().take();
+
+        let mut held = ChunkBitmap::with_exact_size(3);
+        held.mark_received(1, false);
+        let mut response = Vec::new();
+        ResumeResponseSender::for_transfer(0).send_response(
+            "session".to_string(), true, Vec::new(), Some(held.to_bytes()), 2, None,
+            |data, _| {
+                response.extend_from_slice(data);
+                Ok(data.len())
+            },
+        ).unwrap();
+        machine.on_stream_data(streams.resume, &response, true).unwrap();
+        assert!(machine.is_sending());
+
+        let outcomes: Vec<_> = (0..3).map(|chunk_id| {
+            machine.push_chunk(chunk(chunk_id, chunk_id == 2)).unwrap();
+            let outcome = machine.poll_chunk(now, |_| None).unwrap();
+            machine.outbox().take();
+            outcome
+        }).collect();
+        assert_eq!(outcomes, vec![
+            Some(ChunkOutcome::Sent { chunk_id: 0, bytes: 3 }),
+            Some(ChunkOutcome::Resumed { chunk_id: 1 }),
+            Some(ChunkOutcome::Sent { chunk_id: 2, bytes: 3 }),
+        ]);
+        assert_eq!(machine.resumed_chunks(), 1);
+        assert_eq!(machine.stats().chunks_skipped, 1);
+
+        // Chunks cannot be handed in once the upload has finished
+        machine.finish().unwrap();
+        assert!(machine.push_chunk(chunk(3, false)).is_err());
+    }
+
+    #[test]
+    fn test_conflict_outcomes() {
+        let streams = TransferStreams::for_transfer(0);
+        let now = Instant::now();
+        let answer = |outcome, accepted, stored_name: Option<&str>| {
+            let mut machine = UploadMachine::new(streams, manifest(2), None, UploadOptions::default(), now).unwrap();
+            machine.outbox().take();
+            let mut response = Vec::new();
+            ResumeResponseSender::for_transfer(0)
+                .with_conflict(outcome, stored_name.map(str::to_string))
+                .send_response("session".to_string(), accepted, Vec::new(), None, 0, None, |data, _| {
+                    response.extend_from_slice(data);
+                    Ok(data.len())
+                })
+                .unwrap();
+            let result = machine.on_stream_data(streams.resume, &response, true);
+            (machine, result)
+        };
+
+        // An identical file on the server ends the upload without a chunk
+        let (mut machine, result) = answer(ConflictOutcome::Skipped, false, None);
+        result.unwrap();
+        assert!(machine.is_finished() && !machine.wants_chunk());
+        assert!(machine.outbox().take().is_empty());
+        assert_eq!(machine.conflict(), Some((ConflictOutcome::Skipped, "file.bin")));
+
+        let (_, result) = answer(ConflictOutcome::Refused, false, None);
+        assert!(matches!(result, Err(Error::FileExists(name)) if name == "file.bin"));
+
+        let (machine, result) = answer(ConflictOutcome::Renamed, true, Some("file-1.bin"));
+        result.unwrap();
+        assert!(machine.wants_chunk());
+        assert_eq!(machine.conflict(), Some((ConflictOutcome::Renamed, "file-1.bin")));
+
+        let (machine, _) = answer(ConflictOutcome::NoConflict, true, None);
+        assert_eq!(machine.conflict(), None);
+    }
+}
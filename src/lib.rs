@@ -5,6 +5,8 @@ pub mod protocol;
 pub mod proto;
 pub mod chunking;
 pub mod transport;
+pub mod engine;
+pub mod observer;
 pub mod storage;
 pub mod logging;
 pub mod resumption;
@@ -14,5 +16,6 @@ pub mod validation;
 // Re-export commonly used items
 pub use common::{Error, Result, ClientConfig};
 pub use client::Client;
+pub use observer::{ResumeDecision, TransferObserver, TransferSummary};
 
 pub use server::{Server, ServerConfig, ServerConnection, ServerSession};
\ No newline at end of file
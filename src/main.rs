@@ -4,10 +4,17 @@ use clap::{Parser, Subcommand};
 use sftpx::common::cert_gen::generate_self_signed_cert;
 use sftpx::common::config::ClientConfig;
 use sftpx::client::transfer::Transfer;
+use sftpx::client::SyncPlan;
 use sftpx::server::{Server, ServerConfig};
 use sftpx::chunking::compress::CompressionType;
-use sftpx::chunking::ChunkBitmap;
+use sftpx::chunking::{ChunkBitmap, EncryptionConfig};
+use sftpx::protocol::{ConflictOutcome, ConflictPolicy, FileVersion, ManifestSigner, MetadataPolicy};
+use sftpx::storage::Retention;
+use sftpx::Client;
+use sftpx::{ResumeDecision, TransferObserver, TransferSummary};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -24,11 +31,68 @@ struct Cli {
 enum Commands {
     /// Send a file to a remote server
     Send {
-        /// File to send
+        /// File to send (directory with -r, - for stdin)
         file: String,
         
-        /// Server IP address (default: 127.0.0.1)
+        /// Server IP address (default: 127.0.0.1); host:path when sending stdin
         server: Option<String>,
+        
+        /// Encrypt chunks with a passphrase read from SFTPX_PASSPHRASE
+        #[arg(long)]
+        encrypt: bool,
+        
+        /// Encrypt chunks with a 32-byte key file (raw or hex)
+        #[arg(long, conflicts_with = "encrypt")]
+        key_file: Option<PathBuf>,
+        
+        /// Sign the manifest with an Ed25519 key (see `sftpx keygen`)
+        #[arg(long)]
+        sign_key: Option<PathBuf>,
+        
+        /// Send only the chunk hash tree root; chunks carry their own proofs
+        #[arg(long)]
+        tree: bool,
+        
+        /// Skip chunks the server already has (pipelined hash checks)
+        #[arg(long)]
+        dedup: bool,
+        
+        /// Like --dedup, but fetch the server's Bloom filter first
+        #[arg(long)]
+        dedup_filter: bool,
+        
+        /// Upload another file over the same connection (repeatable)
+        #[arg(long, value_name = "FILE")]
+        also: Vec<String>,
+        
+        /// Upload a directory and everything below it
+        #[arg(short, long, conflicts_with = "also")]
+        recursive: bool,
+        
+        /// Pack files of at most this many bytes (default 256 KB) into shared bundles
+        #[arg(long, value_name = "BYTES", num_args = 0..=1, default_missing_value = "262144",
+              conflicts_with_all = ["encrypt", "key_file"])]
+        pack: Option<u64>,
+        
+        /// Stripe the file's chunks across this many connections
+        #[arg(long, value_name = "N", default_value_t = 1, conflicts_with_all = ["also", "recursive"])]
+        stripes: usize,
+        
+        /// Spread each transfer's chunks over this many data streams
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        data_streams: usize,
+        
+        /// Also send file owners (applied where the server runs as root)
+        #[arg(long)]
+        owner: bool,
+        
+        /// Also send extended attributes
+        #[arg(long)]
+        xattrs: bool,
+        
+        /// If the file already exists on the server: overwrite, skip (if identical), fail, rename or version
+        #[arg(long, value_name = "POLICY")]
+        on_conflict: Option<ConflictPolicy>,
     },
     
     /// Start server to receive files
@@ -40,6 +104,97 @@ enum Commands {
         /// Upload directory (default: ./uploads)
         #[arg(long, default_value = "./uploads")]
         upload_dir: String,
+        
+        /// Only accept manifests signed by a key listed in this file
+        #[arg(long)]
+        trusted_keys: Option<String>,
+        
+        /// Apply the owners sent with files (usually needs root)
+        #[arg(long)]
+        owner: bool,
+        
+        /// Apply the extended attributes sent with files (user.* only)
+        #[arg(long)]
+        xattrs: bool,
+        
+        /// Apply extended attributes of every namespace, such as security.capability (implies --xattrs)
+        #[arg(long)]
+        all_xattrs: bool,
+        
+        /// Keep the setuid, setgid and sticky bits sent with files
+        #[arg(long)]
+        special_bits: bool,
+        
+        /// For uploads over an existing file that do not say: overwrite, skip (if identical), fail, rename or version
+        #[arg(long, value_name = "POLICY", default_value = "overwrite")]
+        on_conflict: ConflictPolicy,
+        
+        /// Keep at least the newest N versions of each file, pruning older ones
+        #[arg(long, value_name = "N")]
+        keep_versions: Option<usize>,
+        
+        /// Keep versions for at least this many days, pruning older ones
+        #[arg(long, value_name = "DAYS")]
+        keep_days: Option<u64>,
+        
+        /// Most clients connected at once; others are not answered
+        #[arg(long, value_name = "N", default_value_t = 256)]
+        max_connections: usize,
+    },
+    
+    /// Make a directory on a server match a local one
+    Sync {
+        /// Local directory to mirror
+        local_dir: PathBuf,
+        
+        /// Directory on the server, as host:path below its upload directory
+        target: String,
+        
+        /// Remove files and directories the local directory does not have
+        #[arg(long)]
+        delete: bool,
+        
+        /// Report what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        
+        /// Skip chunks the server already has (pipelined hash checks)
+        #[arg(long)]
+        dedup: bool,
+        
+        /// Sign manifests and removals with an Ed25519 key (see `sftpx keygen`)
+        #[arg(long)]
+        sign_key: Option<PathBuf>,
+    },
+    
+    /// Download a file from a server
+    Get {
+        /// File on the server, as host:path below its upload directory
+        source: String,
+        
+        /// Where to write it, - for stdout (default: the file's name)
+        output: Option<String>,
+    },
+    
+    /// List the versions a server keeps of a file, or restore one
+    Versions {
+        /// File on the server, as host:path below its upload directory
+        target: String,
+        
+        /// Put this version back in place, keeping the current file as a version
+        #[arg(long, value_name = "N")]
+        restore: Option<u64>,
+        
+        /// Sign the restore request with an Ed25519 key (see `sftpx keygen`)
+        #[arg(long)]
+        sign_key: Option<PathBuf>,
+    },
+    
+    /// Generate an Ed25519 key pair for signing manifests
+    Keygen {
+        /// Private key output path (public key is written to <PATH>.pub)
+        #[arg(long, default_value = "sftpx_signing.key")]
+        output: PathBuf,
     },
     
     /// Initialize certificates for QUIC connections
@@ -50,6 +205,102 @@ enum Commands {
     },
 }
 
+/// Prints transfer events for the terminal
+struct CliObserver;
+
+impl TransferObserver for CliObserver {
+    fn on_resume(&self, session_id: &str, decision: ResumeDecision) {
+        if let ResumeDecision::Resume { chunks_present, total_chunks } = decision {
+            println!("🔄 {}: resuming with {}/{} chunks already transferred", session_id, chunks_present, total_chunks);
+        }
+    }
+    
+    fn on_conflict(&self, session_id: &str, outcome: ConflictOutcome, stored_name: &str) {
+        let action = match outcome {
+            ConflictOutcome::NoConflict => return,
+            ConflictOutcome::Overwritten => "overwritten".to_string(),
+            ConflictOutcome::Skipped => "identical, not sent".to_string(),
+            ConflictOutcome::Refused => "refused".to_string(),
+            ConflictOutcome::Renamed => format!("stored as {}", stored_name),
+            ConflictOutcome::Versioned => "previous version kept".to_string(),
+        };
+        println!("📄 {}: file already existed, {}", session_id, action);
+    }
+    
+    fn on_error(&self, session_id: Option<&str>, error: &dyn std::error::Error) {
+        eprintln!("❌ {}: {}", session_id.unwrap_or("connection"), error);
+    }
+    
+    fn on_complete(&self, summary: &TransferSummary) {
+        println!("\n✅ {} transferred", summary.file_name);
+        println!("  Path: {:?}", summary.path);
+        println!("  Size: {} bytes ({:.2} MB)", summary.file_size, summary.file_size as f64 / 1_048_576.0);
+        println!("  Chunks: {} transferred, {} skipped ({} dedup) in {:.2}s",
+            summary.chunks_transferred, summary.chunks_skipped, summary.chunks_deduped,
+            summary.elapsed.as_secs_f64());
+    }
+}
+
+/// Client configuration for the server at `server_ip`
+fn client_config(server_ip: &str) -> Result<ClientConfig> {
+    let server_addr = format!("{}:4443", server_ip).parse()?;
+    let server_name = if server_ip == "127.0.0.1" || server_ip == "localhost" {
+        "localhost".to_string()
+    } else {
+        server_ip.to_string()
+    };
+    Ok(ClientConfig::new(server_addr, server_name).disable_cert_verification())
+}
+
+/// Print one kept version of a file
+fn print_version(version: &FileVersion) {
+    let short_hash = version.file_hash.get(..8).unwrap_or(&version.file_hash);
+    println!("  #{:<4} {:>12} bytes  kept {:<8}  {}",
+        version.number, version.file_size, format_age(version.kept_at), hex::encode(short_hash));
+}
+
+/// Print what a sync changes on the server
+fn print_sync_plan(plan: &SyncPlan) {
+    if plan.is_empty() {
+        println!("{} is up to date ({} files unchanged)", plan.remote_dir, plan.unchanged);
+        return;
+    }
+    
+    println!("Changes to {}:", plan.remote_dir);
+    for path in &plan.replace {
+        println!("  replace {}", path);
+    }
+    for node in &plan.changed {
+        match (node.is_dir, node.size) {
+            (true, _) => println!("  create  {}/", node.relative_path),
+            (false, 0) => println!("  create  {}", node.relative_path),
+            (false, size) => println!("  upload  {} ({} bytes)", node.relative_path, size),
+        }
+    }
+    for path in &plan.remove {
+        println!("  remove  {}", path);
+    }
+    let uploads = plan.changed.iter().filter(|node| node.size > 0).count();
+    let upload_size = plan.upload_size();
+    println!("{} files ({:.2} MB) to upload, {} entries to remove, {} files unchanged",
+        uploads, upload_size as f64 / 1_048_576.0, plan.remove.len(), plan.unchanged);
+}
+
+/// How long ago `unix_seconds` was, roughly
+fn format_age(unix_seconds: u64) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let age = now.saturating_sub(unix_seconds);
+    match age {
+        0..=119 => format!("{}s ago", age),
+        120..=7199 => format!("{}m ago", age / 60),
+        7200..=172_799 => format!("{}h ago", age / 3600),
+        _ => format!("{}d ago", age / 86400),
+    }
+}
+
 fn get_session_id_for_file(file_path: &Path) -> String {
     // Generate deterministic session ID based on file path and name
     let file_name = file_path.file_name()
@@ -100,6 +351,16 @@ fn main() -> Result<()> {
             
             match generate_self_signed_cert(&ip, None) {
                 Ok(()) => {
+                    println!("\n✅ Certificates generated successfully:");
+                    println!("   \"certs/cert.pem\" - Certificate (includes localhost + {})", ip);
+                    println!("   \"certs/key.pem\" - Private key");
+                    println!("\nCertificate SANs:");
+                    println!("   - DNS: localhost");
+                    println!("   - DNS: *.local");
+                    println!("   - IP: 127.0.0.1");
+                    if ip != "127.0.0.1" && ip != "localhost" {
+                        println!("   - IP/DNS: {}", ip);
+                    }
                     println!("\n✅ Certificates initialized successfully!");
                     println!("You can now run the server with: sftpx recv");
                 }
@@ -110,46 +371,173 @@ fn main() -> Result<()> {
             }
         }
         
-        Commands::Send { file, server } => {
+        Commands::Keygen { output } => {
+            println!("=== SFTPX Signing Key Generation ===\n");
+            
+            let pkcs8 = ManifestSigner::generate_pkcs8()?;
+            let signer = ManifestSigner::from_pkcs8(&pkcs8)?;
+            let public_hex = hex::encode(signer.public_key());
+            
+            let mut public_path = output.clone().into_os_string();
+            public_path.push(".pub");
+            let public_path = PathBuf::from(public_path);
+            
+            std::fs::write(&output, &pkcs8)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&output, std::fs::Permissions::from_mode(0o600))?;
+            }
+            std::fs::write(&public_path, format!("{}\n", public_hex))?;
+            
+            println!("Private key: {:?}", output);
+            println!("Public key:  {:?}", public_path);
+            println!("\nAdd this line to the server's trusted keys file:");
+            println!("  {}", public_hex);
+        }
+        
+        Commands::Send { file, server, encrypt, key_file, sign_key, tree, dedup, dedup_filter, also, recursive, pack, stripes, data_streams, owner, xattrs, on_conflict } => {
             println!("=== SFTPX Client Upload ===\n");
             
             let file_path = Path::new(&file);
-            let server_ip = server.as_deref().unwrap_or("127.0.0.1");
+            let server = server.as_deref().unwrap_or("127.0.0.1");
             
-            // Verify file exists
-            if !file_path.exists() {
-                eprintln!("Error: File not found: {:?}", file_path);
+            // Input read from stdin is stored at the path given with the host
+            let (server_ip, remote_path) = match server.split_once(':') {
+                Some((host, path)) => (host, Some(path)),
+                None => (server, None),
+            };
+            let streaming = file == "-";
+            if streaming && remote_path.is_none_or(str::is_empty) {
+                eprintln!("Error: sending stdin needs a remote path (host:path)");
+                return Ok(());
+            }
+            if !streaming && remote_path.is_some() {
+                eprintln!("Error: a remote path (host:path) is only used when sending stdin (-)");
+                return Ok(());
+            }
+            if streaming && (recursive || !also.is_empty()) {
+                eprintln!("Error: stdin is sent on its own");
                 return Ok(());
             }
             
-            let file_size = std::fs::metadata(file_path)?.len();
-            let session_id = get_session_id_for_file(file_path);
-            
-            println!("File to upload:");
-            println!("  Path: {:?}", file_path);
-            println!("  Size: {} bytes ({:.2} MB)", file_size, file_size as f64 / 1_048_576.0);
-            println!("  Session ID: {}", session_id);
+            // Verify files exist
+            let files: Vec<PathBuf> = std::iter::once(&file).chain(&also).map(PathBuf::from).collect();
+            if let Some(missing) = files.iter().find(|f| !streaming && !f.exists()) {
+                eprintln!("Error: File not found: {:?}", missing);
+                return Ok(());
+            }
             
-            // Check for existing transfer to resume
-            let resume_from = check_for_resume(&session_id);
+            if !streaming && file_path.is_dir() != recursive {
+                eprintln!("Error: {:?} {}", file_path, if recursive {
+                    "is not a directory"
+                } else {
+                    "is a directory (use -r to upload it recursively)"
+                });
+                return Ok(());
+            }
             
-            // Create client configuration
-            let server_addr = format!("{}:4443", server_ip).parse()?;
-            let server_name = if server_ip == "127.0.0.1" || server_ip == "localhost" {
-                "localhost".to_string()
+            // Per-file resume state is reported as each file's transfer starts
+            let resume_from = if let Some(remote_path) = remote_path {
+                println!("Streaming stdin to:");
+                println!("  Path: {}", remote_path);
+                None
+            } else if recursive {
+                println!("Directory to upload:");
+                println!("  Path: {:?}", file_path);
+                None
             } else {
-                server_ip.to_string()
+                let file_size = std::fs::metadata(file_path)?.len();
+                let session_id = get_session_id_for_file(file_path);
+                
+                println!("File to upload:");
+                println!("  Path: {:?}", file_path);
+                println!("  Size: {} bytes ({:.2} MB)", file_size, file_size as f64 / 1_048_576.0);
+                println!("  Session ID: {}", session_id);
+                if !also.is_empty() {
+                    println!("  Plus {} more file(s) on the same connection", also.len());
+                }
+                
+                // Check for existing transfer to resume
+                check_for_resume(&session_id)
             };
             
-            let config = ClientConfig::new(server_addr, server_name)
-                .disable_cert_verification()
+            // Create client configuration
+            let mut config = client_config(server_ip)?
                 .with_chunk_size(2097152)?    // 2 MB chunks
                 .with_compression(CompressionType::None);
             
+            if let Some(key_file) = key_file {
+                config = config.with_encryption(EncryptionConfig::key_file(key_file));
+            } else if encrypt {
+                let passphrase = std::env::var("SFTPX_PASSPHRASE")
+                    .map_err(|_| "--encrypt requires the SFTPX_PASSPHRASE environment variable")?;
+                config = config.with_encryption(EncryptionConfig::passphrase(passphrase));
+            }
+            
+            if let Some(sign_key) = sign_key {
+                config = config.with_signing_key(sign_key);
+            }
+            
+            if tree {
+                config = config.with_tree_manifest();
+            }
+            
+            if dedup_filter {
+                config = config.with_dedup_filter();
+            } else if dedup {
+                config = config.with_dedup();
+            }
+            
+            if let Some(threshold) = pack {
+                config = config.with_packing(threshold);
+            }
+            
+            config = config.with_stripes(stripes).with_data_streams(data_streams);
+            
+            if owner {
+                config = config.with_ownership();
+            }
+            if xattrs {
+                config = config.with_xattrs();
+            }
+            if let Some(policy) = on_conflict {
+                config = config.with_conflict_policy(policy);
+            }
+            
             println!("\nClient Configuration:");
-            println!("  Server: {}", server_addr);
+            println!("  Server: {}", config.server_addr);
             println!("  Chunk Size: {} MB", config.chunk_size / (1024*1024));
             println!("  Compression: {:?}", config.compression);
+            if let Some(encryption) = &config.encryption {
+                println!("  Encryption: {} ({:?})", encryption.algorithm.as_str(), encryption.key_source);
+            }
+            if let Some(sign_key) = &config.signing_key {
+                println!("  Signing Key: {:?}", sign_key);
+            }
+            if config.tree_manifest {
+                println!("  Manifest: hash tree root (per-chunk proofs)");
+            }
+            if config.dedup {
+                println!("  Dedup: pipelined hash checks{}", if config.dedup_filter { " + Bloom filter" } else { "" });
+            }
+            if let Some(threshold) = config.pack_threshold {
+                println!("  Packing: files up to {} bytes", threshold);
+            }
+            if config.stripes > 1 {
+                println!("  Stripes: {} connections", config.stripes);
+            }
+            if config.data_streams > 1 {
+                println!("  Data Streams: {} per transfer", config.data_streams);
+            }
+            if config.metadata.ownership || config.metadata.xattrs {
+                println!("  Metadata: modes, times{}{}",
+                    if config.metadata.ownership { ", owners" } else { "" },
+                    if config.metadata.xattrs { ", xattrs" } else { "" });
+            }
+            if let Some(policy) = on_conflict {
+                println!("  On Conflict: {:?}", policy);
+            }
             println!("\nFeatures:");
             println!("  ✓ Integrated orchestration (handshake → manifest → chunks)");
             println!("  ✓ BLAKE3 integrity verification per chunk");
@@ -163,22 +551,124 @@ fn main() -> Result<()> {
             }
             
             // Create transfer and run upload
-            let mut transfer = Transfer::send_file(config, file_path.to_str().unwrap(), "server")?;
+            let transfer = match remote_path {
+                Some(remote_path) => Transfer::send_stream(config, remote_path)?,
+                None => Transfer::send_file(config, file_path.to_str().unwrap(), "server")?,
+            };
+            let mut transfer = transfer.with_observer(Arc::new(CliObserver));
             
-            match transfer.run_send(file_path) {
+            let result = if streaming {
+                transfer.run_send_stream(std::io::stdin())
+            } else if recursive {
+                transfer.run_send_dir(file_path)
+            } else {
+                transfer.run_send_many(&files)
+            };
+            
+            match result {
                 Ok(bytes_sent) => {
                     println!("\n✅ Upload successful!");
                     println!("  Total bytes sent: {} ({:.2} MB)", bytes_sent, bytes_sent as f64 / 1_048_576.0);
                     println!("  Transfer state: {:?}", transfer.state());
                 }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        
+        Commands::Sync { local_dir, target, delete, dry_run, dedup, sign_key } => {
+            println!("=== SFTPX Directory Sync ===\n");
+            
+            let Some((server_ip, remote_dir)) = target.split_once(':').filter(|(_, path)| !path.is_empty()) else {
+                eprintln!("Error: expected host:path, got {:?}", target);
+                return Ok(());
+            };
+            if !local_dir.is_dir() {
+                eprintln!("Error: {:?} is not a directory", local_dir);
+                return Ok(());
+            }
+            
+            let mut config = client_config(server_ip)?.with_chunk_size(2097152)?;
+            if dedup {
+                config = config.with_dedup();
+            }
+            if let Some(sign_key) = sign_key {
+                config = config.with_signing_key(sign_key);
+            }
+            let client = Client::new(config).with_observer(Arc::new(CliObserver));
+            
+            let plan = client.plan_sync(&local_dir, remote_dir, delete)?;
+            print_sync_plan(&plan);
+            if dry_run || plan.is_empty() {
+                return Ok(());
+            }
+            
+            let bytes_sent = client.sync(&plan)?;
+            println!("\n✅ {} now mirrors {:?}", target, local_dir);
+            println!("  Total bytes sent: {} ({:.2} MB)", bytes_sent, bytes_sent as f64 / 1_048_576.0);
+        }
+        
+        Commands::Get { source, output } => {
+            let Some((server_ip, remote_path)) = source.split_once(':').filter(|(_, path)| !path.is_empty()) else {
+                eprintln!("Error: expected host:path, got {:?}", source);
+                return Ok(());
+            };
+            let mut transfer = Client::new(client_config(server_ip)?).download_file(remote_path)?;
+            
+            // Written in chunk order as it arrives, so stdout can be a pipe;
+            // progress then stays off stdout
+            if output.as_deref() == Some("-") {
+                transfer.run_receive_to(std::io::stdout().lock())?;
+                return Ok(());
+            }
+            
+            let output = output.map(PathBuf::from)
+                .or_else(|| Path::new(remote_path).file_name().map(PathBuf::from))
+                .ok_or_else(|| format!("No file name in {:?}", remote_path))?;
+            println!("=== SFTPX Client Download ===\n");
+            println!("Downloading {} to {:?}", remote_path, output);
+            
+            let file = std::fs::File::create(&output)?;
+            match transfer.run_receive_to(std::io::BufWriter::new(file)) {
+                Ok(bytes_written) => {
+                    println!("\n✅ {} downloaded", remote_path);
+                    println!("  Path: {:?}", output);
+                    println!("  Size: {} bytes ({:.2} MB)", bytes_written, bytes_written as f64 / 1_048_576.0);
+                }
                 Err(e) => {
-                    eprintln!("\n❌ Upload failed: {:?}", e);
+                    let _ = std::fs::remove_file(&output);
                     return Err(e.into());
                 }
             }
         }
         
-        Commands::Recv { bind, upload_dir } => {
+        Commands::Versions { target, restore, sign_key } => {
+            let Some((server_ip, remote_path)) = target.split_once(':').filter(|(_, path)| !path.is_empty()) else {
+                eprintln!("Error: expected host:path, got {:?}", target);
+                return Ok(());
+            };
+            let mut config = client_config(server_ip)?;
+            if let Some(sign_key) = sign_key {
+                config = config.with_signing_key(sign_key);
+            }
+            let client = Client::new(config).with_observer(Arc::new(CliObserver));
+            
+            if let Some(number) = restore {
+                let restored = client.restore_version(remote_path, number)?;
+                println!("✅ {} restored to version {} ({} bytes)", remote_path, restored.number, restored.file_size);
+                return Ok(());
+            }
+            
+            let versions = client.list_versions(remote_path)?;
+            if versions.is_empty() {
+                println!("No versions kept of {}", remote_path);
+            } else {
+                println!("Versions of {}:", remote_path);
+                versions.iter().for_each(print_version);
+            }
+        }
+        
+        Commands::Recv { bind, upload_dir, trusted_keys, owner, xattrs, all_xattrs, special_bits, on_conflict, keep_versions, keep_days, max_connections } => {
+            let xattrs = xattrs || all_xattrs;
             println!("=== SFTPX File Server ===\n");
             
             // Create server configuration
@@ -190,6 +680,14 @@ fn main() -> Result<()> {
                 max_data: 100_000_000,
                 max_stream_data: 10_000_000,
                 max_streams: 100,
+                trusted_keys_path: trusted_keys,
+                metadata_policy: MetadataPolicy { ownership: owner, xattrs, all_xattrs, special_bits, ..MetadataPolicy::default() },
+                conflict_policy: on_conflict,
+                retention: Retention {
+                    keep_last: keep_versions,
+                    keep_for: keep_days.map(|days| Duration::from_secs(days * 86400)),
+                },
+                max_connections,
             };
             
             // Set up directories
@@ -203,6 +701,22 @@ fn main() -> Result<()> {
             println!("  Upload Directory: {:?}", upload_path);
             println!("  Max Data: {} MB", config.max_data / 1_048_576);
             println!("  Max Idle Timeout: {}ms", config.max_idle_timeout);
+            if let Some(path) = &config.trusted_keys_path {
+                println!("  Trusted Keys: {} (unsigned uploads rejected)", path);
+            }
+            if owner || xattrs || special_bits {
+                println!("  Metadata: modes{}, times{}{}",
+                    if special_bits { " (with setuid/setgid/sticky)" } else { "" },
+                    if owner { ", owners" } else { "" },
+                    if all_xattrs { ", xattrs (all namespaces)" } else if xattrs { ", xattrs (user.*)" } else { "" });
+            }
+            println!("  On Conflict: {:?}", config.conflict_policy);
+            if !config.retention.keeps_everything() {
+                println!("  Version Retention: {}{}{}",
+                    keep_versions.map(|n| format!("newest {}", n)).unwrap_or_default(),
+                    if keep_versions.is_some() && keep_days.is_some() { " or " } else { "" },
+                    keep_days.map(|days| format!("{} days", days)).unwrap_or_default());
+            }
             
             println!("\nFeatures:");
             println!("  ✓ Integrated orchestration (manifest + chunks)");
@@ -211,7 +725,7 @@ fn main() -> Result<()> {
             println!("  ✓ 4 QUIC streams (Control, Manifest, Data, Status)");
             
             println!("\nStarting QUIC file server...");
-            let mut server = Server::new(config)?;
+            let mut server = Server::new(config)?.with_observer(Arc::new(CliObserver));
             
             println!("✓ Server initialized successfully");
             println!("✓ Listening for connections...");
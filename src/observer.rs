@@ -0,0 +1,146 @@
+// Transfer observers
+//
+// Clients and servers report what their transfers are doing to a
+// `TransferObserver` instead of printing it: state changes, chunks sent and
+// stored, dedup hits, resume decisions, name conflicts, errors and
+// completion totals. Every method has an empty default, so an observer
+// implements only the events it cares about. The library itself writes
+// nothing to stdout; its diagnostics go through `log`, and the CLI prints its
+// output from an observer.
+//
+// Observers are shared by a transfer's threads (stripes, server sessions),
+// so they must be `Send + Sync` and are held in an `Arc`.
+
+use crate::common::types::TransferState;
+use crate::protocol::messages::ConflictOutcome;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How an upload starts, decided by the client's resume state and what the
+/// server already holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeDecision {
+    /// Every chunk is sent
+    Fresh,
+    /// Chunks from an earlier session are kept and only the rest are sent
+    Resume {
+        /// Chunks the receiver already holds
+        chunks_present: u64,
+        total_chunks: u64,
+    },
+}
+
+impl ResumeDecision {
+    /// Decision for an upload that starts with `chunks_present` chunks in place
+    pub fn from_counts(chunks_present: u64, total_chunks: u64) -> Self {
+        if chunks_present == 0 {
+            Self::Fresh
+        } else {
+            Self::Resume { chunks_present, total_chunks }
+        }
+    }
+}
+
+/// Totals of a completed transfer
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferSummary {
+    pub session_id: String,
+    pub file_name: String,
+    /// File sent (client) or where it was stored (server)
+    pub path: PathBuf,
+    /// Size of the file
+    pub file_size: u64,
+    /// Chunk bytes put on the wire (client) or stored (server)
+    pub bytes: u64,
+    /// Chunks sent (client) or received (server) in this session
+    pub chunks_transferred: u64,
+    /// Chunks not transferred because the receiver already had them
+    pub chunks_skipped: u64,
+    /// Of the skipped chunks, those found in the server's chunk index
+    pub chunks_deduped: u64,
+    pub elapsed: Duration,
+}
+
+/// Receives events from transfers
+///
+/// `session_id` identifies the file a transfer event belongs to; events
+/// about a whole connection (handshake, connection failures) have none.
+#[allow(unused_variables)]
+pub trait TransferObserver: Send + Sync {
+    /// A transfer, or the connection when `session_id` is `None`, moved to `state`
+    fn on_state(&self, session_id: Option<&str>, state: TransferState) {}
+
+    /// A chunk was handed to QUIC (client)
+    fn on_chunk_sent(&self, session_id: &str, chunk_id: u64, bytes: u64) {}
+
+    /// A chunk was received, verified and written (server)
+    fn on_chunk_acked(&self, session_id: &str, chunk_id: u64) {}
+
+    /// A chunk was not sent because the server's chunk index holds it
+    fn on_dedup_hit(&self, session_id: &str, chunk_id: u64) {}
+
+    /// An upload decided whether to resume
+    fn on_resume(&self, session_id: &str, decision: ResumeDecision) {}
+
+    /// An upload met a file already stored under its name; it is stored as
+    /// `stored_name`
+    fn on_conflict(&self, session_id: &str, outcome: ConflictOutcome, stored_name: &str) {}
+
+    /// A transfer, or the connection when `session_id` is `None`, failed
+    fn on_error(&self, session_id: Option<&str>, error: &dyn Error) {}
+
+    /// A transfer completed
+    fn on_complete(&self, summary: &TransferSummary) {}
+}
+
+/// Observer that ignores every event, used when none is attached
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl TransferObserver for NoopObserver {}
+
+/// Shared observer handle, as held by transfers and servers
+pub type SharedObserver = Arc<dyn TransferObserver>;
+
+/// Observer handle for when none is attached
+pub fn noop() -> SharedObserver {
+    Arc::new(NoopObserver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl TransferObserver for Recorder {
+        fn on_resume(&self, session_id: &str, decision: ResumeDecision) {
+            self.events.lock().unwrap().push(format!("{} {:?}", session_id, decision));
+        }
+    }
+
+    #[test]
+    fn test_resume_decision_from_counts() {
+        assert_eq!(ResumeDecision::from_counts(0, 10), ResumeDecision::Fresh);
+        assert_eq!(
+            ResumeDecision::from_counts(4, 10),
+            ResumeDecision::Resume { chunks_present: 4, total_chunks: 10 }
+        );
+    }
+
+    #[test]
+    fn test_default_methods_ignore_events() {
+        let recorder = Arc::new(Recorder::default());
+        let observer: SharedObserver = recorder.clone();
+        observer.on_state(None, TransferState::Handshaking);
+        observer.on_chunk_sent("s", 0, 10);
+        observer.on_resume("s", ResumeDecision::Fresh);
+        assert_eq!(*recorder.events.lock().unwrap(), vec!["s Fresh".to_string()]);
+    }
+}
@@ -26,4 +26,8 @@ pub struct ChunkPacket {
     /// Original (uncompressed) size of the chunk data
     #[prost(uint32, tag = "8")]
     pub original_size: u32,
+    /// Sibling hashes from this chunk up to the manifest's hash tree root
+    /// (only sent for tree-hash manifests)
+    #[prost(bytes = "vec", repeated, tag = "9")]
+    pub proof: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
 }
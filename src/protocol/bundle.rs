@@ -0,0 +1,312 @@
+// Small-file bundles
+//
+// Uploading many tiny files one transfer at a time is dominated by the
+// per-file manifest, resume and hash check exchanges. Small files are instead
+// concatenated into a bundle that travels as a single transfer; the bundle
+// index in its manifest records where each file sits so the server can
+// verify and unpack them once the bundle is complete.
+
+use crate::common::error::{Error, Result};
+use crate::protocol::directory::safe_relative_path;
+use crate::protocol::messages::{BundleIndex, BundleMember, FileMetadata};
+use crate::protocol::metadata::{self, MetadataPolicy};
+use prost::Message;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Files at or below this size are packed by default (256 KB)
+pub const DEFAULT_PACK_THRESHOLD: u64 = 256 * 1024;
+
+/// Largest bundle built before starting another (64 MB)
+const MAX_BUNDLE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Most files in one bundle, keeping the index well below a manifest frame
+const MAX_BUNDLE_FILES: usize = 4096;
+
+/// A local file to be packed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackMember {
+    pub path: PathBuf,
+    /// '/'-separated path under the upload root
+    pub relative_path: String,
+    pub size: u64,
+    pub mode: u32,
+}
+
+/// Groups small files into bundles and writes them out
+pub struct BundleBuilder {
+    output_dir: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    metadata: MetadataPolicy,
+}
+
+impl BundleBuilder {
+    /// Create a builder writing bundle files into `output_dir`
+    pub fn new(output_dir: impl AsRef<Path>) -> Self {
+        Self {
+            output_dir: output_dir.as_ref().to_path_buf(),
+            max_bytes: MAX_BUNDLE_BYTES,
+            max_files: MAX_BUNDLE_FILES,
+            metadata: MetadataPolicy::none(),
+        }
+    }
+
+    /// Limit the size and file count of each bundle
+    pub fn limits(mut self, max_bytes: u64, max_files: usize) -> Self {
+        self.max_bytes = max_bytes.max(1);
+        self.max_files = max_files.max(1);
+        self
+    }
+
+    /// Record each file's metadata, as far as `policy` keeps it, in the index
+    pub fn metadata(mut self, policy: MetadataPolicy) -> Self {
+        self.metadata = policy;
+        self
+    }
+
+    /// Split files into groups that each fit in one bundle, keeping their order
+    pub fn group(&self, members: Vec<PackMember>) -> Vec<Vec<PackMember>> {
+        let mut groups = Vec::new();
+        let mut current: Vec<PackMember> = Vec::new();
+        let mut current_bytes = 0u64;
+
+        for member in members {
+            let full = current.len() >= self.max_files
+                || (!current.is_empty() && current_bytes + member.size > self.max_bytes);
+            if full {
+                groups.push(std::mem::take(&mut current));
+                current_bytes = 0;
+            }
+            current_bytes += member.size;
+            current.push(member);
+        }
+
+        if !current.is_empty() {
+            groups.push(current);
+        }
+        groups
+    }
+
+    /// Concatenate files into a bundle file and describe them in an index
+    ///
+    /// The bundle is named after its index, leaving out file metadata, so
+    /// packing unchanged files again yields the same path (and session ID)
+    /// even after reading them moved their access times, which lets
+    /// interrupted bundle uploads resume.
+    pub fn write(&self, members: &[PackMember]) -> Result<(PathBuf, BundleIndex)> {
+        std::fs::create_dir_all(&self.output_dir)?;
+        let temp_path = self.output_dir.join(format!(".bundle-{}.tmp", std::process::id()));
+
+        let mut index = BundleIndex::default();
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        let mut offset = 0u64;
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        for member in members {
+            // Captured before reading, which may move the access time
+            let captured = metadata::capture(&member.path, &self.metadata)?;
+            let mut file = File::open(&member.path)?;
+            let mut hasher = blake3::Hasher::new();
+            let mut size = 0u64;
+
+            loop {
+                let read = file.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+                writer.write_all(&buffer[..read])?;
+                size += read as u64;
+            }
+
+            index.members.push(BundleMember {
+                relative_path: member.relative_path.clone(),
+                offset,
+                size,
+                mode: member.mode,
+                file_hash: hasher.finalize().as_bytes().to_vec(),
+                metadata: (captured != FileMetadata::default()).then_some(captured),
+            });
+            offset += size;
+        }
+
+        writer.flush()?;
+        drop(writer);
+
+        let mut contents_only = index.clone();
+        for member in &mut contents_only.members {
+            member.metadata = None;
+        }
+        let name = blake3::hash(&contents_only.encode_to_vec());
+        let bundle_path = self.output_dir.join(format!("bundle_{}.pack", &name.to_hex()[..16]));
+        std::fs::rename(&temp_path, &bundle_path)?;
+
+        Ok((bundle_path, index))
+    }
+}
+
+/// Split a received bundle back into its files under `upload_root`
+///
+/// Every file is checked against its hash before being written; nothing is
+/// written if any check fails. Once all are checked, `make_way` is asked
+/// where each goes, given its path: the path to write it to, or `None` to
+/// leave the file stored there as it is. An error from it also stops the
+/// unpacking before anything is written. File modes and metadata are
+/// applied as far as `policy` allows.
+///
+/// # Returns
+/// Path and size of each file, unpacked or left as stored
+pub fn unpack_bundle(
+    bundle_path: &Path,
+    index: &BundleIndex,
+    upload_root: &Path,
+    policy: &MetadataPolicy,
+    mut make_way: impl FnMut(&Path, &BundleMember) -> Result<Option<PathBuf>>,
+) -> Result<Vec<(PathBuf, u64)>> {
+    let data = std::fs::read(bundle_path)?;
+
+    let mut files = Vec::with_capacity(index.members.len());
+    for member in &index.members {
+        let relative = safe_relative_path(&member.relative_path)?;
+        let end = member.offset.checked_add(member.size)
+            .filter(|&end| end <= data.len() as u64)
+            .ok_or_else(|| Error::Protocol(format!(
+                "Bundle member {} lies outside the bundle", member.relative_path
+            )))?;
+
+        let contents = &data[member.offset as usize..end as usize];
+        let actual = blake3::hash(contents);
+        if actual.as_bytes()[..] != member.file_hash[..] {
+            return Err(Error::HashMismatch {
+                expected: member.file_hash.clone(),
+                actual: actual.as_bytes().to_vec(),
+            });
+        }
+        files.push((upload_root.join(relative), contents, member));
+    }
+
+    let mut targets = Vec::with_capacity(files.len());
+    for (path, contents, member) in files {
+        let target = make_way(&path, member)?;
+        targets.push((path, target, contents, member));
+    }
+
+    let mut unpacked = Vec::with_capacity(targets.len());
+    for (path, target, contents, member) in targets {
+        let Some(path) = target else {
+            unpacked.push((path, member.size));
+            continue;
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, contents)?;
+        metadata::apply(&path, member.mode, member.metadata.as_ref(), policy)?;
+        unpacked.push((path, contents.len() as u64));
+    }
+
+    Ok(unpacked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_in_place(path: &Path, _: &BundleMember) -> Result<Option<PathBuf>> {
+        Ok(Some(path.to_path_buf()))
+    }
+
+    fn members(dir: &Path, sizes: &[usize]) -> Vec<PackMember> {
+        sizes.iter().enumerate().map(|(i, &size)| {
+            let path = dir.join(format!("f{}.txt", i));
+            std::fs::write(&path, vec![i as u8; size]).unwrap();
+            PackMember {
+                path,
+                relative_path: format!("docs/sub/f{}.txt", i),
+                size: size as u64,
+                mode: 0o640,
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn test_group_respects_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let builder = BundleBuilder::new(temp_dir.path()).limits(100, 3);
+
+        let groups = builder.group(members(temp_dir.path(), &[40, 40, 40, 10, 10, 10, 10, 500]));
+        let sizes: Vec<Vec<u64>> = groups.iter()
+            .map(|g| g.iter().map(|m| m.size).collect())
+            .collect();
+        assert_eq!(sizes, vec![vec![40, 40], vec![40, 10, 10], vec![10, 10], vec![500]]);
+    }
+
+    #[test]
+    fn test_write_and_unpack_roundtrip() {
+        let source = TempDir::new().unwrap();
+        let files = members(source.path(), &[5, 0, 3000, 17]);
+        let builder = BundleBuilder::new(source.path().join("bundles"))
+            .metadata(MetadataPolicy::default());
+
+        let (bundle_path, index) = builder.write(&files).unwrap();
+        assert!(index.members.iter().all(|m| m.metadata.as_ref().is_some_and(|m| m.mtime_ns.is_some())));
+        assert_eq!(std::fs::metadata(&bundle_path).unwrap().len(), 3022);
+        assert_eq!(index.members[2].offset, 5);
+        assert_eq!(index.members[3].offset, 3005);
+
+        // Same files, same bundle
+        let (again, _) = builder.write(&files).unwrap();
+        assert_eq!(again, bundle_path);
+
+        let dest = TempDir::new().unwrap();
+        let unpacked = unpack_bundle(&bundle_path, &index, dest.path(), &MetadataPolicy::default(), write_in_place).unwrap();
+        assert_eq!(unpacked.len(), 4);
+        for (i, file) in files.iter().enumerate() {
+            let path = dest.path().join(format!("docs/sub/f{}.txt", i));
+            assert_eq!(std::fs::read(&path).unwrap(), std::fs::read(&file.path).unwrap());
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().modified().unwrap(),
+                std::fs::metadata(&file.path).unwrap().modified().unwrap(),
+            );
+        }
+
+        // Files may go elsewhere, or be left as stored
+        let elsewhere = TempDir::new().unwrap();
+        let unpacked = unpack_bundle(&bundle_path, &index, dest.path(), &MetadataPolicy::default(), |path, member| {
+            Ok((!member.relative_path.ends_with("f1.txt")).then(|| elsewhere.path().join(path.file_name().unwrap())))
+        }).unwrap();
+        assert_eq!(unpacked[0], (elsewhere.path().join("f0.txt"), 5));
+        assert_eq!(unpacked[1], (dest.path().join("docs/sub/f1.txt"), 0));
+        assert_eq!(std::fs::read(elsewhere.path().join("f2.txt")).unwrap(), vec![2u8; 3000]);
+        assert!(!elsewhere.path().join("f1.txt").exists());
+    }
+
+    #[test]
+    fn test_unpack_rejects_tampered_bundle() {
+        let source = TempDir::new().unwrap();
+        let files = members(source.path(), &[10, 20]);
+        let (bundle_path, mut index) = BundleBuilder::new(source.path()).write(&files).unwrap();
+
+        let dest = TempDir::new().unwrap();
+        let mut bad_hash = index.clone();
+        bad_hash.members[1].file_hash = vec![0; 32];
+        assert!(unpack_bundle(&bundle_path, &bad_hash, dest.path(), &MetadataPolicy::default(), write_in_place).is_err());
+        assert!(!dest.path().join("docs").exists());
+
+        // Nor when a file may not go where it belongs
+        let refused = unpack_bundle(&bundle_path, &index, dest.path(), &MetadataPolicy::default(), |path, member| {
+            match member.relative_path.ends_with("f1.txt") {
+                true => Err(Error::FileExists(path.display().to_string())),
+                false => Ok(Some(path.to_path_buf())),
+            }
+        });
+        assert!(refused.is_err());
+        assert!(!dest.path().join("docs").exists());
+
+        index.members[0].relative_path = "../escape.txt".to_string();
+        assert!(unpack_bundle(&bundle_path, &index, dest.path(), &MetadataPolicy::default(), write_in_place).is_err());
+    }
+}
@@ -4,9 +4,14 @@ use crate::proto::sftpx::protocol::ChunkPacket;
 use crate::chunking::compress::{CompressionType, compress_chunk};
 use prost::Message;
 
+/// Longest chunk packet a data stream may announce: the largest chunk a
+/// manifest may declare, with room for the packet's other fields
+const MAX_FRAME_LEN: usize = 100 * 1024 * 1024 + 64 * 1024;
+
 /// Builder for creating chunk packets using Protocol Buffers
 pub struct ChunkPacketBuilder {
     compression: CompressionType,
+    proof: Vec<Vec<u8>>,
 }
 
 impl ChunkPacketBuilder {
@@ -14,12 +19,22 @@ impl ChunkPacketBuilder {
     pub fn new() -> Self {
         Self {
             compression: CompressionType::None,
+            proof: Vec::new(),
         }
     }
     
     /// Create a new chunk packet builder with compression
     pub fn with_compression(compression: CompressionType) -> Self {
-        Self { compression }
+        Self {
+            compression,
+            proof: Vec::new(),
+        }
+    }
+
+    /// Attach a hash tree proof to the next packet built
+    pub fn with_proof(mut self, proof: Vec<Vec<u8>>) -> Self {
+        self.proof = proof;
+        self
     }
 
     /// Create a new chunk packet builder with specified capacity (ignored in protobuf)
@@ -76,6 +91,7 @@ impl ChunkPacketBuilder {
             data: final_data,
             compression_type,
             original_size,
+            proof: std::mem::take(&mut self.proof),
         };
 
         let mut buffer = Vec::with_capacity(packet.encoded_len());
@@ -120,6 +136,7 @@ impl ChunkPacketParser {
             checksum: packet.checksum,
             end_of_file: packet.end_of_file,
             data: final_data,
+            proof: packet.proof,
         })
     }
 
@@ -140,6 +157,7 @@ pub struct ChunkPacketView {
     pub checksum: Vec<u8>,  // Hash of original (decompressed) data
     pub end_of_file: bool,
     pub data: Vec<u8>,      // Decompressed data
+    pub proof: Vec<Vec<u8>>,  // Hash tree proof (empty for flat manifests)
 }
 
 impl ChunkPacketView {
@@ -163,6 +181,60 @@ impl ChunkPacketView {
     }
 }
 
+/// Prefix a chunk packet with its length, as it goes on a data stream
+pub fn frame_packet(packet: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(4 + packet.len());
+    framed.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+    framed.extend_from_slice(packet);
+    framed
+}
+
+/// Splits what is read from a data stream into the chunk packets on it
+///
+/// Each packet follows its length as a big-endian u32. QUIC streams keep no
+/// message boundaries, so one read may end inside a packet or hold several.
+#[derive(Debug, Default)]
+pub struct ChunkFrameReader {
+    buffer: Vec<u8>,
+}
+
+impl ChunkFrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add bytes read from the stream
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Take the next complete packet, without its length
+    ///
+    /// # Returns
+    /// `None` until a whole packet has been pushed
+    pub fn next_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(len_bytes) = self.buffer.get(..4) else {
+            return Ok(None);
+        };
+        let packet_len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        if packet_len > MAX_FRAME_LEN {
+            return Err(Error::Protocol(format!("Chunk packet of {} bytes is too large", packet_len)));
+        }
+        if self.buffer.len() - 4 < packet_len {
+            return Ok(None);
+        }
+
+        let packet = self.buffer[4..4 + packet_len].to_vec();
+        self.buffer.drain(..4 + packet_len);
+        Ok(Some(packet))
+    }
+
+    /// Bytes pushed that are not part of a packet taken yet
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -202,4 +274,63 @@ mod tests {
         assert_eq!(parsed.byte_offset, 1024);
         assert!(parsed.end_of_file);
     }
+
+    #[test]
+    fn test_proof_round_trip() {
+        let data = b"Proven chunk";
+        let proof = vec![vec![1u8; 32], vec![2u8; 32]];
+
+        let mut builder = ChunkPacketBuilder::new().with_proof(proof.clone());
+        let packet = builder
+            .build(3, 0, data.len() as u32, &[0u8; 32], false, data)
+            .unwrap();
+        assert_eq!(ChunkPacketParser::parse(&packet).unwrap().proof, proof);
+
+        // The proof belongs to one packet only
+        let packet = builder
+            .build(4, 0, data.len() as u32, &[0u8; 32], false, data)
+            .unwrap();
+        assert!(ChunkPacketParser::parse(&packet).unwrap().proof.is_empty());
+    }
+
+    #[test]
+    fn test_frames_split_across_and_within_reads() {
+        let packets: Vec<Vec<u8>> = (0..3u64)
+            .map(|chunk_id| {
+                let data = vec![chunk_id as u8; 100];
+                ChunkPacketBuilder::new()
+                    .build(chunk_id, chunk_id * 100, 100, blake3::hash(&data).as_bytes(), chunk_id == 2, &data)
+                    .unwrap()
+            })
+            .collect();
+        let stream: Vec<u8> = packets.iter().flat_map(|packet| frame_packet(packet)).collect();
+
+        // Reads of 7 bytes cut through prefixes and packets alike
+        let mut reader = ChunkFrameReader::new();
+        let mut chunk_ids = Vec::new();
+        for read in stream.chunks(7) {
+            reader.push(read);
+            while let Some(packet) = reader.next_packet().unwrap() {
+                let chunk = ChunkPacketParser::parse(&packet).unwrap();
+                chunk.verify_checksum().unwrap();
+                chunk_ids.push(chunk.chunk_id);
+            }
+        }
+        assert_eq!(chunk_ids, vec![0, 1, 2]);
+        assert_eq!(reader.buffered_bytes(), 0);
+
+        // One read holding every packet
+        reader.push(&stream);
+        for packet in &packets {
+            assert_eq!(&reader.next_packet().unwrap().unwrap(), packet);
+        }
+        assert!(reader.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_oversized_frame_rejected() {
+        let mut reader = ChunkFrameReader::new();
+        reader.push(&u32::MAX.to_be_bytes());
+        assert!(reader.next_packet().is_err());
+    }
 }
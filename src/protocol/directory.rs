@@ -0,0 +1,395 @@
+// Directory manifests for recursive uploads
+//
+// A recursive upload sends one directory manifest listing every directory
+// and file below the uploaded root, then uploads each non-empty file as its
+// own transfer whose manifest carries the file's path under the upload root.
+// Directories and empty files need no transfer; the receiver creates them
+// from the directory manifest alone, along with their metadata.
+
+use crate::common::error::{Error, Result};
+use crate::protocol::messages::{BundleMember, DirectoryEntry, DirectoryManifest, FileMetadata, Manifest};
+use crate::protocol::metadata::{self, MetadataPolicy};
+use std::fs::Metadata;
+use std::path::{Component, Path, PathBuf};
+
+/// A directory or file found while walking an upload root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeNode {
+    /// Local path of the entry
+    pub path: PathBuf,
+    /// '/'-separated path relative to the root
+    pub relative_path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mode: u32,
+}
+
+/// Walks a directory to be uploaded recursively
+pub struct DirectoryBuilder {
+    root: PathBuf,
+}
+
+impl DirectoryBuilder {
+    /// Create a builder for the directory at `root`
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Name the uploaded directory is recreated under on the server
+    pub fn root_name(&self) -> Result<String> {
+        let canonical = std::fs::canonicalize(&self.root)?;
+        canonical
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(str::to_string)
+            .ok_or_else(|| Error::Protocol(format!("Cannot upload {:?}: no directory name", self.root)))
+    }
+
+    /// List every directory and regular file below the root
+    ///
+    /// Entries are sorted by path with parents before their children.
+    /// Symlinks and special files are skipped.
+    pub fn walk(&self) -> Result<Vec<TreeNode>> {
+        if !std::fs::metadata(&self.root)?.is_dir() {
+            return Err(Error::Protocol(format!("{:?} is not a directory", self.root)));
+        }
+
+        let mut nodes = Vec::new();
+        self.walk_dir(&self.root, "", &mut nodes)?;
+        Ok(nodes)
+    }
+
+    fn walk_dir(&self, dir: &Path, prefix: &str, nodes: &mut Vec<TreeNode>) -> Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                log::warn!("Skipping {:?}: file name is not valid UTF-8", entry.path());
+                continue;
+            };
+
+            let path = entry.path();
+            let metadata = std::fs::symlink_metadata(&path)?;
+            let relative_path = if prefix.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+
+            if metadata.is_dir() {
+                nodes.push(TreeNode {
+                    path: path.clone(),
+                    relative_path: relative_path.clone(),
+                    is_dir: true,
+                    size: 0,
+                    mode: file_mode(&metadata),
+                });
+                self.walk_dir(&path, &relative_path, nodes)?;
+            } else if metadata.is_file() {
+                nodes.push(TreeNode {
+                    path,
+                    relative_path,
+                    is_dir: false,
+                    size: metadata.len(),
+                    mode: file_mode(&metadata),
+                });
+            } else {
+                log::warn!("Skipping {:?}: not a regular file or directory", path);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl DirectoryManifest {
+    /// Start an empty manifest for the directory `root_name`
+    pub fn new(root_name: impl Into<String>) -> Self {
+        Self {
+            root_name: root_name.into(),
+            entries: Vec::new(),
+            total_size: 0,
+        }
+    }
+
+    /// List a directory
+    pub fn add_directory(&mut self, relative_path: &str, mode: u32, metadata: Option<FileMetadata>) {
+        self.entries.push(DirectoryEntry {
+            relative_path: relative_path.to_string(),
+            is_dir: true,
+            file_size: 0,
+            mode,
+            session_id: String::new(),
+            file_hash: Vec::new(),
+            chunk_hashes: Vec::new(),
+            metadata,
+        });
+    }
+
+    /// List a file uploaded as its own transfer with `manifest`
+    pub fn add_file(&mut self, relative_path: &str, mode: u32, manifest: &Manifest) {
+        self.total_size += manifest.file_size;
+        self.entries.push(DirectoryEntry {
+            relative_path: relative_path.to_string(),
+            is_dir: false,
+            file_size: manifest.file_size,
+            mode,
+            session_id: manifest.session_id.clone(),
+            file_hash: manifest.file_hash.clone(),
+            chunk_hashes: manifest.chunk_hashes.clone(),
+            metadata: None,
+        });
+    }
+
+    /// List a file packed into the bundle transfer `bundle_session_id`
+    pub fn add_packed_file(&mut self, relative_path: &str, member: &BundleMember, bundle_session_id: &str) {
+        self.total_size += member.size;
+        self.entries.push(DirectoryEntry {
+            relative_path: relative_path.to_string(),
+            is_dir: false,
+            file_size: member.size,
+            mode: member.mode,
+            session_id: bundle_session_id.to_string(),
+            file_hash: member.file_hash.clone(),
+            chunk_hashes: Vec::new(),
+            metadata: None,
+        });
+    }
+
+    /// List an empty file, which is created without a transfer
+    pub fn add_empty_file(&mut self, relative_path: &str, mode: u32, metadata: Option<FileMetadata>) {
+        self.entries.push(DirectoryEntry {
+            relative_path: relative_path.to_string(),
+            is_dir: false,
+            file_size: 0,
+            mode,
+            session_id: String::new(),
+            file_hash: Vec::new(),
+            chunk_hashes: Vec::new(),
+            metadata,
+        });
+    }
+
+    /// Path of an entry under the upload root, as carried in its file manifest
+    pub fn upload_path(&self, relative_path: &str) -> String {
+        format!("{}/{}", self.root_name, relative_path)
+    }
+
+    /// Check that the root and every entry stay inside the upload root
+    pub fn validate(&self) -> Result<()> {
+        safe_relative_path(&self.root_name)?;
+        for entry in &self.entries {
+            safe_relative_path(&entry.relative_path)?;
+        }
+        Ok(())
+    }
+
+    /// Create the directories and empty files of the tree under `upload_root`,
+    /// applying empty files' metadata as far as `policy` allows
+    ///
+    /// Directory metadata is applied later by [`apply_directory_metadata`],
+    /// once the files inside them have been written.
+    ///
+    /// [`apply_directory_metadata`]: DirectoryManifest::apply_directory_metadata
+    pub fn create_skeleton(&self, upload_root: &Path, policy: &MetadataPolicy) -> Result<()> {
+        self.validate()?;
+        let root = upload_root.join(&self.root_name);
+        std::fs::create_dir_all(&root)?;
+
+        for entry in &self.entries {
+            let path = root.join(safe_relative_path(&entry.relative_path)?);
+            if entry.is_dir {
+                std::fs::create_dir_all(&path)?;
+            } else if entry.session_id.is_empty() {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::File::create(&path)?;
+                metadata::apply(&path, entry.mode, entry.metadata.as_ref(), policy)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply directory modes and times once every file of the tree is in place
+    ///
+    /// Applying them earlier could leave read-only directories that later
+    /// files cannot be written into, and writing those files would move the
+    /// directories' modification times.
+    ///
+    /// # Returns
+    /// Whether the tree was complete and the metadata was applied
+    pub fn apply_directory_metadata(&self, upload_root: &Path, policy: &MetadataPolicy) -> Result<bool> {
+        self.validate()?;
+        let root = upload_root.join(&self.root_name);
+
+        for entry in self.entries.iter().filter(|e| !e.is_dir) {
+            let path = root.join(safe_relative_path(&entry.relative_path)?);
+            match std::fs::metadata(&path) {
+                Ok(metadata) if metadata.len() == entry.file_size => {}
+                _ => return Ok(false),
+            }
+        }
+
+        // Children before parents, so a read-only parent is set last
+        for entry in self.entries.iter().rev().filter(|e| e.is_dir) {
+            let path = root.join(safe_relative_path(&entry.relative_path)?);
+            metadata::apply(&path, entry.mode, entry.metadata.as_ref(), policy)?;
+        }
+
+        Ok(true)
+    }
+}
+
+/// Turn a '/'-separated path from a peer into a relative local path
+///
+/// Rejects empty, absolute and `..` paths so the result always stays below
+/// the directory it is joined to.
+pub fn safe_relative_path(path: &str) -> Result<PathBuf> {
+    if path.is_empty() || path.contains('\\') || path.contains('\0') {
+        return Err(Error::Protocol(format!("Invalid relative path: {:?}", path)));
+    }
+
+    let mut result = PathBuf::new();
+    for part in path.split('/') {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => result.push(name),
+            _ => return Err(Error::Protocol(format!("Invalid relative path: {:?}", path))),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Unix permission bits of a file (0 on other platforms)
+pub fn file_mode(metadata: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        0
+    }
+}
+
+/// Apply Unix permission bits to a file; a mode of 0 leaves it unchanged
+///
+/// Setuid, setgid and sticky bits from a peer are dropped unless
+/// `special_bits` is set.
+pub fn set_mode(path: &Path, mode: u32, special_bits: bool) -> Result<()> {
+    #[cfg(unix)]
+    if mode != 0 {
+        use std::os::unix::fs::PermissionsExt;
+        let mask = if special_bits { 0o7777 } else { 0o777 };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & mask))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode, special_bits);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sample_tree() -> TempDir {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("photos");
+        std::fs::create_dir_all(root.join("2024/summer")).unwrap();
+        std::fs::create_dir_all(root.join("empty")).unwrap();
+        std::fs::write(root.join("2024/summer/beach.jpg"), vec![7u8; 3000]).unwrap();
+        std::fs::write(root.join("2024/notes.txt"), b"notes").unwrap();
+        std::fs::write(root.join("blank.txt"), b"").unwrap();
+        temp_dir
+    }
+
+    #[test]
+    fn test_walk_lists_parents_first() {
+        let temp_dir = sample_tree();
+        let builder = DirectoryBuilder::new(temp_dir.path().join("photos"));
+        assert_eq!(builder.root_name().unwrap(), "photos");
+
+        let nodes = builder.walk().unwrap();
+        let paths: Vec<&str> = nodes.iter().map(|n| n.relative_path.as_str()).collect();
+        assert_eq!(paths, [
+            "2024",
+            "2024/notes.txt",
+            "2024/summer",
+            "2024/summer/beach.jpg",
+            "blank.txt",
+            "empty",
+        ]);
+
+        let beach = &nodes[3];
+        assert!(!beach.is_dir);
+        assert_eq!(beach.size, 3000);
+        assert!(nodes[0].is_dir);
+        assert!(DirectoryBuilder::new(beach.path.clone()).walk().is_err());
+    }
+
+    #[test]
+    fn test_safe_relative_path() {
+        assert_eq!(safe_relative_path("a/b/c.txt").unwrap(), PathBuf::from("a").join("b").join("c.txt"));
+        for bad in ["", "/etc/passwd", "a/../../b", "a//b", "./a", "a/", "a\\b"] {
+            assert!(safe_relative_path(bad).is_err(), "{:?} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn test_skeleton_and_directory_modes() {
+        let source = sample_tree();
+        let nodes = DirectoryBuilder::new(source.path().join("photos")).walk().unwrap();
+
+        let mut tree = DirectoryManifest::new("photos");
+        for node in &nodes {
+            if node.is_dir {
+                tree.add_directory(&node.relative_path, 0o3755, None);
+            } else if node.size == 0 {
+                tree.add_empty_file(&node.relative_path, 0o644, None);
+            } else {
+                let manifest = Manifest {
+                    session_id: format!("s-{}", node.relative_path),
+                    file_size: node.size,
+                    ..Default::default()
+                };
+                tree.add_file(&node.relative_path, 0o644, &manifest);
+            }
+        }
+        assert_eq!(tree.total_size, 3005);
+        assert_eq!(tree.upload_path("2024/notes.txt"), "photos/2024/notes.txt");
+
+        let decoded = DirectoryManifest::decode_from_bytes(&tree.encode_to_vec()).unwrap();
+        assert_eq!(decoded, tree);
+
+        let dest = TempDir::new().unwrap();
+        tree.create_skeleton(dest.path(), &MetadataPolicy::default()).unwrap();
+        let root = dest.path().join("photos");
+        assert!(root.join("empty").is_dir());
+        assert!(root.join("2024/summer").is_dir());
+        assert_eq!(std::fs::read(root.join("blank.txt")).unwrap(), b"");
+
+        // Files with transfers are still missing
+        assert!(!tree.apply_directory_metadata(dest.path(), &MetadataPolicy::default()).unwrap());
+        std::fs::write(root.join("2024/summer/beach.jpg"), vec![7u8; 3000]).unwrap();
+        std::fs::write(root.join("2024/notes.txt"), b"notes").unwrap();
+        assert!(tree.apply_directory_metadata(dest.path(), &MetadataPolicy::default()).unwrap());
+        // Setgid and sticky bits from the sender are dropped
+        #[cfg(unix)]
+        assert_eq!(file_mode(&std::fs::metadata(root.join("empty")).unwrap()), 0o755);
+
+        let mut escaping = DirectoryManifest::new("photos");
+        escaping.add_directory("../outside", 0o755, None);
+        assert!(escaping.create_skeleton(dest.path(), &MetadataPolicy::default()).is_err());
+        assert!(!dest.path().join("outside").exists());
+    }
+}
@@ -1,16 +1,53 @@
 // Hash checking protocol for deduplication
 
+use crate::chunking::BloomFilter;
 use crate::common::error::{Error, Result};
 use crate::protocol::messages::{HashCheckRequest, HashCheckResponse};
+use crate::transport::TransferId;
 use prost::Message;
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+
+/// Chunks per batched hash check request
+pub const HASH_CHECK_BATCH: usize = 256;
+
+/// Batches sent ahead before waiting for responses
+pub const HASH_CHECK_WINDOW: usize = 4;
+
+/// Length-prefix and send one message without finishing the stream
+fn send_frame<M, F>(message: &M, mut send_fn: F) -> Result<usize>
+where
+    M: Message,
+    F: FnMut(&[u8], bool) -> Result<usize>,
+{
+    let body = message.encode_to_vec();
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    
+    let written = send_fn(&frame, false)?;
+    if written < frame.len() {
+        return Err(Error::Protocol(format!(
+            "Hash check frame truncated: {} of {} bytes written",
+            written,
+            frame.len()
+        )));
+    }
+    Ok(body.len())
+}
 
 /// Sender for hash check requests
-pub struct HashCheckRequestSender;
+pub struct HashCheckRequestSender {
+    transfer_id: TransferId,
+}
 
 impl HashCheckRequestSender {
     pub fn new() -> Self {
-        Self
+        Self::for_transfer(0)
+    }
+    
+    /// Sender for one transfer of a multiplexed connection
+    pub fn for_transfer(transfer_id: TransferId) -> Self {
+        Self { transfer_id }
     }
     
     /// Send a hash check request
@@ -31,6 +68,9 @@ impl HashCheckRequestSender {
         let request = HashCheckRequest {
             session_id,
             chunk_hashes,
+            chunk_ids: Vec::new(),
+            want_filter: false,
+            transfer_id: self.transfer_id,
         };
         
         let mut buf = Vec::new();
@@ -43,6 +83,46 @@ impl HashCheckRequestSender {
         
         Ok(buf.len())
     }
+    
+    /// Send one batch of a pipelined hash check, leaving the stream open
+    /// 
+    /// # Arguments
+    /// * `session_id` - Session identifier
+    /// * `chunk_ids` - Chunk IDs in the batch
+    /// * `chunk_hashes` - Hashes of those chunks, in the same order
+    /// * `send_fn` - Callback to send data over the network
+    pub fn send_batch<F>(
+        &self,
+        session_id: String,
+        chunk_ids: Vec<u64>,
+        chunk_hashes: Vec<Vec<u8>>,
+        send_fn: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&[u8], bool) -> Result<usize>,
+    {
+        send_frame(&HashCheckRequest {
+            session_id,
+            chunk_hashes,
+            chunk_ids,
+            want_filter: false,
+            transfer_id: self.transfer_id,
+        }, send_fn)
+    }
+    
+    /// Ask for a Bloom filter of the receiver's chunk index
+    pub fn request_filter<F>(&self, session_id: String, send_fn: F) -> Result<usize>
+    where
+        F: FnMut(&[u8], bool) -> Result<usize>,
+    {
+        send_frame(&HashCheckRequest {
+            session_id,
+            chunk_hashes: Vec::new(),
+            chunk_ids: Vec::new(),
+            want_filter: true,
+            transfer_id: self.transfer_id,
+        }, send_fn)
+    }
 }
 
 /// Receiver for hash check requests
@@ -86,27 +166,45 @@ impl HashCheckRequestReceiver {
                 let request = HashCheckRequest::decode(&self.buffer[..expected])
                     .map_err(|e| Error::Protocol(format!("Failed to decode hash check request: {}", e)))?;
                 
-                self.buffer.clear();
+                self.buffer.drain(..expected);
                 self.expected_length = None;
                 
                 return Ok(Some(request));
             }
         }
         
-        if fin && self.expected_length.is_none() {
+        if fin && !self.buffer.is_empty() {
             return Err(Error::Protocol("Hash check request incomplete".to_string()));
         }
         
         Ok(None)
     }
+    
+    /// Receive data carrying any number of pipelined requests
+    pub fn receive_all(&mut self, data: &[u8], fin: bool) -> Result<Vec<HashCheckRequest>> {
+        let mut requests = Vec::new();
+        let mut next = self.receive_chunk(data, fin)?;
+        while let Some(request) = next {
+            requests.push(request);
+            next = self.receive_chunk(&[], fin)?;
+        }
+        Ok(requests)
+    }
 }
 
 /// Sender for hash check responses
-pub struct HashCheckResponseSender;
+pub struct HashCheckResponseSender {
+    transfer_id: TransferId,
+}
 
 impl HashCheckResponseSender {
     pub fn new() -> Self {
-        Self
+        Self::for_transfer(0)
+    }
+    
+    /// Sender for one transfer of a multiplexed connection
+    pub fn for_transfer(transfer_id: TransferId) -> Self {
+        Self { transfer_id }
     }
     
     /// Send a hash check response
@@ -128,6 +226,9 @@ impl HashCheckResponseSender {
             session_id,
             existing_hashes,
             existing_bitmap: None, // TODO: Implement bitmap for efficiency
+            existing_chunks: Vec::new(),
+            bloom_filter: None,
+            transfer_id: self.transfer_id,
         };
         
         let mut buf = Vec::new();
@@ -140,6 +241,46 @@ impl HashCheckResponseSender {
         
         Ok(buf.len())
     }
+    
+    /// Answer one batch of a pipelined hash check
+    /// 
+    /// # Arguments
+    /// * `session_id` - Session identifier
+    /// * `existing_chunks` - Chunk IDs from the batch that need not be sent
+    /// * `send_fn` - Callback to send data over the network
+    pub fn send_batch_response<F>(
+        &self,
+        session_id: String,
+        existing_chunks: Vec<u64>,
+        send_fn: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&[u8], bool) -> Result<usize>,
+    {
+        send_frame(&HashCheckResponse {
+            session_id,
+            existing_hashes: Vec::new(),
+            existing_bitmap: None,
+            existing_chunks,
+            bloom_filter: None,
+            transfer_id: self.transfer_id,
+        }, send_fn)
+    }
+    
+    /// Send a Bloom filter of the chunk index (`BloomFilter::to_bytes`)
+    pub fn send_filter<F>(&self, session_id: String, filter: Vec<u8>, send_fn: F) -> Result<usize>
+    where
+        F: FnMut(&[u8], bool) -> Result<usize>,
+    {
+        send_frame(&HashCheckResponse {
+            session_id,
+            existing_hashes: Vec::new(),
+            existing_bitmap: None,
+            existing_chunks: Vec::new(),
+            bloom_filter: Some(filter),
+            transfer_id: self.transfer_id,
+        }, send_fn)
+    }
 }
 
 /// Receiver for hash check responses
@@ -183,26 +324,233 @@ impl HashCheckResponseReceiver {
                 let response = HashCheckResponse::decode(&self.buffer[..expected])
                     .map_err(|e| Error::Protocol(format!("Failed to decode hash check response: {}", e)))?;
                 
-                self.buffer.clear();
+                self.buffer.drain(..expected);
                 self.expected_length = None;
                 
                 return Ok(Some(response));
             }
         }
         
-        if fin && self.expected_length.is_none() {
+        if fin && !self.buffer.is_empty() {
             return Err(Error::Protocol("Hash check response incomplete".to_string()));
         }
         
         Ok(None)
     }
     
+    /// Receive data carrying any number of pipelined responses
+    pub fn receive_all(&mut self, data: &[u8], fin: bool) -> Result<Vec<HashCheckResponse>> {
+        let mut responses = Vec::new();
+        let mut next = self.receive_chunk(data, fin)?;
+        while let Some(response) = next {
+            responses.push(response);
+            next = self.receive_chunk(&[], fin)?;
+        }
+        Ok(responses)
+    }
+    
     /// Convert response to a HashSet for efficient lookups
     pub fn to_hash_set(response: &HashCheckResponse) -> HashSet<Vec<u8>> {
         response.existing_hashes.iter().cloned().collect()
     }
 }
 
+/// What to do with a chunk during a pipelined hash check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupDecision {
+    /// No answer yet
+    Pending,
+    /// Receiver does not have the chunk
+    Send,
+    /// Receiver already has the chunk
+    Skip,
+}
+
+/// Client side of the batched hash check
+/// 
+/// Requests run ahead of the data being sent, up to `HASH_CHECK_WINDOW`
+/// batches of `HASH_CHECK_BATCH` chunks, so answers are usually in before
+/// the sender reaches a chunk. With a Bloom filter from the receiver, chunks
+/// the filter rules out are decided locally and never asked about.
+pub struct HashCheckPipeline {
+    session_id: String,
+    transfer_id: TransferId,
+    chunk_hashes: Vec<Vec<u8>>,
+    decisions: Vec<DedupDecision>,
+    filter: Option<BloomFilter>,
+    awaiting_filter: bool,
+    next_chunk: usize,
+    in_flight: VecDeque<Vec<u64>>,
+    sender: HashCheckRequestSender,
+    receiver: HashCheckResponseReceiver,
+    filtered: u64,
+    abandoned: bool,
+}
+
+impl HashCheckPipeline {
+    /// Create a pipeline over a manifest's chunk hashes
+    /// 
+    /// # Arguments
+    /// * `session_id` - Session identifier
+    /// * `chunk_hashes` - Chunk hashes in chunk order
+    /// * `already_present` - Chunks known not to need sending (e.g. resumed)
+    pub fn new(session_id: String, chunk_hashes: Vec<Vec<u8>>, already_present: &HashSet<u64>) -> Self {
+        let decisions = (0..chunk_hashes.len() as u64)
+            .map(|chunk_id| {
+                if already_present.contains(&chunk_id) {
+                    DedupDecision::Skip
+                } else {
+                    DedupDecision::Pending
+                }
+            })
+            .collect();
+        
+        Self {
+            session_id,
+            transfer_id: 0,
+            chunk_hashes,
+            decisions,
+            filter: None,
+            awaiting_filter: false,
+            next_chunk: 0,
+            in_flight: VecDeque::new(),
+            sender: HashCheckRequestSender::new(),
+            receiver: HashCheckResponseReceiver::new(),
+            filtered: 0,
+            abandoned: false,
+        }
+    }
+    
+    /// Run the pipeline for one transfer of a multiplexed connection
+    pub fn for_transfer(mut self, transfer_id: TransferId) -> Self {
+        self.transfer_id = transfer_id;
+        self.sender = HashCheckRequestSender::for_transfer(transfer_id);
+        self
+    }
+    
+    /// Ask for the receiver's Bloom filter before sending any batches
+    pub fn request_filter<F>(&mut self, send_fn: F) -> Result<()>
+    where
+        F: FnMut(&[u8], bool) -> Result<usize>,
+    {
+        self.sender.request_filter(self.session_id.clone(), send_fn)?;
+        self.awaiting_filter = true;
+        Ok(())
+    }
+    
+    /// Send batches until the window is full
+    pub fn pump<F>(&mut self, mut send_fn: F) -> Result<()>
+    where
+        F: FnMut(&[u8], bool) -> Result<usize>,
+    {
+        if self.awaiting_filter || self.abandoned {
+            return Ok(());
+        }
+        
+        while self.in_flight.len() < HASH_CHECK_WINDOW && self.next_chunk < self.chunk_hashes.len() {
+            let mut chunk_ids = Vec::new();
+            let mut chunk_hashes = Vec::new();
+            
+            while chunk_ids.len() < HASH_CHECK_BATCH && self.next_chunk < self.chunk_hashes.len() {
+                let chunk_id = self.next_chunk;
+                self.next_chunk += 1;
+                
+                if self.decisions[chunk_id] != DedupDecision::Pending {
+                    continue;
+                }
+                
+                let hash = &self.chunk_hashes[chunk_id];
+                if self.filter.as_ref().is_some_and(|filter| !filter.contains(hash)) {
+                    self.decisions[chunk_id] = DedupDecision::Send;
+                    self.filtered += 1;
+                    continue;
+                }
+                
+                chunk_ids.push(chunk_id as u64);
+                chunk_hashes.push(hash.clone());
+            }
+            
+            if !chunk_ids.is_empty() {
+                self.sender.send_batch(self.session_id.clone(), chunk_ids.clone(), chunk_hashes, &mut send_fn)?;
+                self.in_flight.push_back(chunk_ids);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Feed data read from the hash check stream
+    pub fn receive(&mut self, data: &[u8], fin: bool) -> Result<()> {
+        for response in self.receiver.receive_all(data, fin)? {
+            if self.abandoned {
+                continue;
+            }
+            
+            if response.transfer_id != self.transfer_id {
+                return Err(Error::Protocol(format!(
+                    "Hash check response for transfer {} on transfer {}",
+                    response.transfer_id, self.transfer_id
+                )));
+            }
+            
+            if let Some(filter) = &response.bloom_filter {
+                self.filter = Some(BloomFilter::from_bytes(filter)?);
+                self.awaiting_filter = false;
+                continue;
+            }
+            
+            let batch = self.in_flight.pop_front().ok_or_else(|| {
+                Error::Protocol("Hash check response without a pending batch".to_string())
+            })?;
+            
+            let existing: HashSet<u64> = response.existing_chunks.iter().copied().collect();
+            if existing.iter().any(|chunk_id| !batch.contains(chunk_id)) {
+                return Err(Error::Protocol("Hash check response names chunks outside its batch".to_string()));
+            }
+            
+            for chunk_id in batch {
+                self.decisions[chunk_id as usize] = if existing.contains(&chunk_id) {
+                    DedupDecision::Skip
+                } else {
+                    DedupDecision::Send
+                };
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Decision for a chunk; unknown chunks are always sent
+    pub fn decision(&self, chunk_id: u64) -> DedupDecision {
+        self.decisions
+            .get(chunk_id as usize)
+            .copied()
+            .unwrap_or(DedupDecision::Send)
+    }
+    
+    /// Stop waiting for answers and send every undecided chunk
+    pub fn abandon(&mut self) {
+        self.abandoned = true;
+        self.awaiting_filter = false;
+        self.in_flight.clear();
+        for decision in &mut self.decisions {
+            if *decision == DedupDecision::Pending {
+                *decision = DedupDecision::Send;
+            }
+        }
+    }
+    
+    /// Chunks decided locally by the Bloom filter
+    pub fn filtered_chunks(&self) -> u64 {
+        self.filtered
+    }
+    
+    /// Batches sent but not yet answered
+    pub fn batches_in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -249,4 +597,114 @@ mod tests {
         assert_eq!(response.session_id, session_id);
         assert_eq!(response.existing_hashes, existing_hashes);
     }
+
+    /// Answer every framed request in `wire` the way a receiver holding
+    /// `held` would, returning the framed responses
+    fn answer(wire: &[u8], held: &HashSet<Vec<u8>>) -> Vec<u8> {
+        let mut receiver = HashCheckRequestReceiver::new();
+        let sender = HashCheckResponseSender::new();
+        let mut responses = Vec::new();
+        
+        for request in receiver.receive_all(wire, false).unwrap() {
+            let mut write = |data: &[u8], _fin: bool| -> Result<usize> {
+                responses.extend_from_slice(data);
+                Ok(data.len())
+            };
+            
+            if request.want_filter {
+                let mut filter = BloomFilter::with_capacity(held.len(), 0.01);
+                held.iter().for_each(|hash| filter.insert(hash));
+                sender.send_filter(request.session_id, filter.to_bytes(), &mut write).unwrap();
+            } else {
+                let existing = request.chunk_ids.iter()
+                    .zip(&request.chunk_hashes)
+                    .filter(|(_, hash)| held.contains(*hash))
+                    .map(|(&chunk_id, _)| chunk_id)
+                    .collect();
+                sender.send_batch_response(request.session_id, existing, &mut write).unwrap();
+            }
+        }
+        
+        responses
+    }
+    
+    fn chunk_hashes(count: u64) -> Vec<Vec<u8>> {
+        (0..count).map(|i| blake3::hash(&i.to_le_bytes()).as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_pipeline_batches_and_window() {
+        let hashes = chunk_hashes(2000);
+        let held: HashSet<Vec<u8>> = hashes.iter().step_by(5).cloned().collect();
+        let resumed: HashSet<u64> = [1, 2].into_iter().collect();
+        let mut pipeline = HashCheckPipeline::new("s".to_string(), hashes, &resumed);
+        
+        let mut wire = Vec::new();
+        pipeline.pump(|data, _| { wire.extend_from_slice(data); Ok(data.len()) }).unwrap();
+        assert_eq!(pipeline.batches_in_flight(), HASH_CHECK_WINDOW);
+        assert_eq!(pipeline.decision(0), DedupDecision::Pending);
+        assert_eq!(pipeline.decision(1), DedupDecision::Skip);
+        
+        // Responses may arrive split at arbitrary points
+        let responses = answer(&wire, &held);
+        let (first, rest) = responses.split_at(responses.len() / 3);
+        pipeline.receive(first, false).unwrap();
+        pipeline.receive(rest, false).unwrap();
+        assert_eq!(pipeline.batches_in_flight(), 0);
+        
+        for chunk_id in 0..(HASH_CHECK_WINDOW * HASH_CHECK_BATCH) as u64 {
+            let expected = if chunk_id % 5 == 0 || resumed.contains(&chunk_id) {
+                DedupDecision::Skip
+            } else {
+                DedupDecision::Send
+            };
+            assert_eq!(pipeline.decision(chunk_id), expected, "chunk {}", chunk_id);
+        }
+        assert_eq!(pipeline.decision(1999), DedupDecision::Pending);
+        
+        // Next window covers the rest
+        let mut wire = Vec::new();
+        pipeline.pump(|data, _| { wire.extend_from_slice(data); Ok(data.len()) }).unwrap();
+        pipeline.receive(&answer(&wire, &held), false).unwrap();
+        assert_eq!(pipeline.decision(1995), DedupDecision::Skip);
+        assert_eq!(pipeline.decision(1999), DedupDecision::Send);
+    }
+
+    #[test]
+    fn test_pipeline_bloom_filter() {
+        let hashes = chunk_hashes(1000);
+        let held: HashSet<Vec<u8>> = hashes[..10].iter().cloned().collect();
+        let mut pipeline = HashCheckPipeline::new("s".to_string(), hashes, &HashSet::new());
+        
+        let mut wire = Vec::new();
+        pipeline.request_filter(|data, _| { wire.extend_from_slice(data); Ok(data.len()) }).unwrap();
+        
+        // Nothing is asked until the filter arrives
+        pipeline.pump(|_, _| panic!("batch sent before filter")).unwrap();
+        pipeline.receive(&answer(&wire, &held), false).unwrap();
+        
+        let mut wire = Vec::new();
+        pipeline.pump(|data, _| { wire.extend_from_slice(data); Ok(data.len()) }).unwrap();
+        pipeline.receive(&answer(&wire, &held), false).unwrap();
+        
+        // Only filter hits needed a round trip
+        assert!(pipeline.filtered_chunks() > 950);
+        assert!((0..10).all(|c| pipeline.decision(c) == DedupDecision::Skip));
+        assert!((10..1000).all(|c| pipeline.decision(c) == DedupDecision::Send));
+    }
+
+    #[test]
+    fn test_pipeline_abandon() {
+        let mut pipeline = HashCheckPipeline::new("s".to_string(), chunk_hashes(10), &HashSet::new());
+        let mut wire = Vec::new();
+        pipeline.pump(|data, _| { wire.extend_from_slice(data); Ok(data.len()) }).unwrap();
+        
+        pipeline.abandon();
+        assert_eq!(pipeline.decision(3), DedupDecision::Send);
+        
+        // Late answers are ignored
+        let held: HashSet<Vec<u8>> = chunk_hashes(10).into_iter().collect();
+        pipeline.receive(&answer(&wire, &held), false).unwrap();
+        assert_eq!(pipeline.decision(3), DedupDecision::Send);
+    }
 }
@@ -1,9 +1,15 @@
 // Manifest message structures
 
 use crate::common::error::{Error, Result};
-use crate::protocol::messages::Manifest;
+use crate::chunking::encrypt::{ChunkCipher, SEALED_OVERHEAD};
+use crate::chunking::parallel::check_cancelled;
+use crate::chunking::sparse;
+use crate::chunking::tree::HashTree;
+use crate::protocol::messages::{ChunkRange, Manifest};
 use std::fs::File;
 use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 /// Default chunk size (1 MB)
 const DEFAULT_CHUNK_SIZE: u32 = 1024 * 1024;
@@ -15,6 +21,9 @@ pub struct ManifestBuilder {
     file_path: Option<std::path::PathBuf>,
     chunk_size: u32,
     compression: String,
+    cipher: Option<ChunkCipher>,
+    detect_holes: bool,
+    cancelled: Option<Arc<AtomicBool>>,
 }
 
 impl ManifestBuilder {
@@ -28,6 +37,9 @@ impl ManifestBuilder {
             file_path: None,
             chunk_size: DEFAULT_CHUNK_SIZE,
             compression: "none".to_string(),
+            cipher: None,
+            detect_holes: false,
+            cancelled: None,
         }
     }
 
@@ -49,6 +61,33 @@ impl ManifestBuilder {
         self
     }
 
+    /// Describe the file as sealed by `cipher`
+    /// 
+    /// Sizes and hashes in the resulting manifest cover the encrypted chunks,
+    /// so the server can verify and deduplicate without seeing plaintext.
+    pub fn encryption(mut self, cipher: ChunkCipher) -> Self {
+        self.chunk_size = cipher.plain_chunk_size();
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// List the chunks lying in holes of a sparse file instead of reading them
+    /// 
+    /// Their hashes are those of zeros and senders skip them. Sealed chunks
+    /// are never zeros, so holes are not looked for with encryption.
+    pub fn detect_holes(mut self) -> Self {
+        self.detect_holes = true;
+        self
+    }
+
+    /// Stop hashing with `Error::Cancelled` once `cancelled` is set
+    /// 
+    /// It is checked between chunks, so a large file can be given up part way.
+    pub fn cancel_flag(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.cancelled = Some(cancelled);
+        self
+    }
+
     /// Build the manifest by reading and hashing the file
     /// 
     /// # Returns
@@ -63,6 +102,19 @@ impl ManifestBuilder {
         self.build_internal(true)
     }
     
+    /// Build a tree-hash manifest for very large files
+    /// 
+    /// Only the root of the chunk hash tree is placed in the manifest, so its
+    /// size no longer grows with the file. The returned tree stays with the
+    /// sender, which attaches each chunk's proof to its packet.
+    pub fn build_tree(self) -> Result<(Manifest, HashTree)> {
+        let mut manifest = self.build_internal(true)?;
+        let tree = HashTree::from_chunk_hashes(&manifest.chunk_hashes)?;
+        manifest.chunk_hashes = Vec::new();
+        manifest.tree_root = Some(tree.root().to_vec());
+        Ok((manifest, tree))
+    }
+    
     fn build_internal(self, use_parallel: bool) -> Result<Manifest> {
         let file_path = self.file_path.ok_or_else(|| {
             Error::Protocol("File path not set".to_string())
@@ -73,10 +125,6 @@ impl ManifestBuilder {
         let metadata = file.metadata()?;
         let file_size = metadata.len();
 
-        if file_size == 0 {
-            return Err(Error::Protocol("File is empty".to_string()));
-        }
-
         // Get file name
         let file_name = file_path
             .file_name()
@@ -84,20 +132,69 @@ impl ManifestBuilder {
             .ok_or_else(|| Error::Protocol("Invalid file name".to_string()))?
             .to_string();
 
-        // Calculate total chunks
+        // Calculate total chunks; an empty file has none
         let total_chunks = (file_size + self.chunk_size as u64 - 1) / self.chunk_size as u64;
+        let cancelled = self.cancelled.as_deref();
+
+        if let Some(cipher) = &self.cipher {
+            let (chunk_hashes, file_hash) = Self::compute_sealed_hashes(
+                &file_path,
+                file_size,
+                total_chunks,
+                self.chunk_size,
+                cipher,
+                cancelled,
+            )?;
+
+            return Ok(Manifest {
+                session_id: self.session_id,
+                file_name,
+                file_size: file_size + total_chunks * SEALED_OVERHEAD as u64,
+                chunk_size: cipher.sealed_chunk_size(),
+                total_chunks,
+                file_hash,
+                chunk_hashes,
+                // Ciphertext does not compress
+                compression: "none".to_string(),
+                original_size: Some(file_size),
+                encryption: Some(cipher.info().clone()),
+                signature: None,
+                tree_root: None,
+                hash_pages: 0,
+                transfer_id: 0,
+                relative_path: String::new(),
+                mode: 0,
+                bundle: None,
+                stripe: None,
+                data_streams: Vec::new(),
+                streaming: false,
+                metadata: None,
+                holes: Vec::new(),
+                conflict_policy: 0,
+            });
+        }
+
+        let holes = if self.detect_holes {
+            sparse::detect_holes(&file_path, self.chunk_size)?
+        } else {
+            Vec::new()
+        };
+        if !holes.is_empty() {
+            log::info!("Sparse file: {} of {} chunks lie in holes",
+                sparse::hole_count(&holes, 0..total_chunks), total_chunks);
+        }
 
         // Compute chunk hashes - use parallel version if requested and file is large enough
         let chunk_hashes = if use_parallel && total_chunks > 4 {
-            use crate::chunking::compute_chunk_hashes_parallel;
-            compute_chunk_hashes_parallel(&file_path, self.chunk_size as usize)?
+            use crate::chunking::parallel::compute_sparse_chunk_hashes;
+            compute_sparse_chunk_hashes(&file_path, self.chunk_size as usize, &holes, cancelled)?
         } else {
             // Sequential version for small files
-            Self::compute_hashes_sequential(&file_path, file_size, total_chunks, self.chunk_size)?
+            Self::compute_hashes_sequential(&file_path, file_size, total_chunks, self.chunk_size, &holes, cancelled)?
         };
         
         // Compute file hash (always sequential since we need to read entire file)
-        let file_hash_bytes = Self::compute_file_hash(&file_path, file_size, self.chunk_size)?;
+        let file_hash_bytes = Self::compute_file_hash(&file_path, file_size, self.chunk_size, &holes, cancelled)?;
 
         // Create manifest
         let manifest = Manifest {
@@ -114,17 +211,35 @@ impl ManifestBuilder {
             } else {
                 Some(file_size)
             },
+            encryption: None,
+            signature: None,
+            tree_root: None,
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes,
+            conflict_policy: 0,
         };
 
         Ok(manifest)
     }
     
     /// Compute chunk hashes sequentially (for small files or fallback)
+    /// 
+    /// Chunks in `holes` are hashed as zeros without being read.
     fn compute_hashes_sequential(
         file_path: &Path,
         file_size: u64,
         total_chunks: u64,
         chunk_size: u32,
+        holes: &[ChunkRange],
+        cancelled: Option<&AtomicBool>,
     ) -> Result<Vec<Vec<u8>>> {
         use std::io::{Read, Seek, SeekFrom};
         use crate::chunking::hasher::ChunkHasher;
@@ -137,10 +252,18 @@ impl ManifestBuilder {
         file.seek(SeekFrom::Start(0))?;
 
         // Process each chunk
-        for _ in 0..total_chunks {
+        for chunk_id in 0..total_chunks {
+            check_cancelled(cancelled)?;
             let remaining = file_size - bytes_read_total;
             let to_read = std::cmp::min(remaining, chunk_size as u64) as usize;
 
+            if sparse::is_hole(holes, chunk_id) {
+                chunk_hashes.push(sparse::zero_chunk_hash(to_read));
+                bytes_read_total += to_read as u64;
+                file.seek(SeekFrom::Start(bytes_read_total))?;
+                continue;
+            }
+
             // Read chunk
             let bytes_read = file.read(&mut buffer[..to_read])?;
             if bytes_read == 0 {
@@ -159,26 +282,74 @@ impl ManifestBuilder {
         Ok(chunk_hashes)
     }
     
-    /// Compute file hash
-    fn compute_file_hash(file_path: &Path, file_size: u64, chunk_size: u32) -> Result<Vec<u8>> {
+    /// Seal every chunk and hash the ciphertext
+    /// 
+    /// # Returns
+    /// Per-chunk hashes and the hash of the whole sealed file
+    fn compute_sealed_hashes(
+        file_path: &Path,
+        file_size: u64,
+        total_chunks: u64,
+        chunk_size: u32,
+        cipher: &ChunkCipher,
+        cancelled: Option<&AtomicBool>,
+    ) -> Result<(Vec<Vec<u8>>, Vec<u8>)> {
         use std::io::Read;
+        use crate::chunking::hasher::ChunkHasher;
+        
+        let mut file = File::open(file_path)?;
+        let mut chunk_hashes = Vec::with_capacity(total_chunks as usize);
+        let mut file_hasher = blake3::Hasher::new();
+        let mut buffer = vec![0u8; chunk_size as usize];
+
+        for chunk_id in 0..total_chunks {
+            check_cancelled(cancelled)?;
+            let offset = chunk_id * chunk_size as u64;
+            let to_read = std::cmp::min(file_size - offset, chunk_size as u64) as usize;
+            file.read_exact(&mut buffer[..to_read])?;
+
+            let sealed = cipher.seal(chunk_id, &buffer[..to_read])?;
+            chunk_hashes.push(ChunkHasher::hash(&sealed));
+            file_hasher.update(&sealed);
+        }
+
+        Ok((chunk_hashes, file_hasher.finalize().as_bytes().to_vec()))
+    }
+    
+    /// Compute file hash
+    /// 
+    /// Chunks in `holes` are hashed as zeros without being read.
+    fn compute_file_hash(
+        file_path: &Path,
+        file_size: u64,
+        chunk_size: u32,
+        holes: &[ChunkRange],
+        cancelled: Option<&AtomicBool>,
+    ) -> Result<Vec<u8>> {
+        use std::io::{Read, Seek, SeekFrom};
         
         let mut file = File::open(file_path)?;
         let mut file_hasher = blake3::Hasher::new();
         let mut buffer = vec![0u8; chunk_size as usize];
+        let zeros = if holes.is_empty() { Vec::new() } else { vec![0u8; chunk_size as usize] };
         let mut bytes_read_total = 0u64;
 
         while bytes_read_total < file_size {
+            check_cancelled(cancelled)?;
             let remaining = file_size - bytes_read_total;
             let to_read = std::cmp::min(remaining, chunk_size as u64) as usize;
             
-            let bytes_read = file.read(&mut buffer[..to_read])?;
-            if bytes_read == 0 {
-                break;
+            if sparse::is_hole(holes, bytes_read_total / chunk_size as u64) {
+                file_hasher.update(&zeros[..to_read]);
+                bytes_read_total += to_read as u64;
+                file.seek(SeekFrom::Start(bytes_read_total))?;
+                continue;
             }
             
-            file_hasher.update(&buffer[..bytes_read]);
-            bytes_read_total += bytes_read as u64;
+            // Whole chunks keep the position aligned for the hole check
+            file.read_exact(&mut buffer[..to_read])?;
+            file_hasher.update(&buffer[..to_read]);
+            bytes_read_total += to_read as u64;
         }
         
         let file_hash = file_hasher.finalize();
@@ -249,6 +420,20 @@ impl ManifestBuilder {
             } else {
                 Some(file_size)
             },
+            encryption: None,
+            signature: None,
+            tree_root: None,
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes: Vec::new(),
+            conflict_policy: 0,
         };
 
         Ok(manifest)
@@ -402,6 +587,89 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_manifest_builder_with_encryption() {
+        use crate::chunking::encrypt::EncryptionConfig;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let test_data = vec![7u8; 100];
+        temp_file.write_all(&test_data).unwrap();
+        temp_file.flush().unwrap();
+
+        let config = EncryptionConfig::passphrase("secret").with_kdf_iterations(1000);
+        let cipher = ChunkCipher::for_session(&config, "session-enc", 30).unwrap();
+
+        let plain = ManifestBuilder::new("session-enc")
+            .file_path(temp_file.path())
+            .chunk_size(30)
+            .build()
+            .unwrap();
+        let manifest = ManifestBuilder::new("session-enc")
+            .file_path(temp_file.path())
+            .encryption(cipher)
+            .build()
+            .unwrap();
+
+        assert_eq!(manifest.total_chunks, 4);
+        assert_eq!(manifest.chunk_size, 30 + SEALED_OVERHEAD as u32);
+        assert_eq!(manifest.file_size, 100 + 4 * SEALED_OVERHEAD as u64);
+        assert_eq!(manifest.original_size, Some(100));
+        assert!(manifest.encryption.is_some());
+
+        // Hashes must not reveal the plaintext
+        assert_ne!(manifest.file_hash, plain.file_hash);
+        assert_ne!(manifest.chunk_hashes[0], plain.chunk_hashes[0]);
+    }
+
+    #[test]
+    fn test_manifest_builder_tree() {
+        use crate::chunking::tree::TreeVerifier;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(&[3u8; 100]).unwrap();
+        temp_file.flush().unwrap();
+
+        let flat = ManifestBuilder::new("session-tree")
+            .file_path(temp_file.path())
+            .chunk_size(30)
+            .build()
+            .unwrap();
+        let (manifest, tree) = ManifestBuilder::new("session-tree")
+            .file_path(temp_file.path())
+            .chunk_size(30)
+            .build_tree()
+            .unwrap();
+
+        assert_eq!(manifest.total_chunks, 4);
+        assert!(manifest.chunk_hashes.is_empty());
+        assert_eq!(manifest.file_hash, flat.file_hash);
+        assert_eq!(manifest.tree_root.as_deref(), Some(tree.root()));
+
+        let verifier = TreeVerifier::new(tree.root(), manifest.total_chunks).unwrap();
+        for (id, hash) in flat.chunk_hashes.iter().enumerate() {
+            verifier.verify(id as u64, hash, &tree.proof(id as u64).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_manifest_builder_cancelled() {
+        use std::sync::atomic::Ordering;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(&[5u8; 1000]).unwrap();
+        temp_file.flush().unwrap();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let builder = ManifestBuilder::new("session-cancel")
+            .file_path(temp_file.path())
+            .chunk_size(100)
+            .cancel_flag(cancelled.clone());
+
+        assert!(builder.clone().build_parallel().is_ok());
+        cancelled.store(true, Ordering::Release);
+        assert!(matches!(builder.clone().build(), Err(Error::Cancelled)));
+        assert!(matches!(builder.build_parallel(), Err(Error::Cancelled)));
+    }
+
     #[test]
     fn test_manifest_builder_no_file_path() {
         let builder = ManifestBuilder::new("session-nofile");
@@ -414,11 +682,15 @@ mod tests {
         let temp_file = NamedTempFile::new().unwrap();
         // Don't write anything - empty file
 
-        let result = ManifestBuilder::new("session-empty")
+        let manifest = ManifestBuilder::new("session-empty")
             .file_path(temp_file.path())
-            .build();
+            .build()
+            .unwrap();
         
-        assert!(result.is_err());
+        assert_eq!(manifest.file_size, 0);
+        assert_eq!(manifest.total_chunks, 0);
+        assert!(manifest.chunk_hashes.is_empty());
+        assert_eq!(manifest.file_hash, blake3::hash(b"").as_bytes().to_vec());
     }
 }
 
@@ -72,6 +72,302 @@ pub struct Manifest {
     /// Original file size (before compression)
     #[prost(uint64, optional, tag = "9")]
     pub original_size: Option<u64>,
+    
+    /// Client-side encryption parameters (absent for plaintext transfers)
+    #[prost(message, optional, tag = "10")]
+    pub encryption: Option<EncryptionInfo>,
+    
+    /// Sender signature over all other manifest fields
+    #[prost(message, optional, tag = "11")]
+    pub signature: Option<ManifestSignature>,
+    
+    /// Root of the chunk hash tree; when set, `chunk_hashes` is empty and
+    /// each chunk packet carries its own proof
+    #[prost(bytes, optional, tag = "12")]
+    pub tree_root: Option<Vec<u8>>,
+    
+    /// Number of `ManifestPage`s following this header on a paged manifest
+    /// stream (0 when `chunk_hashes` is inline)
+    #[prost(uint32, tag = "13")]
+    pub hash_pages: u32,
+    
+    /// Transfer this manifest opens on a multiplexed connection; not
+    /// covered by the signature
+    #[prost(uint32, tag = "14")]
+    pub transfer_id: u32,
+    
+    /// '/'-separated path of the file under the upload root, including the
+    /// uploaded directory's name (empty for single-file uploads)
+    #[prost(string, tag = "15")]
+    pub relative_path: String,
+    
+    /// Unix permission bits to apply to the stored file (0 to leave as is)
+    #[prost(uint32, tag = "16")]
+    pub mode: u32,
+    
+    /// Small files packed into this transfer; the server unpacks them after
+    /// verification instead of storing the bundle itself
+    #[prost(message, optional, tag = "17")]
+    pub bundle: Option<BundleIndex>,
+    
+    /// Set when the file's chunks are striped across several connections,
+    /// each sending this manifest; not covered by the signature
+    #[prost(message, optional, tag = "18")]
+    pub stripe: Option<StripeInfo>,
+    
+    /// Extra streams the transfer's chunks are spread over, besides its own
+    /// data stream; not covered by the signature
+    #[prost(uint64, repeated, tag = "19")]
+    pub data_streams: Vec<u64>,
+    
+    /// Set for input of unknown length (e.g. a pipe): the header carries no
+    /// size, chunk count or hashes; hash pages follow as chunks are read and
+    /// the last page carries a `ManifestTrailer`
+    #[prost(bool, tag = "20")]
+    pub streaming: bool,
+    
+    /// Times, owner and extended attributes to apply to the stored file
+    /// (absent from older senders)
+    #[prost(message, optional, tag = "21")]
+    pub metadata: Option<FileMetadata>,
+    
+    /// Chunks lying wholly inside holes of a sparse file, in order; they
+    /// read as zeros, are never sent and stay holes on the receiver
+    #[prost(message, repeated, tag = "22")]
+    pub holes: Vec<ChunkRange>,
+    
+    /// What the receiver should do if a file is already stored under this
+    /// name (`ConflictPolicy::Default` for its own choice); not covered by
+    /// the signature
+    #[prost(enumeration = "ConflictPolicy", tag = "23")]
+    pub conflict_policy: i32,
+}
+
+/// A run of consecutive chunks
+#[derive(Clone, Copy, PartialEq, Eq, Message)]
+pub struct ChunkRange {
+    #[prost(uint64, tag = "1")]
+    pub first_chunk: u64,
+    
+    #[prost(uint64, tag = "2")]
+    pub chunk_count: u64,
+}
+
+/// File metadata besides permission bits, which travel in `mode`
+#[derive(Clone, PartialEq, Message)]
+pub struct FileMetadata {
+    /// Modification time, in nanoseconds since the Unix epoch
+    #[prost(int64, optional, tag = "1")]
+    pub mtime_ns: Option<i64>,
+    
+    /// Access time, in nanoseconds since the Unix epoch
+    #[prost(int64, optional, tag = "2")]
+    pub atime_ns: Option<i64>,
+    
+    /// Owning user ID (only when the sender preserves ownership)
+    #[prost(uint32, optional, tag = "3")]
+    pub uid: Option<u32>,
+    
+    /// Owning group ID (only when the sender preserves ownership)
+    #[prost(uint32, optional, tag = "4")]
+    pub gid: Option<u32>,
+    
+    /// Extended attributes (only when the sender preserves them)
+    #[prost(message, repeated, tag = "5")]
+    pub xattrs: Vec<ExtendedAttribute>,
+}
+
+/// One extended attribute of a file
+#[derive(Clone, PartialEq, Message)]
+pub struct ExtendedAttribute {
+    /// Attribute name, including its namespace (e.g. `user.comment`)
+    #[prost(string, tag = "1")]
+    pub name: String,
+    
+    #[prost(bytes, tag = "2")]
+    pub value: Vec<u8>,
+}
+
+/// Position of one connection in a striped upload
+#[derive(Clone, PartialEq, Message)]
+pub struct StripeInfo {
+    /// This connection's stripe, from 0
+    #[prost(uint32, tag = "1")]
+    pub index: u32,
+    
+    /// Number of connections the file is striped across
+    #[prost(uint32, tag = "2")]
+    pub count: u32,
+}
+
+/// Layout of the small files concatenated into a bundle transfer
+#[derive(Clone, PartialEq, Message)]
+pub struct BundleIndex {
+    /// Packed files in bundle order
+    #[prost(message, repeated, tag = "1")]
+    pub members: Vec<BundleMember>,
+}
+
+/// One file packed into a bundle
+#[derive(Clone, PartialEq, Message)]
+pub struct BundleMember {
+    /// '/'-separated path under the upload root
+    #[prost(string, tag = "1")]
+    pub relative_path: String,
+    
+    /// Byte offset of the file within the bundle
+    #[prost(uint64, tag = "2")]
+    pub offset: u64,
+    
+    /// File size
+    #[prost(uint64, tag = "3")]
+    pub size: u64,
+    
+    /// Unix permission bits (0 to leave as is)
+    #[prost(uint32, tag = "4")]
+    pub mode: u32,
+    
+    /// File hash (BLAKE3)
+    #[prost(bytes, tag = "5")]
+    pub file_hash: Vec<u8>,
+    
+    /// Times, owner and extended attributes of the file
+    #[prost(message, optional, tag = "6")]
+    pub metadata: Option<FileMetadata>,
+}
+
+/// Directory tree sent on the control stream ahead of a recursive upload
+#[derive(Clone, PartialEq, Message)]
+pub struct DirectoryManifest {
+    /// Name of the uploaded directory, recreated under the upload root
+    #[prost(string, tag = "1")]
+    pub root_name: String,
+    
+    /// Directories and files below the root, parents before children
+    #[prost(message, repeated, tag = "2")]
+    pub entries: Vec<DirectoryEntry>,
+    
+    /// Sum of all file sizes
+    #[prost(uint64, tag = "3")]
+    pub total_size: u64,
+}
+
+/// One directory or file of a directory manifest
+#[derive(Clone, PartialEq, Message)]
+pub struct DirectoryEntry {
+    /// '/'-separated path relative to the uploaded directory
+    #[prost(string, tag = "1")]
+    pub relative_path: String,
+    
+    /// Whether this entry is a directory
+    #[prost(bool, tag = "2")]
+    pub is_dir: bool,
+    
+    /// File size as stored on the server (sealed size when encrypted)
+    #[prost(uint64, tag = "3")]
+    pub file_size: u64,
+    
+    /// Unix permission bits (0 when unknown)
+    #[prost(uint32, tag = "4")]
+    pub mode: u32,
+    
+    /// Session ID of the transfer carrying the file, its own or a bundle's
+    /// (empty for directories and empty files, which are created from this
+    /// entry alone)
+    #[prost(string, tag = "5")]
+    pub session_id: String,
+    
+    /// File hash (BLAKE3)
+    #[prost(bytes, tag = "6")]
+    pub file_hash: Vec<u8>,
+    
+    /// Chunk hashes, as in the file's manifest
+    #[prost(bytes, repeated, tag = "7")]
+    pub chunk_hashes: Vec<Vec<u8>>,
+    
+    /// Times, owner and extended attributes of a directory or empty file;
+    /// other files carry theirs in their own manifest or bundle index
+    #[prost(message, optional, tag = "8")]
+    pub metadata: Option<FileMetadata>,
+}
+
+/// Segment of a manifest's chunk hashes sent after a paged manifest header
+#[derive(Clone, PartialEq, Message)]
+pub struct ManifestPage {
+    /// Chunk ID of the first hash in this page
+    #[prost(uint64, tag = "1")]
+    pub first_chunk: u64,
+    
+    /// Chunk hashes for consecutive chunks starting at `first_chunk`
+    #[prost(bytes, repeated, tag = "2")]
+    pub chunk_hashes: Vec<Vec<u8>>,
+    
+    /// Totals of a streaming manifest, on its last page only
+    #[prost(message, optional, tag = "3")]
+    pub trailer: Option<ManifestTrailer>,
+}
+
+/// Size and hash of a streamed file, known once its input has ended
+#[derive(Clone, PartialEq, Message)]
+pub struct ManifestTrailer {
+    /// File size
+    #[prost(uint64, tag = "1")]
+    pub file_size: u64,
+    
+    /// Total chunks
+    #[prost(uint64, tag = "2")]
+    pub total_chunks: u64,
+    
+    /// File hash (BLAKE3)
+    #[prost(bytes, tag = "3")]
+    pub file_hash: Vec<u8>,
+}
+
+/// Sender signature over a manifest
+#[derive(Clone, PartialEq, Message)]
+pub struct ManifestSignature {
+    /// Signature algorithm ("ed25519")
+    #[prost(string, tag = "1")]
+    pub algorithm: String,
+    
+    /// Signer's public key
+    #[prost(bytes, tag = "2")]
+    pub public_key: Vec<u8>,
+    
+    /// Signature over the manifest encoded without this field
+    #[prost(bytes, tag = "3")]
+    pub signature: Vec<u8>,
+}
+
+/// Parameters needed to decrypt a client-side encrypted file
+/// 
+/// When present in a manifest, sizes, offsets and hashes describe the sealed
+/// (encrypted) chunks the server stores, not the plaintext.
+#[derive(Clone, PartialEq, Message)]
+pub struct EncryptionInfo {
+    /// AEAD algorithm ("chacha20-poly1305" or "aes-256-gcm")
+    #[prost(string, tag = "1")]
+    pub algorithm: String,
+    
+    /// Key derivation ("pbkdf2-sha256" or "key-file")
+    #[prost(string, tag = "2")]
+    pub kdf: String,
+    
+    /// KDF salt (empty for key files)
+    #[prost(bytes, tag = "3")]
+    pub salt: Vec<u8>,
+    
+    /// KDF iteration count (0 for key files)
+    #[prost(uint32, tag = "4")]
+    pub kdf_iterations: u32,
+    
+    /// Plaintext chunk size used when sealing
+    #[prost(uint32, tag = "5")]
+    pub plain_chunk_size: u32,
+    
+    /// Key check value for detecting a wrong key before decrypting
+    #[prost(bytes, tag = "6")]
+    pub key_check: Vec<u8>,
 }
 
 /// Individual chunk packet
@@ -125,13 +421,18 @@ pub struct ResumeRequest {
     #[prost(uint64, repeated, tag = "2")]
     pub received_chunks: Vec<u64>,
     
-    /// Bitmap of received chunks (for efficiency)
+    /// Compact bitmap of received chunks (`ChunkBitmap::to_bytes`),
+    /// sent instead of `received_chunks`
     #[prost(bytes, optional, tag = "3")]
     pub received_bitmap: Option<Vec<u8>>,
     
     /// Last chunk ID successfully received
     #[prost(uint64, optional, tag = "4")]
     pub last_chunk_id: Option<u64>,
+    
+    /// Transfer on a multiplexed connection
+    #[prost(uint32, tag = "5")]
+    pub transfer_id: u32,
 }
 
 /// Response to resume request
@@ -156,6 +457,25 @@ pub struct ResumeResponse {
     /// Optional error message if not accepted
     #[prost(string, optional, tag = "5")]
     pub error: Option<String>,
+    
+    /// Compact bitmap of chunks the receiver already holds
+    /// (`ChunkBitmap::to_bytes`); when set, every other chunk is missing
+    /// and `missing_chunks` is left empty
+    #[prost(bytes, optional, tag = "6")]
+    pub received_bitmap: Option<Vec<u8>>,
+    
+    /// Transfer on a multiplexed connection
+    #[prost(uint32, tag = "7")]
+    pub transfer_id: u32,
+    
+    /// What the receiver does about a file already stored under the
+    /// upload's name
+    #[prost(enumeration = "ConflictOutcome", tag = "8")]
+    pub conflict: i32,
+    
+    /// Name the file is stored under, when renamed to avoid a conflict
+    #[prost(string, optional, tag = "9")]
+    pub stored_name: Option<String>,
 }
 
 /// Status update during transfer
@@ -244,6 +564,18 @@ pub struct HashCheckRequest {
     /// List of chunk hashes (BLAKE3) to check
     #[prost(bytes, repeated, tag = "2")]
     pub chunk_hashes: Vec<Vec<u8>>,
+    
+    /// Chunk IDs matching `chunk_hashes` (batched requests)
+    #[prost(uint64, repeated, tag = "3")]
+    pub chunk_ids: Vec<u64>,
+    
+    /// Ask for a Bloom filter of the receiver's chunk index instead
+    #[prost(bool, tag = "4")]
+    pub want_filter: bool,
+    
+    /// Transfer on a multiplexed connection
+    #[prost(uint32, tag = "5")]
+    pub transfer_id: u32,
 }
 
 /// Response indicating which hashes exist
@@ -260,6 +592,19 @@ pub struct HashCheckResponse {
     /// Bitmap indicating which chunks exist (for efficiency)
     #[prost(bytes, optional, tag = "3")]
     pub existing_bitmap: Option<Vec<u8>>,
+    
+    /// Chunk IDs from a batched request that the receiver already has;
+    /// every other ID in the batch must be sent
+    #[prost(uint64, repeated, tag = "4")]
+    pub existing_chunks: Vec<u64>,
+    
+    /// Bloom filter of the receiver's chunk index (`BloomFilter::to_bytes`)
+    #[prost(bytes, optional, tag = "5")]
+    pub bloom_filter: Option<Vec<u8>>,
+    
+    /// Transfer on a multiplexed connection
+    #[prost(uint32, tag = "6")]
+    pub transfer_id: u32,
 }
 
 /// Request to perform delta sync on a file (rsync-like)
@@ -354,6 +699,101 @@ pub struct DeltaOperation {
     pub target_offset: u64,
 }
 
+/// A version of a stored file kept by the receiver
+#[derive(Clone, PartialEq, Message)]
+pub struct FileVersion {
+    /// Path of the file below the upload directory
+    #[prost(string, tag = "1")]
+    pub path: String,
+    
+    /// Version number, counting up from 1 for each path (0 for the record
+    /// of the file currently stored)
+    #[prost(uint64, tag = "2")]
+    pub number: u64,
+    
+    /// File size
+    #[prost(uint64, tag = "3")]
+    pub file_size: u64,
+    
+    /// File hash (BLAKE3)
+    #[prost(bytes, tag = "4")]
+    pub file_hash: Vec<u8>,
+    
+    /// When the version was kept, in seconds since the Unix epoch
+    #[prost(uint64, tag = "5")]
+    pub kept_at: u64,
+    
+    /// Size of the chunks `chunk_hashes` covers
+    #[prost(uint32, tag = "6")]
+    pub chunk_size: u32,
+    
+    /// Chunk hashes (BLAKE3); left out of listings
+    #[prost(bytes, repeated, tag = "7")]
+    pub chunk_hashes: Vec<Vec<u8>>,
+    
+    /// Unix permission bits
+    #[prost(uint32, tag = "8")]
+    pub mode: u32,
+    
+    /// Times of the file
+    #[prost(message, optional, tag = "9")]
+    pub metadata: Option<FileMetadata>,
+}
+
+/// Request about files stored on the receiver, sent on the status stream
+#[derive(Clone, PartialEq, Message)]
+pub struct StorageRequest {
+    /// Path of the file, or directory, below the upload directory
+    #[prost(string, tag = "1")]
+    pub path: String,
+    
+    /// What to do
+    #[prost(enumeration = "StorageAction", tag = "2")]
+    pub action: i32,
+    
+    /// Version to restore
+    #[prost(uint64, tag = "3")]
+    pub version: u64,
+    
+    /// Entries to remove, '/'-separated paths relative to the directory
+    #[prost(string, repeated, tag = "4")]
+    pub entries: Vec<String>,
+    
+    /// Sender signature; receivers with trusted keys only change stored
+    /// files for a trusted sender
+    #[prost(message, optional, tag = "5")]
+    pub signature: Option<ManifestSignature>,
+}
+
+/// Answer to a `StorageRequest`, on the same stream
+#[derive(Clone, PartialEq, Message)]
+pub struct StorageResponse {
+    /// Path the request was about
+    #[prost(string, tag = "1")]
+    pub path: String,
+    
+    /// Kept versions of the file, oldest first
+    #[prost(message, repeated, tag = "2")]
+    pub versions: Vec<FileVersion>,
+    
+    /// Version now stored at the path, after a restore
+    #[prost(message, optional, tag = "3")]
+    pub restored: Option<FileVersion>,
+    
+    /// Why the request failed
+    #[prost(string, optional, tag = "4")]
+    pub error: Option<String>,
+    
+    /// Directories and files below the directory, parents first, with the
+    /// size and hash of each file as stored
+    #[prost(message, repeated, tag = "5")]
+    pub entries: Vec<DirectoryEntry>,
+    
+    /// Entries removed, as listed in the request
+    #[prost(string, repeated, tag = "6")]
+    pub removed: Vec<String>,
+}
+
 /// Transfer state enumeration
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
 #[repr(i32)]
@@ -380,6 +820,77 @@ pub enum TransferState {
     Cancelled = 9,
 }
 
+/// What a receiver does with an upload whose name is already taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ConflictPolicy {
+    /// Whatever the receiver is configured to do
+    Default = 0,
+    /// Replace the stored file
+    Overwrite = 1,
+    /// Keep the stored file if its hash matches, replace it otherwise
+    SkipIdentical = 2,
+    /// Refuse the upload
+    Fail = 3,
+    /// Store the upload under the name with a numbered suffix
+    Rename = 4,
+    /// Keep the stored file as a numbered version, then replace it
+    Version = 5,
+}
+
+/// What a receiver did about a file already stored under an upload's name
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ConflictOutcome {
+    /// No file was stored under the name
+    NoConflict = 0,
+    /// The stored file is replaced
+    Overwritten = 1,
+    /// The stored file is identical; nothing is sent
+    Skipped = 2,
+    /// The upload is refused
+    Refused = 3,
+    /// The upload is stored under another name
+    Renamed = 4,
+    /// The stored file is kept as a version and replaced
+    Versioned = 5,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "default" => Ok(Self::Default),
+            "overwrite" => Ok(Self::Overwrite),
+            "skip" => Ok(Self::SkipIdentical),
+            "fail" => Ok(Self::Fail),
+            "rename" => Ok(Self::Rename),
+            "version" => Ok(Self::Version),
+            _ => Err(format!(
+                "unknown conflict policy '{}' (expected overwrite, skip, fail, rename or version)", name
+            )),
+        }
+    }
+}
+
+/// What a `StorageRequest` asks for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum StorageAction {
+    /// List the kept versions of a file
+    ListVersions = 0,
+    /// Put a kept version back in place, keeping the current file as a version
+    RestoreVersion = 1,
+    /// List the directories and files below a directory
+    ListTree = 2,
+    /// Remove entries below a directory
+    RemoveEntries = 3,
+    /// Send a file back on the manifest and data streams of transfer 0,
+    /// once the client opens them
+    Download = 4,
+}
+
 /// Helper functions for serialization/deserialization
 impl SessionStart {
     /// Encode to bytes
@@ -411,6 +922,87 @@ impl Manifest {
     }
 }
 
+impl ChunkRange {
+    /// Chunk after the last of the run
+    pub fn end_chunk(&self) -> u64 {
+        self.first_chunk.saturating_add(self.chunk_count)
+    }
+
+    pub fn contains(&self, chunk_id: u64) -> bool {
+        chunk_id >= self.first_chunk && chunk_id < self.end_chunk()
+    }
+}
+
+impl StripeInfo {
+    /// Chunks this stripe carries out of `total_chunks`: a contiguous range,
+    /// with the ranges of all stripes covering the file
+    pub fn chunk_range(&self, total_chunks: u64) -> std::ops::Range<u64> {
+        let count = self.count.max(1) as u64;
+        let index = (self.index as u64).min(count);
+        (total_chunks * index / count)..(total_chunks * (index + 1).min(count) / count)
+    }
+}
+
+impl DirectoryManifest {
+    /// Encode to bytes
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.reserve(self.encoded_len());
+        self.encode(&mut buf).expect("Failed to encode DirectoryManifest");
+        buf
+    }
+    
+    /// Decode from bytes
+    pub fn decode_from_bytes(bytes: &[u8]) -> Result<Self, prost::DecodeError> {
+        Self::decode(bytes)
+    }
+}
+
+impl ManifestPage {
+    /// Encode to bytes
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.reserve(self.encoded_len());
+        self.encode(&mut buf).expect("Failed to encode ManifestPage");
+        buf
+    }
+    
+    /// Decode from bytes
+    pub fn decode_from_bytes(bytes: &[u8]) -> Result<Self, prost::DecodeError> {
+        Self::decode(bytes)
+    }
+}
+
+impl FileMetadata {
+    /// Encode to bytes
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.reserve(self.encoded_len());
+        self.encode(&mut buf).expect("Failed to encode FileMetadata");
+        buf
+    }
+    
+    /// Decode from bytes
+    pub fn decode_from_bytes(bytes: &[u8]) -> Result<Self, prost::DecodeError> {
+        Self::decode(bytes)
+    }
+}
+
+impl EncryptionInfo {
+    /// Encode to bytes
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.reserve(self.encoded_len());
+        self.encode(&mut buf).expect("Failed to encode EncryptionInfo");
+        buf
+    }
+    
+    /// Decode from bytes
+    pub fn decode_from_bytes(bytes: &[u8]) -> Result<Self, prost::DecodeError> {
+        Self::decode(bytes)
+    }
+}
+
 impl ChunkPacket {
     /// Encode to bytes
     pub fn encode_to_vec(&self) -> Vec<u8> {
@@ -531,6 +1123,51 @@ impl DeltaResponse {
     }
 }
 
+impl FileVersion {
+    /// Encode to bytes
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.reserve(self.encoded_len());
+        self.encode(&mut buf).expect("Failed to encode FileVersion");
+        buf
+    }
+    
+    /// Decode from bytes
+    pub fn decode_from_bytes(bytes: &[u8]) -> Result<Self, prost::DecodeError> {
+        Self::decode(bytes)
+    }
+}
+
+impl StorageRequest {
+    /// Encode to bytes
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.reserve(self.encoded_len());
+        self.encode(&mut buf).expect("Failed to encode StorageRequest");
+        buf
+    }
+    
+    /// Decode from bytes
+    pub fn decode_from_bytes(bytes: &[u8]) -> Result<Self, prost::DecodeError> {
+        Self::decode(bytes)
+    }
+}
+
+impl StorageResponse {
+    /// Encode to bytes
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.reserve(self.encoded_len());
+        self.encode(&mut buf).expect("Failed to encode StorageResponse");
+        buf
+    }
+    
+    /// Decode from bytes
+    pub fn decode_from_bytes(bytes: &[u8]) -> Result<Self, prost::DecodeError> {
+        Self::decode(bytes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -565,6 +1202,65 @@ mod tests {
             chunk_hashes: vec![vec![5, 6], vec![7, 8]],
             compression: "lz4hc".to_string(),
             original_size: Some(2048),
+            encryption: None,
+            signature: None,
+            tree_root: None,
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes: Vec::new(),
+            conflict_policy: 0,
+        };
+        
+        let encoded = msg.encode_to_vec();
+        let decoded = Manifest::decode_from_bytes(&encoded).unwrap();
+        
+        assert_eq!(msg, decoded);
+    }
+
+    #[test]
+    fn test_manifest_with_encryption_encode_decode() {
+        let msg = Manifest {
+            session_id: "test-session".to_string(),
+            file_name: "secret.dat".to_string(),
+            file_size: 2104,
+            chunk_size: 540,
+            total_chunks: 4,
+            file_hash: vec![1, 2, 3, 4],
+            chunk_hashes: vec![vec![5, 6], vec![7, 8]],
+            compression: "none".to_string(),
+            original_size: Some(1992),
+            encryption: Some(EncryptionInfo {
+                algorithm: "chacha20-poly1305".to_string(),
+                kdf: "pbkdf2-sha256".to_string(),
+                salt: vec![9; 16],
+                kdf_iterations: 600_000,
+                plain_chunk_size: 512,
+                key_check: vec![3; 16],
+            }),
+            signature: Some(ManifestSignature {
+                algorithm: "ed25519".to_string(),
+                public_key: vec![4; 32],
+                signature: vec![5; 64],
+            }),
+            tree_root: Some(vec![6; 32]),
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes: Vec::new(),
+            conflict_policy: 0,
         };
         
         let encoded = msg.encode_to_vec();
@@ -600,6 +1296,7 @@ mod tests {
             received_chunks: vec![0, 1, 2, 5, 6],
             received_bitmap: Some(vec![0b11100111]),
             last_chunk_id: Some(6),
+            transfer_id: 3,
         };
         
         let encoded = msg.encode_to_vec();
@@ -656,4 +1353,19 @@ mod tests {
         assert_eq!(TransferState::try_from(7), Ok(TransferState::Completed));
         assert!(TransferState::try_from(99).is_err());
     }
+
+    #[test]
+    fn test_stripe_chunk_ranges_cover_file() {
+        for (total_chunks, count) in [(10u64, 3u32), (2, 4), (1000, 7), (0, 2)] {
+            let ranges: Vec<_> = (0..count)
+                .map(|index| StripeInfo { index, count }.chunk_range(total_chunks))
+                .collect();
+            assert_eq!(ranges[0].start, 0);
+            assert_eq!(ranges.last().unwrap().end, total_chunks);
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].end, pair[1].start);
+            }
+        }
+        assert_eq!(StripeInfo { index: 1, count: 3 }.chunk_range(10), 3..6);
+    }
 }
@@ -0,0 +1,296 @@
+// File metadata carried with transfers
+//
+// Besides its contents, a file keeps its permission bits, access and
+// modification times and, when asked, its owner and extended attributes.
+// Permission bits travel in the existing `mode` fields, the rest in a
+// `FileMetadata` beside them. The receiver applies them once the file is in
+// place, as far as its `MetadataPolicy` allows. Owner and attribute changes
+// depend on privileges and filesystem support, so they are best effort.
+
+use crate::common::error::Result;
+use crate::protocol::directory::set_mode;
+use crate::protocol::messages::FileMetadata;
+#[cfg(unix)]
+use crate::protocol::messages::ExtendedAttribute;
+use std::fs::{File, FileTimes};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Which metadata is recorded when sending and applied when receiving
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataPolicy {
+    /// Unix permission bits
+    pub mode: bool,
+    /// Setuid, setgid and sticky bits along with the others; without them
+    /// a peer cannot leave setuid programs behind
+    pub special_bits: bool,
+    /// Access and modification times
+    pub times: bool,
+    /// Owning user and group; applying them usually needs root
+    pub ownership: bool,
+    /// Extended attributes
+    pub xattrs: bool,
+    /// Extended attributes outside the `user.` namespace as well, such as
+    /// `security.capability` or `trusted.*`; without it a peer cannot grant
+    /// file capabilities in place of the setuid bits it may not set
+    pub all_xattrs: bool,
+}
+
+impl Default for MetadataPolicy {
+    fn default() -> Self {
+        Self {
+            mode: true,
+            special_bits: false,
+            times: true,
+            ownership: false,
+            xattrs: false,
+            all_xattrs: false,
+        }
+    }
+}
+
+impl MetadataPolicy {
+    /// Keep everything, ownership and extended attributes included, but
+    /// setuid, setgid and sticky bits and attributes outside `user.`
+    pub fn all() -> Self {
+        Self {
+            ownership: true,
+            xattrs: true,
+            ..Self::default()
+        }
+    }
+
+    /// Keep nothing; files get the receiver's defaults
+    pub fn none() -> Self {
+        Self {
+            mode: false,
+            special_bits: false,
+            times: false,
+            ownership: false,
+            xattrs: false,
+            all_xattrs: false,
+        }
+    }
+}
+
+/// Record the metadata of the file or directory at `path` that `policy` keeps
+pub fn capture(path: &Path, policy: &MetadataPolicy) -> Result<FileMetadata> {
+    let metadata = std::fs::metadata(path)?;
+    let mut captured = FileMetadata::default();
+
+    if policy.times {
+        captured.mtime_ns = metadata.modified().ok().map(to_nanos);
+        captured.atime_ns = metadata.accessed().ok().map(to_nanos);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if policy.ownership {
+            captured.uid = Some(metadata.uid());
+            captured.gid = Some(metadata.gid());
+        }
+        if policy.xattrs {
+            captured.xattrs = read_xattrs(path);
+        }
+    }
+
+    Ok(captured)
+}
+
+/// Apply `mode` and `metadata` to the file or directory at `path`, as far
+/// as `policy` allows
+///
+/// Times are set before the mode, so a read-only or write-only mode cannot
+/// get in the way, and the mode after the owner, which would clear setuid
+/// bits where `special_bits` keeps them. Attributes outside the `user.`
+/// namespace are applied only with `all_xattrs`. Owner and attributes that
+/// cannot be set, or are not allowed, are logged and skipped.
+pub fn apply(path: &Path, mode: u32, metadata: Option<&FileMetadata>, policy: &MetadataPolicy) -> Result<()> {
+    if let Some(metadata) = metadata {
+        #[cfg(unix)]
+        {
+            if policy.xattrs {
+                write_xattrs(path, &metadata.xattrs, policy.all_xattrs);
+            }
+            if policy.ownership && (metadata.uid.is_some() || metadata.gid.is_some()) {
+                if let Err(e) = std::os::unix::fs::chown(path, metadata.uid, metadata.gid) {
+                    log::warn!("Cannot set owner {:?}:{:?} on {:?}: {}", metadata.uid, metadata.gid, path, e);
+                }
+            }
+        }
+
+        if policy.times {
+            set_times(path, metadata)?;
+        }
+    }
+
+    if policy.mode {
+        set_mode(path, mode, policy.special_bits)?;
+    }
+    Ok(())
+}
+
+fn set_times(path: &Path, metadata: &FileMetadata) -> Result<()> {
+    let mut times = FileTimes::new();
+    if let Some(mtime) = metadata.mtime_ns {
+        times = times.set_modified(from_nanos(mtime));
+    }
+    if let Some(atime) = metadata.atime_ns {
+        times = times.set_accessed(from_nanos(atime));
+    }
+    if metadata.mtime_ns.is_some() || metadata.atime_ns.is_some() {
+        File::open(path)?.set_times(times)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> Vec<ExtendedAttribute> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) => {
+            log::warn!("Cannot list extended attributes of {:?}: {}", path, e);
+            return Vec::new();
+        }
+    };
+
+    let mut xattrs = Vec::new();
+    for name in names {
+        let Some(text) = name.to_str() else {
+            log::warn!("Skipping extended attribute {:?} of {:?}: name is not valid UTF-8", name, path);
+            continue;
+        };
+        match xattr::get(path, &name) {
+            Ok(Some(value)) => xattrs.push(ExtendedAttribute { name: text.to_string(), value }),
+            Ok(None) => {}
+            Err(e) => log::warn!("Cannot read extended attribute {} of {:?}: {}", text, path, e),
+        }
+    }
+    xattrs
+}
+
+#[cfg(unix)]
+fn write_xattrs(path: &Path, xattrs: &[ExtendedAttribute], all_namespaces: bool) {
+    for attribute in xattrs {
+        if !all_namespaces && !is_user_xattr(&attribute.name) {
+            log::warn!("Skipping extended attribute {} on {:?}: only user.* attributes are applied", attribute.name, path);
+            continue;
+        }
+        if let Err(e) = xattr::set(path, &attribute.name, &attribute.value) {
+            log::warn!("Cannot set extended attribute {} on {:?}: {}", attribute.name, path, e);
+        }
+    }
+}
+
+/// Whether `name` is in the `user.` namespace, the one ordinary users may
+/// set on their own files
+fn is_user_xattr(name: &str) -> bool {
+    name.strip_prefix("user.").is_some_and(|rest| !rest.is_empty())
+}
+
+/// Nanoseconds since the Unix epoch, negative before it
+fn to_nanos(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+fn from_nanos(nanos: i64) -> SystemTime {
+    let offset = Duration::from_nanos(nanos.unsigned_abs());
+    if nanos >= 0 {
+        UNIX_EPOCH + offset
+    } else {
+        UNIX_EPOCH - offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_capture_and_apply_times_and_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let target = temp_dir.path().join("target.txt");
+        std::fs::write(&source, b"contents").unwrap();
+        std::fs::write(&target, b"contents").unwrap();
+
+        let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        let atime = UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+        File::options().write(true).open(&source).unwrap()
+            .set_times(FileTimes::new().set_modified(mtime).set_accessed(atime))
+            .unwrap();
+
+        let captured = capture(&source, &MetadataPolicy::default()).unwrap();
+        assert_eq!(captured.mtime_ns, Some(1_600_000_000_123_456_789));
+        assert!(captured.uid.is_none() && captured.xattrs.is_empty());
+
+        // Through the wire format and onto another file
+        let decoded = FileMetadata::decode_from_bytes(&captured.encode_to_vec()).unwrap();
+        apply(&target, 0o640, Some(&decoded), &MetadataPolicy::default()).unwrap();
+        let applied = std::fs::metadata(&target).unwrap();
+        assert_eq!(applied.modified().unwrap(), mtime);
+        assert_eq!(applied.accessed().unwrap(), atime);
+        #[cfg(unix)]
+        assert_eq!(crate::protocol::directory::file_mode(&applied), 0o640);
+
+        // A policy without times leaves them alone
+        let other = temp_dir.path().join("other.txt");
+        std::fs::write(&other, b"contents").unwrap();
+        apply(&other, 0, Some(&decoded), &MetadataPolicy::none()).unwrap();
+        assert_ne!(std::fs::metadata(&other).unwrap().modified().unwrap(), mtime);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_bits_only_by_policy() {
+        use crate::protocol::directory::file_mode;
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("tool");
+        std::fs::write(&path, b"#!/bin/sh").unwrap();
+
+        apply(&path, 0o4755, None, &MetadataPolicy::default()).unwrap();
+        assert_eq!(file_mode(&std::fs::metadata(&path).unwrap()), 0o755);
+
+        let special = MetadataPolicy { special_bits: true, ..MetadataPolicy::default() };
+        apply(&path, 0o1755, None, &special).unwrap();
+        assert_eq!(file_mode(&std::fs::metadata(&path).unwrap()), 0o1755);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_only_user_xattrs_by_policy() {
+        assert!(is_user_xattr("user.comment"));
+        assert!(!is_user_xattr("user."));
+        assert!(!is_user_xattr("security.capability"));
+        assert!(!is_user_xattr("trusted.note"));
+        assert!(!is_user_xattr("userx.note"));
+
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("tool");
+        std::fs::write(&path, b"#!/bin/sh").unwrap();
+        let metadata = FileMetadata {
+            xattrs: vec![
+                ExtendedAttribute { name: "trusted.note".to_string(), value: b"set".to_vec() },
+                ExtendedAttribute { name: "security.capability".to_string(), value: vec![0; 20] },
+            ],
+            ..FileMetadata::default()
+        };
+
+        // Neither is tried without `all_xattrs`, even where the caller could set them
+        apply(&path, 0o755, Some(&metadata), &MetadataPolicy::all()).unwrap();
+        for attribute in &metadata.xattrs {
+            assert!(xattr::get(&path, &attribute.name).ok().flatten().is_none(), "{}", attribute.name);
+        }
+    }
+
+    #[test]
+    fn test_times_before_epoch() {
+        assert_eq!(to_nanos(from_nanos(-1_500_000_000)), -1_500_000_000);
+        assert_eq!(to_nanos(from_nanos(0)), 0);
+    }
+}
@@ -1,26 +1,36 @@
 // Protocol module - message definitions and serialization
 
+pub mod bundle;
 pub mod chunk;
 pub mod codec;
 pub mod control;
+pub mod directory;
 pub mod hash_check;
 pub mod manifest;
 pub mod messages;
+pub mod metadata;
 pub mod resume;
 pub mod session;
+pub mod signing;
 pub mod status;
 
-pub use chunk::{ChunkPacketBuilder, ChunkPacketParser, ChunkPacketView};
+pub use chunk::{frame_packet, ChunkFrameReader, ChunkPacketBuilder, ChunkPacketParser, ChunkPacketView};
 pub use control::{ControlMessage, ControlMessageType};
 pub use hash_check::{
     HashCheckRequestSender, HashCheckRequestReceiver,
     HashCheckResponseSender, HashCheckResponseReceiver,
+    HashCheckPipeline, DedupDecision,
 };
+pub use bundle::{BundleBuilder, PackMember, unpack_bundle};
+pub use directory::{DirectoryBuilder, TreeNode};
+pub use metadata::MetadataPolicy;
+pub use signing::{ManifestSigner, TrustedKeys, verify_manifest, verify_stored_file};
 pub use resume::{
     ResumeRequestSender, ResumeRequestReceiver,
     ResumeResponseSender, ResumeResponseReceiver,
 };
 pub use messages::{
-    SessionStart, Manifest, ChunkPacket, ResumeRequest, ResumeResponse,
+    SessionStart, Manifest, ManifestPage, ManifestTrailer, DirectoryManifest, DirectoryEntry, BundleIndex, BundleMember, FileMetadata, ExtendedAttribute, StripeInfo, EncryptionInfo, ManifestSignature, ChunkPacket, ResumeRequest, ResumeResponse,
     StatusUpdate, TransferComplete, TransferState, HashCheckRequest, HashCheckResponse,
+    ConflictPolicy, ConflictOutcome, FileVersion, StorageRequest, StorageAction, StorageResponse,
 };
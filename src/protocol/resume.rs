@@ -1,14 +1,22 @@
 // Resume request and response protocol handlers
 
-use crate::protocol::messages::{ResumeRequest, ResumeResponse};
+use crate::protocol::messages::{ConflictOutcome, ResumeRequest, ResumeResponse};
 use crate::common::error::{Error, Result};
+use crate::transport::TransferId;
 
 /// Handles sending ResumeRequest messages
-pub struct ResumeRequestSender;
+pub struct ResumeRequestSender {
+    transfer_id: TransferId,
+}
 
 impl ResumeRequestSender {
     pub fn new() -> Self {
-        Self
+        Self::for_transfer(0)
+    }
+    
+    /// Sender for one transfer of a multiplexed connection
+    pub fn for_transfer(transfer_id: TransferId) -> Self {
+        Self { transfer_id }
     }
     
     /// Send a resume request with bitmap
@@ -28,6 +36,7 @@ impl ResumeRequestSender {
             received_chunks,
             received_bitmap,
             last_chunk_id,
+            transfer_id: self.transfer_id,
         };
         
         let data = request.encode_to_vec();
@@ -88,19 +97,42 @@ impl ResumeRequestReceiver {
 }
 
 /// Handles sending ResumeResponse messages
-pub struct ResumeResponseSender;
+pub struct ResumeResponseSender {
+    transfer_id: TransferId,
+    conflict: ConflictOutcome,
+    stored_name: Option<String>,
+}
 
 impl ResumeResponseSender {
     pub fn new() -> Self {
-        Self
+        Self::for_transfer(0)
+    }
+    
+    /// Sender for one transfer of a multiplexed connection
+    pub fn for_transfer(transfer_id: TransferId) -> Self {
+        Self {
+            transfer_id,
+            conflict: ConflictOutcome::NoConflict,
+            stored_name: None,
+        }
+    }
+    
+    /// Report what becomes of a file already stored under the upload's
+    /// name, and the name the upload is stored under if it differs
+    pub fn with_conflict(mut self, conflict: ConflictOutcome, stored_name: Option<String>) -> Self {
+        self.conflict = conflict;
+        self.stored_name = stored_name;
+        self
     }
     
-    /// Send a resume response with missing chunks list
+    /// Send a resume response with missing chunks list or received bitmap
+    #[allow(clippy::too_many_arguments)]
     pub fn send_response<F>(
         &self,
         session_id: String,
         accepted: bool,
         missing_chunks: Vec<u64>,
+        received_bitmap: Option<Vec<u8>>,
         chunks_remaining: u64,
         error: Option<String>,
         mut write_fn: F,
@@ -114,6 +146,10 @@ impl ResumeResponseSender {
             missing_chunks,
             chunks_remaining,
             error,
+            received_bitmap,
+            transfer_id: self.transfer_id,
+            conflict: self.conflict as i32,
+            stored_name: self.stored_name.clone(),
         };
         
         let data = response.encode_to_vec();
@@ -0,0 +1,443 @@
+// Manifest signing and verification (Ed25519 via ring)
+//
+// The sender signs the manifest encoded without its signature field, so the
+// file hash and every chunk hash are covered. Receivers check the signature
+// against a list of trusted public keys before accepting any data.
+//
+// Requests that change stored files, such as removing them, are signed the
+// same way, under a context of their own so neither passes for the other.
+
+use crate::common::error::{Error, Result};
+use crate::protocol::messages::{Manifest, ManifestSignature, StorageRequest};
+use crate::storage::verification::verify_file_hash;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use std::path::{Path, PathBuf};
+
+/// Ed25519 public key length in bytes
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Signature algorithm recorded in the manifest
+pub const SIGNATURE_ALGORITHM: &str = "ed25519";
+
+/// Domain separation prefix for signed manifest bytes
+const SIGNING_CONTEXT: &[u8] = b"sftpx manifest v1\0";
+
+/// Domain separation prefix for signed storage request bytes
+const REQUEST_SIGNING_CONTEXT: &[u8] = b"sftpx storage request v1\0";
+
+/// Signs manifests with an Ed25519 key pair
+pub struct ManifestSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl ManifestSigner {
+    /// Generate a new key pair as a PKCS#8 document
+    pub fn generate_pkcs8() -> Result<Vec<u8>> {
+        let rng = SystemRandom::new();
+        let document = Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|_| Error::Signature("Failed to generate Ed25519 key".to_string()))?;
+        Ok(document.as_ref().to_vec())
+    }
+
+    /// Create a signer from a PKCS#8 document
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map_err(|e| Error::Signature(format!("Invalid Ed25519 PKCS#8 key: {}", e)))?;
+        Ok(Self { key_pair })
+    }
+
+    /// Load a signer from a PKCS#8 key file
+    pub fn from_key_file(path: &Path) -> Result<Self> {
+        let pkcs8 = std::fs::read(path)
+            .map_err(|e| Error::ConfigError(format!("Failed to read signing key {:?}: {}", path, e)))?;
+        Self::from_pkcs8(&pkcs8)
+    }
+
+    /// Public key to add to receivers' trusted keys
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    /// Sign a manifest in place, replacing any existing signature
+    pub fn sign(&self, manifest: &mut Manifest) {
+        let payload = signing_payload(manifest);
+        let signature = self.key_pair.sign(&payload);
+
+        manifest.signature = Some(ManifestSignature {
+            algorithm: SIGNATURE_ALGORITHM.to_string(),
+            public_key: self.public_key().to_vec(),
+            signature: signature.as_ref().to_vec(),
+        });
+    }
+
+    /// Sign a request about stored files in place, replacing any existing
+    /// signature
+    pub fn sign_request(&self, request: &mut StorageRequest) {
+        let payload = request_signing_payload(request);
+        let signature = self.key_pair.sign(&payload);
+
+        request.signature = Some(ManifestSignature {
+            algorithm: SIGNATURE_ALGORITHM.to_string(),
+            public_key: self.public_key().to_vec(),
+            signature: signature.as_ref().to_vec(),
+        });
+    }
+}
+
+/// Set of sender public keys a receiver accepts manifests from
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys {
+    keys: Vec<Vec<u8>>,
+}
+
+impl TrustedKeys {
+    /// Create an empty trusted keys list
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust a public key
+    pub fn add(&mut self, public_key: &[u8]) -> Result<()> {
+        if public_key.len() != PUBLIC_KEY_LEN {
+            return Err(Error::Signature(format!(
+                "Invalid public key size: {} (expected {} bytes)",
+                public_key.len(),
+                PUBLIC_KEY_LEN
+            )));
+        }
+
+        if !self.is_trusted(public_key) {
+            self.keys.push(public_key.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Load trusted keys from a text file
+    ///
+    /// One hex-encoded public key per line; anything after the key is a
+    /// comment, as are blank lines and lines starting with `#`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::ConfigError(format!("Failed to read trusted keys {:?}: {}", path, e)))?;
+
+        let mut trusted = Self::new();
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let key_hex = line.split_whitespace().next().unwrap_or_default();
+            let key = hex::decode(key_hex).map_err(|_| {
+                Error::ConfigError(format!("Invalid key on line {} of {:?}", line_no + 1, path))
+            })?;
+            trusted.add(&key)?;
+        }
+
+        Ok(trusted)
+    }
+
+    /// Check whether a public key is trusted
+    pub fn is_trusted(&self, public_key: &[u8]) -> bool {
+        self.keys.iter().any(|k| k.as_slice() == public_key)
+    }
+
+    /// Number of trusted keys
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Check if no keys are trusted
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Verify a manifest's signature without checking who signed it
+///
+/// # Returns
+/// The signer's public key
+pub fn verify_manifest_signature(manifest: &Manifest) -> Result<&[u8]> {
+    let sig = manifest.signature.as_ref()
+        .ok_or_else(|| Error::Signature("Manifest is not signed".to_string()))?;
+
+    if sig.algorithm != SIGNATURE_ALGORITHM {
+        return Err(Error::Signature(format!(
+            "Unsupported signature algorithm: {}",
+            sig.algorithm
+        )));
+    }
+
+    let payload = signing_payload(manifest);
+    UnparsedPublicKey::new(&signature::ED25519, &sig.public_key)
+        .verify(&payload, &sig.signature)
+        .map_err(|_| Error::Signature(format!(
+            "Invalid signature on manifest for {}",
+            manifest.file_name
+        )))?;
+
+    Ok(&sig.public_key)
+}
+
+/// Verify a manifest was signed by a trusted key
+pub fn verify_manifest(manifest: &Manifest, trusted: &TrustedKeys) -> Result<()> {
+    let signer = verify_manifest_signature(manifest)?;
+
+    if !trusted.is_trusted(signer) {
+        return Err(Error::Signature(format!(
+            "Manifest for {} signed by untrusted key {}",
+            manifest.file_name,
+            hex::encode(signer)
+        )));
+    }
+
+    Ok(())
+}
+
+/// Verify a request about stored files was signed by a trusted key
+pub fn verify_request(request: &StorageRequest, trusted: &TrustedKeys) -> Result<()> {
+    let sig = request.signature.as_ref()
+        .ok_or_else(|| Error::Signature(format!("Request about {} is not signed", request.path)))?;
+
+    if sig.algorithm != SIGNATURE_ALGORITHM {
+        return Err(Error::Signature(format!(
+            "Unsupported signature algorithm: {}",
+            sig.algorithm
+        )));
+    }
+
+    let payload = request_signing_payload(request);
+    UnparsedPublicKey::new(&signature::ED25519, &sig.public_key)
+        .verify(&payload, &sig.signature)
+        .map_err(|_| Error::Signature(format!("Invalid signature on request about {}", request.path)))?;
+
+    if !trusted.is_trusted(&sig.public_key) {
+        return Err(Error::Signature(format!(
+            "Request about {} signed by untrusted key {}",
+            request.path,
+            hex::encode(&sig.public_key)
+        )));
+    }
+
+    Ok(())
+}
+
+/// Path of the signed manifest stored beside a received file
+pub fn signature_path(file_path: &Path) -> PathBuf {
+    let file_name = file_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    file_path.with_file_name(format!(".{}.sig", file_name))
+}
+
+/// Store a signed manifest beside the file it describes
+pub fn save_signed_manifest(file_path: &Path, manifest: &Manifest) -> Result<()> {
+    if manifest.signature.is_none() {
+        return Err(Error::Signature("Manifest is not signed".to_string()));
+    }
+    std::fs::write(signature_path(file_path), manifest.encode_to_vec())?;
+    Ok(())
+}
+
+/// Re-verify a stored file against its signed manifest
+///
+/// Checks the signature against `trusted` and the file contents against the
+/// signed file hash.
+///
+/// # Returns
+/// The signed manifest
+pub fn verify_stored_file(file_path: &Path, trusted: &TrustedKeys) -> Result<Manifest> {
+    let bytes = std::fs::read(signature_path(file_path))
+        .map_err(|_| Error::Signature(format!("No signature stored for {:?}", file_path)))?;
+    let manifest = Manifest::decode_from_bytes(&bytes)?;
+
+    verify_manifest(&manifest, trusted)?;
+    verify_file_hash(file_path, &manifest.file_hash)?;
+
+    Ok(manifest)
+}
+
+/// Bytes covered by the signature: context prefix + manifest without signature
+///
+/// The transfer ID, stripe and data streams depend on the connection the
+/// manifest is sent on, and the conflict policy on the upload request, so
+/// they are left out as well.
+fn signing_payload(manifest: &Manifest) -> Vec<u8> {
+    let mut unsigned = manifest.clone();
+    unsigned.signature = None;
+    unsigned.transfer_id = 0;
+    unsigned.stripe = None;
+    unsigned.data_streams.clear();
+    unsigned.conflict_policy = 0;
+
+    let mut payload = SIGNING_CONTEXT.to_vec();
+    payload.extend_from_slice(&unsigned.encode_to_vec());
+    payload
+}
+
+/// Bytes covered by a request's signature: context prefix + request without
+/// signature
+fn request_signing_payload(request: &StorageRequest) -> Vec<u8> {
+    let unsigned = StorageRequest { signature: None, ..request.clone() };
+
+    let mut payload = REQUEST_SIGNING_CONTEXT.to_vec();
+    payload.extend_from_slice(&unsigned.encode_to_vec());
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::protocol::messages::{ConflictPolicy, StorageAction, StripeInfo};
+
+    fn test_manifest(file_hash: Vec<u8>) -> Manifest {
+        Manifest {
+            session_id: "signed-session".to_string(),
+            file_name: "data.bin".to_string(),
+            file_size: 11,
+            chunk_size: 1024,
+            total_chunks: 1,
+            file_hash: file_hash.clone(),
+            chunk_hashes: vec![file_hash],
+            compression: "none".to_string(),
+            original_size: None,
+            encryption: None,
+            signature: None,
+            tree_root: None,
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes: Vec::new(),
+            conflict_policy: 0,
+        }
+    }
+
+    fn test_signer() -> ManifestSigner {
+        ManifestSigner::from_pkcs8(&ManifestSigner::generate_pkcs8().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = test_signer();
+        let mut manifest = test_manifest(vec![1u8; 32]);
+        signer.sign(&mut manifest);
+
+        let mut trusted = TrustedKeys::new();
+        trusted.add(signer.public_key()).unwrap();
+
+        assert!(verify_manifest(&manifest, &trusted).is_ok());
+        assert_eq!(verify_manifest_signature(&manifest).unwrap(), signer.public_key());
+
+        // Sending on another transfer of a connection keeps the signature valid
+        manifest.transfer_id = 7;
+        assert!(verify_manifest(&manifest, &trusted).is_ok());
+
+        // So does striping it or spreading it over more data streams
+        manifest.stripe = Some(StripeInfo { index: 1, count: 4 });
+        manifest.data_streams = vec![24, 28];
+        assert!(verify_manifest(&manifest, &trusted).is_ok());
+
+        // Or asking the server to keep the file it already has
+        manifest.conflict_policy = ConflictPolicy::Version as i32;
+        assert!(verify_manifest(&manifest, &trusted).is_ok());
+    }
+
+    #[test]
+    fn test_unsigned_manifest_rejected() {
+        let manifest = test_manifest(vec![1u8; 32]);
+        assert!(verify_manifest(&manifest, &TrustedKeys::new()).is_err());
+    }
+
+    #[test]
+    fn test_untrusted_signer_rejected() {
+        let signer = test_signer();
+        let mut manifest = test_manifest(vec![1u8; 32]);
+        signer.sign(&mut manifest);
+
+        let mut trusted = TrustedKeys::new();
+        trusted.add(test_signer().public_key()).unwrap();
+
+        assert!(verify_manifest_signature(&manifest).is_ok());
+        assert!(verify_manifest(&manifest, &trusted).is_err());
+    }
+
+    #[test]
+    fn test_tampered_manifest_rejected() {
+        let signer = test_signer();
+        let mut manifest = test_manifest(vec![1u8; 32]);
+        signer.sign(&mut manifest);
+
+        manifest.chunk_hashes[0] = vec![2u8; 32];
+        assert!(verify_manifest_signature(&manifest).is_err());
+    }
+
+    #[test]
+    fn test_signed_requests() {
+        let signer = test_signer();
+        let mut trusted = TrustedKeys::new();
+        trusted.add(signer.public_key()).unwrap();
+        let mut request = StorageRequest {
+            path: "site".to_string(),
+            action: StorageAction::RemoveEntries as i32,
+            version: 0,
+            entries: vec!["index.html".to_string()],
+            signature: None,
+        };
+        assert!(verify_request(&request, &trusted).is_err());
+
+        signer.sign_request(&mut request);
+        assert!(verify_request(&request, &trusted).is_ok());
+        assert!(verify_request(&request, &TrustedKeys::new()).is_err());
+
+        // The signature covers what is removed
+        request.entries.push("app.js".to_string());
+        assert!(verify_request(&request, &trusted).is_err());
+    }
+
+    #[test]
+    fn test_load_trusted_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let signer = test_signer();
+        let path = temp_dir.path().join("trusted_keys");
+        std::fs::write(
+            &path,
+            format!("# build machines\n\n{} ci@example\n", hex::encode(signer.public_key())),
+        ).unwrap();
+
+        let trusted = TrustedKeys::load(&path).unwrap();
+        assert_eq!(trusted.len(), 1);
+        assert!(trusted.is_trusted(signer.public_key()));
+
+        std::fs::write(&path, "not-hex\n").unwrap();
+        assert!(TrustedKeys::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_verify_stored_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("data.bin");
+        std::fs::write(&file_path, b"hello world").unwrap();
+
+        let signer = test_signer();
+        let mut manifest = test_manifest(blake3::hash(b"hello world").as_bytes().to_vec());
+        signer.sign(&mut manifest);
+        save_signed_manifest(&file_path, &manifest).unwrap();
+
+        let mut trusted = TrustedKeys::new();
+        trusted.add(signer.public_key()).unwrap();
+        assert!(verify_stored_file(&file_path, &trusted).is_ok());
+
+        // Modified contents no longer match the signed hash
+        std::fs::write(&file_path, b"hello WORLD").unwrap();
+        assert!(verify_stored_file(&file_path, &trusted).is_err());
+    }
+}
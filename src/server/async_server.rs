@@ -0,0 +1,75 @@
+// Async accept loop for embedding the server in a tokio application
+//
+// The accept loop awaits datagrams on a tokio socket sharing the server's
+// UDP socket, and routes them exactly as `Server::run` does. Sessions keep
+// their blocking per-connection loops and run on tokio's blocking pool, so
+// the protocol code is the same for both servers.
+//
+// The tokio socket needs the shared socket in non-blocking mode, so sessions
+// do not send from it themselves: they queue their datagrams, waiting while
+// the queue is full, and the accept loop sends them as the socket allows.
+
+use super::socket::Outgoing;
+use super::{Routes, Server, MAX_DATAGRAM_SIZE};
+use std::net::UdpSocket as StdUdpSocket;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Datagrams sessions may queue before waiting for the accept loop
+const SEND_QUEUE_LEN: usize = 1024;
+
+impl Server {
+    /// Run the server on the current tokio runtime and accept connections
+    ///
+    /// Each connection's session runs on the runtime's blocking pool, and
+    /// its datagrams go out through this loop. Dropping the future stops
+    /// the server: sessions still running lose their connection and end.
+    pub async fn run_async(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let std_socket = self.socket.try_clone()?;
+        std_socket.set_nonblocking(true)?;
+        let _blocking = BlockingOnDrop(self.socket.try_clone()?);
+        let socket = UdpSocket::from_std(std_socket)?;
+
+        let (queue, mut queued) = mpsc::channel(SEND_QUEUE_LEN);
+        let outgoing = Outgoing::Queue(queue);
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let mut out = [0u8; MAX_DATAGRAM_SIZE];
+        let mut routes = Routes::new();
+
+        self.start_pruner();
+
+        log::info!("Server: waiting for connections...");
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (len, from) = received?;
+                    let reply = self.route_datagram(&mut buf[..len], from, &outgoing, &mut routes, &mut out, |session| {
+                        tokio::task::spawn_blocking(session);
+                    })?;
+                    if let Some(len) = reply {
+                        if let Err(e) = socket.send_to(&out[..len], from).await {
+                            log::warn!("Server: failed to answer {}: {}", from, e);
+                        }
+                    }
+                }
+                Some((datagram, to)) = queued.recv() => {
+                    if let Err(e) = socket.send_to(&datagram, to).await {
+                        log::warn!("Server: failed to send {} bytes to {}: {}", datagram.len(), to, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Puts the shared socket back in blocking mode once the async loop stops,
+/// so `Server::run` can use it again
+struct BlockingOnDrop(StdUdpSocket);
+
+impl Drop for BlockingOnDrop {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.set_nonblocking(false) {
+            log::warn!("Server: failed to make the socket blocking again: {}", e);
+        }
+    }
+}
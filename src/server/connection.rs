@@ -1,8 +1,12 @@
 // Server connection management
 
 use quiche::{Config, Connection, ConnectionId, RecvInfo};
-use std::net::{SocketAddr, UdpSocket};
-use std::time::Instant;
+use super::socket::ConnectionSocket;
+use crate::engine::Outbox;
+use crate::transport::event_socket::wait_time;
+use crate::transport::priority::stream_priority;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 /// Wrapper around a QUIC connection for the server side
 pub struct ServerConnection {
@@ -17,13 +21,17 @@ pub struct ServerConnection {
 
 impl ServerConnection {
     /// Accept a new connection
+    ///
+    /// `odcid` is the connection ID the client first chose, when its address
+    /// was validated with a Retry.
     pub fn accept(
         scid: &ConnectionId,
+        odcid: Option<&ConnectionId>,
         local_addr: SocketAddr,
         peer_addr: SocketAddr,
         config: &mut Config,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let conn = quiche::accept(scid, None, local_addr, peer_addr, config)?;
+        let conn = quiche::accept(scid, odcid, local_addr, peer_addr, config)?;
         Ok(Self { 
             conn, 
             peer_addr,
@@ -45,8 +53,8 @@ impl ServerConnection {
         // Detect peer address migration - this usually means client restarted
         // In this case, we should close this connection and let server accept new one
         if from != self.peer_addr && self.conn.is_established() {
-            println!("Server: Peer migrated from {} to {} - treating as new connection", self.peer_addr, from);
-            println!("Server: Closing old connection to allow new handshake");
+            log::info!("Server: Peer migrated from {} to {} - treating as new connection", self.peer_addr, from);
+            log::info!("Server: Closing old connection to allow new handshake");
             self.migration_detected = true;
             // Close the connection immediately
             let _ = self.conn.close(true, 0x00, b"peer migration");
@@ -60,7 +68,7 @@ impl ServerConnection {
                 Ok(v)
             }
             Err(e) => {
-                eprintln!("Connection recv error: {:?}", e);
+                log::warn!("Connection recv error: {:?}", e);
                 Err(Box::new(e))
             }
         }
@@ -79,7 +87,7 @@ impl ServerConnection {
     /// Send packets to the peer
     pub fn send_packets(
         &mut self,
-        socket: &UdpSocket,
+        socket: &ConnectionSocket,
         out: &mut [u8],
     ) -> Result<(), Box<dyn std::error::Error>> {
         while let Ok((write, send_info)) = self.conn.send(out) {
@@ -123,6 +131,34 @@ impl ServerConnection {
         self.conn.stream_send(stream_id, data, fin)
     }
 
+    /// Set a stream's urgency from its role (see `transport::priority`)
+    pub fn prioritize_stream(&mut self, stream_id: u64) -> Result<(), quiche::Error> {
+        let (urgency, incremental) = stream_priority(stream_id);
+        self.conn.stream_priority(stream_id, urgency, incremental)
+    }
+
+    /// Write a state machine's queued stream writes, as far as the streams
+    /// take them, setting each stream's urgency from its role
+    pub fn flush_outbox(&mut self, outbox: &mut Outbox) -> Result<usize, quiche::Error> {
+        outbox.flush(|stream_id, data, fin| {
+            let _ = self.prioritize_stream(stream_id);
+            self.conn.stream_send(stream_id, data, fin)
+        })
+    }
+
+    /// Block until a datagram arrives, the connection's next timer fires or
+    /// `limit` passes, handling expired timers
+    ///
+    /// # Returns
+    /// Whether a datagram is waiting on `socket`
+    pub fn wait_for_packets(&mut self, socket: &ConnectionSocket, limit: Duration) -> bool {
+        if socket.wait(Some(wait_time(self.conn.timeout(), limit))) {
+            return true;
+        }
+        self.conn.on_timeout();
+        false
+    }
+
     /// Get the peer address
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
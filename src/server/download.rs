@@ -0,0 +1,266 @@
+// Server side of a download
+//
+// A client asks for a stored file with a `Download` request on the status
+// stream. Once the request is accepted, the client opens the manifest and
+// data streams of transfer 0 by finishing its side of them, and the server
+// answers on them: the manifest with every chunk hash, then each chunk
+// packet after its length, the way uploads frame them.
+//
+// Like an upload, a download never touches the connection: the connection
+// loop hands it what it reads from its streams and sends what it leaves in
+// its outbox. Chunks are read from the file only as the outbox drains, so a
+// client that reads slowly holds back the reading instead of filling memory.
+
+use super::transfer::load_encryption_info;
+use crate::chunking::FileChunker;
+use crate::engine::Outbox;
+use crate::protocol::chunk::frame_packet;
+use crate::protocol::directory::file_mode;
+use crate::protocol::manifest::ManifestBuilder;
+use crate::protocol::messages::{FileMetadata, Manifest};
+use crate::protocol::metadata::{self, MetadataPolicy};
+use crate::transport::manifest_pages::PagedManifestSender;
+use crate::transport::TransferStreams;
+use std::collections::HashSet;
+use std::path::Path;
+
+type BoxResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Chunk data queued ahead of what QUIC has taken (4 MB)
+const SEND_AHEAD: usize = 4 * 1024 * 1024;
+
+/// Server side of one download
+pub(crate) struct OutgoingDownload {
+    streams: TransferStreams,
+    manifest: Manifest,
+    chunker: FileChunker,
+    outbox: Outbox,
+    /// Streams the client has opened for the answer
+    opened: HashSet<u64>,
+    manifest_sent: bool,
+    data_sent: bool,
+}
+
+impl OutgoingDownload {
+    /// Start sending the file at `path` on `streams`, cut in chunks of
+    /// `chunk_size` bytes, with the metadata `policy` keeps
+    pub(crate) fn new(
+        streams: TransferStreams,
+        path: &Path,
+        session_id: String,
+        chunk_size: usize,
+        policy: &MetadataPolicy,
+    ) -> BoxResult<Self> {
+        let manifest = download_manifest(path, session_id, chunk_size, policy, &streams)?;
+        let chunker = FileChunker::new(path, Some(chunk_size))?.with_holes(manifest.holes.clone());
+        log::info!("Server: sending {:?} ({} chunks, {} bytes) on transfer {}",
+            path, manifest.total_chunks, manifest.file_size, streams.transfer_id);
+
+        Ok(Self {
+            streams,
+            manifest,
+            chunker,
+            outbox: Outbox::new(),
+            opened: HashSet::new(),
+            manifest_sent: false,
+            data_sent: false,
+        })
+    }
+
+    pub(crate) fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Streams the download is sent on
+    pub(crate) fn stream_ids(&self) -> [u64; 2] {
+        [self.streams.manifest, self.streams.data]
+    }
+
+    /// Hand the download bytes read from one of its streams
+    ///
+    /// The client sends nothing but the end of its side, which opens the
+    /// stream for the answer.
+    pub(crate) fn on_stream_data(&mut self, stream_id: u64, data: &[u8], fin: bool) {
+        if !data.is_empty() {
+            log::warn!("Server: ignoring {} bytes on download stream {}", data.len(), stream_id);
+        }
+        if fin {
+            self.opened.insert(stream_id);
+        }
+    }
+
+    /// Queue the manifest once its stream is open, then chunks while the
+    /// outbox has room for them
+    pub(crate) fn poll(&mut self) -> BoxResult<()> {
+        if !self.manifest_sent && self.opened.contains(&self.streams.manifest) {
+            let encoded = PagedManifestSender::new().encode(&self.manifest)?;
+            self.outbox.write(self.streams.manifest, &encoded, true);
+            self.manifest_sent = true;
+        }
+        if !self.manifest_sent || !self.opened.contains(&self.streams.data) {
+            return Ok(());
+        }
+
+        while !self.data_sent && self.outbox.queued_bytes() < SEND_AHEAD {
+            match self.chunker.next_chunk()? {
+                Some(packet) => {
+                    // The last chunk read finishes the stream
+                    let last = self.chunker.bytes_read() >= self.chunker.file_size();
+                    self.outbox.write(self.streams.data, &frame_packet(&packet), last);
+                    self.data_sent = last;
+                }
+                None => {
+                    // Empty, or ending in a hole: no chunk carried the FIN
+                    self.outbox.write(self.streams.data, &[], true);
+                    self.data_sent = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes waiting to be sent
+    pub(crate) fn outbox(&mut self) -> &mut Outbox {
+        &mut self.outbox
+    }
+
+    /// Whether everything has been handed to QUIC
+    pub(crate) fn is_finished(&self) -> bool {
+        self.data_sent && self.outbox.is_empty()
+    }
+}
+
+/// Build the manifest a download of the file at `path` starts with
+///
+/// It keeps the file's metadata the same way an upload's manifest does, and
+/// the encryption parameters of a client-encrypted file, which is served as
+/// stored for the client to decrypt.
+pub(super) fn download_manifest(
+    path: &Path,
+    session_id: String,
+    chunk_size: usize,
+    policy: &MetadataPolicy,
+    streams: &TransferStreams,
+) -> BoxResult<Manifest> {
+    // Captured before hashing, which may move the access time
+    let file_metadata = metadata::capture(path, policy)?;
+
+    let mut manifest = ManifestBuilder::new(session_id)
+        .file_path(path)
+        .chunk_size(chunk_size as u32)
+        .detect_holes()
+        .build()?;
+
+    if policy.mode {
+        manifest.mode = file_mode(&std::fs::metadata(path)?);
+    }
+    manifest.metadata = (file_metadata != FileMetadata::default()).then_some(file_metadata);
+    manifest.encryption = load_encryption_info(path)?;
+    manifest.transfer_id = streams.transfer_id;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::OrderedChunkWriter;
+    use crate::protocol::chunk::{ChunkFrameReader, ChunkPacketParser};
+    use crate::transport::manifest_pages::{ManifestEvent, PagedManifestReceiver};
+    use tempfile::TempDir;
+
+    /// Run a download of `path` against the client's side of the protocol in
+    /// memory, delivering stream data in reads of `read_size` bytes
+    fn download(path: &Path, chunk_size: usize, read_size: usize) -> (Vec<u8>, u64, usize) {
+        let streams = TransferStreams::for_transfer(0);
+        let mut server = OutgoingDownload::new(streams, path, "download".to_string(), chunk_size,
+            &MetadataPolicy::default()).unwrap();
+
+        // Nothing is sent before the client opens the streams
+        server.poll().unwrap();
+        assert!(server.outbox().is_empty());
+        server.on_stream_data(streams.manifest, &[], true);
+        server.on_stream_data(streams.data, &[], true);
+
+        let mut manifest_receiver = PagedManifestReceiver::new();
+        let mut manifest: Option<Manifest> = None;
+        let mut complete = false;
+        let mut frames = ChunkFrameReader::new();
+        let mut output = Vec::new();
+        let mut unwritten = Some(&mut output);
+        let mut writer = None;
+        let mut most_queued = 0;
+
+        while !server.is_finished() {
+            server.poll().unwrap();
+            most_queued = most_queued.max(server.outbox().queued_bytes());
+            for write in server.outbox().take() {
+                for (index, read) in write.data.chunks(read_size).enumerate() {
+                    let fin = write.fin && (index + 1) * read_size >= write.data.len();
+                    if write.stream_id == streams.manifest {
+                        for event in manifest_receiver.receive_chunk(read, fin).unwrap() {
+                            match event {
+                                ManifestEvent::Header(header) => manifest = Some(*header),
+                                ManifestEvent::Page(page) => manifest.as_mut().unwrap().chunk_hashes.extend(page.chunk_hashes),
+                                ManifestEvent::Complete => complete = true,
+                            }
+                        }
+                    } else {
+                        assert!(complete, "chunks before the manifest");
+                        frames.push(read);
+                    }
+                }
+            }
+
+            let writer = match &mut writer {
+                Some(writer) => writer,
+                None => {
+                    let output = unwritten.take().unwrap();
+                    writer.insert(OrderedChunkWriter::new(output, manifest.as_ref().unwrap(), 1024).unwrap())
+                }
+            };
+            while let Some(packet) = frames.next_packet().unwrap() {
+                writer.push(ChunkPacketParser::parse(&packet).unwrap()).unwrap();
+            }
+        }
+
+        let written = writer.unwrap().finish().unwrap();
+        (output, written, most_queued)
+    }
+
+    #[test]
+    fn test_download_in_memory() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("dump.tar");
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        // Reads that cut through length prefixes and packets
+        let (output, written, _) = download(&path, 1024, 333);
+        assert_eq!(written, 10_000);
+        assert_eq!(output, content);
+    }
+
+    #[test]
+    fn test_download_reads_ahead_only_so_far() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("image.bin");
+        let chunk_size = 1024 * 1024;
+        let content: Vec<u8> = (0..12 * chunk_size as u32).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        let (output, _, most_queued) = download(&path, chunk_size, 65_535);
+        assert_eq!(output, content);
+        assert!(most_queued < SEND_AHEAD + 2 * chunk_size, "{} bytes queued", most_queued);
+    }
+
+    #[test]
+    fn test_download_of_empty_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("empty");
+        std::fs::write(&path, b"").unwrap();
+
+        let (output, written, _) = download(&path, 1024, 100);
+        assert_eq!(written, 0);
+        assert!(output.is_empty());
+    }
+}
@@ -1,21 +1,45 @@
 // Server module - QUIC server implementation
 
+#[cfg(feature = "async")]
+mod async_server;
 mod connection;
+mod download;
+mod retry;
 mod session;
 mod streams;
 mod sender;
+mod socket;
 mod transfer;
+mod upload;
 
 pub use connection::ServerConnection;
 pub use session::ServerSession;
 pub use streams::{StreamManager, StreamType};
 pub use sender::DataSender;
+pub use socket::{ConnectionSocket, Datagram};
+use socket::Outgoing;
 pub use transfer::TransferManager;
 
-use quiche::Config;
-use std::net::UdpSocket;
+use crate::observer::{self, SharedObserver, TransferObserver};
+use crate::protocol::messages::ConflictPolicy;
+use crate::protocol::metadata::MetadataPolicy;
+use crate::protocol::signing::TrustedKeys;
+use crate::storage::{Retention, VersionStore};
+use crossbeam_channel::{Sender, TrySendError};
+use retry::RetryTokens;
+use quiche::{Config, ConnectionId};
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use upload::{Assemblies, SharedChunkIndex};
 
 const MAX_DATAGRAM_SIZE: usize = 1350;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);  // How often kept versions are checked against the retention
+/// Directory uploads are stored in
+pub(crate) const UPLOAD_DIR: &str = "./uploads";
 #[allow(dead_code)]
 const NUM_STREAMS_PER_CONNECTION: usize = 4;
 
@@ -28,6 +52,16 @@ pub struct ServerConfig {
     pub max_data: u64,
     pub max_stream_data: u64,
     pub max_streams: u64,
+    /// File of trusted sender keys; when set, unsigned uploads are rejected
+    pub trusted_keys_path: Option<String>,
+    /// Which of the sender's file metadata is applied to received files
+    pub metadata_policy: MetadataPolicy,
+    /// What to do with uploads whose name is taken, unless they ask otherwise
+    pub conflict_policy: ConflictPolicy,
+    /// How long versions of replaced files are kept
+    pub retention: Retention,
+    /// Most connections served at once; clients beyond it are not answered
+    pub max_connections: usize,
 }
 
 impl Default for ServerConfig {
@@ -40,23 +74,44 @@ impl Default for ServerConfig {
             max_data: 2_560_000_000,  // 2.56GB connection window for parallel processing
             max_stream_data: 268_435_456,  // 256MB per stream for parallel processing
             max_streams: 1000,  // Increased for parallel chunk transfers
+            trusted_keys_path: None,
+            metadata_policy: MetadataPolicy::default(),
+            conflict_policy: ConflictPolicy::Overwrite,
+            retention: Retention::default(),
+            max_connections: 256,
         }
     }
 }
 
 /// Main QUIC server
 pub struct Server {
-    #[allow(dead_code)]
     config: ServerConfig,
-    socket: UdpSocket,
+    socket: Arc<UdpSocket>,
     quic_config: Config,
+    trusted_keys: Option<TrustedKeys>,
+    assemblies: Assemblies,
+    /// Chunk index of the upload directory, shared by every session
+    chunk_index: SharedChunkIndex,
+    observer: SharedObserver,
+    /// Whether the background pruner of kept versions is running
+    pruning: bool,
+    /// Tokens clients prove their address with before a connection is set up
+    retry_tokens: RetryTokens,
 }
 
+/// A connection being served off the accept loop
+struct ConnectionRoute {
+    inbox: Sender<Datagram>,
+    done: Arc<AtomicBool>,
+}
+
+type Routes = HashMap<ConnectionId<'static>, ConnectionRoute>;
+
 impl Server {
     /// Create a new server instance
     pub fn new(config: ServerConfig) -> Result<Self, Box<dyn std::error::Error>> {
         let socket = UdpSocket::bind(&config.bind_addr)?;
-        println!("Server listening on {}", config.bind_addr);
+        log::info!("Server listening on {}", config.bind_addr);
 
         let mut quic_config = Config::new(quiche::PROTOCOL_VERSION)?;
         quic_config.set_application_protos(&[b"sftpx/0.1"])?;
@@ -73,79 +128,245 @@ impl Server {
         quic_config.load_cert_chain_from_pem_file(&config.cert_path)?;
         quic_config.load_priv_key_from_pem_file(&config.key_path)?;
 
+        let trusted_keys = match &config.trusted_keys_path {
+            Some(path) => {
+                let keys = TrustedKeys::load(std::path::Path::new(path))?;
+                log::info!("Loaded {} trusted sender key(s) from {}", keys.len(), path);
+                Some(keys)
+            }
+            None => None,
+        };
+
         Ok(Self {
             config,
-            socket,
+            socket: Arc::new(socket),
             quic_config,
+            trusted_keys,
+            assemblies: Assemblies::new(),
+            chunk_index: SharedChunkIndex::new(transfer::open_chunk_index(Path::new(UPLOAD_DIR))?),
+            observer: observer::noop(),
+            pruning: false,
+            retry_tokens: RetryTokens::new()?,
         })
     }
 
+    /// Address the server is bound to
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Report the events of every upload the server receives to `observer`
+    pub fn with_observer(mut self, observer: Arc<dyn TransferObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
     /// Run the server and accept connections
+    ///
+    /// Each connection is served on its own thread, so several can be active
+    /// at once (e.g. the connections of a striped upload). Datagrams are
+    /// routed to their connection by destination connection ID.
     pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let mut buf = [0u8; MAX_DATAGRAM_SIZE];
         let mut out = [0u8; MAX_DATAGRAM_SIZE];
+        let mut routes = Routes::new();
+
+        self.start_pruner();
 
+        let outgoing = Outgoing::Socket(Arc::clone(&self.socket));
+
+        log::info!("Server: waiting for connections...");
         loop {
-            println!("Server: waiting for initial packet...");
             let (len, from) = self.socket.recv_from(&mut buf)?;
-            println!("Server: received initial packet ({} bytes) from {}", len, from);
+            let reply = self.route_datagram(&mut buf[..len], from, &outgoing, &mut routes, &mut out, |session| {
+                std::thread::spawn(session);
+            })?;
+            if let Some(len) = reply {
+                if let Err(e) = self.socket.send_to(&out[..len], from) {
+                    log::warn!("Server: failed to answer {}: {}", from, e);
+                }
+            }
+        }
+    }
 
-            let mut hdr_buf = &mut buf[..len];
-            let hdr = match quiche::Header::from_slice(&mut hdr_buf, quiche::MAX_CONN_ID_LEN) {
-                Ok(h) => h,
+    /// Start enforcing the retention on kept versions in the background,
+    /// once, whichever accept loop runs
+    fn start_pruner(&mut self) {
+        if !self.pruning && !self.config.retention.keeps_everything() {
+            spawn_pruner(PathBuf::from(UPLOAD_DIR), self.config.retention, self.chunk_index.clone());
+            self.pruning = true;
+        }
+    }
+
+    /// Hand a datagram to its connection, accepting a new one for an Initial
+    /// from a validated address
+    ///
+    /// A new connection's session is handed to `spawn`, which runs it to
+    /// completion off the accept loop; it sends through `outgoing`. A
+    /// connection whose inbox is full misses the datagrams that arrive
+    /// meanwhile, as it would with a full socket buffer.
+    ///
+    /// # Returns
+    /// Length of a packet written to `out` to send back to `from`
+    fn route_datagram<S>(
+        &mut self,
+        datagram: &mut [u8],
+        from: SocketAddr,
+        outgoing: &Outgoing,
+        routes: &mut Routes,
+        out: &mut [u8],
+        spawn: S,
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>>
+    where
+        S: FnOnce(Box<dyn FnOnce() + Send>),
+    {
+        let len = datagram.len();
+        let hdr = match quiche::Header::from_slice(datagram, quiche::MAX_CONN_ID_LEN) {
+            Ok(h) => h,
+            Err(e) => {
+                log::warn!("Failed to parse header: {:?}", e);
+                return Ok(None);
+            }
+        };
+
+        if let Some(route) = routes.get(&hdr.dcid) {
+            match route.inbox.try_send((datagram.to_vec(), from)) {
+                Ok(()) => return Ok(None),
+                Err(TrySendError::Full(_)) => {
+                    log::debug!("Server: connection inbox full, dropping {} byte datagram", len);
+                    return Ok(None);
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    routes.remove(&hdr.dcid);
+                }
+            }
+        }
+
+        // Anything but an Initial belongs to a connection that has ended
+        if hdr.ty != quiche::Type::Initial {
+            return Ok(None);
+        }
+        log::info!("Server: received initial packet ({} bytes) from {}", len, from);
+        routes.retain(|_, route| !route.done.load(Ordering::Acquire));
+        if routes.len() >= self.config.max_connections {
+            log::warn!("Server: refusing connection from {} ({} active)", from, routes.len());
+            return Ok(None);
+        }
+
+        // The client proves its address by sending back the token of a
+        // Retry before anything is set up for it
+        let token = hdr.token.as_deref().unwrap_or_default();
+        if token.is_empty() {
+            let scid = self.retry_tokens.connection_id()?;
+            let token = self.retry_tokens.mint(from, &hdr.dcid, SystemTime::now());
+            return match quiche::retry(&hdr.scid, &hdr.dcid, &scid, &token, hdr.version, out) {
+                Ok(len) => Ok(Some(len)),
                 Err(e) => {
-                    eprintln!("Failed to parse header: {:?}", e);
-                    continue;
+                    log::warn!("Server: failed to write a Retry for {}: {:?}", from, e);
+                    Ok(None)
                 }
             };
+        }
+        let Some(odcid) = self.retry_tokens.validate(from, token, SystemTime::now()) else {
+            log::warn!("Server: invalid address validation token from {}", from);
+            return Ok(None);
+        };
 
-            // Create server connection
-            let scid = quiche::ConnectionId::from_ref(&hdr.dcid);
-            let mut server_conn = ServerConnection::accept(
-                &scid,
-                self.socket.local_addr()?,
-                from,
-                &mut self.quic_config,
-            )?;
-            println!("Server: connection accepted");
-
-            // Process initial packet
-            server_conn.process_packet(&mut buf[..len], from, self.socket.local_addr()?)?;
-            println!("Server: initial packet processed");
-
-            // Send handshake response packets
-            server_conn.send_packets(&self.socket, &mut out)?;
-            println!("Server: sent handshake response");
-
-            // Handle the connection session (this will complete handshake and handle data)
-            // If migration is detected during session, this will close and we'll accept a new connection
-            match self.handle_session(&mut server_conn, &mut buf, &mut out) {
-                Ok(_) => println!("Server: session completed successfully"),
+        // Create server connection
+        let scid = ConnectionId::from_ref(&hdr.dcid).into_owned();
+        let (conn_socket, inbox) = ConnectionSocket::sending(outgoing.clone(), self.socket.local_addr()?);
+        let mut server_conn = ServerConnection::accept(
+            &scid,
+            Some(&odcid),
+            conn_socket.local_addr()?,
+            from,
+            &mut self.quic_config,
+        )?;
+        log::info!("Server: connection accepted ({} active)", routes.len() + 1);
+
+        // Process initial packet; the session sends the handshake response
+        server_conn.process_packet(datagram, from, conn_socket.local_addr()?)?;
+
+        // Handle the connection session (this will complete handshake and handle data)
+        // If migration is detected during session, the client reconnects
+        // and is accepted as a new connection
+        let trusted_keys = self.trusted_keys.clone();
+        let assemblies = self.assemblies.clone();
+        let chunk_index = self.chunk_index.clone();
+        let observer = self.observer.clone();
+        let metadata_policy = self.config.metadata_policy;
+        let conflict_policy = self.config.conflict_policy;
+        let done = Arc::new(AtomicBool::new(false));
+        let finished = Arc::clone(&done);
+        spawn(Box::new(move || {
+            match handle_session(&mut server_conn, &conn_socket, trusted_keys, assemblies, chunk_index, observer.clone(), metadata_policy, conflict_policy) {
+                Ok(_) => log::info!("Server: session completed successfully"),
                 Err(e) => {
                     if server_conn.migration_detected() {
-                        println!("Server: migration detected - closing old connection and waiting for new one");
+                        log::info!("Server: migration detected - closed old connection, waiting for new one");
                     } else {
-                        eprintln!("Server: session error: {:?}", e);
+                        log::error!("Server: session error: {:?}", e);
+                        observer.on_error(None, &*e);
                     }
                 }
             }
+            log::info!("Server: connection closed");
+            finished.store(true, Ordering::Release);
+        }));
+
+        routes.insert(scid, ConnectionRoute { inbox, done });
+        Ok(None)
+    }
+}
 
-            println!("Server: connection closed, ready for next connection\n");
-            // Continue loop to accept next connection
+/// Enforce `retention` on the versions kept below `upload_dir` in the
+/// background, now and every `PRUNE_INTERVAL`
+fn spawn_pruner(upload_dir: PathBuf, retention: Retention, chunk_index: SharedChunkIndex) {
+    log::info!("Server: pruning kept versions ({:?})", retention);
+    std::thread::spawn(move || loop {
+        if let Err(e) = prune_versions(&upload_dir, &retention, &chunk_index) {
+            log::warn!("Server: failed to prune kept versions: {}", e);
         }
+        std::thread::sleep(PRUNE_INTERVAL);
+    });
+}
+
+/// Drop the versions `retention` no longer keeps, and their chunks from the
+/// chunk index
+fn prune_versions(upload_dir: &Path, retention: &Retention, chunk_index: &SharedChunkIndex) -> Result<(), Box<dyn std::error::Error>> {
+    let mut chunk_index = chunk_index.lock();
+    let report = VersionStore::new(upload_dir).prune(retention, SystemTime::now(), &mut chunk_index)?;
+    if report.chunks_removed > 0 {
+        chunk_index.save()?;
     }
+    Ok(())
+}
 
-    /// Handle a complete session with a client
-    fn handle_session(
-        &self,
-        conn: &mut ServerConnection,
-        buf: &mut [u8],
-        out: &mut [u8],
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut session = ServerSession::new(conn);
-        session.run(&self.socket, buf, out)?;
-        Ok(())
+/// Handle a complete session with a client
+#[allow(clippy::too_many_arguments)]
+fn handle_session(
+    conn: &mut ServerConnection,
+    socket: &ConnectionSocket,
+    trusted_keys: Option<TrustedKeys>,
+    assemblies: Assemblies,
+    chunk_index: SharedChunkIndex,
+    observer: SharedObserver,
+    metadata_policy: MetadataPolicy,
+    conflict_policy: ConflictPolicy,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+    let mut out = [0u8; MAX_DATAGRAM_SIZE];
+    let mut session = ServerSession::new(conn);
+    if let Some(keys) = trusted_keys {
+        session.require_signed_manifests(keys);
     }
+    session.share_assemblies(assemblies);
+    session.share_chunk_index(chunk_index);
+    session.set_observer(observer);
+    session.set_metadata_policy(metadata_policy);
+    session.set_conflict_policy(conflict_policy);
+    session.run(socket, &mut buf, &mut out)?;
+    Ok(())
 }
 
 #[cfg(test)]
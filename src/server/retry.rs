@@ -0,0 +1,106 @@
+// Address validation for new connections
+//
+// A client's first Initial is answered with a Retry carrying a token, which
+// the client sends back in its next Initial; only then does the server set
+// up a connection. Initials from spoofed addresses never get that far, and
+// cost the server one packet each and no state.
+//
+// The token holds when it was minted and the connection ID the client first
+// chose, and is authenticated together with the client's address under a
+// key the server makes up when it starts. It is good for `TOKEN_LIFETIME`.
+
+use quiche::ConnectionId;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a client may take to come back with its token
+const TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+
+/// Bytes of the minting time at the start of a token
+const TIME_LEN: usize = 8;
+
+/// Mints and checks the tokens of Retry packets
+pub(crate) struct RetryTokens {
+    key: hmac::Key,
+    rng: SystemRandom,
+}
+
+impl RetryTokens {
+    pub(crate) fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let rng = SystemRandom::new();
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &rng)
+            .map_err(|_| "Failed to generate the address validation key")?;
+        Ok(Self { key, rng })
+    }
+
+    /// Token for a client at `peer` whose first Initial went to `odcid`
+    pub(crate) fn mint(&self, peer: SocketAddr, odcid: &[u8], now: SystemTime) -> Vec<u8> {
+        let minted_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut token = minted_at.to_be_bytes().to_vec();
+        token.extend_from_slice(odcid);
+        let tag = hmac::sign(&self.key, &signed_bytes(peer, &token));
+        token.extend_from_slice(tag.as_ref());
+        token
+    }
+
+    /// The connection ID the client at `peer` first chose, if `token` is
+    /// one minted for it that has not expired
+    pub(crate) fn validate(&self, peer: SocketAddr, token: &[u8], now: SystemTime) -> Option<ConnectionId<'static>> {
+        let tag_len = hmac::HMAC_SHA256.digest_algorithm().output_len();
+        if token.len() < TIME_LEN + tag_len {
+            return None;
+        }
+        let (data, tag) = token.split_at(token.len() - tag_len);
+        hmac::verify(&self.key, &signed_bytes(peer, data), tag).ok()?;
+
+        let (minted_at, odcid) = data.split_at(TIME_LEN);
+        let minted_at = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(minted_at.try_into().ok()?));
+        if now.duration_since(minted_at).ok()? > TOKEN_LIFETIME {
+            return None;
+        }
+        Some(ConnectionId::from_vec(odcid.to_vec()))
+    }
+
+    /// Connection ID for the server's side of a connection being validated
+    pub(crate) fn connection_id(&self) -> Result<ConnectionId<'static>, Box<dyn std::error::Error>> {
+        let mut id = vec![0u8; quiche::MAX_CONN_ID_LEN];
+        self.rng.fill(&mut id).map_err(|_| "Failed to generate connection ID")?;
+        Ok(ConnectionId::from_vec(id))
+    }
+}
+
+/// What a token's tag covers: the client's address and the token's data
+fn signed_bytes(peer: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut bytes = peer.to_string().into_bytes();
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_tokens() {
+        let tokens = RetryTokens::new().unwrap();
+        let peer: SocketAddr = "192.0.2.7:50000".parse().unwrap();
+        let now = SystemTime::now();
+        let token = tokens.mint(peer, b"first-dcid", now);
+
+        let odcid = tokens.validate(peer, &token, now + Duration::from_secs(1)).unwrap();
+        assert_eq!(odcid.as_ref(), b"first-dcid");
+
+        // Only from the address it was minted for, unaltered and in time
+        assert!(tokens.validate("192.0.2.8:50000".parse().unwrap(), &token, now).is_none());
+        let mut altered = token.clone();
+        altered[TIME_LEN] ^= 1;
+        assert!(tokens.validate(peer, &altered, now).is_none());
+        assert!(tokens.validate(peer, &token, now + TOKEN_LIFETIME * 2).is_none());
+        assert!(tokens.validate(peer, &token[..10], now).is_none());
+        assert!(RetryTokens::new().unwrap().validate(peer, &token, now).is_none());
+
+        assert_eq!(tokens.connection_id().unwrap().len(), quiche::MAX_CONN_ID_LEN);
+    }
+}
@@ -3,6 +3,8 @@
 use super::connection::ServerConnection;
 use crate::chunking::FileChunker;
 use crate::common::error::Result;
+use crate::protocol::chunk::frame_packet;
+use crate::protocol::messages::ChunkRange;
 use std::path::Path;
 
 /// Handles sending files to connected clients using chunked transfer
@@ -20,13 +22,14 @@ impl DataSender {
         }
     }
 
-    /// Send a file in chunks over a data stream
+    /// Send a file in chunks over a data stream, each packet after its length
     /// 
     /// # Arguments
     /// * `connection` - The server connection to send data on
     /// * `stream_id` - The data stream ID to send chunks on
     /// * `file_path` - Path to the file to send
     /// * `chunk_size` - Optional chunk size (uses DEFAULT_CHUNK_SIZE if None)
+    /// * `holes` - Chunks the manifest lists as holes, which are not sent
     /// 
     /// # Returns
     /// Total number of bytes sent
@@ -36,8 +39,9 @@ impl DataSender {
         stream_id: u64,
         file_path: &Path,
         chunk_size: Option<usize>,
+        holes: &[ChunkRange],
     ) -> Result<u64> {
-        let mut chunker = FileChunker::new(file_path, chunk_size)?;
+        let mut chunker = FileChunker::new(file_path, chunk_size)?.with_holes(holes.to_vec());
         let total_chunks = chunker.total_chunks();
         
         log::info!(
@@ -50,11 +54,14 @@ impl DataSender {
         let mut bytes_sent = 0u64;
         let mut chunk_count = 0u64;
 
+        let mut fin_sent = false;
+
         while let Some(chunk_packet) = chunker.next_chunk()? {
             // Send the chunk packet on the data stream
             // Don't set FIN until the last chunk
-            let is_last = chunk_count == total_chunks - 1;
-            match connection.stream_send(stream_id, &chunk_packet, is_last) {
+            let is_last = chunker.bytes_read() >= chunker.file_size();
+            fin_sent = is_last;
+            match connection.stream_send(stream_id, &frame_packet(&chunk_packet), is_last) {
                 Ok(written) => {
                     bytes_sent += written as u64;
                     chunk_count += 1;
@@ -74,6 +81,11 @@ impl DataSender {
             }
         }
 
+        // The file ended in a hole (or was empty), so no chunk carried the FIN
+        if !fin_sent {
+            connection.stream_send(stream_id, &[], true)?;
+        }
+
         self.total_bytes_sent += bytes_sent;
         self.total_chunks_sent += chunk_count;
 
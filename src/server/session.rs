@@ -1,15 +1,23 @@
 // Server session management
 
 use super::connection::ServerConnection;
-use super::streams::StreamManager;
+use super::streams::{StreamManager, StreamType};
 use super::sender::DataSender;
+use super::socket::ConnectionSocket;
 use super::transfer::TransferManager;
-use std::net::UdpSocket;
+use super::upload::{Assemblies, SharedChunkIndex};
+use super::UPLOAD_DIR;
+use crate::observer::TransferObserver;
+use crate::protocol::messages::ConflictPolicy;
+use crate::protocol::metadata::MetadataPolicy;
+use crate::protocol::signing::TrustedKeys;
+use crate::transport::TransferStreams;
 use std::time::{Duration, Instant};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
-const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const NETWORK_WAIT: Duration = Duration::from_millis(100);  // Longest wait for packets before re-checking the deadline
 
 /// Manages a complete session with a client
 pub struct ServerSession<'a> {
@@ -19,6 +27,7 @@ pub struct ServerSession<'a> {
     transfer_manager: TransferManager,
     upload_received: bool,
     processing_upload: bool,
+    request_answered: bool,
 }
 
 impl<'a> ServerSession<'a> {
@@ -31,13 +40,44 @@ impl<'a> ServerSession<'a> {
             transfer_manager: TransferManager::new(),
             upload_received: false,
             processing_upload: false,
+            request_answered: false,
         }
     }
 
+    /// Only accept uploads whose manifest is signed by one of `keys`
+    pub fn require_signed_manifests(&mut self, keys: TrustedKeys) {
+        self.transfer_manager.set_trusted_keys(keys);
+    }
+
+    /// Report the events of received uploads to `observer`
+    pub fn set_observer(&mut self, observer: Arc<dyn TransferObserver>) {
+        self.transfer_manager.set_observer(observer);
+    }
+
+    /// Apply the sender's file metadata to received files as far as `policy` allows
+    pub fn set_metadata_policy(&mut self, policy: MetadataPolicy) {
+        self.transfer_manager.set_metadata_policy(policy);
+    }
+
+    /// Handle uploads whose name is taken by `policy`, unless they ask otherwise
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.transfer_manager.set_conflict_policy(policy);
+    }
+
+    /// Share striped uploads with the sessions of other connections
+    pub(crate) fn share_assemblies(&mut self, assemblies: Assemblies) {
+        self.transfer_manager.share_assemblies(assemblies);
+    }
+
+    /// Share the chunk index with the sessions of other connections
+    pub(crate) fn share_chunk_index(&mut self, chunk_index: SharedChunkIndex) {
+        self.transfer_manager.share_chunk_index(chunk_index);
+    }
+
     /// Run the session until completion or timeout
     pub fn run(
         &mut self,
-        socket: &UdpSocket,
+        socket: &ConnectionSocket,
         buf: &mut [u8],
         out: &mut [u8],
     ) -> Result<(), Box<dyn std::error::Error>> {
@@ -48,7 +88,7 @@ impl<'a> ServerSession<'a> {
             return Err("Failed to establish connection".into());
         }
 
-        println!("Connection established, initializing streams...");
+        log::info!("Connection established, initializing streams...");
 
         // Initialize 4 streams for this connection
         self.stream_manager.initialize_streams(self.connection)?;
@@ -62,41 +102,42 @@ impl<'a> ServerSession<'a> {
     /// Complete the QUIC handshake
     fn complete_handshake(
         &mut self,
-        socket: &UdpSocket,
+        socket: &ConnectionSocket,
         buf: &mut [u8],
         out: &mut [u8],
     ) -> Result<(), Box<dyn std::error::Error>> {
         socket.set_nonblocking(true)?;
         let deadline = Instant::now() + Duration::from_secs(5);
         
-        println!("Server: completing handshake...");
+        log::info!("Server: completing handshake...");
         while !self.connection.is_established() && Instant::now() < deadline {
             // Try to receive packets
             match socket.recv_from(buf) {
                 Ok((len, from)) => {
-                    println!("Server: handshake recv {} bytes", len);
+                    log::debug!("Server: handshake recv {} bytes", len);
                     let to = socket.local_addr()?;
                     self.connection.process_packet(&mut buf[..len], from, to)?;
                     
                     // Check if migration was detected during handshake - abort immediately
                     if self.connection.migration_detected() {
-                        println!("Server: migration detected during handshake - aborting to accept new connection");
+                        log::info!("Server: migration detected during handshake - aborting to accept new connection");
                         return Err("Peer migration during handshake".into());
                     }
                     
                     self.connection.send_packets(socket, out)?;
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // No data available, send any pending packets
+                    // No data available, send any pending packets and wait for more
                     self.connection.send_packets(socket, out)?;
-                    std::thread::sleep(Duration::from_millis(10));
+                    let limit = deadline.saturating_duration_since(Instant::now());
+                    self.connection.wait_for_packets(socket, limit);
                 }
                 Err(e) => return Err(e.into()),
             }
         }
         
         if self.connection.is_established() {
-            println!("Server: handshake complete!");
+            log::info!("Server: handshake complete!");
         } else {
             return Err("Handshake timeout".into());
         }
@@ -107,7 +148,7 @@ impl<'a> ServerSession<'a> {
     /// Handle application data exchange
     fn handle_application_data(
         &mut self,
-        socket: &UdpSocket,
+        socket: &ConnectionSocket,
         buf: &mut [u8],
         out: &mut [u8],
     ) -> Result<(), Box<dyn std::error::Error>> {
@@ -117,17 +158,17 @@ impl<'a> ServerSession<'a> {
         while Instant::now() < deadline && !self.connection.is_closed() {
             // Receive packets
             if let Ok((len, from)) = socket.recv_from(buf) {
-                println!("Server: recv {} bytes from {}", len, from);
+                log::debug!("Server: recv {} bytes from {}", len, from);
                 let to = socket.local_addr()?;
                 match self.connection.process_packet(&mut buf[..len], from, to) {
                     Ok(_) => {
                         // Check if migration was detected - abort session to accept new connection
                         if self.connection.migration_detected() {
-                            println!("Server: migration detected - aborting session");
+                            log::info!("Server: migration detected - aborting session");
                             return Err("Peer migration detected - restarting".into());
                         }
                     }
-                    Err(e) => eprintln!("Server: packet processing error: {:?}", e),
+                    Err(e) => log::warn!("Server: packet processing error: {:?}", e),
                 }
             }
 
@@ -138,20 +179,22 @@ impl<'a> ServerSession<'a> {
             self.connection.send_packets(socket, out)?;
 
             // Exit if upload was received and processed
-            if self.upload_received {
-                std::thread::sleep(Duration::from_millis(100));
+            if self.upload_received || self.request_answered {
                 break;
             }
 
-            std::thread::sleep(POLL_INTERVAL);
+            let limit = deadline.saturating_duration_since(Instant::now()).min(NETWORK_WAIT);
+            self.connection.wait_for_packets(socket, limit);
         }
 
         socket.set_nonblocking(false)?;
 
         if self.upload_received {
-            println!("✅ Upload received successfully, closing connection.");
+            log::info!("Upload received successfully, closing connection.");
+        } else if self.request_answered {
+            log::info!("Storage request answered, closing connection.");
         } else {
-            println!("Timeout reached, no upload received. Closing connection.");
+            log::info!("Timeout reached, no upload received. Closing connection.");
         }
 
         Ok(())
@@ -160,40 +203,38 @@ impl<'a> ServerSession<'a> {
     /// Process all readable streams
     fn process_readable_streams(
         &mut self,
-        socket: &UdpSocket,
+        socket: &ConnectionSocket,
         buf: &mut [u8],
     ) -> Result<(), Box<dyn std::error::Error>> {
         let readable: Vec<u64> = self.connection.readable().collect();
         
         if !readable.is_empty() {
-            println!("Server: conn.readable() -> {:?}", readable);
+            log::debug!("Server: conn.readable() -> {:?}", readable);
         }
 
-        // Check if this looks like a file upload (manifest on stream 4)
-        // Process the upload once when we see manifest stream
-        if readable.contains(&4) && !self.processing_upload && !self.upload_received {
-            println!("Server: detected file upload (manifest ready), starting integrated receive...");
+        // Any readable transfer stream starts receiving uploads; the client
+        // may multiplex several of them over this connection, preceded by a
+        // directory manifest on the control stream for recursive uploads
+        let transfer_started = readable.iter().any(|&stream| {
+            stream == StreamType::Control.stream_id() || TransferStreams::lookup(stream).is_some()
+        });
+        if transfer_started && !self.processing_upload && !self.upload_received {
+            log::info!("Server: detected file upload, starting integrated receive...");
             self.processing_upload = true;
             
             // Use integrated file receive
-            let upload_dir = PathBuf::from("./uploads");
+            let upload_dir = PathBuf::from(UPLOAD_DIR);
             std::fs::create_dir_all(&upload_dir)?;
             
-            match self.transfer_manager.receive_file_integrated(
-                self.connection,
-                socket,
-                &upload_dir,
-                4, // STREAM_MANIFEST (client-initiated bidirectional stream 1)
-                8, // STREAM_DATA (client-initiated bidirectional stream 2)
-            ) {
-                Ok((file_path, bytes)) => {
-                    println!("\n✅ File upload successful!");
-                    println!("  File saved to: {:?}", file_path);
-                    println!("  Total bytes: {} ({:.2} MB)", bytes, bytes as f64 / 1_048_576.0);
+            match self.transfer_manager.receive_files(self.connection, socket, &upload_dir) {
+                Ok(uploads) => {
+                    for (file_path, bytes) in &uploads {
+                        log::info!("Server: file upload successful: {:?} ({} bytes)", file_path, bytes);
+                    }
                     self.upload_received = true;
                 }
                 Err(e) => {
-                    eprintln!("❌ File upload failed: {:?}", e);
+                    log::error!("Server: file upload failed: {:?}", e);
                     self.processing_upload = false;
                 }
             }
@@ -206,6 +247,16 @@ impl<'a> ServerSession<'a> {
             return Ok(());
         }
 
+        // A request about stored files comes on the status stream alone
+        if readable.contains(&StreamType::Status.stream_id()) && !self.request_answered {
+            log::info!("Server: answering a storage request...");
+            let upload_dir = PathBuf::from(UPLOAD_DIR);
+            std::fs::create_dir_all(&upload_dir)?;
+            self.transfer_manager.answer_storage_request(self.connection, socket, &upload_dir)?;
+            self.request_answered = true;
+            return Ok(());
+        }
+
         // Process other streams normally
         for stream_id in readable {
             self.handle_stream_data(stream_id, buf)?;
@@ -229,7 +280,7 @@ impl<'a> ServerSession<'a> {
                     }
 
                     let msg = String::from_utf8_lossy(&buf[..read]);
-                    println!("Server received on stream {}: {}", stream_id, msg);
+                    log::info!("Server received on stream {}: {}", stream_id, msg);
 
                     // Send response using DataSender
                     let reply = b"Hello from QUIC server!";
@@ -247,7 +298,7 @@ impl<'a> ServerSession<'a> {
                 }
                 Err(quiche::Error::Done) => break,
                 Err(e) => {
-                    eprintln!("Server: stream_recv error on {}: {:?}", stream_id, e);
+                    log::warn!("Server: stream_recv error on {}: {:?}", stream_id, e);
                     break;
                 }
             }
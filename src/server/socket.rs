@@ -0,0 +1,158 @@
+// Per-connection view of the server's UDP socket
+//
+// The server runs each connection on its own thread so a client can stripe
+// one file across several connections at once. Datagrams are read from the
+// shared socket by the accept loop and routed to the owning connection by
+// destination connection ID; replies go straight out of the shared socket,
+// or, for the async server, are queued for its accept loop to send.
+
+use crossbeam_channel::{Receiver, Select, Sender, TryRecvError};
+use std::cell::Cell;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A datagram routed to one connection, with its source address
+pub type Datagram = (Vec<u8>, SocketAddr);
+
+/// Datagrams queued for a connection before more are dropped
+const INBOX_LEN: usize = 1024;
+
+/// Where a connection's datagrams go out
+#[derive(Clone)]
+pub(crate) enum Outgoing {
+    /// Straight out of the shared socket, waiting while its send buffer is full
+    Socket(Arc<UdpSocket>),
+    /// To the async accept loop, which sends them from its own socket;
+    /// waits while the queue is full
+    #[cfg(feature = "async")]
+    Queue(tokio::sync::mpsc::Sender<Datagram>),
+}
+
+/// Socket handle of one connection
+///
+/// Offers the subset of `UdpSocket` used by sessions, with `recv_from`
+/// reading only this connection's datagrams.
+pub struct ConnectionSocket {
+    outgoing: Outgoing,
+    local_addr: SocketAddr,
+    inbox: Receiver<Datagram>,
+    nonblocking: Cell<bool>,
+}
+
+impl ConnectionSocket {
+    /// Create a connection socket and the sender that feeds it datagrams
+    ///
+    /// The sender holds up to `INBOX_LEN` datagrams the connection has not
+    /// read yet.
+    pub fn new(socket: Arc<UdpSocket>) -> io::Result<(Self, Sender<Datagram>)> {
+        let local_addr = socket.local_addr()?;
+        Ok(Self::sending(Outgoing::Socket(socket), local_addr))
+    }
+
+    /// Create a connection socket that sends through `outgoing`
+    pub(crate) fn sending(outgoing: Outgoing, local_addr: SocketAddr) -> (Self, Sender<Datagram>) {
+        let (sender, inbox) = crossbeam_channel::bounded(INBOX_LEN);
+        (
+            Self {
+                outgoing,
+                local_addr,
+                inbox,
+                nonblocking: Cell::new(false),
+            },
+            sender,
+        )
+    }
+
+    /// Receive the next datagram for this connection
+    ///
+    /// In non-blocking mode, fails with `WouldBlock` when none is queued.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (data, from) = if self.nonblocking.get() {
+            match self.inbox.try_recv() {
+                Ok(datagram) => datagram,
+                Err(TryRecvError::Empty) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Err(io::ErrorKind::NotConnected.into()),
+            }
+        } else {
+            self.inbox.recv().map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?
+        };
+
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    }
+
+    /// Block until a datagram is queued for this connection or `timeout` passes
+    ///
+    /// # Returns
+    /// Whether `recv_from` has something to return
+    pub fn wait(&self, timeout: Option<Duration>) -> bool {
+        let mut select = Select::new();
+        select.recv(&self.inbox);
+        match timeout {
+            Some(timeout) => select.ready_timeout(timeout).is_ok(),
+            None => {
+                select.ready();
+                true
+            }
+        }
+    }
+
+    /// Send a datagram from the shared socket
+    ///
+    /// Fails with `NotConnected` once an async accept loop that sends it
+    /// has stopped.
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match &self.outgoing {
+            Outgoing::Socket(socket) => socket.send_to(buf, addr),
+            #[cfg(feature = "async")]
+            Outgoing::Queue(queue) => {
+                queue
+                    .blocking_send((buf.to_vec(), addr))
+                    .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    /// Address of the shared socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Choose whether `recv_from` waits for a datagram
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.set(nonblocking);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routed_datagrams() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let (conn_socket, sender) = ConnectionSocket::new(socket.clone()).unwrap();
+        assert_eq!(conn_socket.local_addr().unwrap(), socket.local_addr().unwrap());
+
+        let mut buf = [0u8; 16];
+        conn_socket.set_nonblocking(true).unwrap();
+        let err = conn_socket.recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(!conn_socket.wait(Some(Duration::from_millis(10))));
+
+        let from: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        sender.send((b"hello".to_vec(), from)).unwrap();
+        assert!(conn_socket.wait(Some(Duration::from_secs(5))));
+        assert_eq!(conn_socket.recv_from(&mut buf).unwrap(), (5, from));
+        assert_eq!(&buf[..5], b"hello");
+
+        drop(sender);
+        conn_socket.set_nonblocking(false).unwrap();
+        assert_eq!(conn_socket.recv_from(&mut buf).unwrap_err().kind(), io::ErrorKind::NotConnected);
+    }
+}
@@ -89,7 +89,7 @@ impl StreamManager {
             };
             
             self.streams.push(info);
-            println!("Initialized stream: {:?} with ID {}", stream_type, stream_id);
+            log::debug!("Initialized stream: {:?} with ID {}", stream_type, stream_id);
         }
 
         Ok(())
@@ -1,22 +1,46 @@
 // Server-side transfer logic
 
 use super::connection::ServerConnection;
+use super::download::{download_manifest, OutgoingDownload};
 use super::sender::DataSender;
-use crate::protocol::manifest::ManifestBuilder;
-use crate::transport::manifest_stream::ManifestSender;
-use crate::protocol::hash_check::{HashCheckRequestReceiver, HashCheckResponseSender};
-use crate::protocol::resume::{ResumeRequestReceiver, ResumeResponseSender};
-use crate::chunking::ChunkBitmap;
+use super::socket::ConnectionSocket;
+use super::streams::StreamType;
+use super::upload::{Assemblies, IncomingUpload, SharedChunkIndex};
+use crate::chunking::ChunkHashIndex;
+use crate::common::types::TransferState;
+use crate::observer::{self, SharedObserver};
+use crate::common::error::Error;
+use crate::protocol::directory::safe_relative_path;
+use crate::protocol::messages::{
+    ConflictPolicy, DirectoryManifest, EncryptionInfo, FileVersion, StorageAction, StorageRequest, StorageResponse,
+};
+use crate::protocol::metadata::MetadataPolicy;
+use crate::protocol::signing::{self, TrustedKeys};
+use crate::storage::{self, VersionStore};
+use crate::transport::manifest_pages::PagedManifestSender;
+use crate::transport::{TransferId, TransferStreams};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 const DEFAULT_CHUNK_SIZE: usize = 8192;
-const STREAM_HASH_CHECK: u64 = 16;  // Client-initiated bidirectional stream for hash checks (changed from 1)
-const STREAM_RESUME: u64 = 20;      // Client-initiated bidirectional stream for resume protocol
+const MULTIPLEX_LINGER: Duration = Duration::from_secs(1);  // Wait for further transfers once all are done
+const NETWORK_WAIT: Duration = Duration::from_millis(100);  // Longest wait for packets before re-checking deadlines
+const MAX_STORAGE_REQUEST: usize = 4 * 1024 * 1024;  // Largest request on the status stream, like a manifest frame
+
+type BoxResult<T> = Result<T, Box<dyn std::error::Error>>;
 
 /// Manages file transfers to clients
 pub struct TransferManager {
     sender: DataSender,
     chunk_size: usize,
+    trusted_keys: Option<TrustedKeys>,
+    assemblies: Assemblies,
+    /// Chunk index shared with other connections' managers, if any
+    chunk_index: Option<SharedChunkIndex>,
+    observer: SharedObserver,
+    metadata_policy: MetadataPolicy,
+    conflict_policy: ConflictPolicy,
 }
 
 impl TransferManager {
@@ -25,6 +49,12 @@ impl TransferManager {
         Self {
             sender: DataSender::new(),
             chunk_size: DEFAULT_CHUNK_SIZE,
+            trusted_keys: None,
+            assemblies: Assemblies::new(),
+            chunk_index: None,
+            observer: observer::noop(),
+            metadata_policy: MetadataPolicy::default(),
+            conflict_policy: ConflictPolicy::Overwrite,
         }
     }
 
@@ -33,6 +63,12 @@ impl TransferManager {
         Self {
             sender: DataSender::new(),
             chunk_size,
+            trusted_keys: None,
+            assemblies: Assemblies::new(),
+            chunk_index: None,
+            observer: observer::noop(),
+            metadata_policy: MetadataPolicy::default(),
+            conflict_policy: ConflictPolicy::Overwrite,
         }
     }
 
@@ -51,16 +87,17 @@ impl TransferManager {
         stream_id: u64,
         file_path: &Path,
     ) -> Result<u64, Box<dyn std::error::Error>> {
-        println!("TransferManager: starting file transfer from {:?}", file_path);
+        log::info!("TransferManager: starting file transfer from {:?}", file_path);
 
         let bytes_sent = self.sender.send_file(
             connection,
             stream_id,
             file_path,
             Some(self.chunk_size),
+            &[],
         )?;
 
-        println!("TransferManager: file transfer complete ({} bytes)", bytes_sent);
+        log::info!("TransferManager: file transfer complete ({} bytes)", bytes_sent);
         
         Ok(bytes_sent)
     }
@@ -80,7 +117,7 @@ impl TransferManager {
         stream_id: u64,
         data: &[u8],
     ) -> Result<usize, Box<dyn std::error::Error>> {
-        println!(
+        log::debug!(
             "TransferManager: transferring {} bytes on stream {}",
             data.len(),
             stream_id
@@ -128,6 +165,45 @@ impl TransferManager {
     pub fn total_chunks_sent(&self) -> u64 {
         self.sender.total_chunks_sent()
     }
+
+    /// Only accept uploads whose manifest is signed by one of `keys`
+    pub fn set_trusted_keys(&mut self, keys: TrustedKeys) {
+        self.trusted_keys = Some(keys);
+    }
+
+    /// Assemble striped uploads together with other connections' managers
+    pub(crate) fn share_assemblies(&mut self, assemblies: Assemblies) {
+        self.assemblies = assemblies;
+    }
+
+    /// Keep chunks in the index other connections' managers use
+    pub(crate) fn share_chunk_index(&mut self, chunk_index: SharedChunkIndex) {
+        self.chunk_index = Some(chunk_index);
+    }
+
+    /// The shared chunk index, or the one under `output_dir` for a manager
+    /// that shares none
+    fn chunk_index(&self, output_dir: &Path) -> BoxResult<SharedChunkIndex> {
+        match &self.chunk_index {
+            Some(chunk_index) => Ok(chunk_index.clone()),
+            None => Ok(SharedChunkIndex::new(open_chunk_index(output_dir)?)),
+        }
+    }
+
+    /// Report the events of received uploads to `observer`
+    pub fn set_observer(&mut self, observer: SharedObserver) {
+        self.observer = observer;
+    }
+
+    /// Apply the sender's file metadata to received files as far as `policy` allows
+    pub fn set_metadata_policy(&mut self, policy: MetadataPolicy) {
+        self.metadata_policy = policy;
+    }
+
+    /// Handle uploads whose name is taken by `policy`, unless they ask otherwise
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.conflict_policy = policy;
+    }
     
     /// Integrated file send with manifest and chunks
     /// This orchestrates: Manifest build -> Manifest send -> Chunk send
@@ -136,8 +212,8 @@ impl TransferManager {
     /// * `connection` - The server connection
     /// * `file_path` - Path to the file to transfer
     /// * `session_id` - Session ID for this transfer
-    /// * `manifest_stream` - Stream ID for manifest (typically STREAM_MANIFEST = 1)
-    /// * `data_stream` - Stream ID for data chunks (typically STREAM_DATA = 2)
+    /// * `streams` - Streams of the transfer; several downloads can share a
+    ///   connection by using different transfers
     /// 
     /// # Returns
     /// Total bytes sent (manifest + chunks)
@@ -146,24 +222,21 @@ impl TransferManager {
         connection: &mut ServerConnection,
         file_path: &Path,
         session_id: String,
-        manifest_stream: u64,
-        data_stream: u64,
+        streams: &TransferStreams,
     ) -> Result<u64, Box<dyn std::error::Error>> {
         log::info!("TransferManager: starting integrated file send for {:?}", file_path);
         
         // Build manifest
         log::info!("Building manifest...");
-        let manifest = ManifestBuilder::new(session_id)
-            .file_path(file_path)
-            .chunk_size(self.chunk_size as u32)
-            .build()?;
+        let manifest = download_manifest(file_path, session_id, self.chunk_size, &self.metadata_policy, streams)?;
         
         log::info!("Manifest built: {} chunks, {} bytes total", 
             manifest.total_chunks, manifest.file_size);
         
         // Send manifest
+        let manifest_stream = streams.manifest;
         log::info!("Sending manifest on stream {}...", manifest_stream);
-        let manifest_sender = ManifestSender::new();
+        let manifest_sender = PagedManifestSender::new();
         let manifest_bytes = manifest_sender.send_manifest(&manifest, |data, fin| {
             Ok(connection.stream_send(manifest_stream, data, fin)?)
         })?;
@@ -171,12 +244,13 @@ impl TransferManager {
         log::info!("Manifest sent: {} bytes", manifest_bytes);
         
         // Send file chunks
-        log::info!("Sending file chunks on stream {}...", data_stream);
+        log::info!("Sending file chunks on stream {}...", streams.data);
         let chunks_bytes = self.sender.send_file(
             connection,
-            data_stream,
+            streams.data,
             file_path,
             Some(self.chunk_size),
+            &manifest.holes,
         )?;
         
         log::info!("File chunks sent: {} bytes", chunks_bytes);
@@ -194,509 +268,482 @@ impl TransferManager {
     /// * `connection` - The server connection
     /// * `socket` - The UDP socket for receiving packets
     /// * `output_dir` - Directory where received file will be saved
-    /// * `manifest_stream` - Stream ID for manifest (typically STREAM_MANIFEST = 4)
-    /// * `data_stream` - Stream ID for data chunks (typically STREAM_DATA = 8)
+    /// * `streams` - Streams of the transfer to receive (`TransferStreams::for_transfer(0)`
+    ///   for single-file clients)
     /// 
     /// # Returns
-    /// Path to the assembled file and total bytes received
+    /// Path to the assembled file (the output directory for a bundle of
+    /// small files) and total bytes received
     pub fn receive_file_integrated(
         &mut self,
         connection: &mut ServerConnection,
-        socket: &std::net::UdpSocket,
+        socket: &ConnectionSocket,
         output_dir: &Path,
-        manifest_stream: u64,
-        data_stream: u64,
+        streams: TransferStreams,
     ) -> Result<(PathBuf, u64), Box<dyn std::error::Error>> {
-        use crate::transport::manifest_stream::ManifestReceiver;
-        use crate::client::receiver::FileReceiver;
-        use std::time::Duration;
-        
-        log::info!("TransferManager: starting integrated file receive");
+        log::info!("TransferManager: starting integrated file receive on transfer {}", streams.transfer_id);
         
+        let chunk_index = self.chunk_index(output_dir)?;
+        let mut upload = IncomingUpload::new(streams, output_dir)
+            .with_observer(self.observer.clone())
+            .with_metadata_policy(self.metadata_policy)
+            .with_conflict_policy(self.conflict_policy);
         let mut buf = vec![0u8; 65535];
         let mut out = vec![0u8; 65535];
         
-        // --- MANIFEST RECEIVE PHASE ---
-        log::info!("Receiving manifest on stream {}...", manifest_stream);
-        let mut manifest_receiver = ManifestReceiver::new();
-        let mut manifest_buffer = vec![0u8; 65535];
-        
-        let manifest = loop {
-            // First, receive packets from network
-            socket.set_read_timeout(Some(Duration::from_millis(10)))?;
-            if let Ok((len, from)) = socket.recv_from(&mut buf) {
-                let _ = connection.process_packet(&mut buf[..len], from, socket.local_addr()?);
+        loop {
+            pump_network(connection, socket, &mut buf, &mut out)?;
+            if connection.is_closed() {
+                return Err("Connection closed during upload".into());
             }
             
-            // Send any response packets
-            let _ = connection.send_packets(socket, &mut out);
-            
-            // Now try to read from stream
-            match connection.stream_recv(manifest_stream, &mut manifest_buffer) {
-                Ok((read, fin)) => {
-                    if read > 0 {
-                        match manifest_receiver.receive_chunk(&manifest_buffer[..read], fin) {
-                            Ok(Some(m)) => {
-                                log::info!("Server: received manifest for file: {}", m.file_name);
-                                break m;
-                            }
-                            Ok(None) => {
-                                // Need more data
-                                continue;
-                            }
-                            Err(e) => {
-                                return Err(format!("Manifest receive error: {:?}", e).into());
-                            }
-                        }
-                    }
-                }
-                Err(quiche::Error::Done) => {
-                    continue;
+            match self.drive_upload(connection, &mut upload, &chunk_index, &mut buf) {
+                Ok(Some(files)) => {
+                    let _ = connection.send_packets(socket, &mut out);
+                    return Ok(match files.as_slice() {
+                        [single] => single.clone(),
+                        _ => (output_dir.to_path_buf(), files.iter().map(|(_, size)| size).sum()),
+                    });
                 }
+                Ok(None) => {}
                 Err(e) => {
-                    return Err(format!("Stream receive error: {:?}", e).into());
-                }
-            }
-        };
-        
-        log::info!("Manifest received: {} chunks, {} bytes", 
-            manifest.total_chunks, manifest.file_size);
-        
-        // --- RESUME PROTOCOL PHASE ---
-        // Check if client wants to resume a partial transfer
-        log::info!("Server: checking for resume request on stream {}...", STREAM_RESUME);
-        
-        let mut chunk_bitmap = ChunkBitmap::with_exact_size(manifest.total_chunks as u32);
-        let bitmap_path = output_dir.join(format!(".{}.bitmap", manifest.session_id));
-        let mut resume_mode = false;
-        let mut skip_chunks: std::collections::HashSet<u64> = std::collections::HashSet::new();
-        
-        // Wait briefly for resume request
-        let mut resume_request_receiver = ResumeRequestReceiver::new();
-        let mut resume_iterations = 0;
-        const MAX_RESUME_WAIT: usize = 20;  // 20 * 10ms = 200ms (reduced to avoid client timeout)
-        
-        while resume_iterations < MAX_RESUME_WAIT {
-            // Process network I/O
-            socket.set_read_timeout(Some(Duration::from_millis(10)))?;
-            if let Ok((len, from)) = socket.recv_from(&mut buf) {
-                let _ = connection.process_packet(&mut buf[..len], from, socket.local_addr()?);
-            }
-            let _ = connection.send_packets(socket, &mut out);
-            
-            // Check if resume stream is readable
-            let readable_streams: Vec<u64> = connection.readable().collect();
-            if readable_streams.contains(&STREAM_RESUME) {
-                match connection.stream_recv(STREAM_RESUME, &mut buf) {
-                    Ok((read, fin)) => {
-                        if read > 0 {
-                            if let Ok(Some(request)) = resume_request_receiver.receive_chunk(&buf[..read], fin) {
-                                log::info!("Server: received resume request with {} received chunks", request.received_chunks.len());
-                                resume_mode = true;
-                                
-                                // Reconstruct bitmap from received chunks
-                                for &chunk_idx in &request.received_chunks {
-                                    if chunk_idx < manifest.total_chunks {
-                                        chunk_bitmap.mark_received(chunk_idx as u32, chunk_idx == manifest.total_chunks - 1);
-                                        skip_chunks.insert(chunk_idx);
-                                    }
-                                }
-                                
-                                // Find missing chunks
-                                let missing = chunk_bitmap.find_missing();
-                                let missing_u64: Vec<u64> = missing.iter().map(|&x| x as u64).collect();
-                                
-                                log::info!("Server: {} of {} chunks already received, {} missing", 
-                                    request.received_chunks.len(), manifest.total_chunks, missing_u64.len());
-                                
-                                // Send resume response
-                                let resume_response_sender = ResumeResponseSender::new();
-                                resume_response_sender.send_response(
-                                    request.session_id.clone(),
-                                    true,
-                                    missing_u64.clone(),
-                                    missing_u64.len() as u64,
-                                    None,
-                                    |data, fin| connection.stream_send(STREAM_RESUME, data, fin)
-                                )?;
-                                
-                                // Flush response
-                                for _ in 0..10 {
-                                    let _ = connection.send_packets(socket, &mut out);
-                                    std::thread::sleep(Duration::from_millis(5));
-                                }
-                                
-                                log::info!("Server: resume response sent");
-                                break;
-                            }
-                        }
-                    }
-                    Err(quiche::Error::Done) => {}
-                    Err(e) => log::debug!("Server: resume stream error: {:?}", e),
+                    self.report_failure(&upload, &*e);
+                    reset_upload(connection, &mut upload);
+                    let _ = connection.send_packets(socket, &mut out);
+                    return Err(e);
                 }
             }
             
-            resume_iterations += 1;
-            std::thread::sleep(Duration::from_millis(10));
-        }
-        
-        if !resume_mode {
-            log::info!("Server: no resume request received, starting fresh transfer");
+            let _ = connection.send_packets(socket, &mut out);
         }
+    }
+    
+    /// Receive every upload a client multiplexes over one connection
+    /// 
+    /// Each transfer is picked up when its first stream becomes readable and
+    /// runs independently; a failed upload has its streams reset without
+    /// affecting the others. A recursive upload first sends its directory
+    /// manifest on the control stream. Returns once the client closes the
+    /// connection, or when no upload has been active for a short while.
+    /// 
+    /// # Returns
+    /// Path and size of each completed upload, in completion order
+    pub fn receive_files(
+        &mut self,
+        connection: &mut ServerConnection,
+        socket: &ConnectionSocket,
+        output_dir: &Path,
+    ) -> Result<Vec<(PathBuf, u64)>, Box<dyn std::error::Error>> {
+        let chunk_index = self.chunk_index(output_dir)?;
+        let mut uploads: HashMap<TransferId, IncomingUpload> = HashMap::new();
+        let mut finished: HashSet<TransferId> = HashSet::new();
+        // Transfer slots an upload took for extra data streams
+        let mut reserved: HashSet<TransferId> = HashSet::new();
+        let mut completed = Vec::new();
+        let mut last_error = None;
+        let mut idle_since = Instant::now();
+        let mut tree_buffer = Vec::new();
+        let mut tree: Option<DirectoryManifest> = None;
+        let mut buf = vec![0u8; 65535];
+        let mut out = vec![0u8; 65535];
         
-        // --- HASH CHECK PHASE (Deduplication) ---
-        log::info!("Server: waiting for hash check request on stream {} (client-initiated)...", STREAM_HASH_CHECK);
-        
-        // Create chunk index
-        use crate::chunking::ChunkHashIndex;
-        let index_dir = output_dir.join(".sftpx");
-        std::fs::create_dir_all(&index_dir)?;
-        let mut chunk_index = ChunkHashIndex::new(&index_dir).unwrap_or_else(|e| {
-            log::warn!("Server: failed to create/load chunk index: {:?}", e);
-            ChunkHashIndex::new(&std::env::temp_dir()).expect("Failed to create temp index")
-        });
-        
-        // Receive hash check request on client-initiated stream STREAM_HASH_CHECK
-        let mut hash_request_receiver = HashCheckRequestReceiver::new();
-        let mut hash_request_received = false;
-        let mut chunk_hashes_to_check = vec![];
-        let mut idle_iterations = 0;
-        let mut total_bytes_received = 0;
-        const MAX_IDLE: usize = 500;  // Increased timeout: 500 * 10ms = 5 seconds
-        
-        socket.set_read_timeout(Some(Duration::from_millis(10)))?;
-        
-        while !hash_request_received && idle_iterations < MAX_IDLE {
-            let mut made_progress = false;
+        while !connection.is_closed() {
+            pump_network(connection, socket, &mut buf, &mut out)?;
             
-            // Process network I/O to receive hash check request
-            match socket.recv_from(&mut buf) {
-                Ok((len, from)) => {
-                    log::debug!("Server: received {} bytes from {}", len, from);
-                    let _ = connection.process_packet(&mut buf[..len], from, socket.local_addr()?);
-                    made_progress = true;
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
-                    // No data available
+            // Directories and empty files of a recursive upload are created
+            // as soon as its directory manifest arrives
+            while let Ok((read, fin)) = connection.stream_recv(StreamType::Control.stream_id(), &mut buf) {
+                tree_buffer.extend_from_slice(&buf[..read]);
+                if !fin {
+                    continue;
                 }
-                Err(e) => {
-                    log::warn!("Server: socket recv error during hash check: {:?}", e);
+                match DirectoryManifest::decode_from_bytes(&tree_buffer) {
+                    Ok(manifest) => {
+                        manifest.create_skeleton(output_dir, &self.metadata_policy)?;
+                        log::info!("Server: receiving directory {} ({} entries, {} bytes)",
+                            manifest.root_name, manifest.entries.len(), manifest.total_size);
+                        tree = Some(manifest);
+                    }
+                    Err(e) => log::warn!("Server: ignoring control stream data: {}", e),
                 }
+                break;
             }
             
-            // Send any response packets
-            let _ = connection.send_packets(socket, &mut out);
-            
-            // Log readable streams for debugging
-            if idle_iterations % 100 == 0 {
-                let readable_streams: Vec<u64> = connection.readable().collect();
-                if !readable_streams.is_empty() {
-                    log::debug!("Server: readable streams: {:?}", readable_streams);
+            // A readable stream of an unknown transfer opens a new upload
+            let readable: Vec<u64> = connection.readable().collect();
+            for stream_id in readable {
+                let Some((transfer_id, _)) = TransferStreams::lookup(stream_id) else {
+                    continue;
+                };
+                if finished.contains(&transfer_id) || uploads.contains_key(&transfer_id)
+                    || reserved.contains(&transfer_id) {
+                    continue;
                 }
+                log::info!("Server: transfer {} opened (stream {})", transfer_id, stream_id);
+                let upload = IncomingUpload::new(TransferStreams::for_transfer(transfer_id), output_dir)
+                    .with_observer(self.observer.clone())
+                    .with_metadata_policy(self.metadata_policy)
+                    .with_conflict_policy(self.conflict_policy);
+                uploads.insert(transfer_id, upload);
             }
             
-            // Only try to read from stream 16 if it's actually readable
-            let readable_streams: Vec<u64> = connection.readable().collect();
-            if readable_streams.contains(&STREAM_HASH_CHECK) {
-                // Check for hash check request on STREAM_HASH_CHECK
-                loop {
-                    match connection.stream_recv(STREAM_HASH_CHECK, &mut buf[..]) {
-                        Ok((read, fin)) => {
-                            if read > 0 {
-                                total_bytes_received += read;
-                                log::debug!("Server: read {} bytes from hash check stream (total: {}, fin: {})", read, total_bytes_received, fin);
-                                made_progress = true;
-                                
-                                match hash_request_receiver.receive_chunk(&buf[..read], fin) {
-                                    Ok(Some(request)) => {
-                                        chunk_hashes_to_check = request.chunk_hashes;
-                                        hash_request_received = true;
-                                        log::info!("Server: received hash check request with {} hashes ({} bytes)", chunk_hashes_to_check.len(), total_bytes_received);
-                                        break;
-                                    }
-                                    Ok(None) => {
-                                        // Need more data, continue reading
-                                        log::debug!("Server: hash check incomplete, need more data");
-                                        continue;
-                                    }
-                                    Err(e) => {
-                                        log::error!("Server: hash check parse error: {:?}", e);
-                                        break;
-                                    }
-                                }
-                            }
-                            if fin {
-                                log::debug!("Server: hash check stream closed (fin received)");
-                                break;
-                            }
-                            if read == 0 {
-                                break;
-                            }
-                        }
-                        Err(quiche::Error::Done) => break,
-                        Err(e) => {
-                            log::debug!("Server: stream_recv error on stream {}: {:?}", STREAM_HASH_CHECK, e);
-                            break;
-                        }
+            let mut done = Vec::new();
+            for (&transfer_id, upload) in uploads.iter_mut() {
+                let result = self.drive_upload(connection, upload, &chunk_index, &mut buf);
+                reserved.extend(upload.data_streams().iter()
+                    .skip(1)
+                    .filter_map(|&stream_id| TransferStreams::lookup(stream_id))
+                    .map(|(slot, _)| slot));
+                match result {
+                    Ok(Some(files)) => {
+                        completed.extend(files);
+                        done.push(transfer_id);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        log::error!("Server: transfer {} failed: {}", upload.streams().transfer_id, e);
+                        self.report_failure(upload, &*e);
+                        reset_upload(connection, upload);
+                        last_error = Some(e);
+                        done.push(transfer_id);
                     }
                 }
             }
-            
-            if hash_request_received {
-                break;
+            for transfer_id in done {
+                uploads.remove(&transfer_id);
+                finished.insert(transfer_id);
             }
             
-            // Only increment idle counter if we didn't make any progress
-            if !made_progress {
-                idle_iterations += 1;
-            } else {
-                idle_iterations = 0;  // Reset on progress
-            }
+            let _ = connection.send_packets(socket, &mut out);
             
-            if connection.is_closed() {
-                log::warn!("Server: connection closed during hash check");
+            if !uploads.is_empty() {
+                idle_since = Instant::now();
+            } else if !finished.is_empty() && idle_since.elapsed() > MULTIPLEX_LINGER {
                 break;
             }
         }
         
-        if !hash_request_received {
-            log::warn!("Server: hash check request not received after {} iterations, proceeding without dedup", idle_iterations);
-        }
+        log::info!("Server: {} of {} transfers completed on this connection",
+            completed.len(), finished.len());
         
-        // Check which hashes exist in the index
-        let mut existing_hashes = vec![];
-        for hash in &chunk_hashes_to_check {
-            if chunk_index.has_chunk(hash) {
-                existing_hashes.push(hash.clone());
+        if let Some(tree) = &tree {
+            match tree.apply_directory_metadata(output_dir, &self.metadata_policy) {
+                Ok(true) => log::info!("Server: directory {} complete", tree.root_name),
+                Ok(false) => log::info!("Server: directory {} still has files to come", tree.root_name),
+                Err(e) => log::warn!("Server: failed to apply metadata to {}: {}", tree.root_name, e),
             }
         }
         
-        log::info!("Server: {} out of {} chunks already exist", 
-            existing_hashes.len(), chunk_hashes_to_check.len());
+        match last_error {
+            Some(e) if completed.is_empty() => Err(e),
+            _ => Ok(completed),
+        }
+    }
+    
+    /// Answer a client's request about stored files, such as listing or
+    /// restoring the versions of one
+    /// 
+    /// The request arrives on the status stream and is answered on it. A
+    /// download, once accepted there, is sent on the streams of transfer 0.
+    /// Returns once the client closes the connection, or a short while after
+    /// the answer is sent when it is not a download.
+    pub fn answer_storage_request(
+        &mut self,
+        connection: &mut ServerConnection,
+        socket: &ConnectionSocket,
+        output_dir: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let status_stream = StreamType::Status.stream_id();
+        let chunk_index = self.chunk_index(output_dir)?;
+        let mut request = Vec::new();
+        let mut answer: Option<Vec<u8>> = None;
+        let mut answered_at: Option<Instant> = None;
+        let mut download: Option<OutgoingDownload> = None;
+        let mut buf = vec![0u8; 65535];
+        let mut out = vec![0u8; 65535];
         
-        // Send hash check response back on same stream
-        if hash_request_received {
-            let hash_response_sender = HashCheckResponseSender::new();
-            
-            log::info!("Server: sending hash check response with {} existing hashes", existing_hashes.len());
-            
-            hash_response_sender.send_response(
-                manifest.session_id.clone(),
-                existing_hashes.clone(),
-                |data, fin| {
-                    log::debug!("Server: writing {} bytes to hash check stream (fin={})", data.len(), fin);
-                    connection.stream_send(STREAM_HASH_CHECK, data, fin)
-                        .map_err(|e| crate::common::error::Error::Protocol(format!("Failed to send hash check response: {:?}", e)))
-                }
-            )?;
+        while !connection.is_closed() {
+            pump_network(connection, socket, &mut buf, &mut out)?;
             
-            // Flush the response
-            for _ in 0..20 {
-                if let Err(e) = connection.send_packets(socket, &mut out) {
-                    log::warn!("Server: error flushing hash check response: {:?}", e);
+            while answer.is_none() && answered_at.is_none() {
+                let Ok((read, fin)) = connection.stream_recv(status_stream, &mut buf) else {
+                    break;
+                };
+                append_request(&mut request, &buf[..read])?;
+                if fin {
+                    let request = StorageRequest::decode_from_bytes(&request)?;
+                    let response = if request.action() == StorageAction::Download {
+                        let mut response = StorageResponse { path: request.path.clone(), ..StorageResponse::default() };
+                        match self.start_download(&request, output_dir) {
+                            Ok(started) => download = Some(started),
+                            Err(e) => {
+                                log::warn!("Server: download of {} refused: {}", request.path, e);
+                                response.error = Some(e.to_string());
+                            }
+                        }
+                        response
+                    } else {
+                        storage_response(&request, output_dir, &chunk_index, self.trusted_keys.as_ref())
+                    };
+                    answer = Some(response.encode_to_vec());
                 }
-                std::thread::sleep(std::time::Duration::from_millis(10));
             }
             
-            log::info!("Server: hash check response sent, flushing more aggressively...");
-            
-            // More aggressive flush to ensure response reaches client
-            let flush_start = std::time::Instant::now();
-            while flush_start.elapsed() < std::time::Duration::from_millis(500) {
-                // Send packets
-                if let Err(e) = connection.send_packets(socket, &mut out) {
-                    log::warn!("Server: error sending during hash check flush: {:?}", e);
-                }
-                
-                // Receive ACKs
-                socket.set_read_timeout(Some(std::time::Duration::from_millis(10)))?;
-                if let Ok((len, from)) = socket.recv_from(&mut buf) {
-                    let to = socket.local_addr()?;
-                    let _ = connection.process_packet(&mut buf[..len], from, to);
+            // The answer is written as flow control allows
+            if let Some(remaining) = answer.as_mut() {
+                match connection.stream_send(status_stream, remaining, true) {
+                    Ok(written) if written == remaining.len() => {
+                        answer = None;
+                        answered_at = Some(Instant::now());
+                    }
+                    Ok(written) => {
+                        remaining.drain(..written);
+                    }
+                    Err(quiche::Error::Done) => {}
+                    Err(e) => return Err(format!("Failed to answer on stream {}: {:?}", status_stream, e).into()),
                 }
-                
-                std::thread::sleep(std::time::Duration::from_millis(10));
             }
             
-            log::info!("Server: hash check response fully flushed");
-        }
-        
-        // --- FILE RECEIVE PHASE ---
-        log::info!("Receiving file chunks on stream {}...", data_stream);
-        
-        // Create file receiver - it will handle .part file internally
-        let mut receiver = FileReceiver::new(
-            output_dir,
-            &manifest.file_name,
-            manifest.file_size,
-        )?;
-        
-        let mut stream_buffer = Vec::new(); // Accumulate stream data
-        let mut chunk_buffer = vec![0u8; 65535];
-        let mut last_progress = 0.0;
-        let mut chunks_received = 0u64;
-        let mut expecting_length = true;
-        let mut expected_chunk_size = 0usize;
-        let mut stream_finished = false;
-        
-        loop {
-            // Receive network packets first
-            socket.set_read_timeout(Some(Duration::from_millis(10)))?;
-            if let Ok((len, from)) = socket.recv_from(&mut buf) {
-                let to = socket.local_addr()?;
-                let _ = connection.process_packet(&mut buf[..len], from, to);
-                let _ = connection.send_packets(socket, &mut out);
-            }
-            
-            // Read from data stream and accumulate
-            match connection.stream_recv(data_stream, &mut chunk_buffer) {
-                Ok((read, fin)) => {
-                    if read > 0 {
-                        stream_buffer.extend_from_slice(&chunk_buffer[..read]);
-                        
-                        // Process complete messages with length framing
-                        loop {
-                            if expecting_length {
-                                // Need 4 bytes for length prefix
-                                if stream_buffer.len() < 4 {
-                                    break;
-                                }
-                                let len_bytes: [u8; 4] = stream_buffer[0..4].try_into().unwrap();
-                                expected_chunk_size = u32::from_be_bytes(len_bytes) as usize;
-                                stream_buffer.drain(0..4);
-                                expecting_length = false;
-                            } else {
-                                // Need complete chunk packet
-                                if stream_buffer.len() < expected_chunk_size {
-                                    break;
-                                }
-                                
-                                // Extract and process chunk
-                                let chunk_data: Vec<u8> = stream_buffer.drain(0..expected_chunk_size).collect();
-                                match receiver.receive_chunk(&chunk_data) {
-                                    Ok(_chunk) => {
-                                        chunks_received += 1;
-                                        
-                                        // Update bitmap with received chunk
-                                        let is_last = chunks_received == manifest.total_chunks;
-                                        chunk_bitmap.mark_received((chunks_received - 1) as u32, is_last);
-                                        
-                                        // Periodically save bitmap for resume
-                                        if chunks_received % 10 == 0 || is_last {
-                                            if let Err(e) = chunk_bitmap.save_to_disk(&bitmap_path) {
-                                                log::warn!("Server: failed to save bitmap: {:?}", e);
-                                            }
-                                        }
-                                        
-                                        let progress = receiver.progress();
-                                        if chunks_received % 5 == 0 || progress - last_progress > 0.1 {
-                                            log::info!("Server: received chunk {}/{} ({:.1}%)", 
-                                                chunks_received, manifest.total_chunks, progress * 100.0);
-                                            last_progress = progress;
-                                        }
-                                    }
-                                    Err(e) => {
-                                        log::error!("Server: chunk decode error: {:?}", e);
-                                    }
-                                }
-                                expecting_length = true;
-                            }
+            // An accepted download follows on the streams the client opens
+            // for it, until the client has it all and closes the connection
+            if let Some(download) = download.as_mut().filter(|d| answered_at.is_some() && !d.is_finished()) {
+                for stream_id in download.stream_ids() {
+                    while let Ok((read, fin)) = connection.stream_recv(stream_id, &mut buf) {
+                        download.on_stream_data(stream_id, &buf[..read], fin);
+                        if fin {
+                            break;
                         }
                     }
-                    
-                    if fin {
-                        log::info!("Server: received FIN on data stream");
-                        stream_finished = true;
-                        break;
-                    }
-                }
-                Err(quiche::Error::Done) => {
-                    // No data available, check if complete
-                    if receiver.is_complete() {
-                        log::info!("Server: all chunks received!");
-                        break;
-                    }
-                    
-                    // Just wait for more data or FIN, no timeout
-                    std::thread::sleep(Duration::from_millis(10));
-                    continue;
-                }
-                Err(quiche::Error::InvalidStreamState(_)) => {
-                    // Stream not ready yet, wait for data
-                    std::thread::sleep(Duration::from_millis(10));
-                    continue;
-                }
-                Err(e) => {
-                    return Err(format!("Stream receive error: {:?}", e).into());
                 }
+                download.poll()?;
+                connection.flush_outbox(download.outbox())
+                    .map_err(|e| format!("Failed to send {}: {:?}", download.manifest().file_name, e))?;
             }
             
-            // Check if complete
-            if receiver.is_complete() {
-                log::info!("Server: all chunks received!");
+            let _ = connection.send_packets(socket, &mut out);
+            
+            if download.is_none() && answered_at.is_some_and(|at| at.elapsed() > MULTIPLEX_LINGER) {
                 break;
             }
         }
         
-        // If we exited due to FIN but file isn't complete, that's an error
-        if stream_finished && !receiver.is_complete() {
-            return Err(format!(
-                "Stream closed with FIN but file incomplete: {} of {} chunks received",
-                chunks_received, manifest.total_chunks
-            ).into());
-        }
-        
-        // Finalize file
-        let final_path = receiver.finalize()?;
-        let bytes_received = manifest.file_size;
-        
-        // Update chunk index with received chunks
-        log::info!("Server: updating chunk index with {} chunks...", manifest.chunk_hashes.len());
-        use crate::chunking::ChunkLocation;
-        
-        for (chunk_idx, chunk_hash) in manifest.chunk_hashes.iter().enumerate() {
-            let chunk_offset = chunk_idx as u64 * manifest.chunk_size as u64;
-            let chunk_size = if chunk_idx == manifest.chunk_hashes.len() - 1 {
-                // Last chunk might be smaller
-                let remaining = manifest.file_size - chunk_offset;
-                std::cmp::min(remaining, manifest.chunk_size as u64) as u32
-            } else {
-                manifest.chunk_size
-            };
-            
-            let location = ChunkLocation {
-                file_path: final_path.clone(),
-                byte_offset: chunk_offset,
-                chunk_size,
-            };
-            
-            chunk_index.add_chunk(chunk_hash.clone(), location);
-        }
-        
-        // Save updated index
-        if let Err(e) = chunk_index.save() {
-            log::warn!("Server: failed to save chunk index: {:?}", e);
-        } else {
-            log::info!("Server: chunk index saved successfully ({} total unique chunks)", 
-                chunk_index.total_chunks());
+        Ok(())
+    }
+    
+    /// Start sending the stored file a `Download` request names
+    fn start_download(&self, request: &StorageRequest, output_dir: &Path) -> BoxResult<OutgoingDownload> {
+        let path = output_dir.join(safe_relative_path(&request.path)?);
+        storage::check_not_bookkeeping(&request.path)?;
+        if !path.is_file() {
+            return Err(Error::FileNotFound(request.path.clone()).into());
         }
         
-        // Delete bitmap file after successful transfer
-        if bitmap_path.exists() {
-            if let Err(e) = std::fs::remove_file(&bitmap_path) {
-                log::warn!("Server: failed to delete bitmap file: {:?}", e);
-            } else {
-                log::debug!("Server: deleted bitmap file");
+        let path_hash = blake3::hash(request.path.as_bytes());
+        let session_id = format!("download_{}", hex::encode(&path_hash.as_bytes()[..8]));
+        OutgoingDownload::new(TransferStreams::for_transfer(0), &path, session_id, self.chunk_size, &self.metadata_policy)
+    }
+    
+    /// Hand an upload what has arrived on its streams, let it act on it and
+    /// send what it answers
+    /// 
+    /// # Returns
+    /// The stored files once the upload is complete
+    fn drive_upload(
+        &self,
+        connection: &mut ServerConnection,
+        upload: &mut IncomingUpload,
+        chunk_index: &SharedChunkIndex,
+        buf: &mut [u8],
+    ) -> BoxResult<Option<Vec<(PathBuf, u64)>>> {
+        for stream_id in upload.stream_ids() {
+            loop {
+                match connection.stream_recv(stream_id, buf) {
+                    Ok((read, fin)) => {
+                        upload.on_stream_data(stream_id, &buf[..read], fin);
+                        if fin {
+                            break;
+                        }
+                    }
+                    Err(quiche::Error::Done) | Err(quiche::Error::InvalidStreamState(_)) => break,
+                    Err(e) => return Err(format!("Stream {} receive error: {:?}", stream_id, e).into()),
+                }
             }
         }
         
-        log::info!("TransferManager: file receive complete!");
-        log::info!("  File saved to: {:?}", final_path);
-        log::info!("  Total bytes: {}", bytes_received);
-        log::info!("  Resume mode: {}", resume_mode);
+        let result = upload.poll(chunk_index, self.trusted_keys.as_ref(), &self.assemblies)?;
+        
+        connection.flush_outbox(upload.outbox())
+            .map_err(|e| format!("Failed to send on transfer {}: {:?}", upload.streams().transfer_id, e))?;
         
-        Ok((final_path, bytes_received))
+        Ok(result)
+    }
+
+    /// Tell the observer an upload failed
+    fn report_failure(&self, upload: &IncomingUpload, error: &dyn std::error::Error) {
+        self.observer.on_error(upload.session_id(), error);
+        if let Some(session_id) = upload.session_id() {
+            self.observer.on_state(Some(session_id), TransferState::Failed);
+        }
+    }
+}
+
+/// Carry out a request about stored files
+/// 
+/// A request that fails is answered with its error. With `trusted_keys`,
+/// only a request signed by one of them may change stored files.
+fn storage_response(
+    request: &StorageRequest,
+    output_dir: &Path,
+    chunk_index: &SharedChunkIndex,
+    trusted_keys: Option<&TrustedKeys>,
+) -> StorageResponse {
+    let mut response = StorageResponse {
+        path: request.path.clone(),
+        ..StorageResponse::default()
+    };
+    if let Err(e) = act_on_storage_request(request, output_dir, chunk_index, trusted_keys, &mut response) {
+        log::warn!("Server: {:?} of {} failed: {}", request.action(), request.path, e);
+        response.error = Some(e.to_string());
+    }
+    response
+}
+
+fn act_on_storage_request(
+    request: &StorageRequest,
+    output_dir: &Path,
+    chunk_index: &SharedChunkIndex,
+    trusted_keys: Option<&TrustedKeys>,
+    response: &mut StorageResponse,
+) -> BoxResult<()> {
+    let path = output_dir.join(safe_relative_path(&request.path)?);
+    let versions = VersionStore::new(output_dir);
+    
+    // The server's own files are never a request's to list or change
+    storage::check_not_bookkeeping(&request.path)?;
+    for entry in &request.entries {
+        storage::check_not_bookkeeping(entry)?;
     }
+    
+    if let Some(trusted) = trusted_keys {
+        if matches!(request.action(), StorageAction::RemoveEntries | StorageAction::RestoreVersion) {
+            signing::verify_request(request, trusted)?;
+        }
+    }
+    
+    match request.action() {
+        StorageAction::ListTree => {
+            response.entries = storage::list_tree(output_dir, &path)?;
+            return Ok(());
+        }
+        StorageAction::RemoveEntries => {
+            response.removed = storage::remove_entries(&path, &request.entries)?;
+            log::info!("Server: removed {} entries below {}", response.removed.len(), request.path);
+            return Ok(());
+        }
+        StorageAction::RestoreVersion => {
+            let mut chunk_index = chunk_index.lock();
+            let restored = versions.restore(&path, request.version, &mut chunk_index)?;
+            chunk_index.save()?;
+            drop(chunk_index);
+            log::info!("Server: restored {} to version {}", request.path, restored.number);
+            response.restored = Some(without_chunk_hashes(restored));
+        }
+        StorageAction::ListVersions => {}
+        StorageAction::Download => return Err("A download is not answered on the status stream".into()),
+    }
+    
+    response.versions = versions.list(&path)?.into_iter().map(without_chunk_hashes).collect();
+    Ok(())
+}
+
+/// Add bytes read from the status stream to a request, refusing requests
+/// over `MAX_STORAGE_REQUEST`
+fn append_request(request: &mut Vec<u8>, data: &[u8]) -> BoxResult<()> {
+    if request.len() + data.len() > MAX_STORAGE_REQUEST {
+        return Err(format!(
+            "Storage request too large: over {} bytes (max: {})",
+            request.len() + data.len(), MAX_STORAGE_REQUEST
+        ).into());
+    }
+    request.extend_from_slice(data);
+    Ok(())
+}
+
+/// A version as listed to clients, which have no use for its chunk hashes
+fn without_chunk_hashes(version: FileVersion) -> FileVersion {
+    FileVersion { chunk_hashes: Vec::new(), ..version }
+}
+
+/// Give up on an upload, resetting its streams so the client stops sending
+fn reset_upload(connection: &mut ServerConnection, upload: &mut IncomingUpload) {
+    let error_code = upload.reject();
+    let conn = connection.conn_mut();
+    for stream_id in upload.stream_ids() {
+        let _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Read, error_code);
+        let _ = conn.stream_shutdown(stream_id, quiche::Shutdown::Write, error_code);
+    }
+}
+
+/// Open the chunk index kept under the upload directory
+pub(super) fn open_chunk_index(output_dir: &Path) -> Result<ChunkHashIndex, Box<dyn std::error::Error>> {
+    let index_dir = output_dir.join(".sftpx");
+    std::fs::create_dir_all(&index_dir)?;
+    Ok(ChunkHashIndex::new(&index_dir).unwrap_or_else(|e| {
+        log::warn!("Server: failed to create/load chunk index: {:?}", e);
+        ChunkHashIndex::new(&std::env::temp_dir()).expect("Failed to create temp index")
+    }))
+}
+
+/// Process every datagram waiting on the socket and flush responses
+/// 
+/// If none is waiting, blocks until one arrives or the connection's next
+/// timer fires, for at most `NETWORK_WAIT`.
+/// 
+/// # Returns
+/// Whether any datagram was received
+fn pump_network(
+    connection: &mut ServerConnection,
+    socket: &ConnectionSocket,
+    buf: &mut [u8],
+    out: &mut [u8],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    socket.set_nonblocking(true)?;
+    let to = socket.local_addr()?;
+    let mut received = false;
+    
+    let _ = connection.send_packets(socket, out);
+    if !connection.wait_for_packets(socket, NETWORK_WAIT) {
+        let _ = connection.send_packets(socket, out);
+        return Ok(false);
+    }
+    
+    while let Ok((len, from)) = socket.recv_from(buf) {
+        let _ = connection.process_packet(&mut buf[..len], from, to);
+        received = true;
+    }
+    
+    let _ = connection.send_packets(socket, out);
+    Ok(received)
+}
+
+/// Path of the sidecar holding a stored file's encryption parameters
+pub(super) fn encryption_info_path(file_path: &Path) -> PathBuf {
+    let file_name = file_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    file_path.with_file_name(format!(".{}.enc", file_name))
+}
+
+/// Load the encryption parameters stored beside a file, if any
+pub(super) fn load_encryption_info(file_path: &Path) -> Result<Option<EncryptionInfo>, Box<dyn std::error::Error>> {
+    let info_path = encryption_info_path(file_path);
+    if !info_path.exists() {
+        return Ok(None);
+    }
+    
+    let bytes = std::fs::read(&info_path)?;
+    Ok(Some(EncryptionInfo::decode_from_bytes(&bytes)?))
 }
 
 impl Default for TransferManager {
@@ -708,6 +755,7 @@ impl Default for TransferManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::signing::ManifestSigner;
 
     #[test]
     fn test_transfer_manager_creation() {
@@ -727,4 +775,171 @@ mod tests {
         manager.set_chunk_size(16384);
         assert_eq!(manager.chunk_size(), 16384);
     }
+
+    #[test]
+    fn test_storage_requests() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let output_dir = temp_dir.path();
+        let path = output_dir.join("docs").join("plan.txt");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let chunk_index = SharedChunkIndex::new(open_chunk_index(output_dir).unwrap());
+        for content in ["first", "second"] {
+            std::fs::write(&path, content).unwrap();
+            VersionStore::new(output_dir).keep(&path, &mut chunk_index.lock()).unwrap();
+        }
+        std::fs::write(&path, "third").unwrap();
+        
+        let request = |action: StorageAction, version| StorageRequest {
+            path: "docs/plan.txt".to_string(),
+            action: action as i32,
+            version,
+            entries: Vec::new(),
+            signature: None,
+        };
+        let listed = storage_response(&request(StorageAction::ListVersions, 0), output_dir, &chunk_index, None);
+        assert_eq!(listed.error, None);
+        assert_eq!(listed.versions.iter().map(|v| v.file_size).collect::<Vec<_>>(), vec![5, 6]);
+        assert!(listed.versions.iter().all(|v| v.chunk_hashes.is_empty()));
+        
+        let restored = storage_response(&request(StorageAction::RestoreVersion, 1), output_dir, &chunk_index, None);
+        assert_eq!(restored.restored.map(|v| v.number), Some(1));
+        assert_eq!(restored.versions.len(), 3);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first");
+        
+        // A server with trusted keys only restores for a trusted sender
+        let signer = ManifestSigner::from_pkcs8(&ManifestSigner::generate_pkcs8().unwrap()).unwrap();
+        let mut trusted = TrustedKeys::new();
+        trusted.add(signer.public_key()).unwrap();
+        let mut restore = request(StorageAction::RestoreVersion, 2);
+        assert!(storage_response(&restore, output_dir, &chunk_index, Some(&trusted)).error.is_some());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first");
+        signer.sign_request(&mut restore);
+        let restored = storage_response(&restore, output_dir, &chunk_index, Some(&trusted));
+        assert_eq!(restored.restored.map(|v| v.number), Some(2));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        
+        let missing = storage_response(&request(StorageAction::RestoreVersion, 7), output_dir, &chunk_index, None);
+        assert!(missing.error.is_some());
+        let outside = StorageRequest { path: "../plan.txt".to_string(), ..request(StorageAction::ListVersions, 0) };
+        assert!(storage_response(&outside, output_dir, &chunk_index, None).error.is_some());
+        
+        // Trees are listed and pruned below a directory
+        let tree = |action: StorageAction, entries: &[&str]| StorageRequest {
+            path: "docs".to_string(),
+            action: action as i32,
+            version: 0,
+            entries: entries.iter().map(|entry| entry.to_string()).collect(),
+            signature: None,
+        };
+        let listed = storage_response(&tree(StorageAction::ListTree, &[]), output_dir, &chunk_index, None);
+        assert_eq!(listed.entries.len(), 1);
+        assert_eq!(listed.entries[0].file_hash, blake3::hash(b"second").as_bytes().to_vec());
+        
+        // And only removes for one
+        let mut removal = tree(StorageAction::RemoveEntries, &["plan.txt"]);
+        let refused = storage_response(&removal, output_dir, &chunk_index, Some(&trusted));
+        assert!(refused.error.is_some() && refused.removed.is_empty());
+        assert!(path.exists());
+        
+        signer.sign_request(&mut removal);
+        let removed = storage_response(&removal, output_dir, &chunk_index, Some(&trusted));
+        assert_eq!(removed.removed, vec!["plan.txt"]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_storage_requests_leave_bookkeeping_alone() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let output_dir = temp_dir.path();
+        let chunk_index = SharedChunkIndex::new(open_chunk_index(output_dir).unwrap());
+        let path = output_dir.join("plan.txt");
+        for content in ["first", "second"] {
+            std::fs::write(&path, content).unwrap();
+            VersionStore::new(output_dir).keep(&path, &mut chunk_index.lock()).unwrap();
+        }
+        chunk_index.lock().save().unwrap();
+        let index_file = output_dir.join(".sftpx/chunk_index.db");
+        assert!(index_file.exists());
+        
+        // Neither by the path nor by an entry below it, whatever the action
+        let request = |path: &str, action: StorageAction, entries: &[&str]| StorageRequest {
+            path: path.to_string(),
+            action: action as i32,
+            version: 1,
+            entries: entries.iter().map(|entry| entry.to_string()).collect(),
+            signature: None,
+        };
+        for request in [
+            request(".sftpx", StorageAction::RemoveEntries, &["versions"]),
+            request(".sftpx", StorageAction::RemoveEntries, &["chunk_index.db"]),
+            request("plan.txt", StorageAction::RemoveEntries, &[".sftpx"]),
+            request(".sftpx/versions", StorageAction::ListTree, &[]),
+            request(".sftpx/chunk_index.db", StorageAction::RestoreVersion, &[]),
+        ] {
+            let response = storage_response(&request, output_dir, &chunk_index, None);
+            assert!(response.error.is_some_and(|e| e.contains("Permission denied")), "{:?}", request);
+            assert!(response.removed.is_empty() && response.entries.is_empty());
+        }
+        assert!(index_file.exists());
+        assert_eq!(VersionStore::new(output_dir).list(&path).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_storage_request_size_capped() {
+        let mut request = Vec::new();
+        let read = vec![0u8; 65535];
+        while request.len() + read.len() <= MAX_STORAGE_REQUEST {
+            append_request(&mut request, &read).unwrap();
+        }
+        
+        let error = append_request(&mut request, &read).unwrap_err();
+        assert!(error.to_string().contains("too large"), "{}", error);
+        assert!(request.len() <= MAX_STORAGE_REQUEST);
+    }
+    
+    #[test]
+    fn test_download_requests_refused() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let output_dir = temp_dir.path();
+        std::fs::create_dir_all(output_dir.join(".sftpx")).unwrap();
+        std::fs::write(output_dir.join(".sftpx/chunk_index.db"), b"index").unwrap();
+        std::fs::write(output_dir.join("notes.txt"), b"notes").unwrap();
+        let manager = TransferManager::new();
+        let request = |path: &str| StorageRequest {
+            path: path.to_string(),
+            action: StorageAction::Download as i32,
+            ..StorageRequest::default()
+        };
+        
+        let error = manager.start_download(&request("missing.txt"), output_dir).err().unwrap();
+        assert!(error.to_string().contains("not found"), "{}", error);
+        let error = manager.start_download(&request(".sftpx/chunk_index.db"), output_dir).err().unwrap();
+        assert!(error.to_string().contains("Permission denied"), "{}", error);
+        let error = manager.start_download(&request("../notes.txt"), output_dir).err().unwrap();
+        assert!(!error.to_string().is_empty());
+        
+        let download = manager.start_download(&request("notes.txt"), output_dir).unwrap();
+        assert_eq!(download.manifest().file_size, 5);
+    }
+    
+    #[test]
+    fn test_encryption_info_sidecar() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("secret.bin");
+        std::fs::write(&file_path, b"ciphertext").unwrap();
+
+        assert_eq!(encryption_info_path(&file_path), temp_dir.path().join(".secret.bin.enc"));
+        assert!(load_encryption_info(&file_path).unwrap().is_none());
+
+        let info = EncryptionInfo {
+            algorithm: "chacha20-poly1305".to_string(),
+            kdf: "key-file".to_string(),
+            salt: Vec::new(),
+            kdf_iterations: 0,
+            plain_chunk_size: 1024,
+            key_check: vec![1; 16],
+        };
+        std::fs::write(encryption_info_path(&file_path), info.encode_to_vec()).unwrap();
+        assert_eq!(load_encryption_info(&file_path).unwrap(), Some(info));
+    }
 }
@@ -0,0 +1,1976 @@
+// Server side of one upload on a multiplexed connection
+//
+// An upload goes through the same phases as a single-file transfer
+// (manifest, signature check, resume, data with pipelined hash checks), but
+// each phase only uses what its streams have delivered so far and returns, so
+// the connection loop can drive many uploads side by side. The upload never
+// touches the connection: the loop hands it the bytes read from its streams
+// and sends what it leaves in its outbox.
+//
+// The file is assembled in an `Assembly`. A striped upload has one
+// `IncomingUpload` per connection, all writing into the same assembly; the
+// one that completes the file stores it.
+//
+// A streaming upload enters the data phase on its manifest header alone;
+// chunk hashes and the file's totals keep arriving on the manifest stream
+// while chunks do, and the file is complete only once the totals are in.
+// Its resume bitmap is saved only after the chunks it lists are synced, as
+// there are no chunk hashes to check a .part file against on resume.
+//
+// If a file is already stored under the upload's name, the resume response
+// tells the client what will become of it. An upload that is skipped or
+// refused for it ends there, without a data phase.
+
+use super::transfer::encryption_info_path;
+use crate::chunking::tree::HASH_LEN;
+use crate::chunking::{sparse, ChunkBitmap, ChunkHashIndex, ChunkLocation};
+use crate::client::receiver::FileReceiver;
+use crate::common::types::TransferState;
+use crate::engine::{Inbox, Outbox};
+use crate::observer::{self, ResumeDecision, SharedObserver, TransferObserver, TransferSummary};
+use crate::protocol::bundle::unpack_bundle;
+use crate::protocol::directory::safe_relative_path;
+use crate::protocol::hash_check::{HashCheckRequestReceiver, HashCheckResponseSender};
+use crate::protocol::messages::{ConflictOutcome, ConflictPolicy, Manifest, ManifestPage, ResumeRequest};
+use crate::protocol::metadata::{self, MetadataPolicy};
+use crate::protocol::resume::{ResumeRequestReceiver, ResumeResponseSender};
+use crate::protocol::signing::{self, TrustedKeys};
+use crate::storage::conflict::{self, Resolution, StoredHash};
+use crate::storage::versions::VersionStore;
+use crate::storage::partial::{part_file_path, scan_partial_file};
+use crate::transport::manifest_pages::{ManifestEvent, PagedManifestReceiver};
+use crate::transport::TransferStreams;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Instant;
+
+const ERROR_UNTRUSTED_MANIFEST: u64 = 0x10;  // Application error code for rejected manifests
+const ERROR_TRANSFER_FAILED: u64 = 0x11;                // Application error code for other failed uploads
+const DEDUP_FILTER_FP_RATE: f64 = 0.01;                 // False positive rate of the chunk index Bloom filter
+
+type BoxResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// One upload's progress through its phases
+enum Phase {
+    /// Reading the manifest header and hash pages
+    Manifest,
+    /// Manifest accepted, waiting for the client's resume request
+    Resume(Box<Manifest>),
+    /// Receiving chunks and answering hash checks
+    Data(Box<DataPhase>),
+    /// Finished or failed; nothing more is read
+    Done,
+}
+
+/// Where the resume exchange leads
+enum Resumed {
+    /// Chunks are to be received
+    Data(Box<DataPhase>),
+    /// The name is taken and nothing is to be stored; holds the stored file
+    /// if it was kept as identical
+    Declined(Vec<(PathBuf, u64)>),
+}
+
+/// State held while chunks arrive
+struct DataPhase {
+    manifest: Manifest,
+    assembly: Arc<Mutex<Assembly>>,
+    lanes: Vec<DataLane>,
+    chunks_received: u64,
+    deduped_chunks: u64,
+    last_progress: f64,
+    resume_mode: bool,
+    /// Chunks already present when the data phase started
+    chunks_present: u64,
+    /// Whether the manifest's totals are in; a stream's follow its chunks
+    totals_known: bool,
+    started: Instant,
+}
+
+/// One data stream of an upload
+struct DataLane {
+    stream_id: u64,
+    /// Bytes of a chunk packet still being received
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+/// A file being assembled from its chunks
+struct Assembly {
+    receiver: FileReceiver,
+    chunk_bitmap: ChunkBitmap,
+    bitmap_path: PathBuf,
+    /// Chunk hashes proven against a tree root (tree-hash manifests only),
+    /// saved beside the bitmap so a resumed upload can check its .part file
+    proven_hashes: Vec<Vec<u8>>,
+    /// Set once the file has been stored
+    finished: bool,
+}
+
+/// Files being assembled by striped uploads, by session ID
+///
+/// Entries live as long as one of the stripes does; the partial file is
+/// kept for resume once all of them are gone.
+#[derive(Clone, Default)]
+pub(crate) struct Assemblies(Arc<Mutex<HashMap<String, Weak<Mutex<Assembly>>>>>);
+
+impl Assemblies {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, Weak<Mutex<Assembly>>>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Join the assembly of `session_id`, opening it if no stripe has yet
+    fn join(
+        &self,
+        session_id: &str,
+        open: impl FnOnce() -> BoxResult<Assembly>,
+    ) -> BoxResult<Arc<Mutex<Assembly>>> {
+        let mut entries = self.entries();
+        if let Some(assembly) = entries.get(session_id).and_then(Weak::upgrade) {
+            return Ok(assembly);
+        }
+
+        entries.retain(|_, assembly| assembly.strong_count() > 0);
+        let assembly = Arc::new(Mutex::new(open()?));
+        entries.insert(session_id.to_string(), Arc::downgrade(&assembly));
+        Ok(assembly)
+    }
+
+    fn remove(&self, session_id: &str) {
+        self.entries().remove(session_id);
+    }
+}
+
+/// The chunk index, shared by every session and the pruner
+///
+/// Each makes its changes to this one copy under its lock, and saves it
+/// from there, so none can overwrite another's entries on disk.
+#[derive(Clone)]
+pub(crate) struct SharedChunkIndex(Arc<Mutex<ChunkHashIndex>>);
+
+impl SharedChunkIndex {
+    pub(crate) fn new(chunk_index: ChunkHashIndex) -> Self {
+        Self(Arc::new(Mutex::new(chunk_index)))
+    }
+
+    /// Lock the index, even if a session panicked while holding it
+    pub(crate) fn lock(&self) -> MutexGuard<'_, ChunkHashIndex> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Server side of one upload
+pub(crate) struct IncomingUpload {
+    streams: TransferStreams,
+    /// Streams chunks arrive on: the transfer's data stream, then any extra
+    /// ones listed in its manifest
+    data_streams: Vec<u64>,
+    output_dir: PathBuf,
+    /// Directory the file is stored in; below `output_dir` for files of a
+    /// recursive upload
+    file_dir: PathBuf,
+    phase: Phase,
+    manifest_receiver: PagedManifestReceiver,
+    header: Option<Manifest>,
+    indexed_chunks: usize,
+    resume_receiver: ResumeRequestReceiver,
+    hash_requests: HashCheckRequestReceiver,
+    hash_filter: Option<Vec<u8>>,
+    /// Stream data handed in by the connection loop, not yet consumed
+    inbox: Inbox,
+    /// Responses waiting to be sent
+    outbox: Outbox,
+    /// Application error code to reset the streams with if the upload fails
+    error_code: u64,
+    observer: SharedObserver,
+    /// Which of the sender's file metadata is applied to stored files
+    metadata_policy: MetadataPolicy,
+    /// What to do when the file's name is taken, unless its manifest asks
+    conflict_policy: ConflictPolicy,
+    /// Hash of the file at the taken name, to tell whether it is the same
+    stored_hash: StoredHash,
+    /// What becomes of the upload if its name is taken, once decided
+    resolution: Option<Resolution>,
+}
+
+impl IncomingUpload {
+    /// Start receiving an upload on `streams`, storing it under `output_dir`
+    pub(crate) fn new(streams: TransferStreams, output_dir: &Path) -> Self {
+        Self {
+            data_streams: vec![streams.data],
+            streams,
+            output_dir: output_dir.to_path_buf(),
+            file_dir: output_dir.to_path_buf(),
+            phase: Phase::Manifest,
+            manifest_receiver: PagedManifestReceiver::new(),
+            header: None,
+            indexed_chunks: 0,
+            resume_receiver: ResumeRequestReceiver::new(),
+            hash_requests: HashCheckRequestReceiver::new(),
+            hash_filter: None,
+            inbox: Inbox::new(),
+            outbox: Outbox::new(),
+            error_code: ERROR_TRANSFER_FAILED,
+            observer: observer::noop(),
+            metadata_policy: MetadataPolicy::default(),
+            conflict_policy: ConflictPolicy::Overwrite,
+            stored_hash: StoredHash::new(),
+            resolution: None,
+        }
+    }
+
+    /// Report the upload's events to `observer`
+    pub(crate) fn with_observer(mut self, observer: SharedObserver) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Apply the sender's file metadata as far as `policy` allows
+    pub(crate) fn with_metadata_policy(mut self, policy: MetadataPolicy) -> Self {
+        self.metadata_policy = policy;
+        self
+    }
+
+    /// Handle taken names by `policy` when the manifest leaves it to the server
+    pub(crate) fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    pub(crate) fn streams(&self) -> &TransferStreams {
+        &self.streams
+    }
+
+    /// Session of the file being uploaded, once its manifest header is in
+    pub(crate) fn session_id(&self) -> Option<&str> {
+        let manifest = match &self.phase {
+            Phase::Resume(manifest) => Some(manifest.as_ref()),
+            Phase::Data(data) => Some(&data.manifest),
+            Phase::Manifest | Phase::Done => self.header.as_ref(),
+        };
+        manifest.map(|manifest| manifest.session_id.as_str())
+    }
+
+    /// Data streams of the upload, known once its manifest is accepted
+    pub(crate) fn data_streams(&self) -> &[u64] {
+        &self.data_streams
+    }
+
+    /// Every stream the upload uses: its transfer's streams and any extra
+    /// data streams
+    pub(crate) fn stream_ids(&self) -> Vec<u64> {
+        let extra_streams = self.data_streams.iter().skip(1).copied();
+        self.streams.all().into_iter().chain(extra_streams).collect()
+    }
+
+    /// Take in bytes read from one of the upload's streams
+    pub(crate) fn on_stream_data(&mut self, stream_id: u64, data: &[u8], fin: bool) {
+        self.inbox.push(stream_id, data, fin);
+    }
+
+    /// Responses waiting to be written to the upload's streams
+    pub(crate) fn outbox(&mut self) -> &mut Outbox {
+        &mut self.outbox
+    }
+
+    /// Process whatever has been handed in from this upload's streams
+    ///
+    /// # Returns
+    /// The stored files and their sizes once the upload is complete; a
+    /// bundle yields each file unpacked from it
+    ///
+    /// A stripe whose chunks have all arrived completes with no files unless
+    /// it was the one to complete the file.
+    pub(crate) fn poll(
+        &mut self,
+        chunk_index: &SharedChunkIndex,
+        trusted_keys: Option<&TrustedKeys>,
+        assemblies: &Assemblies,
+    ) -> BoxResult<Option<Vec<(PathBuf, u64)>>> {
+        loop {
+            let next = match &mut self.phase {
+                Phase::Manifest => match self.read_manifest(chunk_index)? {
+                    Some(manifest) => {
+                        self.check_signature(&manifest, trusted_keys)?;
+                        self.data_streams = self.check_data_streams(&manifest)?;
+                        self.file_dir = self.resolve_file_dir(&manifest)?;
+                        Phase::Resume(Box::new(manifest))
+                    }
+                    None => return Ok(None),
+                },
+                Phase::Resume(_) => {
+                    let Phase::Resume(manifest) = std::mem::replace(&mut self.phase, Phase::Done) else {
+                        unreachable!();
+                    };
+                    match self.read_resume(&manifest, assemblies)? {
+                        Some(Resumed::Data(data)) => Phase::Data(data),
+                        Some(Resumed::Declined(files)) => return Ok(Some(files)),
+                        None => {
+                            self.phase = Phase::Resume(manifest);
+                            return Ok(None);
+                        }
+                    }
+                }
+                Phase::Data(_) => {
+                    let outcome = self.receive_data(chunk_index)?;
+                    if outcome == DataOutcome::Pending {
+                        return Ok(None);
+                    }
+                    let Phase::Data(data) = std::mem::replace(&mut self.phase, Phase::Done) else {
+                        unreachable!();
+                    };
+                    if outcome == DataOutcome::StripeDone {
+                        log::info!("Server: transfer {}: stripe of {} received, {} chunks",
+                            self.streams.transfer_id, data.manifest.file_name, data.chunks_received);
+                        return Ok(Some(Vec::new()));
+                    }
+                    return self.finish(*data, chunk_index, assemblies).map(Some);
+                }
+                Phase::Done => return Ok(None),
+            };
+            self.phase = next;
+        }
+    }
+
+    /// Give up on the upload
+    ///
+    /// # Returns
+    /// The application error code to reset its streams with, so the client
+    /// stops sending
+    pub(crate) fn reject(&mut self) -> u64 {
+        self.phase = Phase::Done;
+        self.outbox.take();
+        self.error_code
+    }
+
+    /// Read manifest frames, returning the manifest once complete
+    fn read_manifest(&mut self, chunk_index: &SharedChunkIndex) -> BoxResult<Option<Manifest>> {
+        let Some((data, fin)) = self.inbox.take(self.streams.manifest) else {
+            return Ok(None);
+        };
+
+        let events = self.manifest_receiver.receive_chunk(&data, fin)
+            .map_err(|e| format!("Manifest receive error: {:?}", e))?;
+
+        for event in events {
+            match event {
+                ManifestEvent::Header(h) => {
+                    self.streams.check_transfer_id(h.transfer_id)?;
+                    if h.streaming && (h.stripe.is_some() || h.bundle.is_some() || h.tree_root.is_some()) {
+                        return Err("Streaming manifests cannot be striped, bundled or tree-hashed".into());
+                    }
+                    log::info!("Server: transfer {}: manifest header for file: {} ({} hash pages)",
+                        self.streams.transfer_id, h.file_name, h.hash_pages);
+                    self.observer.on_state(Some(&h.session_id), TransferState::ReceivingManifest);
+                    self.header = Some(*h);
+                }
+                ManifestEvent::Page(page) => {
+                    let index = chunk_index.lock();
+                    self.indexed_chunks += page.chunk_hashes.iter()
+                        .filter(|hash| index.has_chunk(hash))
+                        .count();
+                    log::debug!("Server: manifest page at chunk {} ({} hashes, {}/{} received)",
+                        page.first_chunk, page.chunk_hashes.len(),
+                        self.manifest_receiver.hashes_received(),
+                        self.header.as_ref().map_or(0, |h| h.total_chunks));
+
+                    // Kept for signature checks and indexing after the upload
+                    if let Some(h) = &mut self.header {
+                        add_page(h, page);
+                    }
+                }
+                ManifestEvent::Complete => {
+                    let mut manifest = self.header.take()
+                        .ok_or("Manifest complete without a header")?;
+                    manifest.hash_pages = 0;
+
+                    log::info!("Manifest received: {} chunks, {} bytes",
+                        manifest.total_chunks, manifest.file_size);
+                    if self.indexed_chunks > 0 {
+                        log::info!("Server: {} of {} chunks already in the chunk index",
+                            self.indexed_chunks, manifest.total_chunks);
+                    }
+                    return Ok(Some(manifest));
+                }
+            }
+        }
+
+        // A stream's chunks arrive alongside the rest of its manifest
+        if self.header.as_ref().is_some_and(|h| h.streaming) {
+            return Ok(self.header.take());
+        }
+
+        if fin {
+            return Err("Manifest stream finished before the manifest was complete".into());
+        }
+        Ok(None)
+    }
+
+    /// Read the rest of a streaming manifest during the data phase
+    fn read_stream_manifest(&mut self) -> BoxResult<()> {
+        let Phase::Data(data) = &mut self.phase else {
+            return Ok(());
+        };
+        let Some((received, fin)) = self.inbox.take(self.streams.manifest) else {
+            return Ok(());
+        };
+
+        let events = self.manifest_receiver.receive_chunk(&received, fin)
+            .map_err(|e| format!("Manifest receive error: {:?}", e))?;
+
+        for event in events {
+            match event {
+                ManifestEvent::Header(_) => return Err("Manifest header sent twice".into()),
+                ManifestEvent::Page(page) => add_page(&mut data.manifest, page),
+                ManifestEvent::Complete => {
+                    log::info!("Server: transfer {}: stream ended at {} chunks, {} bytes",
+                        self.streams.transfer_id, data.manifest.total_chunks, data.manifest.file_size);
+                    apply_totals(&data.manifest, &mut lock(&data.assembly).receiver)?;
+                    data.totals_known = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject untrusted manifests before any data is written
+    fn check_signature(&mut self, manifest: &Manifest, trusted_keys: Option<&TrustedKeys>) -> BoxResult<()> {
+        let signature_check = match trusted_keys {
+            Some(trusted) => signing::verify_manifest(manifest, trusted),
+            None if manifest.signature.is_some() => {
+                signing::verify_manifest_signature(manifest).map(|_| ())
+            }
+            None => Ok(()),
+        };
+
+        if let Err(e) = signature_check {
+            log::error!("Server: rejecting manifest for {}: {}", manifest.file_name, e);
+            self.error_code = ERROR_UNTRUSTED_MANIFEST;
+            return Err(e.into());
+        }
+
+        if let Some(sig) = &manifest.signature {
+            log::info!("Server: manifest signed by {}", hex::encode(&sig.public_key));
+        }
+        Ok(())
+    }
+
+    /// Extra data streams must come from transfer slots after this one's
+    fn check_data_streams(&self, manifest: &Manifest) -> BoxResult<Vec<u64>> {
+        let mut data_streams = vec![self.streams.data];
+        for &stream_id in &manifest.data_streams {
+            let later_slot = TransferStreams::lookup(stream_id)
+                .is_some_and(|(transfer_id, _)| transfer_id > self.streams.transfer_id);
+            if !later_slot || data_streams.contains(&stream_id) {
+                return Err(format!("Invalid data stream {} for transfer {}",
+                    stream_id, self.streams.transfer_id).into());
+            }
+            data_streams.push(stream_id);
+        }
+        if data_streams.len() > 1 {
+            log::info!("Server: transfer {}: {} data streams", self.streams.transfer_id, data_streams.len());
+        }
+        Ok(data_streams)
+    }
+
+    /// Directory to store the file in, creating it for files of a recursive upload
+    fn resolve_file_dir(&self, manifest: &Manifest) -> BoxResult<PathBuf> {
+        if manifest.bundle.is_some() && manifest.encryption.is_some() {
+            return Err("Encrypted bundles cannot be unpacked by the server".into());
+        }
+
+        if manifest.relative_path.is_empty() {
+            return Ok(self.output_dir.clone());
+        }
+
+        let relative = safe_relative_path(&manifest.relative_path)?;
+        if relative.file_name().and_then(|n| n.to_str()) != Some(manifest.file_name.as_str()) {
+            return Err(format!("Path {:?} does not end in file name {:?}",
+                manifest.relative_path, manifest.file_name).into());
+        }
+
+        let file_dir = match relative.parent() {
+            Some(parent) => self.output_dir.join(parent),
+            None => self.output_dir.clone(),
+        };
+        std::fs::create_dir_all(&file_dir)?;
+        log::info!("Server: transfer {}: storing {}", self.streams.transfer_id, manifest.relative_path);
+        Ok(file_dir)
+    }
+
+    /// Answer the client's resume request and set up the data phase
+    ///
+    /// Clients that send data without asking to resume start fresh. Stripes
+    /// after the first join the file already being assembled.
+    fn read_resume(
+        &mut self,
+        manifest: &Manifest,
+        assemblies: &Assemblies,
+    ) -> BoxResult<Option<Resumed>> {
+        // A taken name may end the upload before any chunk is sent; telling
+        // whether it holds the same file can take a while, and the request
+        // waits meanwhile
+        let Some(resolution) = self.resolve_conflict(manifest)? else {
+            return Ok(None);
+        };
+
+        let request = match self.inbox.take(self.streams.resume) {
+            Some((data, fin)) => match self.resume_receiver.receive_chunk(&data, fin)? {
+                Some(request) => Some(request),
+                None if fin => return Err("Resume stream finished without a request".into()),
+                None => return Ok(None),
+            },
+            None => {
+                if !self.data_streams.iter().any(|&stream| self.inbox.has_data(stream)) {
+                    return Ok(None);
+                }
+                log::info!("Server: no resume request received, starting fresh transfer");
+                None
+            }
+        };
+
+        let resume_mode = request.is_some();
+
+        if matches!(resolution.outcome, ConflictOutcome::Skipped | ConflictOutcome::Refused) {
+            return self.decline(manifest, request.as_ref(), &resolution).map(|files| Some(Resumed::Declined(files)));
+        }
+
+        // Client's view, from the compact bitmap or the older chunk list
+        let client_chunks = match &request {
+            Some(request) => {
+                self.streams.check_transfer_id(request.transfer_id)?;
+                let chunks = match &request.received_bitmap {
+                    Some(bytes) => match ChunkBitmap::from_bytes(bytes) {
+                        Ok(bitmap) => bitmap.get_received_chunks(),
+                        Err(e) => {
+                            log::warn!("Server: ignoring invalid resume bitmap: {}", e);
+                            Vec::new()
+                        }
+                    },
+                    None => request.received_chunks.clone(),
+                };
+                log::info!("Server: received resume request with {} received chunks", chunks.len());
+                Some(chunks)
+            }
+            None => None,
+        };
+
+        let open = || self.open_assembly(manifest, client_chunks.as_deref());
+        let assembly = match &manifest.stripe {
+            Some(stripe) => {
+                log::info!("Server: transfer {}: stripe {} of {} for {}",
+                    self.streams.transfer_id, stripe.index + 1, stripe.count, manifest.file_name);
+                assemblies.join(&manifest.session_id, open)?
+            }
+            None => Arc::new(Mutex::new(open()?)),
+        };
+
+        if let Some(request) = request {
+            // Report what we hold as a compact bitmap; everything else is missing
+            let (bitmap, received) = {
+                let assembly = lock(&assembly);
+                (assembly.chunk_bitmap.to_bytes(), assembly.chunk_bitmap.received_count())
+            };
+            let missing_count = manifest.total_chunks.saturating_sub(received as u64);
+            let stored_name = match resolution.outcome {
+                ConflictOutcome::Renamed => resolution.path.file_name().map(|name| name.to_string_lossy().into_owned()),
+                _ => None,
+            };
+            let (outbox, resume_stream) = (&mut self.outbox, self.streams.resume);
+            ResumeResponseSender::for_transfer(self.streams.transfer_id)
+                .with_conflict(resolution.outcome, stored_name)
+                .send_response(
+                request.session_id.clone(),
+                true,
+                Vec::new(),
+                Some(bitmap),
+                missing_count,
+                None,
+                |data, fin| Ok(outbox.write(resume_stream, data, fin))
+            )?;
+
+            log::info!("Server: resume response sent ({} chunks missing)", missing_count);
+        }
+
+        log::info!("Receiving file chunks on streams {:?}...", self.data_streams);
+
+        // Holes count as present but were never anyone's to send
+        let hole_chunks = sparse::hole_count(&manifest.holes, 0..manifest.total_chunks);
+        let chunks_present = (lock(&assembly).chunk_bitmap.received_count() as u64).saturating_sub(hole_chunks);
+        self.observer.on_resume(&manifest.session_id,
+            ResumeDecision::from_counts(chunks_present, manifest.total_chunks - hole_chunks));
+        self.observer.on_state(Some(&manifest.session_id), TransferState::Transferring);
+
+        Ok(Some(Resumed::Data(Box::new(DataPhase {
+            manifest: manifest.clone(),
+            assembly,
+            lanes: self.data_streams.iter()
+                .map(|&stream_id| DataLane { stream_id, buffer: Vec::new(), finished: false })
+                .collect(),
+            chunks_received: 0,
+            deduped_chunks: 0,
+            last_progress: 0.0,
+            resume_mode,
+            chunks_present,
+            totals_known: !manifest.streaming || self.manifest_receiver.is_complete(),
+            started: Instant::now(),
+        }))))
+    }
+
+    /// Policy for a taken name: the manifest's, or the server's by default
+    fn conflict_policy_for(&self, manifest: &Manifest) -> ConflictPolicy {
+        match manifest.conflict_policy() {
+            ConflictPolicy::Default => self.conflict_policy,
+            policy => policy,
+        }
+    }
+
+    /// What becomes of the upload if its name is taken
+    ///
+    /// A bundle itself is never stored; each of its files is resolved as it
+    /// is unpacked. `None` while the file at the taken name is still being
+    /// hashed.
+    fn resolve_conflict(&mut self, manifest: &Manifest) -> BoxResult<Option<Resolution>> {
+        if let Some(resolution) = &self.resolution {
+            return Ok(Some(resolution.clone()));
+        }
+        let path = self.file_dir.join(&manifest.file_name);
+        let resolution = if manifest.bundle.is_some() {
+            Resolution { outcome: ConflictOutcome::NoConflict, path, version: None }
+        } else {
+            let policy = self.conflict_policy_for(manifest);
+            let versions = VersionStore::new(&self.output_dir);
+            match conflict::resolve(&path, policy, manifest, &versions, &mut self.stored_hash)? {
+                Some(resolution) => resolution,
+                None => return Ok(None),
+            }
+        };
+
+        if resolution.outcome != ConflictOutcome::NoConflict {
+            log::info!("Server: transfer {}: {} already exists ({:?})",
+                self.streams.transfer_id, manifest.file_name, resolution.outcome);
+        }
+        self.resolution = Some(resolution.clone());
+        Ok(Some(resolution))
+    }
+
+    /// Tell the client its upload is skipped or refused for a taken name
+    ///
+    /// Only a resume response can say so; a client that did not ask to
+    /// resume has its streams reset instead.
+    ///
+    /// # Returns
+    /// The stored file if it was kept as identical
+    fn decline(
+        &mut self,
+        manifest: &Manifest,
+        request: Option<&ResumeRequest>,
+        resolution: &Resolution,
+    ) -> BoxResult<Vec<(PathBuf, u64)>> {
+        let skipped = resolution.outcome == ConflictOutcome::Skipped;
+        let Some(request) = request else {
+            return Err(format!("{:?} already exists", resolution.path).into());
+        };
+
+        let error = if skipped { "Identical file already stored" } else { "File already exists" };
+        let (outbox, resume_stream) = (&mut self.outbox, self.streams.resume);
+        ResumeResponseSender::for_transfer(self.streams.transfer_id)
+            .with_conflict(resolution.outcome, None)
+            .send_response(
+                request.session_id.clone(),
+                false,
+                Vec::new(),
+                None,
+                0,
+                Some(error.to_string()),
+                |data, fin| Ok(outbox.write(resume_stream, data, fin))
+            )?;
+
+        self.observer.on_conflict(&manifest.session_id, resolution.outcome, &manifest.file_name);
+        if skipped {
+            log::info!("Server: transfer {}: {:?} is identical, skipping upload",
+                self.streams.transfer_id, resolution.path);
+            self.observer.on_state(Some(&manifest.session_id), TransferState::Completed);
+            Ok(vec![(resolution.path.clone(), manifest.file_size)])
+        } else {
+            log::warn!("Server: transfer {}: refusing upload over {:?}",
+                self.streams.transfer_id, resolution.path);
+            self.observer.on_state(Some(&manifest.session_id), TransferState::Failed);
+            Ok(Vec::new())
+        }
+    }
+
+    /// Open the file a manifest describes for assembly
+    ///
+    /// When the client asked to resume, chunks already in the .part file
+    /// are verified and kept.
+    fn open_assembly(&self, manifest: &Manifest, client_chunks: Option<&[u64]>) -> BoxResult<Assembly> {
+        if manifest.streaming {
+            return self.open_stream_assembly(manifest, client_chunks.is_some());
+        }
+
+        let mut chunk_bitmap = ChunkBitmap::with_exact_size(manifest.total_chunks as u32);
+
+        // Decide from the .part file itself which chunks are present;
+        // the client's bitmap may be stale, missing or wrong. A tree-hash
+        // manifest lists no chunk hashes, so its .part file is checked
+        // against the hashes proven when the chunks first arrived.
+        let part_path = part_file_path(&self.file_dir, &manifest.file_name);
+        let bitmap_path = self.output_dir.join(format!(".{}.bitmap", manifest.session_id));
+        let saved_hashes = match client_chunks {
+            Some(_) if manifest.tree_root.is_some() => load_proven_hashes(&bitmap_path, manifest.total_chunks),
+            _ => None,
+        };
+        let scan = |manifest: &Manifest| match scan_partial_file(&part_path, manifest) {
+            Ok(verified) => verified,
+            Err(e) => {
+                log::warn!("Server: failed to scan {:?}: {:?}", part_path, e);
+                Vec::new()
+            }
+        };
+        let present_chunks = match client_chunks {
+            None => Vec::new(),
+            Some(_) if !part_path.exists() => Vec::new(),
+            Some(_) if manifest.tree_root.is_some() => match &saved_hashes {
+                Some(hashes) => scan(&Manifest { chunk_hashes: hashes.clone(), ..manifest.clone() }),
+                None => {
+                    log::info!("Server: no proven hashes kept for {}, not resuming", manifest.file_name);
+                    Vec::new()
+                }
+            },
+            Some(_) => scan(manifest),
+        };
+
+        for &chunk_idx in &present_chunks {
+            chunk_bitmap.mark_received(chunk_idx as u32, chunk_idx + 1 == manifest.total_chunks);
+        }
+
+        if let Some(client_chunks) = client_chunks {
+            log::info!("Server: {} of {} chunks verified in partial file (client reported {}), {} missing",
+                present_chunks.len(), manifest.total_chunks, client_chunks.len(),
+                manifest.total_chunks - chunk_bitmap.received_count() as u64);
+        }
+
+        // Create file receiver - it will handle .part file internally
+        let mut receiver = FileReceiver::new(
+            &self.file_dir,
+            &manifest.file_name,
+            manifest.file_size,
+        )?;
+
+        // Keep the .part file around if this session fails so it can be resumed
+        receiver.keep_partial_on_drop();
+        if !present_chunks.is_empty() {
+            receiver.mark_existing_chunks(&present_chunks, manifest.chunk_size as u64, manifest.total_chunks)?;
+            log::info!("Server: resuming with {} chunks already on disk", present_chunks.len());
+        }
+
+        // Holes of a sparse file are never sent; they stay holes here
+        if !manifest.holes.is_empty() {
+            receiver.mark_holes(&manifest.holes, manifest.chunk_size as u64, manifest.total_chunks)?;
+            for range in &manifest.holes {
+                for chunk_idx in range.first_chunk..range.end_chunk().min(manifest.total_chunks) {
+                    chunk_bitmap.mark_received(chunk_idx as u32, chunk_idx + 1 == manifest.total_chunks);
+                }
+            }
+        }
+
+        // An empty file is complete, and checked against its hash, as it stands
+        if manifest.total_chunks == 0 {
+            apply_totals(manifest, &mut receiver)?;
+        }
+
+        // Tree-hash manifests carry no chunk hashes; collect them from chunks
+        // as they are proven against the root so they can still be indexed
+        let mut proven_hashes = Vec::new();
+        if let Some(root) = &manifest.tree_root {
+            receiver.set_tree_root(root, manifest.total_chunks)?;
+            proven_hashes = vec![Vec::new(); manifest.total_chunks as usize];
+            if let Some(saved) = &saved_hashes {
+                for &chunk_idx in &present_chunks {
+                    proven_hashes[chunk_idx as usize] = saved[chunk_idx as usize].clone();
+                }
+            }
+        }
+
+        Ok(Assembly {
+            receiver,
+            chunk_bitmap,
+            bitmap_path,
+            proven_hashes,
+            finished: false,
+        })
+    }
+
+    /// Open the file of a streaming upload for assembly
+    ///
+    /// Its chunk hashes are still to come, so a resumed stream keeps the
+    /// chunks its saved bitmap lists rather than scanning the .part file.
+    fn open_stream_assembly(&self, manifest: &Manifest, resume: bool) -> BoxResult<Assembly> {
+        let bitmap_path = self.output_dir.join(format!(".{}.bitmap", manifest.session_id));
+        let part_path = part_file_path(&self.file_dir, &manifest.file_name);
+
+        let chunk_bitmap = match ChunkBitmap::load_from_disk(&bitmap_path) {
+            Ok(bitmap) if resume && part_path.exists() => bitmap,
+            _ => ChunkBitmap::new(0),
+        };
+        let present_chunks = chunk_bitmap.get_received_chunks();
+
+        let mut receiver = FileReceiver::new(&self.file_dir, &manifest.file_name, 0)?;
+        receiver.keep_partial_on_drop();
+        if !present_chunks.is_empty() {
+            // The number of chunks is not known until the stream ends
+            receiver.mark_existing_chunks(&present_chunks, manifest.chunk_size as u64, u64::MAX)?;
+            log::info!("Server: resuming stream with {} chunks already on disk", present_chunks.len());
+        }
+        if self.manifest_receiver.is_complete() {
+            apply_totals(manifest, &mut receiver)?;
+        }
+
+        Ok(Assembly {
+            receiver,
+            chunk_bitmap,
+            bitmap_path,
+            proven_hashes: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Take in hash checks and chunks
+    fn receive_data(&mut self, chunk_index: &SharedChunkIndex) -> BoxResult<DataOutcome> {
+        self.read_stream_manifest()?;
+        let Phase::Data(data) = &mut self.phase else {
+            return Ok(DataOutcome::Pending);
+        };
+
+        // Hash checks arrive on their own stream while data is flowing;
+        // chunks found in the index are copied in before answering
+        if let Some((requests, fin)) = self.inbox.take(self.streams.hash_check) {
+            let mut assembly = lock(&data.assembly);
+            let copied = answer_hash_checks(
+                &requests,
+                fin,
+                &mut self.outbox,
+                &self.streams,
+                &mut self.hash_requests,
+                &mut self.hash_filter,
+                &chunk_index.lock(),
+                &data.manifest,
+                &mut assembly.receiver,
+            )?;
+            for &chunk_id in &copied {
+                assembly.chunk_bitmap.mark_received(chunk_id as u32, chunk_id + 1 == data.manifest.total_chunks);
+                self.observer.on_dedup_hit(&data.manifest.session_id, chunk_id);
+            }
+            data.deduped_chunks += copied.len() as u64;
+        }
+
+        // Each data stream carries whole chunk packets of its own
+        for lane in 0..data.lanes.len() {
+            let stream_id = data.lanes[lane].stream_id;
+            if data.lanes[lane].finished {
+                continue;
+            }
+            let Some((received, fin)) = self.inbox.take(stream_id) else {
+                continue;
+            };
+
+            let mut buffer = std::mem::take(&mut data.lanes[lane].buffer);
+            buffer.extend_from_slice(&received);
+            data.process_frames(&mut buffer, self.observer.as_ref());
+            data.lanes[lane].buffer = buffer;
+
+            if fin {
+                log::info!("Server: received FIN on data stream {}", stream_id);
+                data.lanes[lane].finished = true;
+            }
+        }
+        // A stream has ended once its totals are in as well
+        let stream_finished = data.lanes.iter().all(|lane| lane.finished) && data.totals_known;
+
+        let assembly = lock(&data.assembly);
+        if assembly.receiver.is_complete() && data.totals_known && !assembly.finished {
+            log::info!("Server: all chunks received!");
+            return Ok(DataOutcome::Complete);
+        }
+
+        if !stream_finished && !assembly.finished {
+            return Ok(DataOutcome::Pending);
+        }
+
+        // Other stripes carry the rest of the file, but this one's chunks
+        // must all be present
+        if let Some(stripe) = &data.manifest.stripe {
+            let range = stripe.chunk_range(data.manifest.total_chunks);
+            let missing = assembly.chunk_bitmap.find_missing_in_range(range.start as u32, range.end as u32);
+            if !missing.is_empty() && !assembly.finished {
+                return Err(format!(
+                    "Stripe {} of {} ended with {} of its chunks missing",
+                    stripe.index + 1, stripe.count, missing.len()
+                ).into());
+            }
+            return Ok(DataOutcome::StripeDone);
+        }
+
+        // If the stream ended but the file isn't complete, that's an error
+        Err(format!(
+            "Stream closed with FIN but file incomplete: {} of {} chunks received",
+            data.chunks_received, data.manifest.total_chunks
+        ).into())
+    }
+
+    /// Store the completed file and record its chunks in the index
+    fn finish(
+        &mut self,
+        data: DataPhase,
+        chunk_index: &SharedChunkIndex,
+        assemblies: &Assemblies,
+    ) -> BoxResult<Vec<(PathBuf, u64)>> {
+        let manifest = &data.manifest;
+        let mut assembly = lock(&data.assembly);
+        assembly.finished = true;
+        if manifest.stripe.is_some() {
+            assemblies.remove(&manifest.session_id);
+        }
+
+        if data.deduped_chunks > 0 {
+            log::info!("Server: {} of {} chunks filled from the chunk index (dedup)",
+                data.deduped_chunks, manifest.total_chunks);
+        }
+
+        // Make way for the file if its name has been taken meanwhile; a
+        // bundle's files make way as they are unpacked
+        let versions = VersionStore::new(&self.output_dir);
+        let resolution = match &manifest.bundle {
+            Some(_) => None,
+            None => Some(conflict::make_way(
+                assembly.receiver.final_path(),
+                self.conflict_policy_for(manifest),
+                &versions,
+                &mut chunk_index.lock(),
+            )?),
+        };
+        if let Some(resolution) = &resolution {
+            assembly.receiver.set_final_path(resolution.path.clone());
+        }
+
+        // Finalize file; a stream that fails its hash check cannot be
+        // resumed into a good file, so the next attempt starts over
+        let final_path = match assembly.receiver.finalize() {
+            Ok(path) => path,
+            Err(e) => {
+                if manifest.streaming {
+                    remove_bitmap(&assembly.bitmap_path);
+                }
+                if let Some(resolution) = &resolution {
+                    if let Some(number) = resolution.version {
+                        let _ = versions.forget(&resolution.path, number);
+                    }
+                }
+                return Err(e.into());
+            }
+        };
+        if let Some(resolution) = resolution.filter(|r| r.outcome != ConflictOutcome::NoConflict) {
+            log::info!("Server: transfer {}: stored as {:?} ({:?}{})", self.streams.transfer_id, final_path,
+                resolution.outcome,
+                resolution.version.map(|n| format!(", previous file kept as version {}", n)).unwrap_or_default());
+            let stored_name = final_path.file_name().unwrap_or_default().to_string_lossy();
+            self.observer.on_conflict(&manifest.session_id, resolution.outcome, &stored_name);
+        }
+        let bytes_received = manifest.file_size;
+
+        // A bundle is only a carrier; its files are kept, not the bundle
+        if let Some(bundle) = &manifest.bundle {
+            let policy = self.conflict_policy_for(manifest);
+            let mut written = Vec::new();
+            let unpacked = unpack_bundle(&final_path, bundle, &self.output_dir, &self.metadata_policy, |path, member| {
+                let resolution = conflict::make_way_for_member(
+                    path,
+                    policy,
+                    &member.file_hash,
+                    &versions,
+                    &mut chunk_index.lock(),
+                )?;
+                if resolution.outcome != ConflictOutcome::NoConflict {
+                    log::info!("Server: transfer {}: bundled {} already exists ({:?})",
+                        self.streams.transfer_id, member.relative_path, resolution.outcome);
+                    self.observer.on_conflict(&manifest.session_id, resolution.outcome, &member.relative_path);
+                }
+                if resolution.outcome == ConflictOutcome::Skipped {
+                    return Ok(None);
+                }
+                written.push(resolution.path.clone());
+                Ok(Some(resolution.path))
+            });
+            std::fs::remove_file(&final_path)?;
+            remove_bitmap(&assembly.bitmap_path);
+            let unpacked = unpacked?;
+            self.index_unpacked(&written, manifest.chunk_size, &versions, chunk_index);
+            log::info!("Server: transfer {}: unpacked {} files ({} bytes) from bundle",
+                self.streams.transfer_id, unpacked.len(), bytes_received);
+            self.report_complete(&data, &self.output_dir);
+            return Ok(unpacked);
+        }
+
+        if let Err(e) = metadata::apply(&final_path, manifest.mode, manifest.metadata.as_ref(), &self.metadata_policy) {
+            log::warn!("Server: failed to apply metadata to {:?}: {}", final_path, e);
+        }
+
+        // Keep the signed manifest so the file can be re-verified later
+        if manifest.signature.is_some() {
+            signing::save_signed_manifest(&final_path, manifest)?;
+        }
+
+        // Keep encryption parameters beside the ciphertext for later downloads
+        if let Some(info) = &manifest.encryption {
+            std::fs::write(encryption_info_path(&final_path), info.encode_to_vec())?;
+            log::info!("Server: stored client-encrypted file ({})", info.algorithm);
+        }
+
+        // Update chunk index with received chunks
+        let chunk_hashes = if manifest.tree_root.is_some() {
+            &assembly.proven_hashes
+        } else {
+            &manifest.chunk_hashes
+        };
+        log::info!("Server: updating chunk index with {} chunks...", chunk_hashes.len());
+        let mut chunk_index = chunk_index.lock();
+        index_chunks(&mut chunk_index, &final_path, chunk_hashes, manifest.chunk_size, manifest.file_size);
+
+        // The hashes spare hashing the file again if it is kept as a version
+        if let Err(e) = versions.record(&final_path, manifest, chunk_hashes) {
+            log::warn!("Server: failed to record hashes of {:?}: {}", final_path, e);
+        }
+
+        // Save updated index
+        if let Err(e) = chunk_index.save() {
+            log::warn!("Server: failed to save chunk index: {:?}", e);
+        } else {
+            log::info!("Server: chunk index saved successfully ({} total unique chunks)",
+                chunk_index.total_chunks());
+        }
+
+        // Delete bitmap file after successful transfer
+        remove_bitmap(&assembly.bitmap_path);
+
+        log::info!("TransferManager: file receive complete!");
+        log::info!("  Transfer: {}", self.streams.transfer_id);
+        log::info!("  File saved to: {:?}", final_path);
+        log::info!("  Total bytes: {}", bytes_received);
+        log::info!("  Resume mode: {}", data.resume_mode);
+
+        self.report_complete(&data, &final_path);
+        Ok(vec![(final_path, bytes_received)])
+    }
+
+    /// Record the hashes of files just unpacked from a bundle and add their
+    /// chunks of `chunk_size` to the index, as for a file uploaded alone
+    ///
+    /// Bundled files are small, so they are read back to hash them.
+    fn index_unpacked(&self, paths: &[PathBuf], chunk_size: u32, versions: &VersionStore, chunk_index: &SharedChunkIndex) {
+        let mut chunk_index = chunk_index.lock();
+        for path in paths {
+            let contents = match std::fs::read(path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    log::warn!("Server: failed to read back {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let chunk_hashes: Vec<Vec<u8>> = contents.chunks(chunk_size as usize)
+                .map(|chunk| blake3::hash(chunk).as_bytes().to_vec())
+                .collect();
+            index_chunks(&mut chunk_index, path, &chunk_hashes, chunk_size, contents.len() as u64);
+
+            let described = Manifest {
+                file_size: contents.len() as u64,
+                file_hash: blake3::hash(&contents).as_bytes().to_vec(),
+                chunk_size,
+                ..Manifest::default()
+            };
+            if let Err(e) = versions.record(path, &described, &chunk_hashes) {
+                log::warn!("Server: failed to record hashes of {:?}: {}", path, e);
+            }
+        }
+
+        if let Err(e) = chunk_index.save() {
+            log::warn!("Server: failed to save chunk index: {:?}", e);
+        }
+    }
+
+    /// Tell the observer the upload is complete and stored at `path`
+    fn report_complete(&self, data: &DataPhase, path: &Path) {
+        let manifest = &data.manifest;
+        self.observer.on_state(Some(&manifest.session_id), TransferState::Completed);
+        self.observer.on_complete(&TransferSummary {
+            session_id: manifest.session_id.clone(),
+            file_name: manifest.file_name.clone(),
+            path: path.to_path_buf(),
+            file_size: manifest.file_size,
+            bytes: manifest.file_size,
+            chunks_transferred: data.chunks_received,
+            chunks_skipped: data.chunks_present + data.deduped_chunks,
+            chunks_deduped: data.deduped_chunks,
+            elapsed: data.started.elapsed(),
+        });
+    }
+}
+
+/// How far a data phase has got
+#[derive(Debug, PartialEq, Eq)]
+enum DataOutcome {
+    /// More chunks are expected on this upload's data stream
+    Pending,
+    /// Every chunk of the file is present
+    Complete,
+    /// This stripe's data stream has ended; other stripes complete the file
+    StripeDone,
+}
+
+/// Add a manifest page's chunk hashes, and a stream's totals from its trailer
+fn add_page(manifest: &mut Manifest, page: ManifestPage) {
+    manifest.chunk_hashes.extend(page.chunk_hashes);
+    if let Some(trailer) = page.trailer {
+        manifest.file_size = trailer.file_size;
+        manifest.total_chunks = trailer.total_chunks;
+        manifest.file_hash = trailer.file_hash;
+    }
+}
+
+/// Give a receiver the file's totals and hash up front: a stream's once its
+/// manifest ends, or an empty file's, which has no final chunk to set them
+fn apply_totals(manifest: &Manifest, receiver: &mut FileReceiver) -> BoxResult<()> {
+    receiver.set_totals(manifest.file_size, manifest.total_chunks)?;
+    receiver.set_expected_hash(manifest.file_hash.clone())?;
+    Ok(())
+}
+
+/// Add the chunks of the file stored at `path` to the index
+///
+/// Chunks with no hash (resumed in a tree-hash upload and not proven) are
+/// left out.
+fn index_chunks(chunk_index: &mut ChunkHashIndex, path: &Path, chunk_hashes: &[Vec<u8>], chunk_size: u32, file_size: u64) {
+    for (chunk_idx, chunk_hash) in chunk_hashes.iter().enumerate() {
+        if chunk_hash.is_empty() {
+            continue; // Resumed chunk not proven in this session
+        }
+
+        // Last chunk might be smaller
+        let chunk_offset = chunk_idx as u64 * chunk_size as u64;
+        let location = ChunkLocation {
+            file_path: path.to_path_buf(),
+            byte_offset: chunk_offset,
+            chunk_size: (file_size - chunk_offset).min(chunk_size as u64) as u32,
+        };
+
+        chunk_index.add_chunk(chunk_hash.clone(), location);
+    }
+}
+
+/// Lock an assembly, even if a stripe panicked while holding it
+fn lock(assembly: &Mutex<Assembly>) -> MutexGuard<'_, Assembly> {
+    assembly.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Delete an upload's resume bitmap, and the hashes proven with it, once
+/// they are no longer needed
+fn remove_bitmap(bitmap_path: &Path) {
+    if bitmap_path.exists() {
+        if let Err(e) = std::fs::remove_file(bitmap_path) {
+            log::warn!("Server: failed to delete bitmap file: {:?}", e);
+        } else {
+            log::debug!("Server: deleted bitmap file");
+        }
+    }
+    let hashes_path = proven_hashes_path(bitmap_path);
+    if hashes_path.exists() {
+        if let Err(e) = std::fs::remove_file(&hashes_path) {
+            log::warn!("Server: failed to delete proven hashes: {:?}", e);
+        }
+    }
+}
+
+/// Where the chunk hashes proven for the upload with `bitmap_path` are kept
+fn proven_hashes_path(bitmap_path: &Path) -> PathBuf {
+    bitmap_path.with_extension("hashes")
+}
+
+/// Save proven chunk hashes beside the bitmap, one after another, with
+/// zeros for chunks not received yet
+fn save_proven_hashes(bitmap_path: &Path, hashes: &[Vec<u8>]) -> std::io::Result<()> {
+    let mut data = Vec::with_capacity(hashes.len() * HASH_LEN);
+    for hash in hashes {
+        match hash.len() {
+            HASH_LEN => data.extend_from_slice(hash),
+            _ => data.extend_from_slice(&[0; HASH_LEN]),
+        }
+    }
+    let hashes_path = proven_hashes_path(bitmap_path);
+    let temp_path = hashes_path.with_extension("hashes.tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, &hashes_path)
+}
+
+/// Load the chunk hashes proven by an earlier session of an upload
+///
+/// Chunks that were not received load as all zeros, which no chunk hashes to.
+fn load_proven_hashes(bitmap_path: &Path, total_chunks: u64) -> Option<Vec<Vec<u8>>> {
+    let data = std::fs::read(proven_hashes_path(bitmap_path)).ok()?;
+    if data.len() as u64 != total_chunks * HASH_LEN as u64 {
+        log::warn!("Server: ignoring proven hashes of {:?}: wrong size", bitmap_path);
+        return None;
+    }
+    Some(data.chunks(HASH_LEN).map(<[u8]>::to_vec).collect())
+}
+
+impl DataPhase {
+    /// Decode every complete length-prefixed chunk packet in a data stream's buffer
+    fn process_frames(&mut self, buffer: &mut Vec<u8>, observer: &dyn TransferObserver) {
+        let mut consumed = 0;
+
+        while buffer.len() - consumed >= 4 {
+            let len_bytes: [u8; 4] = buffer[consumed..consumed + 4].try_into().unwrap();
+            let packet_len = u32::from_be_bytes(len_bytes) as usize;
+            if buffer.len() - consumed - 4 < packet_len {
+                break;
+            }
+
+            let start = consumed + 4;
+            consumed = start + packet_len;
+            self.process_chunk(&buffer[start..consumed], observer);
+        }
+
+        buffer.drain(..consumed);
+    }
+
+    fn process_chunk(&mut self, packet: &[u8], observer: &dyn TransferObserver) {
+        let mut assembly = lock(&self.assembly);
+        let chunk = match assembly.receiver.receive_chunk(packet) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                log::error!("Server: chunk decode error: {:?}", e);
+                return;
+            }
+        };
+
+        self.chunks_received += 1;
+        let chunk_id = chunk.chunk_id;
+        let total_chunks = self.manifest.total_chunks;
+
+        if let Some(slot) = assembly.proven_hashes.get_mut(chunk_id as usize) {
+            *slot = chunk.checksum;
+        }
+        observer.on_chunk_acked(&self.manifest.session_id, chunk_id);
+
+        // Update bitmap with received chunk
+        assembly.chunk_bitmap.mark_received(chunk_id as u32, chunk.end_of_file);
+        let is_last = assembly.receiver.is_complete();
+
+        // Periodically save bitmap for resume; a stream's must only list
+        // chunks that are on disk
+        if self.chunks_received % 10 == 0 || is_last {
+            let synced = if self.manifest.streaming { assembly.receiver.sync() } else { Ok(()) };
+            if let Err(e) = synced {
+                log::warn!("Server: failed to sync {}: {:?}", self.manifest.file_name, e);
+            } else if let Err(e) = assembly.chunk_bitmap.save_to_disk(&assembly.bitmap_path) {
+                log::warn!("Server: failed to save bitmap: {:?}", e);
+            } else if !assembly.proven_hashes.is_empty() {
+                if let Err(e) = save_proven_hashes(&assembly.bitmap_path, &assembly.proven_hashes) {
+                    log::warn!("Server: failed to save proven hashes: {:?}", e);
+                }
+            }
+        }
+
+        let progress = assembly.receiver.progress();
+        if self.chunks_received % 5 == 0 || progress - self.last_progress > 0.1 {
+            log::info!("Server: received chunk {}/{} ({:.1}%)",
+                self.chunks_received, total_chunks, progress * 100.0);
+            self.last_progress = progress;
+        }
+    }
+}
+
+/// Answer the pipelined hash checks in `received`, read from the hash check stream
+///
+/// Chunks the index holds are read back, verified and written into the
+/// receiver before being reported, so the client can skip them safely.
+///
+/// # Returns
+/// IDs of chunks filled in from the index
+#[allow(clippy::too_many_arguments)]
+fn answer_hash_checks(
+    received: &[u8],
+    fin: bool,
+    outbox: &mut Outbox,
+    streams: &TransferStreams,
+    requests: &mut HashCheckRequestReceiver,
+    filter_cache: &mut Option<Vec<u8>>,
+    chunk_index: &ChunkHashIndex,
+    manifest: &Manifest,
+    receiver: &mut FileReceiver,
+) -> BoxResult<Vec<u64>> {
+    let pending = requests.receive_all(received, fin)?;
+
+    let sender = HashCheckResponseSender::for_transfer(streams.transfer_id);
+    let hash_check_stream = streams.hash_check;
+    let mut send = |data: &[u8], fin: bool| Ok(outbox.write(hash_check_stream, data, fin));
+    let mut copied = Vec::new();
+
+    for request in pending {
+        streams.check_transfer_id(request.transfer_id)?;
+
+        if request.want_filter {
+            let filter = filter_cache.get_or_insert_with(|| {
+                let filter = chunk_index.bloom_filter(DEDUP_FILTER_FP_RATE);
+                log::info!("Server: built dedup filter over {} indexed chunks ({} bytes)",
+                    chunk_index.total_chunks(), filter.size_bytes());
+                filter.to_bytes()
+            });
+            sender.send_filter(request.session_id, filter.clone(), &mut send)?;
+            continue;
+        }
+
+        let mut existing = Vec::new();
+        for (&chunk_id, hash) in request.chunk_ids.iter().zip(&request.chunk_hashes) {
+            // Only fill chunks the manifest vouches for
+            if manifest.chunk_hashes.get(chunk_id as usize) != Some(hash) {
+                continue;
+            }
+
+            let offset = chunk_id * manifest.chunk_size as u64;
+            let length = (manifest.chunk_size as u64).min(manifest.file_size.saturating_sub(offset));
+            if let Some(data) = chunk_index.read_chunk(hash) {
+                if data.len() as u64 == length {
+                    receiver.write_local_chunk(chunk_id, &data, manifest.chunk_size as u64, manifest.total_chunks)?;
+                    existing.push(chunk_id);
+                }
+            }
+        }
+
+        log::debug!("Server: hash check batch of {} chunks, {} found in index",
+            request.chunk_ids.len(), existing.len());
+        copied.extend_from_slice(&existing);
+        sender.send_batch_response(request.session_id, existing, &mut send)?;
+    }
+
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::{CompressionType, ParallelChunker, StreamChunker, StreamItem};
+    use crate::client::SyncPlan;
+    use crate::protocol::bundle::{BundleBuilder, PackMember};
+    use crate::engine::{UploadMachine, UploadOptions, UploadStats};
+    use std::io::Cursor;
+    use crate::protocol::manifest::ManifestBuilder;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    fn open(dir: &Path) -> BoxResult<Assembly> {
+        Ok(Assembly {
+            receiver: FileReceiver::new(dir, "striped.bin", 4096)?,
+            chunk_bitmap: ChunkBitmap::with_exact_size(4),
+            bitmap_path: dir.join(".striped.bitmap"),
+            proven_hashes: Vec::new(),
+            finished: false,
+        })
+    }
+
+    #[test]
+    fn test_stripes_share_an_assembly() {
+        let temp_dir = TempDir::new().unwrap();
+        let assemblies = Assemblies::new();
+
+        let first = assemblies.join("session", || open(temp_dir.path())).unwrap();
+        let second = assemblies.join("session", || panic!("should join the open assembly")).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        lock(&first).chunk_bitmap.mark_received(2, false);
+        assert!(lock(&second).chunk_bitmap.is_received(2));
+
+        // Once every stripe is gone the next one starts over
+        drop(first);
+        drop(second);
+        let third = assemblies.join("session", || open(temp_dir.path())).unwrap();
+        assert!(!lock(&third).chunk_bitmap.is_received(2));
+
+        assemblies.remove("session");
+        assert!(assemblies.entries().is_empty());
+    }
+
+    #[test]
+    fn test_extra_data_streams_from_later_slots() {
+        let temp_dir = TempDir::new().unwrap();
+        let upload = IncomingUpload::new(TransferStreams::for_transfer(1), temp_dir.path());
+        let mut manifest = Manifest {
+            data_streams: vec![40, 44],
+            ..Manifest::default()
+        };
+
+        assert_eq!(upload.check_data_streams(&manifest).unwrap(), vec![28, 40, 44]);
+
+        // The control stream, this transfer's own streams, earlier slots,
+        // server-initiated streams and duplicates are all refused
+        for data_streams in [vec![0], vec![32], vec![8], vec![41], vec![40, 40]] {
+            manifest.data_streams = data_streams;
+            assert!(upload.check_data_streams(&manifest).is_err());
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        states: Mutex<Vec<TransferState>>,
+        acked: Mutex<Vec<u64>>,
+        resumes: Mutex<Vec<ResumeDecision>>,
+        summaries: Mutex<Vec<TransferSummary>>,
+    }
+
+    impl TransferObserver for Recorder {
+        fn on_state(&self, _session_id: Option<&str>, state: TransferState) {
+            self.states.lock().unwrap().push(state);
+        }
+
+        fn on_chunk_acked(&self, _session_id: &str, chunk_id: u64) {
+            self.acked.lock().unwrap().push(chunk_id);
+        }
+
+        fn on_resume(&self, _session_id: &str, decision: ResumeDecision) {
+            self.resumes.lock().unwrap().push(decision);
+        }
+
+        fn on_complete(&self, summary: &TransferSummary) {
+            self.summaries.lock().unwrap().push(summary.clone());
+        }
+    }
+
+    #[test]
+    fn test_upload_in_memory() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source.bin");
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(&source, &content).unwrap();
+        let output_dir = temp_dir.path().join("received");
+        std::fs::create_dir_all(&output_dir).unwrap();
+
+        let manifest = ManifestBuilder::new("memory_session")
+            .file_path(&source)
+            .chunk_size(1024)
+            .build_parallel()
+            .unwrap();
+        let streams = TransferStreams::for_transfer(0);
+        let now = Instant::now();
+        let mut client = UploadMachine::new(streams, manifest, None, UploadOptions::default(), now).unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let mut server = IncomingUpload::new(streams, &output_dir).with_observer(recorder.clone());
+        let chunk_index = SharedChunkIndex::new(ChunkHashIndex::new(&temp_dir.path().join("index")).unwrap());
+        let assemblies = Assemblies::new();
+        let mut chunks = ParallelChunker::new(&source, Some(1024), CompressionType::None, Some(2))
+            .unwrap()
+            .process_chunks()
+            .unwrap();
+
+        // Both machines run in lockstep with every write delivered whole and
+        // the clock standing still
+        let mut files = None;
+        for _ in 0..100 {
+            for write in client.outbox().take() {
+                server.on_stream_data(write.stream_id, &write.data, write.fin);
+            }
+            files = server.poll(&chunk_index, None, &assemblies).unwrap();
+            if files.is_some() {
+                break;
+            }
+
+            for write in server.outbox().take() {
+                client.on_stream_data(write.stream_id, &write.data, write.fin).unwrap();
+            }
+            if client.wants_chunk() {
+                match chunks.next() {
+                    Some(chunk) => client.push_chunk(chunk.unwrap()).unwrap(),
+                    None => client.finish().unwrap(),
+                }
+            }
+            client.poll_chunk(now, |_| None).unwrap();
+        }
+
+        let files = files.expect("upload did not complete");
+        assert_eq!(files, vec![(output_dir.join("source.bin"), 10_000)]);
+        assert_eq!(std::fs::read(output_dir.join("source.bin")).unwrap(), content);
+        assert_eq!(client.stats().chunks_handled, 10);
+        assert_eq!(client.stats().chunks_skipped, 0);
+
+        // The server reported the upload as it went
+        assert_eq!(*recorder.states.lock().unwrap(), vec![
+            TransferState::ReceivingManifest,
+            TransferState::Transferring,
+            TransferState::Completed,
+        ]);
+        assert_eq!(*recorder.resumes.lock().unwrap(), vec![ResumeDecision::Fresh]);
+        assert_eq!(*recorder.acked.lock().unwrap(), (0..10).collect::<Vec<u64>>());
+        let summaries = recorder.summaries.lock().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].session_id, "memory_session");
+        assert_eq!(summaries[0].path, output_dir.join("source.bin"));
+        assert_eq!(summaries[0].chunks_transferred, 10);
+        assert_eq!(summaries[0].chunks_skipped, 0);
+    }
+
+    #[test]
+    fn test_empty_file_upload() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("_SUCCESS");
+        std::fs::write(&source, b"").unwrap();
+        let output_dir = temp_dir.path().join("received");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        // A stale .part file from an earlier attempt is cut back to nothing
+        std::fs::write(output_dir.join("_SUCCESS.part"), b"stale").unwrap();
+
+        let manifest = ManifestBuilder::new("empty_session")
+            .file_path(&source)
+            .build_parallel()
+            .unwrap();
+        let streams = TransferStreams::for_transfer(0);
+        let now = Instant::now();
+        let mut client = UploadMachine::new(streams, manifest, None, UploadOptions::default(), now).unwrap();
+        let mut server = IncomingUpload::new(streams, &output_dir);
+        let chunk_index = SharedChunkIndex::new(ChunkHashIndex::new(&temp_dir.path().join("index")).unwrap());
+        let assemblies = Assemblies::new();
+
+        // The server stores the file once the resume request is in, with no
+        // chunk to wait for
+        let mut files = None;
+        for _ in 0..10 {
+            for write in client.outbox().take() {
+                server.on_stream_data(write.stream_id, &write.data, write.fin);
+            }
+            files = server.poll(&chunk_index, None, &assemblies).unwrap();
+            if files.is_some() {
+                break;
+            }
+            for write in server.outbox().take() {
+                client.on_stream_data(write.stream_id, &write.data, write.fin).unwrap();
+            }
+            if client.wants_chunk() {
+                client.finish().unwrap();
+            }
+        }
+
+        assert_eq!(files.expect("upload did not complete"), vec![(output_dir.join("_SUCCESS"), 0)]);
+        assert_eq!(std::fs::read(output_dir.join("_SUCCESS")).unwrap(), b"");
+        assert!(!output_dir.join("_SUCCESS.part").exists());
+
+        // Empty input on stdin makes an empty file too
+        let (files, stats) = stream_upload(temp_dir.path(), b"", usize::MAX);
+        let stored = temp_dir.path().join("received/backups/db.sql");
+        assert_eq!(files.expect("stream did not complete"), vec![(stored.clone(), 0)]);
+        assert_eq!(std::fs::read(&stored).unwrap(), b"");
+        assert_eq!(stats.chunks_handled, 0);
+    }
+
+    #[test]
+    fn test_sparse_file_upload() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("disk.img");
+        let mut content = vec![0u8; 8 * 1024 + 500];
+        content[3 * 1024..3 * 1024 + 100].fill(7);
+        std::fs::write(&source, &content).unwrap();
+        let output_dir = temp_dir.path().join("received");
+        std::fs::create_dir_all(&output_dir).unwrap();
+
+        // Holes are listed by hand, as not every filesystem reports them
+        let mut manifest = ManifestBuilder::new("sparse_session")
+            .file_path(&source)
+            .chunk_size(1024)
+            .build_parallel()
+            .unwrap();
+        manifest.holes = sparse::hole_chunks(&[0..3 * 1024, 4 * 1024..content.len() as u64], 1024, content.len() as u64);
+        assert_eq!(sparse::hole_count(&manifest.holes, 0..9), 8);
+        let holes = manifest.holes.clone();
+        let streams = TransferStreams::for_transfer(0);
+        let now = Instant::now();
+        let mut client = UploadMachine::new(streams, manifest, None, UploadOptions::default(), now).unwrap();
+        let mut server = IncomingUpload::new(streams, &output_dir);
+        let chunk_index = SharedChunkIndex::new(ChunkHashIndex::new(&temp_dir.path().join("index")).unwrap());
+        let assemblies = Assemblies::new();
+        let mut chunks = ParallelChunker::new(&source, Some(1024), CompressionType::None, Some(2))
+            .unwrap()
+            .with_holes(&holes)
+            .process_chunks()
+            .unwrap();
+
+        let mut files = None;
+        for _ in 0..100 {
+            for write in client.outbox().take() {
+                server.on_stream_data(write.stream_id, &write.data, write.fin);
+            }
+            files = server.poll(&chunk_index, None, &assemblies).unwrap();
+            if files.is_some() {
+                break;
+            }
+
+            for write in server.outbox().take() {
+                client.on_stream_data(write.stream_id, &write.data, write.fin).unwrap();
+            }
+            if client.wants_chunk() {
+                match chunks.next() {
+                    Some(chunk) => client.push_chunk(chunk.unwrap()).unwrap(),
+                    None => client.finish().unwrap(),
+                }
+            }
+            client.poll_chunk(now, |_| None).unwrap();
+        }
+
+        // Only the one chunk holding data was sent
+        let files = files.expect("upload did not complete");
+        assert_eq!(files, vec![(output_dir.join("disk.img"), content.len() as u64)]);
+        assert_eq!(std::fs::read(output_dir.join("disk.img")).unwrap(), content);
+        assert_eq!(client.stats().chunks_handled, 1);
+    }
+
+    type StoredFiles = Vec<(PathBuf, u64)>;
+
+    /// Upload `source` through a client and server machine in lockstep,
+    /// with the server handling taken names by `policy` unless the upload
+    /// `asks` for another
+    fn upload_over(
+        source: &Path,
+        output_dir: &Path,
+        policy: ConflictPolicy,
+        asks: ConflictPolicy,
+    ) -> (BoxResult<Option<StoredFiles>>, UploadMachine) {
+        let mut manifest = ManifestBuilder::new("conflict_session")
+            .file_path(source)
+            .chunk_size(1024)
+            .build_parallel()
+            .unwrap();
+        manifest.conflict_policy = asks as i32;
+        let server = IncomingUpload::new(TransferStreams::for_transfer(0), output_dir).with_conflict_policy(policy);
+        let chunk_index = SharedChunkIndex::new(ChunkHashIndex::new(&output_dir.join(".index")).unwrap());
+        run_upload(source, manifest, server, &chunk_index)
+    }
+
+    /// Run an upload of `source` described by `manifest` through a client
+    /// machine and `server` in lockstep, until the server is done with it
+    fn run_upload(
+        source: &Path,
+        manifest: Manifest,
+        mut server: IncomingUpload,
+        chunk_index: &SharedChunkIndex,
+    ) -> (BoxResult<Option<StoredFiles>>, UploadMachine) {
+        let now = Instant::now();
+        let mut client = UploadMachine::new(*server.streams(), manifest, None, UploadOptions::default(), now).unwrap();
+        let assemblies = Assemblies::new();
+        let mut chunks = ParallelChunker::new(source, Some(1024), CompressionType::None, Some(2))
+            .unwrap()
+            .process_chunks()
+            .unwrap();
+
+        for _ in 0..100 {
+            for write in client.outbox().take() {
+                server.on_stream_data(write.stream_id, &write.data, write.fin);
+            }
+            let files = server.poll(chunk_index, None, &assemblies);
+            for write in server.outbox().take() {
+                if let Err(e) = client.on_stream_data(write.stream_id, &write.data, write.fin) {
+                    return (Err(e.into()), client);
+                }
+            }
+            if !matches!(files, Ok(None)) {
+                return (files, client);
+            }
+            if client.wants_chunk() {
+                match chunks.next() {
+                    Some(chunk) => client.push_chunk(chunk.unwrap()).unwrap(),
+                    None => client.finish().unwrap(),
+                }
+            }
+            client.poll_chunk(now, |_| None).unwrap();
+        }
+        (Ok(None), client)
+    }
+
+    #[test]
+    fn test_upload_over_existing_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("build.tar");
+        std::fs::write(&source, vec![3u8; 3000]).unwrap();
+        let output_dir = temp_dir.path().join("received");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let stored = output_dir.join("build.tar");
+
+        let (files, client) = upload_over(&source, &output_dir, ConflictPolicy::Fail, ConflictPolicy::Default);
+        assert_eq!(files.unwrap(), Some(vec![(stored.clone(), 3000)]));
+        assert_eq!(client.conflict(), None);
+
+        // The same file again is not sent at all
+        let (files, client) = upload_over(&source, &output_dir, ConflictPolicy::SkipIdentical, ConflictPolicy::Default);
+        assert_eq!(files.unwrap(), Some(vec![(stored.clone(), 3000)]));
+        assert_eq!(client.conflict(), Some((ConflictOutcome::Skipped, "build.tar")));
+        assert!(client.is_finished());
+        assert_eq!(client.stats().chunks_handled, 0);
+
+        // A changed one is refused, kept beside it, or replaces it keeping the old one
+        std::fs::write(&source, vec![4u8; 2000]).unwrap();
+        let (files, client) = upload_over(&source, &output_dir, ConflictPolicy::Fail, ConflictPolicy::Default);
+        assert!(files.is_err());
+        assert_eq!(client.conflict(), Some((ConflictOutcome::Refused, "build.tar")));
+        assert_eq!(std::fs::read(&stored).unwrap(), vec![3u8; 3000]);
+
+        let (files, client) = upload_over(&source, &output_dir, ConflictPolicy::Rename, ConflictPolicy::Default);
+        assert_eq!(files.unwrap(), Some(vec![(output_dir.join("build-1.tar"), 2000)]));
+        assert_eq!(client.conflict(), Some((ConflictOutcome::Renamed, "build-1.tar")));
+
+        let (files, client) = upload_over(&source, &output_dir, ConflictPolicy::Version, ConflictPolicy::Default);
+        assert_eq!(files.unwrap(), Some(vec![(stored.clone(), 2000)]));
+        assert_eq!(client.conflict(), Some((ConflictOutcome::Versioned, "build.tar")));
+        assert_eq!(std::fs::read(&stored).unwrap(), vec![4u8; 2000]);
+        // The old file is kept from the hashes recorded when it was received
+        let kept = VersionStore::new(&output_dir).list(&stored).unwrap();
+        assert_eq!((kept.len(), kept[0].file_size, kept[0].chunk_size), (1, 3000, 1024));
+    }
+
+    /// Pack `files` into a bundle and upload it, with the server handling
+    /// taken names by `policy`
+    fn upload_bundle(dir: &Path, files: &[(&str, &[u8])], policy: ConflictPolicy) -> BoxResult<Option<StoredFiles>> {
+        let source_dir = dir.join("source");
+        std::fs::create_dir_all(&source_dir).unwrap();
+        let members: Vec<PackMember> = files.iter().map(|(name, content)| {
+            let path = source_dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            PackMember { path, relative_path: name.to_string(), size: content.len() as u64, mode: 0 }
+        }).collect();
+        let (bundle_path, index) = BundleBuilder::new(dir.join("bundles")).write(&members).unwrap();
+
+        let mut manifest = ManifestBuilder::new(bundle_path.file_name().unwrap().to_string_lossy())
+            .file_path(&bundle_path)
+            .chunk_size(1024)
+            .build_parallel()
+            .unwrap();
+        manifest.bundle = Some(index);
+        let output_dir = dir.join("received");
+        let server = IncomingUpload::new(TransferStreams::for_transfer(0), &output_dir).with_conflict_policy(policy);
+        let chunk_index = SharedChunkIndex::new(ChunkHashIndex::new(&output_dir.join(".sftpx")).unwrap());
+        run_upload(&bundle_path, manifest, server, &chunk_index).0
+    }
+
+    #[test]
+    fn test_bundled_files_follow_the_conflict_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        let received = temp_dir.path().join("received");
+        std::fs::create_dir_all(&received).unwrap();
+        let files = upload_bundle(temp_dir.path(), &[("a.txt", b"one"), ("b.txt", b"two")], ConflictPolicy::Fail);
+        assert_eq!(files.unwrap().map(|files| files.len()), Some(2));
+
+        // Refused as a whole while any of its files is taken
+        let taken = upload_bundle(temp_dir.path(), &[("b.txt", b"TWO"), ("c.txt", b"three")], ConflictPolicy::Fail);
+        assert!(taken.is_err());
+        assert_eq!(std::fs::read(received.join("b.txt")).unwrap(), b"two");
+        assert!(!received.join("c.txt").exists());
+
+        // An identical file is left as it is, a changed one replaced
+        let long_ago = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        std::fs::File::options().write(true).open(received.join("a.txt")).unwrap().set_modified(long_ago).unwrap();
+        let files = upload_bundle(temp_dir.path(), &[("a.txt", b"one"), ("b.txt", b"TWO")], ConflictPolicy::SkipIdentical);
+        assert_eq!(files.unwrap(), Some(vec![(received.join("a.txt"), 3), (received.join("b.txt"), 3)]));
+        assert_eq!(std::fs::metadata(received.join("a.txt")).unwrap().modified().unwrap(), long_ago);
+        assert_eq!(std::fs::read(received.join("b.txt")).unwrap(), b"TWO");
+    }
+
+    #[test]
+    fn test_bundled_files_keep_versions_and_are_indexed() {
+        let temp_dir = TempDir::new().unwrap();
+        let received = temp_dir.path().join("received");
+        std::fs::create_dir_all(&received).unwrap();
+        let versions = VersionStore::new(&received);
+        let stored = received.join("notes.txt");
+
+        for content in [b"first draft", b"final draft"] {
+            let files = upload_bundle(temp_dir.path(), &[("notes.txt", content)], ConflictPolicy::Version);
+            assert_eq!(files.unwrap(), Some(vec![(stored.clone(), 11)]));
+            // Recorded and indexed as a file uploaded alone would be
+            assert_eq!(versions.recorded_hash(&stored).unwrap(), Some(blake3::hash(content).as_bytes().to_vec()));
+            let chunk_index = ChunkHashIndex::new(&received.join(".sftpx")).unwrap();
+            assert!(chunk_index.has_chunk(blake3::hash(content).as_bytes()));
+        }
+
+        // The replaced file was kept from its record
+        let kept = versions.list(&stored).unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].file_hash, blake3::hash(b"first draft").as_bytes().to_vec());
+        assert_eq!(std::fs::read(&stored).unwrap(), b"final draft");
+    }
+
+    #[test]
+    fn test_concurrent_uploads_share_the_chunk_index() {
+        let temp_dir = TempDir::new().unwrap();
+        let output_dir = temp_dir.path().join("received");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let index_dir = output_dir.join(".sftpx");
+        let chunk_index = SharedChunkIndex::new(ChunkHashIndex::new(&index_dir).unwrap());
+
+        // Two sessions store files at once, each saving the index as it finishes
+        let uploads: Vec<_> = (0..2u8).map(|n| {
+            let source = temp_dir.path().join(format!("file-{}.bin", n));
+            std::fs::write(&source, vec![n + 1; 5000]).unwrap();
+            let manifest = ManifestBuilder::new(format!("session_{}", n))
+                .file_path(&source)
+                .chunk_size(1024)
+                .build_parallel()
+                .unwrap();
+            let hashes = manifest.chunk_hashes.clone();
+            let server = IncomingUpload::new(TransferStreams::for_transfer(0), &output_dir);
+            let chunk_index = chunk_index.clone();
+            let upload = std::thread::spawn(move || run_upload(&source, manifest, server, &chunk_index).0.unwrap());
+            (upload, hashes)
+        }).collect();
+
+        let mut hashes = Vec::new();
+        for (upload, file_hashes) in uploads {
+            assert!(upload.join().unwrap().is_some());
+            hashes.extend(file_hashes);
+        }
+
+        // The index on disk holds the chunks of both
+        let saved = ChunkHashIndex::new(&index_dir).unwrap();
+        assert!(hashes.iter().all(|hash| saved.has_chunk(hash)));
+        assert_eq!(saved.total_chunks(), 4);
+    }
+
+    #[test]
+    fn test_sync_replaces_on_renaming_server() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("index.html");
+        let output_dir = temp_dir.path().join("received");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let stored = output_dir.join("index.html");
+        let asks = SyncPlan::conflict_policy(ConflictPolicy::Default);
+
+        // A changed file replaces the stored one, so the next sync finds
+        // nothing to send
+        for content in [b"<h1>v1</h1>", b"<h1>v2</h1>"] {
+            std::fs::write(&source, content).unwrap();
+            let (files, _) = upload_over(&source, &output_dir, ConflictPolicy::Rename, asks);
+            assert_eq!(files.unwrap(), Some(vec![(stored.clone(), 11)]));
+            assert_eq!(std::fs::read(&stored).unwrap(), content);
+        }
+        assert!(!output_dir.join("index-1.html").exists());
+    }
+
+    /// Stream `content` through a client and server machine in lockstep,
+    /// handing the client at most `max_chunks` chunks
+    fn stream_upload(dir: &Path, content: &[u8], max_chunks: usize) -> (Option<Vec<(PathBuf, u64)>>, UploadStats) {
+        let manifest = Manifest {
+            session_id: "stream_session".to_string(),
+            file_name: "db.sql".to_string(),
+            chunk_size: 64,
+            relative_path: "backups/db.sql".to_string(),
+            streaming: true,
+            ..Manifest::default()
+        };
+        let streams = TransferStreams::for_transfer(0);
+        let now = Instant::now();
+        let mut client = UploadMachine::new(streams, manifest, None, UploadOptions::default(), now).unwrap();
+        let mut server = IncomingUpload::new(streams, &dir.join("received"));
+        let chunk_index = SharedChunkIndex::new(ChunkHashIndex::new(&dir.join("index")).unwrap());
+        let assemblies = Assemblies::new();
+        let mut chunks = StreamChunker::new(Cursor::new(content.to_vec()), 64, CompressionType::None);
+        let mut pushed = 0;
+
+        for _ in 0..2000 {
+            for write in client.outbox().take() {
+                server.on_stream_data(write.stream_id, &write.data, write.fin);
+            }
+            let files = server.poll(&chunk_index, None, &assemblies).unwrap();
+            if files.is_some() {
+                return (files, client.stats().clone());
+            }
+
+            for write in server.outbox().take() {
+                client.on_stream_data(write.stream_id, &write.data, write.fin).unwrap();
+            }
+            if client.wants_chunk() && pushed < max_chunks {
+                match chunks.next().unwrap().unwrap() {
+                    StreamItem::Chunk(chunk) => {
+                        client.push_chunk(chunk).unwrap();
+                        pushed += 1;
+                    }
+                    StreamItem::End(trailer) => client.finish_stream(trailer).unwrap(),
+                }
+            }
+            client.poll_chunk(now, |_| None).unwrap();
+        }
+        (None, client.stats().clone())
+    }
+
+    #[test]
+    fn test_tree_upload_resumes_only_proven_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("image.bin");
+        let content: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(&source, &content).unwrap();
+        let (manifest, _) = ManifestBuilder::new("tree_session")
+            .file_path(&source)
+            .chunk_size(1024)
+            .build_tree()
+            .unwrap();
+        let chunk_hashes: Vec<Vec<u8>> = content.chunks(1024)
+            .map(|chunk| blake3::hash(chunk).as_bytes().to_vec())
+            .collect();
+
+        // Chunks 0 to 2 were proven before, but chunk 2 did not reach the
+        // disk intact, and chunk 3 never arrived
+        let output_dir = temp_dir.path().join("received");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let mut part = content.clone();
+        part[2500] ^= 0xff;
+        part[3072..].fill(0);
+        std::fs::write(part_file_path(&output_dir, "image.bin"), &part).unwrap();
+        let server = IncomingUpload::new(TransferStreams::for_transfer(0), &output_dir);
+        let claimed = [0, 1, 2, 3];
+
+        // Without hashes of its own the server keeps nothing the client claims
+        let assembly = server.open_assembly(&manifest, Some(&claimed)).unwrap();
+        assert_eq!(assembly.chunk_bitmap.received_count(), 0);
+
+        let mut proven = chunk_hashes.clone();
+        proven[3] = Vec::new();
+        save_proven_hashes(&assembly.bitmap_path, &proven).unwrap();
+        let assembly = server.open_assembly(&manifest, Some(&claimed)).unwrap();
+        assert_eq!(assembly.chunk_bitmap.get_received_chunks(), vec![0, 1]);
+        assert_eq!(assembly.proven_hashes[..2], chunk_hashes[..2]);
+        assert!(assembly.proven_hashes[2].is_empty() && assembly.proven_hashes[3].is_empty());
+
+        remove_bitmap(&assembly.bitmap_path);
+        assert!(!proven_hashes_path(&assembly.bitmap_path).exists());
+    }
+
+    #[test]
+    fn test_streaming_upload_resumes_from_synced_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("received")).unwrap();
+        // 313 chunks, so the hashes take two manifest pages
+        let content: Vec<u8> = (0..20_000u32).map(|i| (i * 13 % 251) as u8).collect();
+
+        // The first attempt stops partway; its bitmap was last saved at 20 chunks
+        let (files, _) = stream_upload(temp_dir.path(), &content, 25);
+        assert!(files.is_none());
+
+        let (files, stats) = stream_upload(temp_dir.path(), &content, usize::MAX);
+        let stored = temp_dir.path().join("received/backups/db.sql");
+        assert_eq!(files.expect("upload did not complete"), vec![(stored.clone(), 20_000)]);
+        assert_eq!(std::fs::read(&stored).unwrap(), content);
+        assert_eq!(stats.chunks_handled, 313);
+        assert_eq!(stats.chunks_skipped, 20);
+    }
+}
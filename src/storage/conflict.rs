@@ -0,0 +1,262 @@
+// Uploads whose name is already taken
+//
+// When a file already exists where an upload is to be stored, the receiver
+// follows a `ConflictPolicy`: the one the upload asks for, or its own. What
+// it will do is decided when the manifest arrives, so the client can be told
+// before any chunk is sent; the name is made free when the file is stored,
+// since another upload may have taken or freed it meanwhile.
+//
+// Renamed uploads get a numbered suffix before the extension
+// (`report-1.pdf`); kept versions go to the version store (`versions`).
+//
+// Telling whether a taken name holds the same file takes its hash. The
+// version store's record of the upload gives it while that still describes
+// the file; otherwise the file is hashed on a thread of its own, so a large
+// one does not hold up the connection, and the manifest is answered once
+// that is done.
+
+use super::versions::VersionStore;
+use crate::chunking::ChunkHashIndex;
+use crate::common::error::{Error, Result};
+use crate::protocol::messages::{ConflictOutcome, ConflictPolicy, Manifest};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+/// What becomes of an upload to a taken name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolution {
+    pub outcome: ConflictOutcome,
+    /// Where the upload is stored
+    pub path: PathBuf,
+    /// Version the old file is kept as, once it has been
+    pub version: Option<u64>,
+}
+
+impl Resolution {
+    fn new(outcome: ConflictOutcome, path: PathBuf) -> Self {
+        Self { outcome, path, version: None }
+    }
+}
+
+/// Hash of the file at a taken name, once it is known
+#[derive(Debug, Default)]
+pub struct StoredHash {
+    hashing: Option<Receiver<Result<Vec<u8>>>>,
+}
+
+impl StoredHash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hash of the file at `path`, or `None` while it is being hashed
+    fn of(&mut self, path: &Path, versions: &VersionStore) -> Result<Option<Vec<u8>>> {
+        let hashing = match &self.hashing {
+            Some(hashing) => hashing,
+            None => {
+                if let Some(hash) = versions.recorded_hash(path)? {
+                    return Ok(Some(hash));
+                }
+                let (sender, receiver) = mpsc::channel();
+                let path = path.to_path_buf();
+                std::thread::spawn(move || {
+                    let hash = File::open(&path)
+                        .map_err(Error::from)
+                        .and_then(|mut file| super::compute_file_hash(&mut file))
+                        .map(|hash| hash.as_bytes().to_vec());
+                    let _ = sender.send(hash);
+                });
+                self.hashing.insert(receiver)
+            }
+        };
+        match hashing.try_recv() {
+            Ok(hash) => hash.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(Error::Protocol(format!("hashing {:?} stopped", path))),
+        }
+    }
+}
+
+/// Decide what becomes of the upload `manifest` describes when stored at `path`
+///
+/// Only a file of the same size and hash is skipped; a stream's hash is not
+/// known in advance, so it is never skipped. While the file at `path` is
+/// being hashed, `None` is returned; ask again with the same `stored_hash`.
+pub fn resolve(
+    path: &Path,
+    policy: ConflictPolicy,
+    manifest: &Manifest,
+    versions: &VersionStore,
+    stored_hash: &mut StoredHash,
+) -> Result<Option<Resolution>> {
+    if !path.exists() {
+        return Ok(Some(Resolution::new(ConflictOutcome::NoConflict, path.to_path_buf())));
+    }
+
+    let same_size = std::fs::metadata(path)?.len() == manifest.file_size;
+    let outcome = match policy {
+        ConflictPolicy::SkipIdentical if !manifest.streaming && same_size => match stored_hash.of(path, versions)? {
+            Some(hash) if hash == manifest.file_hash => ConflictOutcome::Skipped,
+            Some(_) => ConflictOutcome::Overwritten,
+            None => return Ok(None),
+        },
+        ConflictPolicy::Fail => ConflictOutcome::Refused,
+        ConflictPolicy::Rename => return Ok(Some(Resolution::new(ConflictOutcome::Renamed, free_name(path)))),
+        ConflictPolicy::Version => ConflictOutcome::Versioned,
+        ConflictPolicy::Default | ConflictPolicy::Overwrite | ConflictPolicy::SkipIdentical => ConflictOutcome::Overwritten,
+    };
+    Ok(Some(Resolution::new(outcome, path.to_path_buf())))
+}
+
+/// Make way for a finished upload to `path`, just before it is stored
+///
+/// A file to keep as a version is taken into `versions`, its new chunks
+/// into `chunk_index`; it stays in place until the upload replaces it. An
+/// identical file was skipped before any chunk was sent, so one found now
+/// is overwritten.
+pub fn make_way(
+    path: &Path,
+    policy: ConflictPolicy,
+    versions: &VersionStore,
+    chunk_index: &mut ChunkHashIndex,
+) -> Result<Resolution> {
+    if !path.exists() {
+        return Ok(Resolution::new(ConflictOutcome::NoConflict, path.to_path_buf()));
+    }
+
+    match policy {
+        ConflictPolicy::Fail => Err(Error::FileExists(path.display().to_string())),
+        ConflictPolicy::Rename => Ok(Resolution::new(ConflictOutcome::Renamed, free_name(path))),
+        ConflictPolicy::Version => Ok(Resolution {
+            outcome: ConflictOutcome::Versioned,
+            path: path.to_path_buf(),
+            version: Some(versions.keep(path, chunk_index)?.number),
+        }),
+        ConflictPolicy::Default | ConflictPolicy::Overwrite | ConflictPolicy::SkipIdentical => {
+            Ok(Resolution::new(ConflictOutcome::Overwritten, path.to_path_buf()))
+        }
+    }
+}
+
+/// Make way for a file unpacked from a bundle at `path`, just before it is
+/// stored
+///
+/// Bundled files are not resolved before their chunks are sent, so an
+/// identical one is only found here, hashing the stored file if it has no
+/// record; bundled files are small. Anything else goes as for `make_way`.
+pub fn make_way_for_member(
+    path: &Path,
+    policy: ConflictPolicy,
+    file_hash: &[u8],
+    versions: &VersionStore,
+    chunk_index: &mut ChunkHashIndex,
+) -> Result<Resolution> {
+    if policy == ConflictPolicy::SkipIdentical && path.is_file() && versions.file_hash(path)? == file_hash {
+        return Ok(Resolution::new(ConflictOutcome::Skipped, path.to_path_buf()));
+    }
+    make_way(path, policy, versions, chunk_index)
+}
+
+/// First free `<stem>-<n>.<ext>` beside `path`
+fn free_name(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{}-{}{}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .expect("some suffix is free")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn manifest_for(content: &[u8]) -> Manifest {
+        Manifest {
+            file_size: content.len() as u64,
+            file_hash: blake3::hash(content).as_bytes().to_vec(),
+            ..Manifest::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve_taken_name() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("report.pdf");
+        let same = manifest_for(b"v1");
+        let other = manifest_for(b"v2");
+
+        let versions = VersionStore::new(temp_dir.path());
+        // Asked again until the taken name's file is hashed
+        let resolve = |policy, manifest| {
+            let mut stored_hash = StoredHash::new();
+            loop {
+                if let Some(resolution) = resolve(&path, policy, manifest, &versions, &mut stored_hash).unwrap() {
+                    return resolution;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        };
+
+        let free = resolve(ConflictPolicy::Fail, &same);
+        assert_eq!(free, Resolution::new(ConflictOutcome::NoConflict, path.clone()));
+
+        std::fs::write(&path, b"v1").unwrap();
+        std::fs::write(temp_dir.path().join("report-1.pdf"), b"taken too").unwrap();
+        let outcome = |policy, manifest| resolve(policy, manifest).outcome;
+        assert_eq!(outcome(ConflictPolicy::Default, &same), ConflictOutcome::Overwritten);
+        assert_eq!(outcome(ConflictPolicy::SkipIdentical, &same), ConflictOutcome::Skipped);
+        assert_eq!(outcome(ConflictPolicy::SkipIdentical, &other), ConflictOutcome::Overwritten);
+        assert_eq!(outcome(ConflictPolicy::Fail, &same), ConflictOutcome::Refused);
+        assert_eq!(outcome(ConflictPolicy::Version, &same), ConflictOutcome::Versioned);
+        assert_eq!(resolve(ConflictPolicy::Rename, &same).path, temp_dir.path().join("report-2.pdf"));
+
+        // A stream's hash is not known yet
+        let stream = Manifest { streaming: true, ..same.clone() };
+        assert_eq!(outcome(ConflictPolicy::SkipIdentical, &stream), ConflictOutcome::Overwritten);
+    }
+
+    #[test]
+    fn test_identical_from_record() {
+        let temp_dir = TempDir::new().unwrap();
+        let versions = VersionStore::new(temp_dir.path());
+        let path = temp_dir.path().join("disk.img");
+        std::fs::write(&path, b"image").unwrap();
+        let manifest = Manifest { chunk_size: 1024, ..manifest_for(b"image") };
+        versions.record(&path, &manifest, &[blake3::hash(b"image").as_bytes().to_vec()]).unwrap();
+
+        // The recorded hash answers at once, with no file to read
+        let mut stored_hash = StoredHash::new();
+        let resolution = resolve(&path, ConflictPolicy::SkipIdentical, &manifest, &versions, &mut stored_hash).unwrap();
+        assert_eq!(resolution.map(|resolution| resolution.outcome), Some(ConflictOutcome::Skipped));
+        assert!(stored_hash.hashing.is_none());
+    }
+
+    #[test]
+    fn test_make_way_keeps_versions() {
+        let temp_dir = TempDir::new().unwrap();
+        let versions = VersionStore::new(temp_dir.path());
+        let mut chunk_index = ChunkHashIndex::new(&temp_dir.path().join(".sftpx")).unwrap();
+        let mut make_way = |path: &Path, policy| make_way(path, policy, &versions, &mut chunk_index);
+        let path = temp_dir.path().join("build.tar");
+        assert_eq!(make_way(&path, ConflictPolicy::Version).unwrap().outcome, ConflictOutcome::NoConflict);
+
+        std::fs::write(&path, b"first").unwrap();
+        let resolution = make_way(&path, ConflictPolicy::Version).unwrap();
+        assert_eq!(resolution.outcome, ConflictOutcome::Versioned);
+        assert_eq!(resolution.path, path);
+        assert_eq!(resolution.version, Some(1));
+        // Stored uploads are renamed into place, leaving the version alone
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, b"second").unwrap();
+        assert_eq!(make_way(&path, ConflictPolicy::Version).unwrap().version, Some(2));
+
+        assert!(make_way(&path, ConflictPolicy::Fail).is_err());
+        assert_eq!(make_way(&path, ConflictPolicy::Rename).unwrap().path, temp_dir.path().join("build-1.tar"));
+
+        let kept: Vec<u64> = versions.list(&path).unwrap().iter().map(|version| version.file_size).collect();
+        assert_eq!(kept, vec![5, 6]);
+    }
+}
@@ -1,6 +1,12 @@
 // Storage module - file and partial file management
 
 pub mod verification;
+pub mod partial;
+pub mod conflict;
+pub mod versions;
+pub mod tree;
 
 pub use verification::{verify_file_hash, compute_file_hash, verify_file_hash_bytes};
-
+pub use partial::{part_file_path, scan_partial_file};
+pub use versions::{Retention, VersionStore};
+pub use tree::{check_not_bookkeeping, list_tree, remove_entries};
@@ -1 +1,146 @@
 // Partial file (.part) management
+
+use crate::common::error::Result;
+use crate::protocol::messages::Manifest;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Path of the partial file a transfer is assembled in
+pub fn part_file_path(output_dir: &Path, file_name: &str) -> PathBuf {
+    output_dir.join(format!("{}.part", file_name))
+}
+
+/// Find the chunks of a partial file that already match a manifest
+///
+/// Every chunk in the file is read and hashed in parallel and compared with
+/// the manifest's chunk hash, so the result does not depend on any bitmap
+/// the client kept. Tree-hash manifests list no chunk hashes and always
+/// scan as empty.
+///
+/// # Returns
+/// IDs of verified chunks in ascending order
+pub fn scan_partial_file(part_path: &Path, manifest: &Manifest) -> Result<Vec<u64>> {
+    let part_size = std::fs::metadata(part_path)?.len();
+    let chunk_size = manifest.chunk_size as u64;
+
+    if manifest.chunk_hashes.len() as u64 != manifest.total_chunks || chunk_size == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut present: Vec<u64> = (0..manifest.total_chunks)
+        .into_par_iter()
+        .filter(|&chunk_id| {
+            let offset = chunk_id * chunk_size;
+            let length = chunk_size.min(manifest.file_size.saturating_sub(offset));
+            if length == 0 || offset + length > part_size {
+                return false;
+            }
+
+            chunk_matches(part_path, offset, length as usize, &manifest.chunk_hashes[chunk_id as usize])
+                .unwrap_or(false)
+        })
+        .collect();
+
+    present.sort_unstable();
+    Ok(present)
+}
+
+fn chunk_matches(part_path: &Path, offset: u64, length: usize, expected: &[u8]) -> Result<bool> {
+    let mut file = File::open(part_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut buffer = vec![0u8; length];
+    file.read_exact(&mut buffer)?;
+
+    Ok(blake3::hash(&buffer).as_bytes() == expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn manifest_for(data: &[u8], chunk_size: usize) -> Manifest {
+        let chunk_hashes: Vec<Vec<u8>> = data
+            .chunks(chunk_size)
+            .map(|chunk| blake3::hash(chunk).as_bytes().to_vec())
+            .collect();
+
+        Manifest {
+            session_id: "partial-session".to_string(),
+            file_name: "data.bin".to_string(),
+            file_size: data.len() as u64,
+            chunk_size: chunk_size as u32,
+            total_chunks: chunk_hashes.len() as u64,
+            file_hash: blake3::hash(data).as_bytes().to_vec(),
+            chunk_hashes,
+            compression: "none".to_string(),
+            original_size: None,
+            encryption: None,
+            signature: None,
+            tree_root: None,
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes: Vec::new(),
+            conflict_policy: 0,
+        }
+    }
+
+    #[test]
+    fn test_scan_finds_verified_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251 + 1) as u8).collect();
+        let manifest = manifest_for(&data, 100);
+
+        // Preallocated .part with chunks 0, 1 and 7 written, chunk 3 corrupted
+        let mut part = vec![0u8; data.len()];
+        for chunk_id in [0usize, 1, 3, 7] {
+            let range = chunk_id * 100..(chunk_id + 1) * 100;
+            part[range.clone()].copy_from_slice(&data[range]);
+        }
+        part[350] ^= 0xff;
+
+        let part_path = part_file_path(temp_dir.path(), "data.bin");
+        std::fs::write(&part_path, &part).unwrap();
+
+        assert_eq!(scan_partial_file(&part_path, &manifest).unwrap(), vec![0, 1, 7]);
+    }
+
+    #[test]
+    fn test_scan_truncated_part_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let data = vec![9u8; 250];
+        let manifest = manifest_for(&data, 100);
+
+        // Only the first chunk and half of the second made it to disk
+        let part_path = part_file_path(temp_dir.path(), "data.bin");
+        std::fs::write(&part_path, &data[..150]).unwrap();
+        assert_eq!(scan_partial_file(&part_path, &manifest).unwrap(), vec![0]);
+
+        // The short final chunk verifies once the file is complete
+        std::fs::write(&part_path, &data).unwrap();
+        assert_eq!(scan_partial_file(&part_path, &manifest).unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_scan_tree_manifest_is_empty() {
+        let temp_dir = TempDir::new().unwrap();
+        let data = vec![5u8; 300];
+        let mut manifest = manifest_for(&data, 100);
+        manifest.chunk_hashes.clear();
+        manifest.tree_root = Some(vec![0u8; 32]);
+
+        let part_path = part_file_path(temp_dir.path(), "data.bin");
+        std::fs::write(&part_path, &data).unwrap();
+        assert!(scan_partial_file(&part_path, &manifest).unwrap().is_empty());
+    }
+}
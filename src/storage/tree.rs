@@ -0,0 +1,153 @@
+// Stored directory trees, as a mirroring client sees them
+//
+// A client making a directory on the server match a local one first lists
+// what is stored there: every directory and file with each file's size and
+// hash, taken from the version store's record of the upload where that
+// still describes the file. It then uploads what differs and asks for what
+// it no longer has to be removed.
+//
+// Files the server keeps beside uploads for itself are left out of listings,
+// and no request may name them; removing a file takes its sidecars with it.
+
+use super::versions::VersionStore;
+use crate::common::error::{Error, Result};
+use crate::protocol::directory::{safe_relative_path, DirectoryBuilder};
+use crate::protocol::messages::DirectoryEntry;
+use crate::protocol::signing::signature_path;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Suffixes of the hidden files kept beside uploads: resume bitmaps and
+/// the chunk hashes proven with them, encryption parameters, signed manifests and files being restored or
+/// unpacked
+const SIDECAR_SUFFIXES: [&str; 6] = [".bitmap", ".hashes", ".enc", ".sig", ".restore", ".tmp"];
+
+/// List the directories and files below `dir` in `upload_dir`, parents first
+///
+/// A directory not created yet lists as empty.
+pub fn list_tree(upload_dir: &Path, dir: &Path) -> Result<Vec<DirectoryEntry>> {
+    match fs::metadata(dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Ok(metadata) if !metadata.is_dir() => {
+            return Err(Error::Protocol(format!("{:?} is not a directory", dir)));
+        }
+        result => {
+            result?;
+        }
+    }
+
+    let versions = VersionStore::new(upload_dir);
+    let mut entries = Vec::new();
+    for node in DirectoryBuilder::new(dir).walk()? {
+        if node.relative_path.split('/').any(is_bookkeeping) {
+            continue;
+        }
+        let file_hash = if node.is_dir { Vec::new() } else { versions.file_hash(&node.path)? };
+        entries.push(DirectoryEntry {
+            relative_path: node.relative_path,
+            is_dir: node.is_dir,
+            file_size: node.size,
+            mode: node.mode,
+            file_hash,
+            ..DirectoryEntry::default()
+        });
+    }
+    Ok(entries)
+}
+
+/// Remove `entries`, '/'-separated paths below `dir`, directories with
+/// everything in them
+///
+/// # Returns
+/// The entries that were there to remove
+pub fn remove_entries(dir: &Path, entries: &[String]) -> Result<Vec<String>> {
+    let mut removed = Vec::new();
+    for entry in entries {
+        let path = dir.join(safe_relative_path(entry)?);
+        let metadata = match fs::symlink_metadata(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            metadata => metadata?,
+        };
+
+        if metadata.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            for sidecar in [signature_path(&path), path.with_file_name(format!(".{}.enc", file_name))] {
+                if let Err(e) = fs::remove_file(sidecar) {
+                    if e.kind() != ErrorKind::NotFound {
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+        log::info!("Tree: removed {:?}", path);
+        removed.push(entry.clone());
+    }
+    Ok(removed)
+}
+
+/// Refuse a '/'-separated path below the upload directory that is, or lies
+/// below, one of the files the server keeps for itself
+pub fn check_not_bookkeeping(path: &str) -> Result<()> {
+    if path.split('/').any(is_bookkeeping) {
+        return Err(Error::PermissionDenied(path.to_string()));
+    }
+    Ok(())
+}
+
+/// Whether `name` is one the server keeps beside uploads for itself: its
+/// `.sftpx` directory, partial files and hidden sidecars
+fn is_bookkeeping(name: &str) -> bool {
+    name == ".sftpx"
+        || name.ends_with(".part")
+        || (name.starts_with('.') && SIDECAR_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_list_and_remove_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let upload_dir = temp_dir.path();
+        let dir = upload_dir.join("site");
+        assert!(list_tree(upload_dir, &dir).unwrap().is_empty());
+
+        fs::create_dir_all(dir.join("css")).unwrap();
+        fs::write(dir.join("index.html"), b"<html>").unwrap();
+        fs::write(dir.join("css/main.css"), b"body {}").unwrap();
+        fs::write(dir.join("app.js.part"), b"half").unwrap();
+        fs::write(dir.join(".index.html.sig"), b"signed").unwrap();
+
+        let entries = list_tree(upload_dir, &dir).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.relative_path.as_str()).collect();
+        assert_eq!(paths, vec!["css", "css/main.css", "index.html"]);
+        assert!(entries[0].is_dir);
+        assert_eq!(entries[2].file_size, 6);
+        assert_eq!(entries[2].file_hash, blake3::hash(b"<html>").as_bytes().to_vec());
+        assert!(list_tree(upload_dir, &dir.join("index.html")).is_err());
+
+        let requested = ["css".to_string(), "index.html".to_string(), "gone.txt".to_string()];
+        let removed = remove_entries(&dir, &requested).unwrap();
+        assert_eq!(removed, vec!["css", "index.html"]);
+        assert!(!dir.join("css").exists());
+        assert!(!dir.join(".index.html.sig").exists());
+        assert!(dir.join("app.js.part").exists());
+        assert!(remove_entries(&dir, &["../site".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_bookkeeping_paths_refused() {
+        for path in [".sftpx", ".sftpx/versions", "chunk_index.db.part", "site/.index.html.enc", "a/.b.bitmap/c"] {
+            assert!(matches!(check_not_bookkeeping(path), Err(Error::PermissionDenied(_))), "{}", path);
+        }
+        for path in ["site", "site/index.html", "sftpx/versions", ".config", "notes.part.txt"] {
+            assert!(check_not_bookkeeping(path).is_ok(), "{}", path);
+        }
+    }
+}
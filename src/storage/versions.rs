@@ -0,0 +1,614 @@
+// Kept versions of stored files
+//
+// A file about to be replaced under the `Version` conflict policy is taken
+// into the version store: a record of its size, hash and chunk hashes, and
+// each of its chunks the store does not hold yet. Chunks are stored once by
+// hash and shared by every version that has them, so a new version costs
+// only the chunks that changed. They are also added to the chunk index, so
+// uploads can be deduplicated against old versions too.
+//
+// Every stored upload leaves a record of the hashes computed while it was
+// received. When that file is kept as a version, the record spares hashing
+// it again: only chunks new to the store are read, and checked against it.
+//
+// Layout, below `.sftpx/versions` in the upload directory:
+//
+//   files/<key>/current      record of the file now stored at the path
+//   files/<key>/<n>.version  record of version n
+//   chunks/<xx>/<hash>       chunk data, named by BLAKE3 hash
+//
+// where <key> is derived from the file's path below the upload directory.
+// `prune` drops the versions a `Retention` no longer keeps and then every
+// chunk no remaining version refers to, from the store and the chunk index.
+
+use crate::chunking::{ChunkHashIndex, ChunkLocation};
+use crate::common::error::{Error, Result};
+use crate::common::types::DEFAULT_CHUNK_SIZE;
+use crate::protocol::directory::file_mode;
+use crate::protocol::messages::{FileVersion, Manifest};
+use crate::protocol::metadata::{self, MetadataPolicy};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the record of the file now stored at a path
+const CURRENT_RECORD: &str = "current";
+
+/// Held while the store changes, so a prune never drops the chunks of a
+/// version still being kept
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+/// How long kept versions are retained
+///
+/// A version stays while either rule keeps it; with neither set, every
+/// version is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Keep the newest this many versions of each file
+    pub keep_last: Option<usize>,
+    /// Keep versions for this long after they were kept
+    pub keep_for: Option<Duration>,
+}
+
+impl Retention {
+    /// Whether no version is ever dropped
+    pub fn keeps_everything(&self) -> bool {
+        self.keep_last.is_none() && self.keep_for.is_none()
+    }
+
+    /// Whether the version `rank` places from the newest, kept `age` ago, stays
+    fn keeps(&self, rank: usize, age: Duration) -> bool {
+        self.keeps_everything()
+            || self.keep_last.is_some_and(|last| rank < last)
+            || self.keep_for.is_some_and(|period| age <= period)
+    }
+}
+
+/// What a prune removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub versions_removed: usize,
+    pub chunks_removed: usize,
+    pub bytes_freed: u64,
+}
+
+/// Versions of the files below an upload directory
+#[derive(Debug, Clone)]
+pub struct VersionStore {
+    upload_dir: PathBuf,
+    dir: PathBuf,
+}
+
+impl VersionStore {
+    /// The version store of the files below `upload_dir`
+    pub fn new(upload_dir: &Path) -> Self {
+        Self {
+            upload_dir: upload_dir.to_path_buf(),
+            dir: upload_dir.join(".sftpx").join("versions"),
+        }
+    }
+
+    /// Record the hashes of the upload just stored at `path`
+    ///
+    /// `chunk_hashes` are those of the stored file's chunks of
+    /// `manifest.chunk_size`; without all of them (chunks resumed in a
+    /// tree-hash upload are not proven) no record is left.
+    pub fn record(&self, path: &Path, manifest: &Manifest, chunk_hashes: &[Vec<u8>]) -> Result<()> {
+        let relative = self.relative_path(path)?;
+        let record_path = self.file_dir(&relative).join(CURRENT_RECORD);
+        let complete = manifest.chunk_size > 0
+            && !manifest.file_hash.is_empty()
+            && chunk_hashes.len() as u64 == manifest.file_size.div_ceil(manifest.chunk_size as u64)
+            && chunk_hashes.iter().all(|hash| !hash.is_empty());
+        if !complete {
+            return remove_if_present(&record_path);
+        }
+
+        let record = FileVersion {
+            path: relative,
+            number: 0,
+            file_size: manifest.file_size,
+            file_hash: manifest.file_hash.clone(),
+            kept_at: 0,
+            chunk_size: manifest.chunk_size,
+            chunk_hashes: chunk_hashes.to_vec(),
+            mode: file_mode(&fs::metadata(path)?),
+            metadata: Some(metadata::capture(path, &MetadataPolicy::default())?),
+        };
+        write_record(&record_path, &record)
+    }
+
+    /// Keep the file at `path` as its newest version, before it is replaced
+    ///
+    /// Chunks the store lacks are copied in and added to `chunk_index`,
+    /// which the caller saves. A file its record no longer describes is
+    /// hashed before the store is locked, so hashing a large one holds up
+    /// neither a prune nor other files being kept.
+    pub fn keep(&self, path: &Path, chunk_index: &mut ChunkHashIndex) -> Result<FileVersion> {
+        let relative = self.relative_path(path)?;
+        let file_dir = self.file_dir(&relative);
+        let mut file = File::open(path)?;
+
+        // A record is only trusted while the file has its size and time
+        let mut version = match read_record(&file_dir.join(CURRENT_RECORD)) {
+            Ok(record) if describes(&record, path) => record,
+            recorded => {
+                let chunk_size = recorded.map_or(DEFAULT_CHUNK_SIZE as u32, |record| record.chunk_size);
+                hash_chunks(&mut file, chunk_size, |_, _| Ok(()))?
+            }
+        };
+
+        let _guard = lock();
+        if !self.store_recorded(&mut file, &version, chunk_index)? {
+            // The file changed while it was hashed; hash it as it is now
+            version = self.store_hashed(&mut file, version.chunk_size, chunk_index)?;
+        }
+
+        version.path = relative;
+        version.number = version_numbers(&file_dir)?.last().map_or(1, |last| last + 1);
+        version.kept_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        version.mode = file_mode(&file.metadata()?);
+        version.metadata = Some(metadata::capture(path, &MetadataPolicy::default())?);
+        write_record(&version_path(&file_dir, version.number), &version)?;
+
+        log::info!("Versions: kept {} as version {} ({} bytes, {} chunks)",
+            version.path, version.number, version.file_size, version.chunk_hashes.len());
+        Ok(version)
+    }
+
+    /// Hash of the file now stored at `path`, from its record while that
+    /// still describes the file, or read from the file otherwise
+    pub fn file_hash(&self, path: &Path) -> Result<Vec<u8>> {
+        match self.recorded_hash(path)? {
+            Some(hash) => Ok(hash),
+            None => Ok(super::compute_file_hash(&mut File::open(path)?)?.as_bytes().to_vec()),
+        }
+    }
+
+    /// Hash of the file at `path` as recorded when it was received, if the
+    /// record still describes it
+    pub fn recorded_hash(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let relative = self.relative_path(path)?;
+        match read_record(&self.file_dir(&relative).join(CURRENT_RECORD)) {
+            Ok(record) if describes(&record, path) => Ok(Some(record.file_hash)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Kept versions of the file at `path`, oldest first
+    pub fn list(&self, path: &Path) -> Result<Vec<FileVersion>> {
+        let file_dir = self.file_dir(&self.relative_path(path)?);
+        version_numbers(&file_dir)?
+            .into_iter()
+            .map(|number| read_record(&version_path(&file_dir, number)))
+            .collect()
+    }
+
+    /// Drop version `number` of the file at `path`; chunks only it had go
+    /// with the next prune
+    pub fn forget(&self, path: &Path, number: u64) -> Result<()> {
+        let _guard = lock();
+        let file_dir = self.file_dir(&self.relative_path(path)?);
+        remove_if_present(&version_path(&file_dir, number))
+    }
+
+    /// Put version `number` of the file at `path` back in place
+    ///
+    /// The file is rebuilt from the store and checked against the version's
+    /// hash before it replaces the current file, which is kept as a version
+    /// first.
+    ///
+    /// # Returns
+    /// The version restored
+    ///
+    /// The store is not locked meanwhile: a version pruned while it is
+    /// rebuilt fails to restore for lack of its chunks.
+    pub fn restore(&self, path: &Path, number: u64, chunk_index: &mut ChunkHashIndex) -> Result<FileVersion> {
+        let relative = self.relative_path(path)?;
+        let file_dir = self.file_dir(&relative);
+        let version = read_record(&version_path(&file_dir, number))
+            .map_err(|_| Error::FileNotFound(format!("{} version {}", relative, number)))?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let rebuilt = path.with_file_name(format!(".{}.restore", file_name));
+        if let Err(e) = self.rebuild(&version, &rebuilt) {
+            let _ = fs::remove_file(&rebuilt);
+            return Err(e);
+        }
+
+        if path.exists() {
+            self.keep(path, chunk_index)?;
+        }
+        fs::rename(&rebuilt, path)?;
+        metadata::apply(path, version.mode, version.metadata.as_ref(), &MetadataPolicy::default())?;
+
+        // The restored file's hashes are known, as for an upload
+        let current = FileVersion {
+            number: 0,
+            kept_at: 0,
+            metadata: Some(metadata::capture(path, &MetadataPolicy::default())?),
+            ..version.clone()
+        };
+        write_record(&file_dir.join(CURRENT_RECORD), &current)?;
+
+        log::info!("Versions: restored version {} of {}", number, relative);
+        Ok(version)
+    }
+
+    /// Drop the versions `retention` no longer keeps, then the chunks no
+    /// remaining version refers to, along with their entries in
+    /// `chunk_index`, which the caller saves
+    ///
+    /// Ages are measured up to `now`.
+    pub fn prune(&self, retention: &Retention, now: SystemTime, chunk_index: &mut ChunkHashIndex) -> Result<PruneReport> {
+        let _guard = lock();
+        let mut report = PruneReport::default();
+        let mut referenced = HashSet::new();
+        let mut removed_chunks = HashSet::new();
+
+        for file_dir in subdirectories(&self.dir.join("files"))? {
+            let mut remaining = 0;
+            for (rank, number) in version_numbers(&file_dir)?.into_iter().rev().enumerate() {
+                let record_path = version_path(&file_dir, number);
+                let version = read_record(&record_path)?;
+                let age = now
+                    .duration_since(UNIX_EPOCH + Duration::from_secs(version.kept_at))
+                    .unwrap_or_default();
+                if retention.keeps(rank, age) {
+                    referenced.extend(version.chunk_hashes);
+                    remaining += 1;
+                } else {
+                    fs::remove_file(&record_path)?;
+                    report.versions_removed += 1;
+                }
+            }
+
+            // Nothing is left of a file deleted since with no versions kept
+            let stored = read_record(&file_dir.join(CURRENT_RECORD))
+                .is_ok_and(|current| self.upload_dir.join(&current.path).exists());
+            if remaining == 0 && !stored {
+                fs::remove_dir_all(&file_dir)?;
+            }
+        }
+
+        for chunk_dir in subdirectories(&self.dir.join("chunks"))? {
+            for entry in fs::read_dir(&chunk_dir)? {
+                let entry = entry?;
+                let hash = hex::decode(entry.file_name().to_string_lossy().as_ref());
+                if hash.is_ok_and(|hash| referenced.contains(&hash)) {
+                    continue;
+                }
+                report.bytes_freed += entry.metadata()?.len();
+                report.chunks_removed += 1;
+                fs::remove_file(entry.path())?;
+                removed_chunks.insert(entry.path());
+            }
+            let _ = fs::remove_dir(&chunk_dir); // Only once empty
+        }
+        chunk_index.remove_files(&removed_chunks);
+
+        if report.versions_removed > 0 || report.chunks_removed > 0 {
+            log::info!("Versions: pruned {} versions and {} chunks ({} bytes)",
+                report.versions_removed, report.chunks_removed, report.bytes_freed);
+        }
+        Ok(report)
+    }
+
+    /// Copy the chunks of `record` the store lacks from `file`
+    ///
+    /// # Returns
+    /// Whether every chunk read matched the record
+    fn store_recorded(&self, file: &mut File, record: &FileVersion, chunk_index: &mut ChunkHashIndex) -> Result<bool> {
+        let chunk_size = record.chunk_size as u64;
+        for (chunk_id, hash) in record.chunk_hashes.iter().enumerate() {
+            if self.chunk_path(hash).exists() {
+                continue;
+            }
+
+            let offset = chunk_id as u64 * chunk_size;
+            let mut data = vec![0u8; chunk_size.min(record.file_size.saturating_sub(offset)) as usize];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data)?;
+            if blake3::hash(&data).as_bytes() != hash.as_slice() {
+                log::warn!("Versions: chunk {} of {} no longer matches its record, hashing the file",
+                    chunk_id, record.path);
+                return Ok(false);
+            }
+            self.store_chunk(hash, &data, chunk_index)?;
+        }
+        Ok(true)
+    }
+
+    /// Hash `file` in chunks of `chunk_size`, copying those the store lacks
+    fn store_hashed(&self, file: &mut File, chunk_size: u32, chunk_index: &mut ChunkHashIndex) -> Result<FileVersion> {
+        hash_chunks(file, chunk_size, |hash, data| {
+            if !self.chunk_path(hash).exists() {
+                self.store_chunk(hash, data, chunk_index)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Add a chunk to the store and the chunk index
+    fn store_chunk(&self, hash: &[u8], data: &[u8], chunk_index: &mut ChunkHashIndex) -> Result<()> {
+        let chunk_path = self.chunk_path(hash);
+        write_atomically(&chunk_path, data)?;
+        chunk_index.add_chunk(hash.to_vec(), ChunkLocation {
+            file_path: chunk_path,
+            byte_offset: 0,
+            chunk_size: data.len() as u32,
+        });
+        Ok(())
+    }
+
+    /// Write `version`'s file to `target` from the store, checking its hash
+    fn rebuild(&self, version: &FileVersion, target: &Path) -> Result<()> {
+        let mut output = File::create(target)?;
+        let mut hasher = blake3::Hasher::new();
+        for hash in &version.chunk_hashes {
+            let data = fs::read(self.chunk_path(hash)).map_err(|_| Error::Protocol(format!(
+                "Chunk {} of {} version {} is missing", hex::encode(hash), version.path, version.number
+            )))?;
+            hasher.update(&data);
+            output.write_all(&data)?;
+        }
+        output.sync_all()?;
+
+        let actual = hasher.finalize();
+        if actual.as_bytes() != version.file_hash.as_slice() {
+            return Err(Error::HashMismatch {
+                expected: version.file_hash.clone(),
+                actual: actual.as_bytes().to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Path of `path` below the upload directory, with `/` separators
+    fn relative_path(&self, path: &Path) -> Result<String> {
+        let relative = path.strip_prefix(&self.upload_dir).map_err(|_| {
+            Error::Protocol(format!("{:?} is not below {:?}", path, self.upload_dir))
+        })?;
+        Ok(relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"))
+    }
+
+    /// Directory of the records for the file at `relative`
+    fn file_dir(&self, relative: &str) -> PathBuf {
+        let key = blake3::hash(relative.as_bytes());
+        self.dir.join("files").join(hex::encode(&key.as_bytes()[..16]))
+    }
+
+    /// Where the chunk with `hash` is stored
+    fn chunk_path(&self, hash: &[u8]) -> PathBuf {
+        let name = hex::encode(hash);
+        let bucket = name.get(..2).unwrap_or("00").to_string();
+        self.dir.join("chunks").join(bucket).join(name)
+    }
+}
+
+/// Hash `file` in chunks of `chunk_size`, handing each chunk's hash and
+/// data to `on_chunk`
+fn hash_chunks<F>(file: &mut File, chunk_size: u32, mut on_chunk: F) -> Result<FileVersion>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<()>,
+{
+    file.seek(SeekFrom::Start(0))?;
+    let mut version = FileVersion { chunk_size, ..FileVersion::default() };
+    let mut hasher = blake3::Hasher::new();
+    let mut data = Vec::with_capacity(chunk_size as usize);
+
+    loop {
+        data.clear();
+        Read::by_ref(file).take(chunk_size as u64).read_to_end(&mut data)?;
+        if data.is_empty() {
+            break;
+        }
+
+        hasher.update(&data);
+        let hash = blake3::hash(&data).as_bytes().to_vec();
+        on_chunk(&hash, &data)?;
+        version.chunk_hashes.push(hash);
+        version.file_size += data.len() as u64;
+    }
+
+    version.file_hash = hasher.finalize().as_bytes().to_vec();
+    Ok(version)
+}
+
+/// Whether `record` still matches the file at `path` in size and modification time
+fn describes(record: &FileVersion, path: &Path) -> bool {
+    let modified = metadata::capture(path, &MetadataPolicy::default())
+        .ok()
+        .and_then(|captured| captured.mtime_ns);
+    fs::metadata(path).is_ok_and(|stat| stat.len() == record.file_size)
+        && modified.is_some()
+        && modified == record.metadata.as_ref().and_then(|recorded| recorded.mtime_ns)
+}
+
+fn lock() -> MutexGuard<'static, ()> {
+    STORE_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn version_path(file_dir: &Path, number: u64) -> PathBuf {
+    file_dir.join(format!("{}.version", number))
+}
+
+/// Numbers of the versions recorded in `file_dir`, in ascending order
+fn version_numbers(file_dir: &Path) -> Result<Vec<u64>> {
+    if !file_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(file_dir)? {
+        let name = entry?.file_name();
+        if let Some(number) = name.to_string_lossy().strip_suffix(".version").and_then(|n| n.parse().ok()) {
+            numbers.push(number);
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+fn read_record(path: &Path) -> Result<FileVersion> {
+    let bytes = fs::read(path)?;
+    FileVersion::decode_from_bytes(&bytes)
+        .map_err(|e| Error::DeserializationError(format!("{:?}: {}", path, e)))
+}
+
+fn write_record(path: &Path, record: &FileVersion) -> Result<()> {
+    write_atomically(path, &record.encode_to_vec())
+}
+
+/// Write `data` to `path` through a temporary file, so it is never seen half written
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = path.with_extension("tmp");
+    fs::write(&partial, data)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+fn remove_if_present(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn subdirectories(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut subdirectories = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            subdirectories.push(entry.path());
+        }
+    }
+    Ok(subdirectories)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const CHUNK_SIZE: u32 = 4;
+
+    /// Store `content` at `path` as an upload would, recording its hashes
+    fn upload(store: &VersionStore, path: &Path, content: &[u8]) {
+        let _ = fs::remove_file(path);
+        fs::write(path, content).unwrap();
+        let chunk_hashes: Vec<Vec<u8>> = content
+            .chunks(CHUNK_SIZE as usize)
+            .map(|chunk| blake3::hash(chunk).as_bytes().to_vec())
+            .collect();
+        let manifest = Manifest {
+            file_size: content.len() as u64,
+            file_hash: blake3::hash(content).as_bytes().to_vec(),
+            chunk_size: CHUNK_SIZE,
+            total_chunks: chunk_hashes.len() as u64,
+            ..Manifest::default()
+        };
+        store.record(path, &manifest, &chunk_hashes).unwrap();
+    }
+
+    fn stored_chunks(upload_dir: &Path) -> usize {
+        subdirectories(&upload_dir.join(".sftpx/versions/chunks"))
+            .unwrap()
+            .iter()
+            .map(|dir| fs::read_dir(dir).unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn test_keep_and_restore_versions() {
+        let temp_dir = TempDir::new().unwrap();
+        let upload_dir = temp_dir.path();
+        let store = VersionStore::new(upload_dir);
+        let mut chunk_index = ChunkHashIndex::new(&upload_dir.join(".sftpx")).unwrap();
+        let path = upload_dir.join("notes.txt");
+
+        upload(&store, &path, b"aaaabbbbcc");
+        assert_eq!(store.keep(&path, &mut chunk_index).unwrap().number, 1);
+        assert_eq!(stored_chunks(upload_dir), 3);
+
+        // Unchanged chunks are shared with the earlier version
+        upload(&store, &path, b"aaaaddddcc");
+        let second = store.keep(&path, &mut chunk_index).unwrap();
+        assert_eq!(second.number, 2);
+        assert_eq!(second.file_hash, blake3::hash(b"aaaaddddcc").as_bytes().to_vec());
+        assert_eq!(stored_chunks(upload_dir), 4);
+        assert!(chunk_index.has_chunk(blake3::hash(b"dddd").as_bytes()));
+
+        // A file changed since its record is hashed again
+        fs::write(&path, b"changed outside").unwrap();
+        let third = store.keep(&path, &mut chunk_index).unwrap();
+        assert_eq!(third.file_hash, blake3::hash(b"changed outside").as_bytes().to_vec());
+        assert_eq!(third.chunk_size, CHUNK_SIZE);
+
+        let restored = store.restore(&path, 1, &mut chunk_index).unwrap();
+        assert_eq!(restored.number, 1);
+        assert_eq!(fs::read(&path).unwrap(), b"aaaabbbbcc");
+
+        // The file restored over is kept too
+        let numbers: Vec<u64> = store.list(&path).unwrap().iter().map(|v| v.number).collect();
+        assert_eq!(numbers, vec![1, 2, 3, 4]);
+        assert!(matches!(store.restore(&path, 9, &mut chunk_index), Err(Error::FileNotFound(_))));
+    }
+
+    #[test]
+    fn test_prune_by_retention() {
+        let temp_dir = TempDir::new().unwrap();
+        let upload_dir = temp_dir.path();
+        let store = VersionStore::new(upload_dir);
+        let mut chunk_index = ChunkHashIndex::new(&upload_dir.join(".sftpx")).unwrap();
+        let path = upload_dir.join("data.bin");
+
+        for content in [b"aaaa1111", b"aaaa2222", b"aaaa3333"] {
+            upload(&store, &path, content);
+            store.keep(&path, &mut chunk_index).unwrap();
+        }
+        assert_eq!(stored_chunks(upload_dir), 4);
+
+        let now = SystemTime::now();
+        assert_eq!(store.prune(&Retention::default(), now, &mut chunk_index).unwrap(), PruneReport::default());
+
+        let last_two = Retention { keep_last: Some(2), keep_for: None };
+        let report = store.prune(&last_two, now, &mut chunk_index).unwrap();
+        assert_eq!((report.versions_removed, report.chunks_removed, report.bytes_freed), (1, 1, 4));
+        let numbers: Vec<u64> = store.list(&path).unwrap().iter().map(|v| v.number).collect();
+        assert_eq!(numbers, vec![2, 3]);
+        // Dedup no longer finds the chunk that went
+        assert!(!chunk_index.has_chunk(blake3::hash(b"1111").as_bytes()));
+        assert!(chunk_index.has_chunk(blake3::hash(b"2222").as_bytes()));
+
+        // Either rule keeps a version
+        let week = Retention { keep_last: Some(1), keep_for: Some(Duration::from_secs(7 * 86400)) };
+        assert_eq!(store.prune(&week, now, &mut chunk_index).unwrap().versions_removed, 0);
+        let later = now + Duration::from_secs(8 * 86400);
+        assert_eq!(store.prune(&week, later, &mut chunk_index).unwrap().versions_removed, 1);
+
+        // Once the file is gone, so is what was kept of it
+        fs::remove_file(&path).unwrap();
+        let report = store.prune(&Retention { keep_last: Some(0), keep_for: None }, later, &mut chunk_index).unwrap();
+        assert_eq!((report.versions_removed, report.chunks_removed), (1, 2));
+        assert!(store.list(&path).unwrap().is_empty());
+        assert_eq!(stored_chunks(upload_dir), 0);
+    }
+}
@@ -0,0 +1,118 @@
+// Readiness-driven UDP socket
+//
+// Connection loops block in `wait` until a datagram arrives or the QUIC
+// connection's next timer is due, instead of polling the socket with short
+// read timeouts and sleeping in between. The socket is registered with a
+// mio poller; reads never block, so callers drain it until `WouldBlock`
+// before waiting again.
+
+use mio::net::UdpSocket;
+use mio::{Events, Interest, Poll, Token};
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const SOCKET: Token = Token(0);
+
+/// Non-blocking UDP socket with a poller waiting on it
+pub struct EventSocket {
+    socket: UdpSocket,
+    poll: RefCell<Poll>,
+    events: RefCell<Events>,
+}
+
+impl EventSocket {
+    /// Bind a socket and register it for readability
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let mut socket = UdpSocket::bind(addr)?;
+        let poll = Poll::new()?;
+        poll.registry().register(&mut socket, SOCKET, Interest::READABLE)?;
+        Ok(Self {
+            socket,
+            poll: RefCell::new(poll),
+            events: RefCell::new(Events::with_capacity(8)),
+        })
+    }
+
+    /// Only exchange datagrams with `addr`
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.socket.connect(addr)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Receive a datagram, failing with `WouldBlock` when none is queued
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+
+    /// Send a datagram
+    ///
+    /// A datagram the send buffer has no room for is dropped, as the network
+    /// might drop it; QUIC loss recovery sends its contents again.
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self.socket.send_to(buf, addr) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                log::debug!("Send buffer full, dropping {} byte datagram", buf.len());
+                Ok(buf.len())
+            }
+            result => result,
+        }
+    }
+
+    /// Block until a datagram can be read or `timeout` passes
+    ///
+    /// # Returns
+    /// Whether the socket became readable; `false` on timeout or when the
+    /// wait was interrupted by a signal
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut poll = self.poll.borrow_mut();
+        let mut events = self.events.borrow_mut();
+        match poll.poll(&mut events, timeout) {
+            Ok(()) => Ok(!events.is_empty()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// How long to wait for the network: until the connection's next timer
+/// (`timer`, from `quiche::Connection::timeout`) or `limit`, whichever is first
+pub fn wait_time(timer: Option<Duration>, limit: Duration) -> Duration {
+    timer.map_or(limit, |timer| timer.min(limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_wait_wakes_on_datagram() {
+        let receiver = EventSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = [0u8; 16];
+
+        let err = receiver.recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(!receiver.wait(Some(Duration::from_millis(10))).unwrap());
+
+        sender.send_to(b"ping", receiver.local_addr().unwrap()).unwrap();
+        let started = Instant::now();
+        assert!(receiver.wait(Some(Duration::from_secs(5))).unwrap());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(receiver.recv_from(&mut buf).unwrap().0, 4);
+        assert_eq!(&buf[..4], b"ping");
+    }
+
+    #[test]
+    fn test_wait_time() {
+        let limit = Duration::from_millis(100);
+        assert_eq!(wait_time(None, limit), limit);
+        assert_eq!(wait_time(Some(Duration::from_millis(3)), limit), Duration::from_millis(3));
+        assert_eq!(wait_time(Some(Duration::from_secs(30)), limit), limit);
+    }
+}
@@ -0,0 +1,557 @@
+// Paged manifest stream
+//
+// The manifest is sent as a header followed by pages of chunk hashes, each
+// framed with a 4-byte big-endian length like packets on the data stream.
+// Frames are decoded as they complete, so receive memory is bounded by one
+// page rather than the whole manifest, and callers can act on early pages
+// (dedup lookups, resume checks) while later pages are still in flight.
+//
+// A streaming manifest, for input of unknown length, announces no pages:
+// they are sent as chunks are read, and the last one carries the trailer
+// with the file's size, chunk count and hash.
+
+use crate::common::error::{Error, Result};
+use crate::protocol::messages::{Manifest, ManifestPage};
+
+/// Default number of chunk hashes per page (~128 KB)
+pub const DEFAULT_PAGE_HASHES: usize = 4096;
+
+/// Largest frame accepted from the stream (4 MB)
+const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// Length prefix size for each frame
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Sends a manifest as a header plus chunk hash pages
+pub struct PagedManifestSender {
+    page_hashes: usize,
+}
+
+impl PagedManifestSender {
+    /// Create a sender with the default page size
+    pub fn new() -> Self {
+        Self::with_page_hashes(DEFAULT_PAGE_HASHES)
+    }
+
+    /// Create a sender putting at most `page_hashes` chunk hashes in each page
+    pub fn with_page_hashes(page_hashes: usize) -> Self {
+        Self {
+            page_hashes: page_hashes.max(1),
+        }
+    }
+
+    /// Encode a manifest as the framed bytes to write on the manifest stream
+    pub fn encode(&self, manifest: &Manifest) -> Result<Vec<u8>> {
+        let pages: Vec<&[Vec<u8>]> = manifest.chunk_hashes.chunks(self.page_hashes).collect();
+
+        let header = Manifest {
+            session_id: manifest.session_id.clone(),
+            file_name: manifest.file_name.clone(),
+            file_size: manifest.file_size,
+            chunk_size: manifest.chunk_size,
+            total_chunks: manifest.total_chunks,
+            file_hash: manifest.file_hash.clone(),
+            chunk_hashes: Vec::new(),
+            compression: manifest.compression.clone(),
+            original_size: manifest.original_size,
+            encryption: manifest.encryption.clone(),
+            signature: manifest.signature.clone(),
+            tree_root: manifest.tree_root.clone(),
+            hash_pages: pages.len() as u32,
+            transfer_id: manifest.transfer_id,
+            relative_path: manifest.relative_path.clone(),
+            mode: manifest.mode,
+            bundle: manifest.bundle.clone(),
+            stripe: manifest.stripe.clone(),
+            data_streams: manifest.data_streams.clone(),
+            streaming: manifest.streaming,
+            metadata: manifest.metadata.clone(),
+            holes: manifest.holes.clone(),
+            conflict_policy: manifest.conflict_policy,
+        };
+
+        let mut encoded = Vec::new();
+        push_frame(&mut encoded, &header.encode_to_vec())?;
+
+        let mut first_chunk = 0u64;
+        for hashes in pages {
+            let page = ManifestPage {
+                first_chunk,
+                chunk_hashes: hashes.to_vec(),
+                trailer: None,
+            };
+            push_frame(&mut encoded, &page.encode_to_vec())?;
+            first_chunk += hashes.len() as u64;
+        }
+
+        Ok(encoded)
+    }
+
+    /// Encode one page of a streaming manifest as a frame
+    ///
+    /// The header is sent first with `encode`; pages then follow as their
+    /// chunks are read, the last with the trailer.
+    pub fn encode_page(&self, page: &ManifestPage) -> Result<Vec<u8>> {
+        let mut encoded = Vec::new();
+        push_frame(&mut encoded, &page.encode_to_vec())?;
+        Ok(encoded)
+    }
+
+    /// Send a manifest over a QUIC stream
+    ///
+    /// # Arguments
+    /// * `manifest` - The manifest to send
+    /// * `send_fn` - Function to send data on the stream (data, fin)
+    ///
+    /// # Returns
+    /// * `Ok(bytes_written)` - Number of bytes written
+    /// * `Err(Error)` - If sending fails
+    pub fn send_manifest<F>(&self, manifest: &Manifest, mut send_fn: F) -> Result<usize>
+    where
+        F: FnMut(&[u8], bool) -> Result<usize>,
+    {
+        let encoded = self.encode(manifest)?;
+        let bytes_written = send_fn(&encoded, true)?;
+
+        if bytes_written != encoded.len() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                format!("Partial write: {}/{} bytes", bytes_written, encoded.len()),
+            )));
+        }
+
+        Ok(bytes_written)
+    }
+}
+
+impl Default for PagedManifestSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Something decoded from a paged manifest stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestEvent {
+    /// Manifest without its chunk hashes (always first)
+    Header(Box<Manifest>),
+    /// Next page of chunk hashes
+    Page(ManifestPage),
+    /// All pages announced by the header have arrived
+    Complete,
+}
+
+/// Header fields needed to check the pages that follow it
+#[derive(Debug, Clone, Copy)]
+struct ExpectedPages {
+    pages: u32,
+    total_chunks: u64,
+    /// Pages and totals are not known until the trailer
+    streaming: bool,
+}
+
+/// Decodes a paged manifest stream frame by frame
+pub struct PagedManifestReceiver {
+    buffer: Vec<u8>,
+    expected: Option<ExpectedPages>,
+    pages_received: u32,
+    next_chunk: u64,
+    complete: bool,
+}
+
+impl PagedManifestReceiver {
+    /// Create a new paged manifest receiver
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            expected: None,
+            pages_received: 0,
+            next_chunk: 0,
+            complete: false,
+        }
+    }
+
+    /// Receive data from the stream
+    ///
+    /// # Returns
+    /// Header, pages and completion, in stream order, for every frame the
+    /// data finished
+    pub fn receive_chunk(&mut self, data: &[u8], fin: bool) -> Result<Vec<ManifestEvent>> {
+        if self.complete && !data.is_empty() {
+            return Err(Error::Protocol("Data after end of manifest".to_string()));
+        }
+
+        self.buffer.extend_from_slice(data);
+
+        let mut events = Vec::new();
+        while self.buffer.len() >= LENGTH_PREFIX_SIZE {
+            let len_bytes: [u8; 4] = self.buffer[..LENGTH_PREFIX_SIZE].try_into().unwrap();
+            let frame_len = u32::from_be_bytes(len_bytes) as usize;
+            if frame_len > MAX_FRAME_SIZE {
+                return Err(Error::Protocol(format!(
+                    "Manifest frame too large: {} bytes (max: {})",
+                    frame_len, MAX_FRAME_SIZE
+                )));
+            }
+
+            let frame_end = LENGTH_PREFIX_SIZE + frame_len;
+            if self.buffer.len() < frame_end {
+                break;
+            }
+
+            let frame: Vec<u8> = self.buffer.drain(..frame_end).skip(LENGTH_PREFIX_SIZE).collect();
+            events.push(self.decode_frame(&frame)?);
+
+            if self.is_complete() {
+                events.push(ManifestEvent::Complete);
+                if !self.buffer.is_empty() {
+                    return Err(Error::Protocol("Data after end of manifest".to_string()));
+                }
+                break;
+            }
+        }
+
+        if fin && !self.complete {
+            return Err(Error::Protocol(
+                "Stream finished but manifest incomplete".to_string()
+            ));
+        }
+
+        Ok(events)
+    }
+
+    fn decode_frame(&mut self, frame: &[u8]) -> Result<ManifestEvent> {
+        let expected = match self.expected {
+            Some(expected) => expected,
+            None => {
+                let header = Manifest::decode_from_bytes(frame)?;
+                self.expected = Some(ExpectedPages {
+                    pages: header.hash_pages,
+                    total_chunks: header.total_chunks,
+                    streaming: header.streaming,
+                });
+                self.complete = !header.streaming && header.hash_pages == 0;
+                return Ok(ManifestEvent::Header(Box::new(header)));
+            }
+        };
+
+        let page = ManifestPage::decode_from_bytes(frame)?;
+        if page.first_chunk != self.next_chunk {
+            return Err(Error::Protocol(format!(
+                "Manifest page out of order: starts at chunk {}, expected {}",
+                page.first_chunk, self.next_chunk
+            )));
+        }
+
+        self.next_chunk += page.chunk_hashes.len() as u64;
+        self.pages_received += 1;
+
+        if expected.streaming {
+            if let Some(trailer) = &page.trailer {
+                if self.next_chunk != trailer.total_chunks {
+                    return Err(Error::Protocol(format!(
+                        "Manifest pages list {} chunk hashes, trailer says {}",
+                        self.next_chunk, trailer.total_chunks
+                    )));
+                }
+                self.complete = true;
+            }
+            return Ok(ManifestEvent::Page(page));
+        }
+        if page.trailer.is_some() {
+            return Err(Error::Protocol("Manifest trailer on a manifest of known length".to_string()));
+        }
+
+        if self.next_chunk > expected.total_chunks {
+            return Err(Error::Protocol(format!(
+                "Manifest pages list more than {} chunk hashes",
+                expected.total_chunks
+            )));
+        }
+
+        if self.pages_received == expected.pages {
+            if self.next_chunk != expected.total_chunks {
+                return Err(Error::Protocol(format!(
+                    "Manifest pages list {} chunk hashes, expected {}",
+                    self.next_chunk, expected.total_chunks
+                )));
+            }
+            self.complete = true;
+        }
+
+        Ok(ManifestEvent::Page(page))
+    }
+
+    /// Check if every announced page has been received
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Number of chunk hashes received so far
+    pub fn hashes_received(&self) -> u64 {
+        self.next_chunk
+    }
+
+    /// Bytes buffered for a frame that has not fully arrived
+    pub fn buffer_size(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+impl Default for PagedManifestReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reassembles a paged manifest into a complete `Manifest`
+///
+/// For receivers that need every chunk hash at once, e.g. to check a
+/// signature. The reassembled manifest is identical to the one sent.
+pub struct ManifestAssembler {
+    receiver: PagedManifestReceiver,
+    manifest: Option<Manifest>,
+}
+
+impl ManifestAssembler {
+    /// Create a new manifest assembler
+    pub fn new() -> Self {
+        Self {
+            receiver: PagedManifestReceiver::new(),
+            manifest: None,
+        }
+    }
+
+    /// Receive data from the stream
+    ///
+    /// # Returns
+    /// * `Ok(Some(Manifest))` - If the manifest is complete
+    /// * `Ok(None)` - If more data is needed
+    /// * `Err(Error)` - If the stream is malformed
+    pub fn receive_chunk(&mut self, data: &[u8], fin: bool) -> Result<Option<Manifest>> {
+        for event in self.receiver.receive_chunk(data, fin)? {
+            match event {
+                ManifestEvent::Header(header) => self.manifest = Some(*header),
+                ManifestEvent::Page(page) => {
+                    if let Some(manifest) = &mut self.manifest {
+                        manifest.chunk_hashes.extend(page.chunk_hashes);
+                        if let Some(trailer) = page.trailer {
+                            manifest.file_size = trailer.file_size;
+                            manifest.total_chunks = trailer.total_chunks;
+                            manifest.file_hash = trailer.file_hash;
+                        }
+                    }
+                }
+                ManifestEvent::Complete => {
+                    let mut manifest = self.manifest.take().ok_or_else(|| {
+                        Error::Protocol("Manifest complete without a header".to_string())
+                    })?;
+                    manifest.hash_pages = 0;
+                    return Ok(Some(manifest));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Default for ManifestAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn push_frame(out: &mut Vec<u8>, frame: &[u8]) -> Result<()> {
+    if frame.len() > MAX_FRAME_SIZE {
+        return Err(Error::Protocol(format!(
+            "Manifest frame too large: {} bytes (max: {})",
+            frame.len(),
+            MAX_FRAME_SIZE
+        )));
+    }
+
+    out.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    out.extend_from_slice(frame);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::messages::ManifestTrailer;
+
+    fn create_test_manifest(total_chunks: u64) -> Manifest {
+        Manifest {
+            session_id: "paged-session".to_string(),
+            file_name: "large.bin".to_string(),
+            file_size: total_chunks * 1024,
+            chunk_size: 1024,
+            total_chunks,
+            file_hash: vec![1u8; 32],
+            chunk_hashes: (0..total_chunks)
+                .map(|i| blake3::hash(&i.to_le_bytes()).as_bytes().to_vec())
+                .collect(),
+            compression: "none".to_string(),
+            original_size: None,
+            encryption: None,
+            signature: None,
+            tree_root: None,
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes: Vec::new(),
+            conflict_policy: 0,
+        }
+    }
+
+    #[test]
+    fn test_pages_stream_in_order() {
+        let manifest = create_test_manifest(10);
+        let encoded = PagedManifestSender::with_page_hashes(4).encode(&manifest).unwrap();
+
+        let mut receiver = PagedManifestReceiver::new();
+        let mut events = Vec::new();
+        for piece in encoded.chunks(7) {
+            events.extend(receiver.receive_chunk(piece, false).unwrap());
+            // Never more than one partial frame is held
+            assert!(receiver.buffer_size() < 4 + 4 * 40);
+        }
+        assert!(receiver.receive_chunk(&[], true).unwrap().is_empty());
+
+        assert_eq!(events.len(), 5);
+        match &events[0] {
+            ManifestEvent::Header(header) => {
+                assert!(header.chunk_hashes.is_empty());
+                assert_eq!(header.hash_pages, 3);
+            }
+            other => panic!("expected header, got {:?}", other),
+        }
+
+        let firsts: Vec<u64> = events[1..4].iter().map(|e| match e {
+            ManifestEvent::Page(page) => page.first_chunk,
+            other => panic!("expected page, got {:?}", other),
+        }).collect();
+        assert_eq!(firsts, vec![0, 4, 8]);
+        assert_eq!(events[4], ManifestEvent::Complete);
+        assert_eq!(receiver.hashes_received(), 10);
+    }
+
+    #[test]
+    fn test_assembler_roundtrip() {
+        use crate::protocol::signing::{verify_manifest_signature, ManifestSigner};
+
+        let signer = ManifestSigner::from_pkcs8(&ManifestSigner::generate_pkcs8().unwrap()).unwrap();
+        let mut manifest = create_test_manifest(9);
+        signer.sign(&mut manifest);
+
+        let mut transmitted = Vec::new();
+        PagedManifestSender::with_page_hashes(2).send_manifest(&manifest, |data, _fin| {
+            transmitted.extend_from_slice(data);
+            Ok(data.len())
+        }).unwrap();
+
+        let mut assembler = ManifestAssembler::new();
+        let (head, tail) = transmitted.split_at(transmitted.len() / 2);
+        assert!(assembler.receive_chunk(head, false).unwrap().is_none());
+        let received = assembler.receive_chunk(tail, true).unwrap().unwrap();
+
+        assert_eq!(received, manifest);
+        assert!(verify_manifest_signature(&received).is_ok());
+    }
+
+    #[test]
+    fn test_header_only_manifest() {
+        let mut manifest = create_test_manifest(3);
+        manifest.chunk_hashes.clear();
+        manifest.tree_root = Some(vec![2u8; 32]);
+
+        let encoded = PagedManifestSender::new().encode(&manifest).unwrap();
+        let mut assembler = ManifestAssembler::new();
+        assert_eq!(assembler.receive_chunk(&encoded, true).unwrap(), Some(manifest));
+    }
+
+    #[test]
+    fn test_streaming_manifest_ends_with_trailer() {
+        let full = create_test_manifest(5);
+        let mut header = full.clone();
+        header.file_size = 0;
+        header.total_chunks = 0;
+        header.file_hash.clear();
+        header.chunk_hashes.clear();
+        header.streaming = true;
+
+        let sender = PagedManifestSender::new();
+        let mut encoded = sender.encode(&header).unwrap();
+        let mut assembler = ManifestAssembler::new();
+        assert!(assembler.receive_chunk(&encoded, false).unwrap().is_none());
+
+        // Pages arrive as chunks are read, the last with the totals
+        encoded = sender.encode_page(&ManifestPage {
+            first_chunk: 0,
+            chunk_hashes: full.chunk_hashes[..3].to_vec(),
+            trailer: None,
+        }).unwrap();
+        assert!(assembler.receive_chunk(&encoded, false).unwrap().is_none());
+        encoded = sender.encode_page(&ManifestPage {
+            first_chunk: 3,
+            chunk_hashes: full.chunk_hashes[3..].to_vec(),
+            trailer: Some(ManifestTrailer {
+                file_size: full.file_size,
+                total_chunks: 5,
+                file_hash: full.file_hash.clone(),
+            }),
+        }).unwrap();
+        let received = assembler.receive_chunk(&encoded, true).unwrap().unwrap();
+
+        assert_eq!(received, Manifest { streaming: true, ..full });
+
+        // A trailer must match the hashes sent
+        let mut receiver = PagedManifestReceiver::new();
+        let mut frames = sender.encode(&header).unwrap();
+        frames.extend(sender.encode_page(&ManifestPage {
+            first_chunk: 0,
+            chunk_hashes: vec![vec![1u8; 32]],
+            trailer: Some(ManifestTrailer { file_size: 10, total_chunks: 2, file_hash: vec![1u8; 32] }),
+        }).unwrap());
+        assert!(receiver.receive_chunk(&frames, true).is_err());
+    }
+
+    #[test]
+    fn test_malformed_streams_rejected() {
+        let manifest = create_test_manifest(6);
+        let sender = PagedManifestSender::with_page_hashes(2);
+        let encoded = sender.encode(&manifest).unwrap();
+
+        // Truncated stream
+        let mut receiver = PagedManifestReceiver::new();
+        assert!(receiver.receive_chunk(&encoded[..encoded.len() - 1], true).is_err());
+
+        // Trailing garbage
+        let mut receiver = PagedManifestReceiver::new();
+        let mut extended = encoded.clone();
+        extended.extend_from_slice(&[0, 0, 0, 1, 0]);
+        assert!(receiver.receive_chunk(&extended, false).is_err());
+
+        // Oversized frame
+        let mut receiver = PagedManifestReceiver::new();
+        assert!(receiver.receive_chunk(&u32::MAX.to_be_bytes(), false).is_err());
+
+        // Page out of order
+        let mut frames = Vec::new();
+        let mut header = manifest.clone();
+        header.chunk_hashes.clear();
+        header.hash_pages = 1;
+        push_frame(&mut frames, &header.encode_to_vec()).unwrap();
+        let page = ManifestPage { first_chunk: 2, chunk_hashes: manifest.chunk_hashes.clone(), trailer: None };
+        push_frame(&mut frames, &page.encode_to_vec()).unwrap();
+        let mut receiver = PagedManifestReceiver::new();
+        assert!(receiver.receive_chunk(&frames, true).is_err());
+    }
+}
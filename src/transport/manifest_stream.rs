@@ -235,6 +235,20 @@ mod tests {
             chunk_hashes: vec![vec![0u8; 32]; 4],
             compression: "none".to_string(),
             original_size: Some(1024),
+            encryption: None,
+            signature: None,
+            tree_root: None,
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes: Vec::new(),
+            conflict_policy: 0,
         }
     }
 
@@ -342,6 +356,20 @@ mod tests {
             chunk_hashes: vec![vec![0u8; 32]; 1_000_000], // ~32 MB of hashes
             compression: "none".to_string(),
             original_size: None,
+            encryption: None,
+            signature: None,
+            tree_root: None,
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes: Vec::new(),
+            conflict_policy: 0,
         };
         
         let result = sender.send_manifest(&huge_manifest, |_data, _fin| Ok(0));
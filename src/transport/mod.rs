@@ -1,7 +1,15 @@
 // QUIC transport layer module
 
 pub mod control_stream;
+pub mod event_socket;
 pub mod manifest_stream;
+pub mod manifest_pages;
+pub mod priority;
+pub mod streams;
 
 pub use control_stream::{ControlStreamHandler, ControlMessageSender, ControlMessageHandler, ControlMessageDispatcher};
 pub use manifest_stream::{ManifestSender, ManifestReceiver};
+pub use manifest_pages::{PagedManifestSender, PagedManifestReceiver, ManifestAssembler, ManifestEvent};
+pub use streams::{StreamAllocator, TransferId, TransferStreamKind, TransferStreams};
+pub use priority::DataStreamScheduler;
+pub use event_socket::EventSocket;
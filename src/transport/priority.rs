@@ -1 +1,132 @@
 // Stream priority management
+//
+// quiche sends pending data of streams with a lower urgency value first.
+// Control, status and the per-transfer negotiation streams (resume, hash
+// check) are urgent so their small messages are not queued behind bulk
+// chunk data; manifests come next, and data streams share the lowest
+// urgency incrementally, taking turns packet by packet.
+//
+// A transfer can spread its chunks over a pool of data streams so a lost
+// packet only holds up the chunks queued on its own stream. The
+// `DataStreamScheduler` picks the stream each chunk goes out on.
+
+use super::streams::{TransferStreamKind, TransferStreams};
+
+/// Control stream (0)
+pub const URGENCY_CONTROL: u8 = 0;
+/// Resume and hash check exchanges, which the sender waits on
+pub const URGENCY_NEGOTIATION: u8 = 1;
+/// Status stream (12)
+pub const URGENCY_STATUS: u8 = 2;
+/// Manifest header and hash pages
+pub const URGENCY_MANIFEST: u8 = 3;
+/// Chunk data
+pub const URGENCY_DATA: u8 = 5;
+/// Streams with no known role
+pub const URGENCY_UNKNOWN: u8 = 7;
+
+const STREAM_CONTROL: u64 = 0;
+const STREAM_STATUS: u64 = 12;
+
+/// Urgency and incremental flag for a stream, from its role
+///
+/// Extra data streams of a transfer are not recognizable by ID alone; use
+/// `data_stream_priority` for them.
+pub fn stream_priority(stream_id: u64) -> (u8, bool) {
+    match stream_id {
+        STREAM_CONTROL => (URGENCY_CONTROL, false),
+        STREAM_STATUS => (URGENCY_STATUS, false),
+        _ => match TransferStreams::lookup(stream_id) {
+            Some((_, TransferStreamKind::Manifest)) => (URGENCY_MANIFEST, false),
+            Some((_, TransferStreamKind::Data)) => data_stream_priority(),
+            Some((_, TransferStreamKind::HashCheck | TransferStreamKind::Resume)) => (URGENCY_NEGOTIATION, false),
+            None => (URGENCY_UNKNOWN, true),
+        },
+    }
+}
+
+/// Urgency and incremental flag for every data stream
+pub fn data_stream_priority() -> (u8, bool) {
+    (URGENCY_DATA, true)
+}
+
+/// Spreads a transfer's chunks over its data streams
+///
+/// Chunks go round-robin, skipping streams without room for the chunk in
+/// their send window, so a stream stalled on a lost packet is passed over
+/// until it drains.
+#[derive(Debug, Clone)]
+pub struct DataStreamScheduler {
+    streams: Vec<u64>,
+    next: usize,
+}
+
+impl DataStreamScheduler {
+    /// Schedule over `streams`, the transfer's own data stream first
+    pub fn new(streams: Vec<u64>) -> Self {
+        assert!(!streams.is_empty(), "scheduler needs at least one data stream");
+        Self { streams, next: 0 }
+    }
+
+    /// Data streams in use
+    pub fn streams(&self) -> &[u64] {
+        &self.streams
+    }
+
+    /// Choose the stream for a chunk of `len` bytes
+    ///
+    /// `capacity` reports how many bytes a stream can take right now (`None`
+    /// if unknown). If no stream has room, the next one in turn is used and
+    /// the caller waits on it.
+    pub fn next_stream(&mut self, len: usize, mut capacity: impl FnMut(u64) -> Option<usize>) -> u64 {
+        let count = self.streams.len();
+        let chosen = (0..count)
+            .map(|offset| (self.next + offset) % count)
+            .find(|&index| capacity(self.streams[index]).is_some_and(|room| room >= len))
+            .unwrap_or(self.next);
+
+        self.next = (chosen + 1) % count;
+        self.streams[chosen]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiation_outranks_data() {
+        let streams = TransferStreams::for_transfer(3);
+        let (control, _) = stream_priority(0);
+        let (resume, _) = stream_priority(streams.resume);
+        let (hash_check, _) = stream_priority(streams.hash_check);
+        let (manifest, _) = stream_priority(streams.manifest);
+        let (data, incremental) = stream_priority(streams.data);
+
+        assert!(control < resume && resume == hash_check);
+        assert!(hash_check < manifest && manifest < data);
+        assert!(incremental);
+        assert_eq!(stream_priority(8), data_stream_priority());
+        assert_eq!(stream_priority(25).0, URGENCY_UNKNOWN);
+    }
+
+    #[test]
+    fn test_scheduler_round_robin() {
+        let mut scheduler = DataStreamScheduler::new(vec![8, 24, 28]);
+        let picked: Vec<u64> = (0..5).map(|_| scheduler.next_stream(100, |_| Some(1000))).collect();
+        assert_eq!(picked, vec![8, 24, 28, 8, 24]);
+    }
+
+    #[test]
+    fn test_scheduler_skips_full_streams() {
+        let mut scheduler = DataStreamScheduler::new(vec![8, 24, 28]);
+        let room = |stream_id| Some(if stream_id == 24 { 10 } else { 1000 });
+        assert_eq!(scheduler.next_stream(100, room), 8);
+        assert_eq!(scheduler.next_stream(100, room), 28);
+        assert_eq!(scheduler.next_stream(100, room), 8);
+
+        // Nothing has room: wait on the next stream in turn
+        assert_eq!(scheduler.next_stream(100, |_| Some(0)), 24);
+        assert_eq!(scheduler.next_stream(100, |_| None), 28);
+    }
+}
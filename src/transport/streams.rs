@@ -1 +1,231 @@
 // Stream management and prioritization
+//
+// Each file transfer on a connection gets its own set of client-initiated
+// bidirectional streams (manifest, data, hash check, resume), derived from a
+// per-connection transfer ID. Control (0) and status (12) stay shared.
+//
+// Transfer 0 keeps the original fixed IDs (4, 8, 16, 20) so single-file
+// peers interoperate unchanged; transfer n >= 1 takes the four streams
+// starting at 24 + 16 * (n - 1).
+//
+// Extra data streams for a transfer take whole transfer slots, all four of
+// whose streams carry data; the transfer's manifest lists them so the
+// receiver does not mistake them for a new transfer.
+
+use crate::common::error::{Error, Result};
+
+/// Per-connection transfer identifier, carried in every transfer message
+pub type TransferId = u32;
+
+/// First stream ID handed out to transfers after transfer 0
+const FIRST_DYNAMIC_STREAM: u64 = 24;
+
+/// Client-initiated bidirectional stream IDs advance by 4
+const STREAM_ID_STEP: u64 = 4;
+
+/// Streams allocated to each transfer
+const STREAMS_PER_TRANSFER: u64 = 4;
+
+/// Role of a stream within a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransferStreamKind {
+    Manifest,
+    Data,
+    HashCheck,
+    Resume,
+}
+
+impl TransferStreamKind {
+    const ALL: [TransferStreamKind; 4] = [
+        TransferStreamKind::Manifest,
+        TransferStreamKind::Data,
+        TransferStreamKind::HashCheck,
+        TransferStreamKind::Resume,
+    ];
+}
+
+/// Stream IDs used by one transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransferStreams {
+    pub transfer_id: TransferId,
+    pub manifest: u64,
+    pub data: u64,
+    pub hash_check: u64,
+    pub resume: u64,
+}
+
+impl TransferStreams {
+    /// Streams for a transfer ID
+    pub fn for_transfer(transfer_id: TransferId) -> Self {
+        if transfer_id == 0 {
+            return Self {
+                transfer_id,
+                manifest: 4,
+                data: 8,
+                hash_check: 16,
+                resume: 20,
+            };
+        }
+
+        let base = FIRST_DYNAMIC_STREAM
+            + (transfer_id as u64 - 1) * STREAMS_PER_TRANSFER * STREAM_ID_STEP;
+        Self {
+            transfer_id,
+            manifest: base,
+            data: base + STREAM_ID_STEP,
+            hash_check: base + 2 * STREAM_ID_STEP,
+            resume: base + 3 * STREAM_ID_STEP,
+        }
+    }
+
+    /// Transfer and role a stream belongs to, if it is a transfer stream
+    pub fn lookup(stream_id: u64) -> Option<(TransferId, TransferStreamKind)> {
+        match stream_id {
+            4 => return Some((0, TransferStreamKind::Manifest)),
+            8 => return Some((0, TransferStreamKind::Data)),
+            16 => return Some((0, TransferStreamKind::HashCheck)),
+            20 => return Some((0, TransferStreamKind::Resume)),
+            _ => {}
+        }
+
+        // Only client-initiated bidirectional streams carry transfers
+        if stream_id < FIRST_DYNAMIC_STREAM || stream_id & 3 != 0 {
+            return None;
+        }
+
+        let index = (stream_id - FIRST_DYNAMIC_STREAM) / STREAM_ID_STEP;
+        let transfer_id = TransferId::try_from(index / STREAMS_PER_TRANSFER + 1).ok()?;
+        let kind = TransferStreamKind::ALL[(index % STREAMS_PER_TRANSFER) as usize];
+        Some((transfer_id, kind))
+    }
+
+    /// Stream ID for a role
+    pub fn stream(&self, kind: TransferStreamKind) -> u64 {
+        match kind {
+            TransferStreamKind::Manifest => self.manifest,
+            TransferStreamKind::Data => self.data,
+            TransferStreamKind::HashCheck => self.hash_check,
+            TransferStreamKind::Resume => self.resume,
+        }
+    }
+
+    /// All stream IDs of this transfer
+    pub fn all(&self) -> [u64; 4] {
+        [self.manifest, self.data, self.hash_check, self.resume]
+    }
+
+    /// Reject a message whose transfer ID does not match the stream it arrived on
+    pub fn check_transfer_id(&self, transfer_id: TransferId) -> Result<()> {
+        if transfer_id != self.transfer_id {
+            return Err(Error::Protocol(format!(
+                "Message for transfer {} received on a stream of transfer {}",
+                transfer_id, self.transfer_id
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Hands out transfer IDs on one connection
+#[derive(Debug, Default)]
+pub struct StreamAllocator {
+    next_transfer: TransferId,
+}
+
+impl StreamAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate the streams of a new transfer
+    ///
+    /// `max_streams_bidi` is the peer's bidirectional stream limit; transfers
+    /// whose streams would exceed it are refused.
+    pub fn allocate(&mut self, max_streams_bidi: u64) -> Result<TransferStreams> {
+        let streams = TransferStreams::for_transfer(self.next_transfer);
+        let highest = streams.all().into_iter().max().unwrap_or(0);
+        if highest / STREAM_ID_STEP >= max_streams_bidi {
+            return Err(Error::Protocol(format!(
+                "Transfer {} would exceed the peer's limit of {} streams",
+                self.next_transfer, max_streams_bidi
+            )));
+        }
+
+        self.next_transfer += 1;
+        Ok(streams)
+    }
+
+    /// Allocate `count` extra data streams for a transfer
+    ///
+    /// Streams are taken four at a time from unused transfer slots.
+    pub fn allocate_data_streams(&mut self, count: usize, max_streams_bidi: u64) -> Result<Vec<u64>> {
+        let mut data_streams = Vec::with_capacity(count);
+        while data_streams.len() < count {
+            let slot = self.allocate(max_streams_bidi)?;
+            data_streams.extend(slot.all().into_iter().take(count - data_streams.len()));
+        }
+        Ok(data_streams)
+    }
+
+    /// Number of transfers allocated so far
+    pub fn allocated(&self) -> u32 {
+        self.next_transfer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_transfer_zero_uses_fixed_streams() {
+        let streams = TransferStreams::for_transfer(0);
+        assert_eq!(streams.all(), [4, 8, 16, 20]);
+        assert_eq!(TransferStreams::lookup(12), None);
+        assert_eq!(TransferStreams::lookup(0), None);
+    }
+
+    #[test]
+    fn test_streams_are_unique_and_reversible() {
+        let mut seen = HashSet::new();
+        for transfer_id in 0..200 {
+            let streams = TransferStreams::for_transfer(transfer_id);
+            for kind in TransferStreamKind::ALL {
+                let stream_id = streams.stream(kind);
+                assert_eq!(stream_id & 3, 0, "not client-initiated bidi");
+                assert!(seen.insert(stream_id), "stream {} reused", stream_id);
+                assert_eq!(TransferStreams::lookup(stream_id), Some((transfer_id, kind)));
+            }
+        }
+        assert_eq!(TransferStreams::lookup(25), None);
+    }
+
+    #[test]
+    fn test_allocator_respects_stream_limit() {
+        let mut allocator = StreamAllocator::new();
+        assert_eq!(allocator.allocate(100).unwrap().transfer_id, 0);
+        assert_eq!(allocator.allocate(100).unwrap().manifest, 24);
+
+        // Transfer 2 ends at stream 52, the 14th bidirectional stream
+        let mut tight = StreamAllocator::new();
+        for _ in 0..3 {
+            tight.allocate(14).unwrap();
+        }
+        assert!(tight.allocate(14).is_err());
+        assert_eq!(tight.allocated(), 3);
+    }
+
+    #[test]
+    fn test_allocate_data_streams() {
+        let mut allocator = StreamAllocator::new();
+        let transfer = allocator.allocate(100).unwrap();
+        let data_streams = allocator.allocate_data_streams(5, 100).unwrap();
+        assert_eq!(data_streams, vec![24, 28, 32, 36, 40]);
+        assert!(!data_streams.contains(&transfer.data));
+
+        // The next transfer starts after the slots used for data
+        assert_eq!(allocator.allocate(100).unwrap().manifest, 56);
+        assert!(allocator.allocate_data_streams(100, 100).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 // Manifest validation
 
 use crate::common::error::{Error, Result};
-use crate::protocol::messages::Manifest;
+use crate::protocol::messages::{ChunkRange, Manifest};
 
 /// BLAKE3 hash size in bytes
 const BLAKE3_HASH_SIZE: usize = 32;
@@ -86,7 +86,11 @@ impl ManifestValidator {
         self.validate_chunk_size(manifest.chunk_size)?;
         self.validate_chunk_count(manifest.file_size, manifest.chunk_size, manifest.total_chunks)?;
         self.validate_file_hash(&manifest.file_hash)?;
-        self.validate_chunk_hashes(&manifest.chunk_hashes, manifest.total_chunks)?;
+        match &manifest.tree_root {
+            Some(root) => self.validate_tree_root(root, &manifest.chunk_hashes)?,
+            None => self.validate_chunk_hashes(&manifest.chunk_hashes, manifest.total_chunks)?,
+        }
+        self.validate_holes(&manifest.holes, manifest.total_chunks)?;
         
         if self.strict_mode {
             self.validate_compression(&manifest.compression)?;
@@ -146,14 +150,8 @@ impl ManifestValidator {
         Ok(())
     }
 
-    /// Validate file size
+    /// Validate file size; empty files are allowed
     pub fn validate_file_size(&self, file_size: u64) -> Result<()> {
-        if file_size == 0 {
-            return Err(Error::Protocol(
-                "File size cannot be zero".to_string()
-            ));
-        }
-
         if file_size > MAX_FILE_SIZE {
             return Err(Error::Protocol(format!(
                 "File size too large: {} bytes (max: {})",
@@ -190,18 +188,14 @@ impl ManifestValidator {
     }
 
     /// Validate chunk count matches file size and chunk size
+    /// 
+    /// An empty file has no chunks.
     pub fn validate_chunk_count(
         &self,
         file_size: u64,
         chunk_size: u32,
         total_chunks: u64,
     ) -> Result<()> {
-        if total_chunks == 0 {
-            return Err(Error::Protocol(
-                "Total chunks cannot be zero".to_string()
-            ));
-        }
-
         // Calculate expected chunk count
         let expected_chunks = (file_size + chunk_size as u64 - 1) / chunk_size as u64;
 
@@ -265,6 +259,46 @@ impl ManifestValidator {
         Ok(())
     }
 
+    /// Validate the hash tree root of a tree-hash manifest
+    pub fn validate_tree_root(&self, tree_root: &[u8], chunk_hashes: &[Vec<u8>]) -> Result<()> {
+        if tree_root.len() != BLAKE3_HASH_SIZE {
+            return Err(Error::Protocol(format!(
+                "Invalid hash tree root size: {} (expected {})",
+                tree_root.len(),
+                BLAKE3_HASH_SIZE
+            )));
+        }
+
+        // Chunks are proven against the root, never listed
+        if !chunk_hashes.is_empty() {
+            return Err(Error::Protocol(
+                "Tree-hash manifest must not list chunk hashes".to_string()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Validate the hole ranges of a sparse file
+    /// 
+    /// Ranges must be non-empty, in order, apart and within the file.
+    pub fn validate_holes(&self, holes: &[ChunkRange], total_chunks: u64) -> Result<()> {
+        let mut next_chunk = 0;
+        for range in holes {
+            if range.chunk_count == 0 || range.first_chunk < next_chunk || range.end_chunk() > total_chunks {
+                return Err(Error::Protocol(format!(
+                    "Invalid hole range: chunks {}..{} (total_chunks={})",
+                    range.first_chunk,
+                    range.end_chunk(),
+                    total_chunks
+                )));
+            }
+            next_chunk = range.end_chunk();
+        }
+
+        Ok(())
+    }
+
     /// Validate compression algorithm
     pub fn validate_compression(&self, compression: &str) -> Result<()> {
         const VALID_COMPRESSION: &[&str] = &["none", "lz4", "lz4hc", "zstd", "lzma2"];
@@ -286,12 +320,6 @@ impl ManifestValidator {
         original_size: Option<u64>,
     ) -> Result<()> {
         if let Some(orig_size) = original_size {
-            if orig_size == 0 {
-                return Err(Error::Protocol(
-                    "Original size cannot be zero".to_string()
-                ));
-            }
-
             if orig_size > MAX_FILE_SIZE {
                 return Err(Error::Protocol(format!(
                     "Original size too large: {} bytes",
@@ -345,6 +373,20 @@ mod tests {
             chunk_hashes: vec![vec![0u8; 32]; 4],
             compression: "none".to_string(),
             original_size: Some(4096),
+            encryption: None,
+            signature: None,
+            tree_root: None,
+            hash_pages: 0,
+            transfer_id: 0,
+            relative_path: String::new(),
+            mode: 0,
+            bundle: None,
+            stripe: None,
+            data_streams: Vec::new(),
+            streaming: false,
+            metadata: None,
+            holes: Vec::new(),
+            conflict_policy: 0,
         }
     }
 
@@ -404,6 +446,26 @@ mod tests {
         // Valid
         assert!(validator.validate_chunk_count(1024, 256, 4).is_ok());
         assert!(validator.validate_chunk_count(1000, 256, 4).is_ok()); // 1000/256 = 3.9... -> 4
+        
+        // Empty files have no chunks, anything else has some
+        assert!(validator.validate_chunk_count(0, 256, 0).is_ok());
+        assert!(validator.validate_chunk_count(0, 256, 1).is_err());
+        assert!(validator.validate_chunk_count(1, 256, 0).is_err());
+    }
+
+    #[test]
+    fn test_invalid_holes() {
+        let validator = ManifestValidator::new();
+        let range = |first_chunk, chunk_count| ChunkRange { first_chunk, chunk_count };
+
+        assert!(validator.validate_holes(&[], 0).is_ok());
+        assert!(validator.validate_holes(&[range(0, 2), range(4, 6)], 10).is_ok());
+
+        // Empty, overlapping, out-of-order and past-the-end ranges
+        assert!(validator.validate_holes(&[range(3, 0)], 10).is_err());
+        assert!(validator.validate_holes(&[range(0, 4), range(3, 2)], 10).is_err());
+        assert!(validator.validate_holes(&[range(5, 1), range(2, 1)], 10).is_err());
+        assert!(validator.validate_holes(&[range(8, 3)], 10).is_err());
     }
 
     #[test]
@@ -478,6 +540,22 @@ mod tests {
         assert!(validator.validate(&manifest).is_err());
     }
 
+    #[test]
+    fn test_tree_manifest_validation() {
+        let validator = ManifestValidator::new();
+        let mut manifest = create_valid_manifest();
+        manifest.tree_root = Some(vec![0u8; 32]);
+
+        // Root and a chunk hash list are mutually exclusive
+        assert!(validator.validate(&manifest).is_err());
+
+        manifest.chunk_hashes = vec![];
+        assert!(validator.validate(&manifest).is_ok());
+
+        manifest.tree_root = Some(vec![0u8; 16]);
+        assert!(validator.validate(&manifest).is_err());
+    }
+
     #[test]
     fn test_quick_validation() {
         let validator = ManifestValidator::new();
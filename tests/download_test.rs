@@ -0,0 +1,70 @@
+// End-to-end test for downloads
+//
+// Runs a `Server` in-process on loopback, stores a file in its upload
+// directory and downloads it with the real client, in chunk order into
+// memory. The server stores below the current directory, so the test runs
+// in a temporary one.
+//
+// Run with: cargo test --test download_test -- --ignored
+
+use sftpx::client::Client;
+use sftpx::common::cert_gen::generate_self_signed_cert;
+use sftpx::common::ClientConfig;
+use sftpx::server::{Server, ServerConfig};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+/// Start a server on a free loopback port
+fn start_server(cert_dir: &Path) -> SocketAddr {
+    let config = ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        cert_path: cert_dir.join("cert.pem").to_string_lossy().into_owned(),
+        key_path: cert_dir.join("key.pem").to_string_lossy().into_owned(),
+        ..ServerConfig::default()
+    };
+
+    let (bound, addr) = mpsc::channel();
+    thread::spawn(move || {
+        let mut server = Server::new(config).expect("server starts");
+        bound.send(server.local_addr().expect("server is bound")).unwrap();
+        let _ = server.run();
+    });
+    addr.recv().expect("server starts")
+}
+
+#[test]
+#[ignore] // Needs loopback networking and TLS
+fn test_download_to_writer() {
+    let work_dir = tempfile::TempDir::new().unwrap();
+    std::env::set_current_dir(work_dir.path()).unwrap();
+    let cert_dir = work_dir.path().join("certs");
+    generate_self_signed_cert("127.0.0.1", Some(cert_dir.to_str().unwrap())).unwrap();
+
+    // Several chunks, more than one read of the data stream each
+    let content: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::create_dir_all("uploads/backups").unwrap();
+    std::fs::write("uploads/backups/site.tar", &content).unwrap();
+
+    let server_addr = start_server(&cert_dir);
+    let config = ClientConfig::new(server_addr, "localhost".to_string())
+        .disable_cert_verification()
+        .with_session_dir(work_dir.path().join("sessions"));
+    let client = Client::new(config);
+
+    let mut output = Vec::new();
+    let written = client.download_file("backups/site.tar").unwrap()
+        .run_receive_to(&mut output)
+        .unwrap();
+    assert_eq!(written, content.len() as u64);
+    assert_eq!(output, content);
+
+    // Files the server does not have, or keeps for itself, are refused
+    for refused in ["backups/missing.tar", ".sftpx/chunk_index.db"] {
+        let mut output = Vec::new();
+        let result = client.download_file(refused).unwrap().run_receive_to(&mut output);
+        assert!(result.is_err(), "{} was served", refused);
+        assert!(output.is_empty());
+    }
+}
@@ -112,6 +112,7 @@ fn test_resume_protocol_messages() {
         received_chunks: vec![0, 1, 2, 3, 4],
         received_bitmap: Some(vec![0xFF, 0x00]),
         last_chunk_id: Some(4),
+        transfer_id: 0,
     };
     
     let encoded = request.encode_to_vec();
@@ -129,6 +130,10 @@ fn test_resume_protocol_messages() {
         missing_chunks: vec![5, 6, 7, 8, 9],
         chunks_remaining: 5,
         error: None,
+        received_bitmap: None,
+        transfer_id: 0,
+        conflict: 0,
+        stored_name: None,
     };
     
     let encoded = response.encode_to_vec();